@@ -0,0 +1,96 @@
+//! Attribute macros for the `sentry-actix` middleware.
+//!
+//! The macros are re-exported by `sentry-actix` with its `macros` feature, and
+//! expand to code referring to it, so this crate is not meant to be used
+//! directly.
+
+#![warn(missing_docs)]
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::quote;
+use syn::{parse_macro_input, parse_quote, AttributeArgs, FnArg, ItemFn, Lit, Meta, NestedMeta};
+
+/// Marks a handler with metadata for the events of its errors.
+///
+/// The attribute accepts `op`, `owner` and `level_on_error`, and adds a
+/// request argument to the handler which records them in the extensions of the
+/// request.  See `sentry_actix::HandlerMetadata`.
+#[proc_macro_attribute]
+pub fn monitored(args: TokenStream, item: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(args as AttributeArgs);
+    let item = parse_macro_input!(item as ItemFn);
+    match expand(args, item) {
+        Ok(tokens) => tokens.into(),
+        Err(error) => error.to_compile_error().into(),
+    }
+}
+
+fn expand(args: AttributeArgs, mut item: ItemFn) -> syn::Result<proc_macro2::TokenStream> {
+    let mut op = quote!(None);
+    let mut owner = quote!(None);
+    let mut level_on_error = quote!(None);
+    for arg in args {
+        let (path, value) = match arg {
+            NestedMeta::Meta(Meta::NameValue(ref pair)) => match pair.lit {
+                Lit::Str(ref value) => (&pair.path, value.clone()),
+                ref lit => return Err(syn::Error::new_spanned(lit, "expected a string")),
+            },
+            ref arg => {
+                return Err(syn::Error::new_spanned(
+                    arg,
+                    "expected `op`, `owner` or `level_on_error` with a string",
+                ))
+            }
+        };
+        if path.is_ident("op") {
+            op = quote!(Some(::std::borrow::Cow::Borrowed(#value)));
+        } else if path.is_ident("owner") {
+            owner = quote!(Some(::std::borrow::Cow::Borrowed(#value)));
+        } else if path.is_ident("level_on_error") {
+            let level = match value.value().to_ascii_lowercase().as_str() {
+                "debug" => quote!(Debug),
+                "info" => quote!(Info),
+                "warning" => quote!(Warning),
+                "error" => quote!(Error),
+                "fatal" => quote!(Fatal),
+                _ => {
+                    return Err(syn::Error::new_spanned(
+                        value,
+                        "expected `debug`, `info`, `warning`, `error` or `fatal`",
+                    ))
+                }
+            };
+            level_on_error = quote!(Some(::sentry_actix::__private::Level::#level));
+        } else {
+            return Err(syn::Error::new_spanned(
+                path,
+                "expected `op`, `owner` or `level_on_error`",
+            ));
+        }
+    }
+    if item.sig.asyncness.is_none() {
+        return Err(syn::Error::new_spanned(
+            item.sig.fn_token,
+            "only async handlers can be monitored",
+        ));
+    }
+
+    // The request is extracted like any other argument, so other attributes such
+    // as `#[get(...)]` see a regular handler whether they expand before or after
+    let req = syn::Ident::new("__sentry_actix_req", Span::call_site());
+    let arg: FnArg = parse_quote!(#req: ::sentry_actix::__private::HttpRequest);
+    item.sig.inputs.insert(0, arg);
+    item.block.stmts.insert(
+        0,
+        parse_quote! {
+            ::sentry_actix::HandlerMetadata {
+                op: #op,
+                owner: #owner,
+                level_on_error: #level_on_error,
+            }
+            .attach(&#req);
+        },
+    );
+    Ok(quote!(#item))
+}
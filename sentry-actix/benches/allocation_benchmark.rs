@@ -0,0 +1,174 @@
+//! Sentry Actix Middleware Allocation Benchmarks
+//!
+//! Run the benchmarks with:
+//!
+//! ```text
+//! $ cargo bench -p sentry-actix --bench allocation_benchmark
+//! ```
+//!
+//! The benchmarks count the heap allocations per request, which do not vary
+//! between runs, for a request with the headers of a browser behind a proxy:
+//! * Building its request data, which the middleware only does for events
+//! * Without the middleware, as a baseline
+//! * With the middleware, for successful and failing requests
+//! * With the middleware, for successful requests without full request data
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use actix_web::http::{HeaderName, HeaderValue};
+use actix_web::test::{call_service, init_service, TestRequest};
+use actix_web::web::BytesMut;
+use actix_web::{error, web, App, Error, HttpResponse};
+use sentry::test::TestTransport;
+use sentry::{ClientOptions, Hub};
+use sentry_actix::{to_sentry_request, RequestDataOptions, Sentry};
+
+/// Counts the heap allocations of the process.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+const ITERATIONS: u64 = 1000;
+
+const HEADERS: &[(&str, &str)] = &[
+    ("host", "api.example.com"),
+    (
+        "user-agent",
+        "Mozilla/5.0 (X11; Linux x86_64) Gecko/20100101",
+    ),
+    ("accept", "application/json"),
+    ("accept-encoding", "gzip, deflate, br"),
+    ("accept-language", "en-US,en;q=0.5"),
+    ("cache-control", "no-cache"),
+    ("connection", "keep-alive"),
+    ("content-type", "application/json"),
+    ("cookie", "session=4f2a; theme=dark"),
+    ("origin", "https://www.example.com"),
+    ("pragma", "no-cache"),
+    ("referer", "https://www.example.com/items"),
+    ("x-forwarded-for", "203.0.113.7"),
+    ("x-forwarded-proto", "https"),
+    ("x-request-id", "0b8e9a4c-5bd1-4a8e-9d1f-3f0d3c9a6c2e"),
+    (
+        "traceparent",
+        "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+    ),
+];
+
+/// Creates a test request with the headers of a browser behind a proxy.
+///
+/// The header values share a single buffer, like those of requests decoded by
+/// the server, so that copying them does not allocate.
+fn request(uri: &str) -> TestRequest {
+    let mut head = BytesMut::new();
+    for (_, value) in HEADERS {
+        head.extend_from_slice(value.as_bytes());
+    }
+    let head = head.split().freeze();
+
+    let mut request = TestRequest::get().uri(uri);
+    let mut start = 0;
+    for (name, value) in HEADERS {
+        let value = head.slice(start..start + value.len());
+        start += value.len();
+        request = request.insert_header((
+            HeaderName::from_static(name),
+            HeaderValue::from_maybe_shared(value).unwrap(),
+        ));
+    }
+    request
+}
+
+/// Creates a hub with a client discarding events in a test transport.
+fn hub() -> Arc<Hub> {
+    let client = sentry::Client::from(ClientOptions {
+        dsn: Some("https://public@sentry.invalid/1".parse().unwrap()),
+        transport: Some(Arc::new(TestTransport::new())),
+        ..Default::default()
+    });
+    Arc::new(Hub::new(Some(Arc::new(client)), Default::default()))
+}
+
+/// Reports the mean number of allocations of `f`.
+fn report<F: FnMut()>(name: &str, mut f: F) {
+    // Warm up lazily initialized state
+    f();
+    let started = ALLOCATIONS.load(Ordering::Relaxed);
+    for _ in 0..ITERATIONS {
+        f();
+    }
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - started;
+    println!(
+        "{:<24} {:>8.1} allocs",
+        name,
+        allocations as f64 / ITERATIONS as f64
+    );
+}
+
+async fn failing() -> Result<HttpResponse, Error> {
+    Err(error::ErrorInternalServerError("Test Error"))
+}
+
+fn main() {
+    let system = actix_rt::System::new();
+
+    let req = request("/items/42?expand=owner&limit=10").to_http_request();
+    report("request_data", || {
+        to_sentry_request(&req, &RequestDataOptions::default());
+    });
+
+    let app = system.block_on(init_service(
+        App::new().route("/items/{id}", web::get().to(HttpResponse::Ok)),
+    ));
+    report("baseline", || {
+        system.block_on(call_service(&app, request("/items/42").to_request()));
+    });
+
+    let app = system.block_on(init_service(
+        App::new()
+            .wrap(Sentry::builder().with_hub(hub()).finish())
+            .route("/items/{id}", web::get().to(HttpResponse::Ok))
+            .route("/failing", web::get().to(failing)),
+    ));
+    report("sentry", || {
+        system.block_on(call_service(&app, request("/items/42").to_request()));
+    });
+    report("sentry_error", || {
+        system.block_on(call_service(&app, request("/failing").to_request()));
+    });
+
+    let app = system.block_on(init_service(
+        App::new()
+            .wrap(
+                Sentry::builder()
+                    .with_hub(hub())
+                    .full_request_data_sample_rate(0.0)
+                    .finish(),
+            )
+            .route("/items/{id}", web::get().to(HttpResponse::Ok)),
+    ));
+    report("sentry_minimal_data", || {
+        system.block_on(call_service(&app, request("/items/42").to_request()));
+    });
+}
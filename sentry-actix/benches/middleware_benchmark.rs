@@ -0,0 +1,74 @@
+//! Sentry Actix Middleware Benchmarks
+//!
+//! Run the benchmarks with:
+//!
+//! ```text
+//! $ cargo bench -p sentry-actix
+//! ```
+//!
+//! The benchmarks measure the overhead the middleware adds to successful requests,
+//! whose request data is only built if an event is captured:
+//! * Without the middleware, as a baseline
+//! * With the middleware, with and without default PII
+
+use std::sync::Arc;
+
+use actix_web::test::{call_service, init_service, TestRequest};
+use actix_web::{web, App, HttpResponse};
+use criterion::{criterion_group, criterion_main, Criterion};
+use sentry::test::TestTransport;
+use sentry::{ClientOptions, Hub};
+use sentry_actix::Sentry;
+
+/// Creates a test request with a realistic set of headers.
+fn request() -> TestRequest {
+    TestRequest::get()
+        .uri("/items/42?expand=owner&limit=10")
+        .insert_header(("host", "api.example.com"))
+        .insert_header((
+            "user-agent",
+            "Mozilla/5.0 (X11; Linux x86_64) Gecko/20100101",
+        ))
+        .insert_header(("accept", "application/json"))
+        .insert_header(("accept-encoding", "gzip, deflate, br"))
+        .insert_header(("x-forwarded-for", "203.0.113.7"))
+}
+
+/// Creates a hub with a client discarding events in a test transport.
+fn hub(send_default_pii: bool) -> Arc<Hub> {
+    let client = sentry::Client::from(ClientOptions {
+        dsn: Some("https://public@sentry.invalid/1".parse().unwrap()),
+        transport: Some(Arc::new(TestTransport::new())),
+        send_default_pii,
+        ..Default::default()
+    });
+    Arc::new(Hub::new(Some(Arc::new(client)), Default::default()))
+}
+
+fn middleware_benchmark(c: &mut Criterion) {
+    let system = actix_rt::System::new();
+    let mut group = c.benchmark_group("middleware");
+
+    let app = system.block_on(init_service(
+        App::new().route("/items/{id}", web::get().to(HttpResponse::Ok)),
+    ));
+    group.bench_function("baseline", |b| {
+        b.iter(|| system.block_on(call_service(&app, request().to_request())))
+    });
+
+    for &(name, send_default_pii) in [("sentry", false), ("sentry_pii", true)].iter() {
+        let app = system.block_on(init_service(
+            App::new()
+                .wrap(Sentry::builder().with_hub(hub(send_default_pii)).finish())
+                .route("/items/{id}", web::get().to(HttpResponse::Ok)),
+        ));
+        group.bench_function(name, |b| {
+            b.iter(|| system.block_on(call_service(&app, request().to_request())))
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, middleware_benchmark);
+criterion_main!(benches);
@@ -0,0 +1,29 @@
+use std::env;
+use std::io;
+
+use actix_web::{App, HttpServer};
+use sentry::types::Dsn;
+
+#[actix_web::main]
+async fn main() -> io::Result<()> {
+    let dsn: Dsn = env::var("SENTRY_DSN")
+        .expect("SENTRY_DSN must be set")
+        .parse()
+        .expect("SENTRY_DSN must be a valid DSN");
+
+    let addr = "127.0.0.1:3001";
+
+    println!("Tunneling envelopes on http://{}/tunnel", addr);
+
+    HttpServer::new(move || {
+        App::new().route(
+            "/tunnel",
+            sentry_actix::tunnel::envelope_endpoint(vec![dsn.clone()]),
+        )
+    })
+    .bind(addr)?
+    .run()
+    .await?;
+
+    Ok(())
+}
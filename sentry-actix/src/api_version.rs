@@ -0,0 +1,221 @@
+//! Tagging events with the API version of requests.
+
+use actix_web::http::{header, HeaderMap, HeaderName};
+
+/// The longest version recorded, as tag values are limited in length.
+const MAX_VERSION_LENGTH: usize = 64;
+
+/// Where the API version of requests is read from.
+///
+/// The version is recorded as the `api_version` tag of the events of a request,
+/// see [`SentryBuilder::api_version`](crate::SentryBuilder::api_version).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ApiVersionSource {
+    /// A vendor media type of the `Accept` header, as in
+    /// `application/vnd.myapp.v3+json`, or its `version` parameter, as in
+    /// `application/vnd.myapp+json; version=3`.
+    ///
+    /// The first media type of the `Accept` headers with a version is used.
+    AcceptVendor,
+    /// The segment of the path at an index, as `v2` in `/api/v2/items` at index
+    /// `1`.  Segments that do not look like a version, as `v2` or `2.1` do, are
+    /// ignored.
+    PathSegment(usize),
+    /// The value of a header, as in `X-Api-Version: 2024-01-01`.
+    Header(HeaderName),
+}
+
+impl ApiVersionSource {
+    /// Reads the API version of a request.
+    pub(crate) fn extract(&self, path: &str, headers: &HeaderMap) -> Option<String> {
+        let version = match self {
+            ApiVersionSource::AcceptVendor => headers
+                .get_all(header::ACCEPT)
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(','))
+                .find_map(accept_version)?,
+            ApiVersionSource::PathSegment(index) => {
+                let segment = path.split('/').filter(|s| !s.is_empty()).nth(*index)?;
+                if !is_version(segment) {
+                    return None;
+                }
+                segment.to_owned()
+            }
+            ApiVersionSource::Header(name) => {
+                let value = headers.get(name)?.to_str().ok()?.trim();
+                if value.is_empty() {
+                    return None;
+                }
+                value.to_owned()
+            }
+        };
+        Some(truncate(version))
+    }
+}
+
+/// Reads the version of a vendor media type of an `Accept` header.
+fn accept_version(media_range: &str) -> Option<String> {
+    let mut parts = media_range.split(';');
+    let media_type = parts.next()?.trim();
+    let subtype = media_type.splitn(2, '/').nth(1)?;
+    let vendor = subtype.get(..4)?;
+    if !vendor.eq_ignore_ascii_case("vnd.") {
+        return None;
+    }
+    // The structured syntax suffix, as in `+json`, is not part of the name
+    let name = subtype.split('+').next().unwrap_or_default();
+    if let Some(version) = name.split('.').skip(1).find(|part| is_version(part)) {
+        return Some(version.to_owned());
+    }
+    parts.find_map(|param| {
+        let mut pair = param.splitn(2, '=');
+        let key = pair.next()?.trim();
+        let value = pair.next()?.trim().trim_matches('"');
+        if key.eq_ignore_ascii_case("version") && !value.is_empty() {
+            Some(value.to_owned())
+        } else {
+            None
+        }
+    })
+}
+
+/// Returns whether a segment looks like a version, as in `v2`, `V2.1` or `2`.
+fn is_version(segment: &str) -> bool {
+    let number = segment
+        .strip_prefix('v')
+        .or_else(|| segment.strip_prefix('V'))
+        .unwrap_or(segment);
+    !number.is_empty()
+        && number.starts_with(|c: char| c.is_ascii_digit())
+        && number.chars().all(|c| c.is_ascii_digit() || c == '.')
+}
+
+fn truncate(mut version: String) -> String {
+    if version.len() > MAX_VERSION_LENGTH {
+        let mut end = MAX_VERSION_LENGTH;
+        while !version.is_char_boundary(end) {
+            end -= 1;
+        }
+        version.truncate(end);
+    }
+    version
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::HeaderValue;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{web, App};
+    use futures::executor::block_on;
+    use sentry_core::Hub;
+
+    use super::*;
+    use crate::Sentry;
+
+    fn accept(values: &[&'static str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(header::ACCEPT, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn test_accept_vendor() {
+        let source = ApiVersionSource::AcceptVendor;
+        let extract = |values: &[&'static str]| source.extract("/", &accept(values));
+
+        assert_eq!(
+            extract(&["application/vnd.myapp.v3+json"]).as_deref(),
+            Some("v3")
+        );
+        assert_eq!(
+            extract(&["application/vnd.myapp+json; version=2.1"]).as_deref(),
+            Some("2.1")
+        );
+        assert_eq!(
+            extract(&["text/html, application/vnd.myapp.v2+json;q=0.9"]).as_deref(),
+            Some("v2")
+        );
+        assert_eq!(
+            extract(&["application/json", "application/VND.myapp.v4"]).as_deref(),
+            Some("v4")
+        );
+        assert_eq!(extract(&["application/vnd.myapp+json"]), None);
+        assert_eq!(extract(&["application/json; version=1"]), None);
+        assert_eq!(extract(&[]), None);
+    }
+
+    #[test]
+    fn test_path_segment() {
+        let headers = HeaderMap::new();
+        let extract =
+            |index: usize, path: &str| ApiVersionSource::PathSegment(index).extract(path, &headers);
+
+        assert_eq!(extract(1, "/api/v2/items").as_deref(), Some("v2"));
+        assert_eq!(extract(0, "/2.1/items").as_deref(), Some("2.1"));
+        assert_eq!(extract(1, "/api/items/42"), None);
+        assert_eq!(extract(1, "/api/vendors"), None);
+        assert_eq!(extract(3, "/api/v2"), None);
+    }
+
+    #[test]
+    fn test_header() {
+        let source = ApiVersionSource::Header(HeaderName::from_static("x-api-version"));
+        let mut headers = HeaderMap::new();
+        assert_eq!(source.extract("/", &headers), None);
+
+        headers.insert(
+            HeaderName::from_static("x-api-version"),
+            HeaderValue::from_static(" 2024-01-01 "),
+        );
+        assert_eq!(source.extract("/", &headers).as_deref(), Some("2024-01-01"));
+
+        let long = "1".repeat(100);
+        headers.insert(
+            HeaderName::from_static("x-api-version"),
+            HeaderValue::from_str(&long).unwrap(),
+        );
+        assert_eq!(
+            source.extract("/", &headers).unwrap().len(),
+            MAX_VERSION_LENGTH
+        );
+    }
+
+    /// Ensures events of requests are tagged with their API version.
+    #[actix_rt::test]
+    async fn test_api_version_tag() {
+        let events = sentry::test::with_captured_events(|| {
+            block_on(async {
+                async fn failing() -> Result<String, actix_web::Error> {
+                    Err(actix_web::error::ErrorInternalServerError("Test Error"))
+                }
+
+                let app = init_service(
+                    App::new()
+                        .wrap(
+                            Sentry::builder()
+                                .with_hub(Hub::current())
+                                .api_version(ApiVersionSource::AcceptVendor)
+                                .finish(),
+                        )
+                        .route("/items", web::get().to(failing)),
+                )
+                .await;
+
+                for accept in &["application/vnd.myapp.v3+json", "application/json"] {
+                    let req = TestRequest::get()
+                        .uri("/items")
+                        .insert_header((header::ACCEPT, *accept))
+                        .to_request();
+                    let res = call_service(&app, req).await;
+                    assert!(res.status().is_server_error());
+                }
+            })
+        });
+
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].tags["api_version"], "v3");
+        assert!(!events[1].tags.contains_key("api_version"));
+    }
+}
@@ -0,0 +1,194 @@
+//! Capturing application errors before actix erases their type.
+
+use std::error::Error;
+use std::future::Future;
+
+use actix_web::dev::Handler;
+use actix_web::{FromRequest, HttpRequest, Responder, ResponseError};
+use futures_util::future::LocalBoxFuture;
+use futures_util::FutureExt;
+
+use sentry_core::types::Uuid;
+use sentry_core::{event_from_error, Hub};
+
+use crate::capture::SentryClassify;
+
+/// Marks a request whose error was captured by [`capture_app_error`].
+pub(crate) struct AppErrorCaptured(pub(crate) Uuid);
+
+/// Captures an application error of a request on the current hub.
+///
+/// The event is created from the error itself rather than from the
+/// `actix_web::Error` it is converted into, so its exception has the type of the
+/// error, such as the variant of an error enum, and its chain of sources.  The
+/// level and tags of the event are those of the [`SentryClassify`]
+/// implementation of the error.  The status of its response is recorded as the
+/// `http.status_code` tag, and the event is tagged with the
+/// `sentry_actix.capture_source` `app_error`.
+///
+/// The request is marked as captured, so that the [`Sentry`](crate::Sentry)
+/// middleware does not capture the error again when the handler returns it.
+/// Errors are captured regardless of their status.
+pub fn capture_app_error<E>(req: &HttpRequest, error: &E) -> Uuid
+where
+    E: SentryClassify + ResponseError + Error,
+{
+    let mut event = event_from_error(error);
+    event.level = error.level();
+    event.tags.extend(error.tags());
+    event
+        .tags
+        .insert("sentry_actix.capture_source".into(), "app_error".into());
+    event.tags.insert(
+        "http.status_code".into(),
+        error.status_code().as_str().into(),
+    );
+    let event_id = Hub::current().capture_event(event);
+    req.extensions_mut().insert(AppErrorCaptured(event_id));
+    event_id
+}
+
+/// Wraps a handler to capture its errors before they are converted.
+///
+/// The wrapped handler extracts the request along with the arguments of the
+/// handler, and captures the errors it returns with [`capture_app_error`].
+///
+/// # Example
+///
+/// ```
+/// use actix_web::{web, App, HttpResponse, ResponseError};
+/// use sentry_actix::{wrap_handler, SentryClassify};
+/// use sentry::Level;
+///
+/// #[derive(Debug, thiserror::Error)]
+/// enum AppError {
+///     #[error("item {0} is out of stock")]
+///     OutOfStock(u32),
+/// }
+///
+/// impl ResponseError for AppError {}
+///
+/// impl SentryClassify for AppError {
+///     fn level(&self) -> Level {
+///         Level::Warning
+///     }
+/// }
+///
+/// async fn order(id: web::Path<u32>) -> Result<HttpResponse, AppError> {
+///     Err(AppError::OutOfStock(id.into_inner()))
+/// }
+///
+/// let app = App::new()
+///     .wrap(sentry_actix::Sentry::new())
+///     .route("/items/{id}/order", web::post().to(wrap_handler(order)));
+/// ```
+pub fn wrap_handler<F, T, R, O, E>(
+    handler: F,
+) -> impl Fn(HttpRequest, T) -> LocalBoxFuture<'static, Result<O, E>> + Clone + 'static
+where
+    F: Handler<T, R>,
+    T: FromRequest + 'static,
+    R: Future<Output = Result<O, E>> + 'static,
+    Result<O, E>: Responder,
+    E: SentryClassify + ResponseError + Error + 'static,
+{
+    move |req: HttpRequest, args: T| {
+        let fut = handler.call(args);
+        async move {
+            let result = fut.await;
+            if let Err(ref error) = result {
+                capture_app_error(&req, error);
+            }
+            result
+        }
+        .boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::fmt;
+
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{web, App, HttpResponse};
+    use futures::executor::block_on;
+    use sentry_core::Level;
+
+    use super::*;
+    use crate::Sentry;
+
+    #[derive(Debug)]
+    enum AppError {
+        OutOfStock { id: u32 },
+    }
+
+    impl fmt::Display for AppError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                AppError::OutOfStock { id } => write!(f, "item {} is out of stock", id),
+            }
+        }
+    }
+
+    impl Error for AppError {}
+
+    impl ResponseError for AppError {
+        fn status_code(&self) -> StatusCode {
+            StatusCode::SERVICE_UNAVAILABLE
+        }
+    }
+
+    impl SentryClassify for AppError {
+        fn level(&self) -> Level {
+            Level::Warning
+        }
+
+        fn tags(&self) -> BTreeMap<String, String> {
+            let mut tags = BTreeMap::new();
+            tags.insert("domain".into(), "orders".into());
+            tags
+        }
+    }
+
+    async fn order(id: web::Path<u32>, _body: String) -> Result<HttpResponse, AppError> {
+        Err(AppError::OutOfStock {
+            id: id.into_inner(),
+        })
+    }
+
+    /// Ensures errors of wrapped handlers are captured once, with their concrete type.
+    #[actix_rt::test]
+    async fn test_wrap_handler() {
+        let events = sentry::test::with_captured_events(|| {
+            block_on(async {
+                let app = init_service(
+                    App::new()
+                        .wrap(Sentry::builder().with_hub(Hub::current()).finish())
+                        .route("/items/{id}/order", web::post().to(wrap_handler(order))),
+                )
+                .await;
+
+                let req = TestRequest::post().uri("/items/42/order").to_request();
+                let res = call_service(&app, req).await;
+                assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+            })
+        });
+
+        assert_eq!(events.len(), 1);
+        let event = &events[0];
+        assert_eq!(event.exception.values.len(), 1);
+        assert_eq!(event.exception.values[0].ty, "OutOfStock");
+        assert_eq!(
+            event.exception.values[0].value.as_deref(),
+            Some("item 42 is out of stock")
+        );
+        assert_eq!(event.level, Level::Warning);
+        assert_eq!(event.tags["domain"], "orders");
+        assert_eq!(event.tags["sentry_actix.capture_source"], "app_error");
+        assert_eq!(event.tags["http.status_code"], "503");
+        assert_eq!(event.transaction.as_deref(), Some("/items/{id}/order"));
+        assert!(event.request.is_some());
+    }
+}
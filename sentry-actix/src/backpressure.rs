@@ -0,0 +1,117 @@
+//! Tracking how long the wrapped service stays unready.
+//!
+//! Saturated workers show up as the inner service returning `Pending` from
+//! `poll_ready`.  The middleware records when this starts and ends, and events
+//! captured shortly after carry the duration as the `service_unready_ms` extra.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// How long after the service became ready again events carry the duration.
+pub(crate) const UNREADY_WINDOW: Duration = Duration::from_secs(10);
+
+/// Lock-free bookkeeping of the periods in which the service was unready.
+///
+/// Instants are stored as microseconds since the creation of the tracker, offset
+/// by one so that zero means unset.
+#[derive(Debug)]
+pub(crate) struct Backpressure {
+    epoch: Instant,
+    pending_since: AtomicU64,
+    ready_at: AtomicU64,
+    unready_ms: AtomicU64,
+}
+
+impl Backpressure {
+    /// Creates a tracker measuring instants from `epoch` on.
+    pub(crate) fn new(epoch: Instant) -> Self {
+        Backpressure {
+            epoch,
+            pending_since: AtomicU64::new(0),
+            ready_at: AtomicU64::new(0),
+            unready_ms: AtomicU64::new(0),
+        }
+    }
+
+    fn offset(&self, now: Instant) -> u64 {
+        now.saturating_duration_since(self.epoch).as_micros() as u64 + 1
+    }
+
+    /// Records that the service returned `Pending`.
+    ///
+    /// Only the first `Pending` of a period starts it.
+    pub(crate) fn record_pending(&self, now: Instant) {
+        let offset = self.offset(now);
+        self.pending_since
+            .compare_exchange(0, offset, Ordering::Relaxed, Ordering::Relaxed)
+            .ok();
+    }
+
+    /// Records that the service is ready, ending the current period if any.
+    pub(crate) fn record_ready(&self, now: Instant) {
+        // Avoid contending on the common path of a service that was never unready
+        if self.pending_since.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let since = self.pending_since.swap(0, Ordering::Relaxed);
+        if since == 0 {
+            return;
+        }
+        let offset = self.offset(now);
+        let unready = Duration::from_micros(offset.saturating_sub(since));
+        self.unready_ms
+            .store(unready.as_millis() as u64, Ordering::Relaxed);
+        self.ready_at.store(offset, Ordering::Relaxed);
+    }
+
+    /// Returns how long the service has recently been unready, in milliseconds.
+    ///
+    /// While the service is unready, this is the duration of the ongoing period.
+    /// Afterwards, it is the duration of the last period for [`UNREADY_WINDOW`].
+    pub(crate) fn unready_ms(&self, now: Instant) -> Option<u64> {
+        let offset = self.offset(now);
+        let since = self.pending_since.load(Ordering::Relaxed);
+        if since != 0 {
+            return Some(Duration::from_micros(offset.saturating_sub(since)).as_millis() as u64);
+        }
+        let ready_at = self.ready_at.load(Ordering::Relaxed);
+        let elapsed = Duration::from_micros(offset.saturating_sub(ready_at));
+        if ready_at == 0 || elapsed > UNREADY_WINDOW {
+            return None;
+        }
+        Some(self.unready_ms.load(Ordering::Relaxed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backpressure() {
+        let start = Instant::now();
+        let backpressure = Backpressure::new(start);
+        let at = |ms| start + Duration::from_millis(ms);
+
+        backpressure.record_ready(at(0));
+        assert_eq!(backpressure.unready_ms(at(0)), None);
+
+        // Repeated pending polls do not restart the period
+        backpressure.record_pending(at(100));
+        backpressure.record_pending(at(200));
+        assert_eq!(backpressure.unready_ms(at(250)), Some(150));
+
+        backpressure.record_ready(at(400));
+        backpressure.record_ready(at(500));
+        assert_eq!(backpressure.unready_ms(at(1_000)), Some(300));
+
+        // The duration expires after the window
+        let expired = 400 + UNREADY_WINDOW.as_millis() as u64 + 1;
+        assert_eq!(backpressure.unready_ms(at(expired)), None);
+
+        // A new period replaces the last one
+        backpressure.record_pending(at(expired));
+        backpressure.record_ready(at(expired + 50));
+        assert_eq!(backpressure.unready_ms(at(expired + 60)), Some(50));
+    }
+}
@@ -0,0 +1,935 @@
+//! Configuration of the middleware.
+
+use std::borrow::Cow;
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::dev::ServiceRequest;
+use actix_web::http::StatusCode;
+use actix_web::ResponseError;
+use futures_util::future::LocalBoxFuture;
+
+use sentry_core::protocol::{User, Value};
+use sentry_core::{Hub, Scope, SessionMode};
+use thiserror::Error;
+
+use crate::api_version::ApiVersionSource;
+#[cfg(any(test, feature = "test-util"))]
+use crate::backpressure::Backpressure;
+use crate::capture::{
+    classifier, error_type_fn, CaptureLimit, CaptureMode, OpaqueRoutes, SentryClassify,
+};
+#[cfg(any(test, feature = "test-util"))]
+use crate::capture::{CaptureRateLimit, ABORTED_CAPTURE_INTERVAL, POLL_READY_CAPTURE_INTERVAL};
+#[cfg(any(test, feature = "test-util"))]
+use crate::clock::{Clock, ManualClock, SequentialIds};
+use crate::event_link::EventLink;
+use crate::helpers::ProcessorPosition;
+#[cfg(feature = "jwt")]
+use crate::jwt::UserJwtConfig;
+use crate::middleware::{Config, Sentry};
+use crate::normalize::PathNormalizer;
+#[cfg(feature = "rate-limits")]
+use crate::rate_limits::RateLimits;
+use crate::redact::Redactor;
+use crate::request::{parse_summary_format, EnvProfile, IpPolicy, RequestUrl};
+use crate::sessions::RouteSessionCounters;
+
+/// A helper construct that can be used to reconfigure and build the middleware.
+pub struct SentryBuilder {
+    pub(crate) config: Config,
+}
+
+impl SentryBuilder {
+    /// Finishes the building and returns a middleware
+    ///
+    /// # Panics
+    ///
+    /// Panics if the configuration is invalid, see [`build`](Self::build).
+    pub fn finish(self) -> Sentry {
+        self.build().unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Validates the configuration and returns a middleware.
+    pub fn build(self) -> Result<Sentry, SentryConfigError> {
+        let mut config = self.config;
+        if !is_valid_sample_rate(config.error_sample_rate) {
+            return Err(SentryConfigError::InvalidSampleRate(
+                config.error_sample_rate,
+            ));
+        }
+        if !is_valid_sample_rate(config.full_request_data_sample_rate) {
+            return Err(SentryConfigError::InvalidRequestDataSampleRate(
+                config.full_request_data_sample_rate,
+            ));
+        }
+        for (&status, &rate) in config.status_sample_rates.iter() {
+            if !status.is_server_error() {
+                return Err(SentryConfigError::UnsampledStatus(status));
+            }
+            if !is_valid_sample_rate(rate) {
+                return Err(SentryConfigError::InvalidStatusSampleRate(status, rate));
+            }
+        }
+        if config.tags.keys().any(|key| key.is_empty()) {
+            return Err(SentryConfigError::EmptyTagKey);
+        }
+        if config.extra.keys().any(|key| key.is_empty()) {
+            return Err(SentryConfigError::EmptyExtraKey);
+        }
+        if let Some(format) = config.request_summary_format.take() {
+            config.request_summary = Some(Arc::new(parse_summary_format(&format)?));
+        }
+        Ok(Sentry::from_config(config))
+    }
+
+    /// Reconfigures the middleware so that it uses a specific hub instead of the default one.
+    ///
+    /// Requests are not handled on this hub itself, but on hubs derived from its
+    /// top scope, so that requests handled at the same time never share the
+    /// scope holding their request data, whether or not the hub is bound.
+    pub fn with_hub(mut self, hub: Arc<Hub>) -> Self {
+        self.config.hub = Some(hub);
+        self.config.hub_fn = None;
+        self
+    }
+
+    /// Reconfigures the middleware so that it selects the hub for each request.
+    ///
+    /// The function is called at the start of every request, and the request hub
+    /// is derived from the hub it returns.  This allows replacing the hub, for
+    /// instance to rotate the DSN, after the middleware has been constructed.
+    pub fn with_hub_fn<F>(mut self, f: F) -> Self
+    where
+        F: Fn() -> Arc<Hub> + Send + Sync + 'static,
+    {
+        self.config.hub = None;
+        self.config.hub_fn = Some(Arc::new(f));
+        self
+    }
+
+    /// Names the transactions of requests not matching a route after their path.
+    ///
+    /// The path is normalized with the given normalizer, so that identifiers do
+    /// not create a distinct transaction for every request.  Requests matching a
+    /// route pattern keep the pattern as their name.
+    pub fn normalize_unmatched_paths(mut self, normalizer: PathNormalizer) -> Self {
+        self.config.path_normalizer = Some(Arc::new(normalizer));
+        self
+    }
+
+    /// Starts the scope of every request from the given scope.
+    ///
+    /// By default, request scopes start from the top scope of the hub.  With a base
+    /// scope, its tags, extras, contexts, user and level apply instead, while the
+    /// client of the hub is still used.  Tags and extras of the middleware and the
+    /// values it sets per request, such as the transaction, take precedence over
+    /// those of the base scope.  A user set while handling the request replaces the
+    /// base user, and the IP address derived from the request is only added if the
+    /// base user has none.
+    pub fn with_base_scope(mut self, scope: Scope) -> Self {
+        self.config.base_scope = Some(Arc::new(scope));
+        self
+    }
+
+    /// Binds the request hub as the current hub while the service is polled.
+    ///
+    /// When disabled, the middleware still captures its own events on the request
+    /// hub with the full request context, but `Hub::current()` is left alone in
+    /// handlers.  Events captured there, for instance with `sentry::capture_message`,
+    /// go to the hub of the thread without the request context, and features
+    /// relying on the current hub, such as [`named`](crate::named) handlers and
+    /// [`set_measurement`](crate::set_measurement), have no effect.  The default is
+    /// `true`.
+    pub fn bind_hub(mut self, val: bool) -> Self {
+        self.config.bind_hub = val;
+        self
+    }
+
+    /// Reconfigures the middleware so that it uses a specific hub instead of the default one.
+    pub fn with_default_hub(mut self) -> Self {
+        self.config.hub = None;
+        self.config.hub_fn = None;
+        self
+    }
+
+    /// If configured the sentry id is attached to a X-Sentry-Event header.
+    pub fn emit_header(mut self, val: bool) -> Self {
+        self.config.emit_header = val;
+        self
+    }
+
+    /// Uses the hyphenated UUID format for the X-Sentry-Event header.
+    ///
+    /// The default is the simple format without hyphens.
+    pub fn emit_header_hyphenated(mut self, val: bool) -> Self {
+        self.config.emit_header_hyphenated = val;
+        self
+    }
+
+    /// Attaches a link to the captured event to an X-Sentry-Event-Url header.
+    ///
+    /// The header is added alongside the X-Sentry-Event header, if any, to
+    /// responses with a captured event, unless the client has no DSN.  See
+    /// [`EventLink`](crate::EventLink) for the formats.  The default is to not
+    /// emit the header.
+    pub fn emit_event_link(mut self, link: Option<EventLink>) -> Self {
+        self.config.event_link = link.map(Arc::new);
+        self
+    }
+
+    /// Enables or disables error reporting.
+    ///
+    /// The default is to report all errors.
+    pub fn capture_server_errors(mut self, val: bool) -> Self {
+        self.config.capture_server_errors = val;
+        self
+    }
+
+    /// Captures errors returned by `poll_ready` of the wrapped service.
+    ///
+    /// Such errors occur before a request is handled, so they are captured on the
+    /// hub of the middleware without request context, tagged with the
+    /// `sentry_actix.capture_source` `poll_ready` and the type of the service as
+    /// the `service` extra.  At most one event is captured per minute.  The default
+    /// is to not capture them.
+    pub fn capture_poll_ready_errors(mut self, val: bool) -> Self {
+        self.config.capture_poll_ready_errors = val;
+        self
+    }
+
+    /// Captures a warning for requests dropped before their service completed.
+    ///
+    /// Workers stopping during a graceful shutdown drop their in-flight requests,
+    /// which then fail without an error to capture.  Such requests are captured
+    /// as a warning with the request data, tagged with the
+    /// `sentry_actix.capture_source` `aborted`.  Requests of clients that
+    /// disconnect are dropped as well.  At most one event is captured per minute.
+    /// The default is to not capture them.
+    pub fn capture_aborted_requests(mut self, val: bool) -> Self {
+        self.config.capture_aborted_requests = val;
+        self
+    }
+
+    /// Diagnoses errors whose type was erased by the error they are wrapped in.
+    ///
+    /// Errors such as those created with `io::Error::new` are captured with the
+    /// type of their wrapper, such as `Custom`.  When such an error has no source
+    /// either, its event is marked with the `capture_quality` extra `opaque`, and
+    /// an info event naming the route is captured the first time a route fails
+    /// with one.  The default is to not diagnose errors.
+    pub fn diagnose_opaque_errors(mut self, val: bool) -> Self {
+        self.config.opaque_routes = if val {
+            Some(Arc::new(OpaqueRoutes::default()))
+        } else {
+            None
+        };
+        self
+    }
+
+    /// Captures `405 Method Not Allowed` responses as info events.
+    ///
+    /// Such responses usually carry no error, for instance when a request does not
+    /// match the method guards of a resource.  The events are tagged with the
+    /// attempted `http.method` and, if the response has an `Allow` header, the
+    /// `http.allowed_methods`.  The default is to not capture them.
+    pub fn capture_method_not_allowed(mut self, val: bool) -> Self {
+        self.config.capture_method_not_allowed = val;
+        self
+    }
+
+    /// Treats `text/event-stream` responses as long-lived.
+    ///
+    /// Server-Sent Events endpoints respond with headers right away and then
+    /// stream for as long as the client stays connected.  Their errors are
+    /// captured when the headers are sent, even in deferred capture mode, and the
+    /// session of the request ends then.  Errors of the stream itself are captured
+    /// when they occur, tagged with `long_lived_response` and carrying the time
+    /// since the request started as the `duration_ms` extra.  The `stream_error`
+    /// tag tells clients going away, captured as `client_cancelled` at the info
+    /// level, from failures of the stream, captured as `write_failed`.  The default
+    /// is to handle them like other responses.
+    pub fn detect_event_streams(mut self, val: bool) -> Self {
+        self.config.detect_event_streams = val;
+        self
+    }
+
+    /// Sets the CGI-style variables added to the `env` of the request data.
+    ///
+    /// The default is [`EnvProfile::Minimal`].
+    pub fn env_profile(mut self, profile: EnvProfile) -> Self {
+        self.config.env_profile = profile;
+        self
+    }
+
+    /// Sets how the addresses of clients are recorded if `send_default_pii` is enabled.
+    ///
+    /// This applies to the request data, the IP address of the user and the
+    /// request summary.  The default is [`IpPolicy::Full`].
+    pub fn ip_policy(mut self, policy: IpPolicy) -> Self {
+        self.config.ip_policy = policy;
+        self
+    }
+
+    /// Adds a `routing` context to the events of captured errors.
+    ///
+    /// The context holds the matched route `pattern` and `name`, the `mount_path`
+    /// of the scopes enclosing the middleware, the raw `path` and `query`, redacted
+    /// like the URL, and whether the request fell through to a `default_service`.
+    /// Routes are looked up without evaluating guards, so a pattern differing from
+    /// the transaction of the event hints at a guard ordering issue.  The default
+    /// is to not add the context.
+    pub fn routing_context(mut self, val: bool) -> Self {
+        self.config.routing_context = val;
+        self
+    }
+
+    /// Reads the time from the given clock instead of the system clock.
+    ///
+    /// The clock is used for the durations attached to events, rate limits and the
+    /// tracking of unready services.  The hang detection waits on the timers of the
+    /// runtime, which tests can pause instead.
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn with_clock(mut self, clock: ManualClock) -> Self {
+        let now = clock.now();
+        self.config.poll_ready_rate_limit =
+            Arc::new(CaptureRateLimit::new(POLL_READY_CAPTURE_INTERVAL, now));
+        self.config.aborted_rate_limit =
+            Arc::new(CaptureRateLimit::new(ABORTED_CAPTURE_INTERVAL, now));
+        self.config.backpressure = Arc::new(Backpressure::new(now));
+        self.config.clock = Arc::new(clock);
+        self
+    }
+
+    /// Assigns sequential ids to the events captured by the middleware.
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn with_sequential_ids(mut self, ids: SequentialIds) -> Self {
+        self.config.ids = Arc::new(ids);
+        self
+    }
+
+    /// Adds breadcrumbs for the events captured on the request hub to later events.
+    ///
+    /// Each event captured during a request, including those captured manually,
+    /// is recorded as a breadcrumb with the `sentry` category, so that later events
+    /// of the same request show the chain of captures.  The default is `true`.
+    pub fn breadcrumb_captures(mut self, val: bool) -> Self {
+        self.config.breadcrumb_captures = val;
+        self
+    }
+
+    /// Collapses identical messages captured on the request hub into single events.
+    ///
+    /// Messages with the same level, message and fingerprint captured while the
+    /// request is handled are sent as one event once the handler has completed,
+    /// with the number of captures as the `occurrences` extra.  Events with
+    /// exceptions are never collapsed.  As this delays the capture of messages
+    /// until the end of the request, the default is `false`.
+    pub fn aggregate_repeated_messages(mut self, val: bool) -> Self {
+        self.config.aggregate_repeated_messages = val;
+        self
+    }
+
+    /// Sets the path in the URL of request data when inner middlewares rewrote it.
+    ///
+    /// The path is compared once the wrapped service has returned its response,
+    /// so events captured before, such as those captured by handlers, keep the
+    /// path as received.  The default is [`RequestUrl::Effective`].
+    pub fn request_url(mut self, request_url: RequestUrl) -> Self {
+        self.config.request_url = request_url;
+        self
+    }
+
+    /// Counts the outcomes of request sessions by route.
+    ///
+    /// Release Health aggregates sessions per release only.  With this option,
+    /// each request session is additionally counted as ok, errored or crashed
+    /// under the transaction name of its request, which can be read with
+    /// [`Sentry::route_session_stats`], for instance to export them as metrics.
+    /// Sessions of routes beyond the first `max_routes` are counted under
+    /// [`OTHER_ROUTE`](crate::OTHER_ROUTE).  Requests without a session, as configured with
+    /// [`session_mode`](Self::session_mode), are not counted.
+    pub fn route_session_stats(mut self, max_routes: usize) -> Self {
+        self.config.route_sessions = Some(Arc::new(RouteSessionCounters::new(max_routes)));
+        self
+    }
+
+    /// Fails the creation of services if configured features would have no effect.
+    ///
+    /// Features such as [`emit_header`](Self::emit_header) do nothing without an
+    /// enabled client on the hub of the middleware, and request sessions are not
+    /// tracked if the client disables `auto_session_tracking`.  Such features are
+    /// reported once to the debug log of the SDK when the middleware creates its
+    /// services.  In strict mode the creation of the services fails instead, so
+    /// that the app does not start.  The default is `false`.
+    ///
+    /// Actix only reports that the creation failed, and the reason is only written
+    /// to the debug log, so [`Sentry::check`] can be called beforehand to handle the
+    /// error like other configuration errors.
+    pub fn strict(mut self, val: bool) -> Self {
+        self.config.strict = val;
+        self
+    }
+
+    /// Logs a summary of each capture decision and captured event to the debug
+    /// log of the SDK.
+    ///
+    /// This shows what would be sent during local development.  The debug log is
+    /// printed when the client has `debug` enabled, or forwarded to the `log`
+    /// crate with the `debug-logs` feature; use
+    /// [`debug_log_captures_with`](Self::debug_log_captures_with) to see the
+    /// summaries without a client.  Event summaries carry the event id, the route and status, the
+    /// method and URL of the request data and the chain of errors, and do not
+    /// include anything the event would not.  The default is `false`.
+    pub fn debug_log_captures(mut self, val: bool) -> Self {
+        self.config.debug_log_captures = val;
+        self
+    }
+
+    /// Logs the summaries of [`debug_log_captures`](Self::debug_log_captures)
+    /// with a custom function, such as one forwarding them to `log::debug!`.
+    pub fn debug_log_captures_with<F>(mut self, log: F) -> Self
+    where
+        F: Fn(&str) + Send + Sync + 'static,
+    {
+        self.config.debug_log_captures = true;
+        self.config.debug_log = Some(Arc::new(log));
+        self
+    }
+
+    /// Adds a tag to every event captured on the request hub.
+    ///
+    /// Tags are applied to the request scope before the handler runs, so
+    /// handlers can still override them per request.
+    pub fn tag<K: Into<String>, V: ToString>(mut self, key: K, value: V) -> Self {
+        Arc::make_mut(&mut self.config.tags).insert(key.into(), value.to_string());
+        self
+    }
+
+    /// Adds an extra value to every event captured on the request hub.
+    ///
+    /// Like tags, extras can be overridden by handlers per request.
+    pub fn extra<K: Into<String>, V: Into<Value>>(mut self, key: K, value: V) -> Self {
+        Arc::make_mut(&mut self.config.extra).insert(key.into(), value.into());
+        self
+    }
+
+    /// Sets the fraction of errors that are captured, between `0.0` and `1.0`.
+    ///
+    /// The sampling happens in the middleware before an error is captured and
+    /// does not affect the response sent to the client.  The default is `1.0`.
+    pub fn error_sample_rate(mut self, rate: f32) -> Self {
+        self.config.error_sample_rate = rate;
+        self
+    }
+
+    /// Overrides the error sample rate for responses with a specific status code.
+    pub fn status_sample_rate(mut self, status: StatusCode, rate: f32) -> Self {
+        Arc::make_mut(&mut self.config.status_sample_rates).insert(status, rate);
+        self
+    }
+
+    /// Sets the error messages considered too generic to identify an error.
+    ///
+    /// Captured errors whose message matches one of these (ignoring case) are
+    /// tagged with `generic_error: true`, and the middleware tries to attach more
+    /// details from the error's source chain and `Debug` representation.  The
+    /// defaults include the empty string and `Internal Server Error`.
+    pub fn generic_error_messages<I, S>(mut self, messages: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.config.generic_error_messages =
+            Arc::new(messages.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Sets when errors are captured.
+    ///
+    /// The default is [`CaptureMode::Immediate`].
+    pub fn capture_mode(mut self, mode: CaptureMode) -> Self {
+        self.config.capture_mode = mode;
+        self
+    }
+
+    /// Enables or disables counting the bytes read from the request payload.
+    ///
+    /// When enabled, the number of bytes actually read by the handler is reported
+    /// as the `request_body_bytes` extra, which also covers chunked requests
+    /// without a `Content-Length` header.  Otherwise the declared length is used.
+    /// Tracked payloads also report the bytes read as `http.request_content_length`.
+    pub fn track_request_body(mut self, val: bool) -> Self {
+        self.config.track_request_body = val;
+        self
+    }
+
+    /// Derives the transaction name of requests with the given function.
+    ///
+    /// The function is called once per request before the handler runs and takes
+    /// precedence over the name or pattern of the matched route.  Returning `None`
+    /// falls back to the default transaction name.
+    pub fn transaction_name_fn<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&ServiceRequest) -> Option<String> + Send + Sync + 'static,
+    {
+        self.config.transaction_name_fn = Some(Arc::new(f));
+        self
+    }
+
+    /// Classifies errors of type `E` with their [`SentryClassify`] implementation.
+    ///
+    /// Errors are matched by type, including when wrapped in a
+    /// [`SentryError`](crate::SentryError).  Types registered first take
+    /// precedence.
+    pub fn classify_error<E>(mut self) -> Self
+    where
+        E: SentryClassify + ResponseError + 'static,
+    {
+        Arc::make_mut(&mut self.config.classifiers).push(classifier::<E>());
+        Arc::make_mut(&mut self.config.error_types).push(error_type_fn::<E>());
+        self
+    }
+
+    /// Names exceptions after the concrete type of their error.
+    ///
+    /// The type of an exception is otherwise parsed from the `Debug` output of
+    /// the error, which is `Custom` for any `io::Error` and groups unrelated
+    /// issues.  With this, errors of the types registered with
+    /// [`classify_error`](Self::classify_error), of `io::Error` and of the
+    /// payload errors of actix-web are named after their type, as `io::Error` or
+    /// `AppError::OutOfStock` for a variant of an enum, and the parsed type is
+    /// kept as the `actix_error_kind` extra.
+    ///
+    /// This changes the grouping of existing issues, so it is disabled by
+    /// default until the next breaking release.
+    pub fn use_concrete_error_types(mut self, val: bool) -> Self {
+        self.config.use_concrete_error_types = val;
+        self
+    }
+
+    /// Flushes events of matching errors before the response is returned.
+    ///
+    /// When the predicate matches a captured error and its status, the middleware
+    /// waits until the client has flushed its events, or for at most `timeout`,
+    /// before returning the response.  The flush runs on the blocking thread pool
+    /// and does not block the worker thread.  Errors whose capture is deferred until
+    /// the response has been written are not flushed.
+    pub fn flush_on_capture<F>(mut self, predicate: F, timeout: Duration) -> Self
+    where
+        F: Fn(&actix_web::Error, StatusCode) -> bool + Send + Sync + 'static,
+    {
+        self.config.flush_on_capture = Some((Arc::new(predicate), timeout));
+        self
+    }
+
+    /// Overrides the session mode of the client for requests to this middleware.
+    ///
+    /// With `Some(SessionMode::Request)`, a session is started for every request,
+    /// even if the client uses application mode.  These sessions are sent
+    /// individually, as only clients in request mode aggregate sessions.  With
+    /// `Some(SessionMode::Application)` or `None`, no sessions are started for
+    /// requests.  In any case, sessions are only started if the
+    /// `auto_session_tracking` client option is enabled.
+    pub fn session_mode(mut self, session_mode: Option<SessionMode>) -> Self {
+        self.config.session_mode = Some(session_mode);
+        self
+    }
+
+    /// Configures whether sessions are started for `HEAD` requests.
+    ///
+    /// Resources without method guards also serve `HEAD` requests, so probes
+    /// issuing them count as sessions by default.  Errors of `HEAD` requests are
+    /// captured either way.
+    pub fn count_head_as_session(mut self, val: bool) -> Self {
+        self.config.count_head_as_session = val;
+        self
+    }
+
+    /// Tags the events of requests with their API version as `api_version`.
+    ///
+    /// Requests without a version, according to the source, are not tagged.
+    /// See [`ApiVersionSource`] for the sources.
+    pub fn api_version(mut self, source: ApiVersionSource) -> Self {
+        self.config.api_version = Some(source);
+        self
+    }
+
+    /// Configures whether service errors carry the hub of their request.
+    ///
+    /// Middleware wrapping the Sentry middleware can capture against the hub of
+    /// a response with [`hub_from_service_response`](crate::hub_from_service_response),
+    /// but errors returned by the wrapped services do not carry the request.
+    /// With this, such errors are wrapped in an error rendering the same
+    /// response, whose hub is returned by [`hub_from_error`](crate::hub_from_error).
+    /// Outer middleware can then no longer downcast the errors to their type,
+    /// so this is disabled by default.
+    pub fn expose_hub_in_errors(mut self, val: bool) -> Self {
+        self.config.expose_hub_in_errors = val;
+        self
+    }
+
+    /// Configures whether sessions are only started for requests matching a route.
+    ///
+    /// Scanners requesting random paths otherwise add a session for each of their
+    /// requests.  Errors of unmatched requests are captured either way.  The
+    /// default is `false`.
+    pub fn sessions_require_route_match(mut self, val: bool) -> Self {
+        self.config.sessions_require_route_match = val;
+        self
+    }
+
+    /// Configures whether the trace of a request is emitted in response headers.
+    ///
+    /// Events of a request carry a trace context continuing the trace of the
+    /// `sentry-trace` header, or of the W3C `traceparent` header if there is no
+    /// valid `sentry-trace` header.  When enabled, responses carry both headers
+    /// with the trace and span id of the request.
+    pub fn emit_trace_headers(mut self, val: bool) -> Self {
+        self.config.emit_trace_headers = val;
+        self
+    }
+
+    /// Renders captured errors as RFC 7807 `application/problem+json` responses.
+    ///
+    /// The body contains the `status`, a generic `title` and the id of the event as
+    /// `sentry_event_id`, formatted like the event header.  Only clients accepting
+    /// JSON receive problem details, and error responses the handler rendered with
+    /// a content type other than plain text are left alone.
+    pub fn problem_json(mut self, val: bool) -> Self {
+        self.config.problem_json = val;
+        self
+    }
+
+    /// Names events of GraphQL requests to the given path after their operation.
+    ///
+    /// When the request body is captured, the `operationName` of the GraphQL
+    /// request, or the name in its query, is recorded as the `graphql.operation` tag
+    /// and the transaction is named like `POST /graphql (ListItems)`.  The type of
+    /// the operation is recorded as the `graphql.operation_type` tag.  Unless
+    /// `send_default_pii` is enabled, the variables of the request are filtered
+    /// from the attached request data.  Bodies that cannot be parsed keep the name
+    /// of the route.
+    pub fn graphql<P: Into<String>>(mut self, path: P) -> Self {
+        self.config.graphql_path = Some(Arc::from(path.into()));
+        self
+    }
+
+    /// Sets the maximum number of breadcrumbs kept for each request.
+    ///
+    /// This overrides the `max_breadcrumbs` client option for the request scope.
+    /// When earlier breadcrumbs are dropped because of the limit, their number is
+    /// added to events as the `breadcrumbs_dropped` extra.
+    pub fn max_breadcrumbs(mut self, max_breadcrumbs: usize) -> Self {
+        self.config.max_breadcrumbs = Some(max_breadcrumbs);
+        self
+    }
+
+    /// Sets the user of events from the bearer JWT of the request.
+    ///
+    /// The claims are extracted before the handler runs, so handlers can still
+    /// replace the user.  The token itself is never attached to events.
+    #[cfg(feature = "jwt")]
+    pub fn user_from_jwt(mut self, config: UserJwtConfig) -> Self {
+        self.config.user_jwt = Some(Arc::new(config));
+        self
+    }
+
+    /// Sets the user of events from an asynchronous lookup, such as in a
+    /// session store.
+    ///
+    /// The middleware waits for the lookup before polling the wrapped service,
+    /// for at most [`async_user_timeout`](Self::async_user_timeout).  Lookups
+    /// that time out, find no user or panic leave the user unset, and never fail
+    /// the request.  The user replaces one derived from a JWT.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use actix_web::dev::ServiceRequest;
+    /// use futures_util::FutureExt;
+    /// use sentry::User;
+    /// use sentry_actix::Sentry;
+    ///
+    /// let middleware = Sentry::builder()
+    ///     .with_async_user_extractor(|req: &ServiceRequest| {
+    ///         let session = req
+    ///             .headers()
+    ///             .get("x-session")
+    ///             .and_then(|value| value.to_str().ok())
+    ///             .map(ToOwned::to_owned);
+    ///         async move {
+    ///             // Look the session up in the session store
+    ///             Some(User {
+    ///                 id: session,
+    ///                 ..Default::default()
+    ///             })
+    ///         }
+    ///         .boxed_local()
+    ///     })
+    ///     .finish();
+    /// ```
+    pub fn with_async_user_extractor<F>(mut self, extractor: F) -> Self
+    where
+        F: Fn(&ServiceRequest) -> LocalBoxFuture<'static, Option<User>> + Send + Sync + 'static,
+    {
+        self.config.async_user_extractor = Some(Arc::new(extractor));
+        self
+    }
+
+    /// Sets how long the middleware waits for the user of a request.
+    ///
+    /// This bounds the delay added to requests by
+    /// [`with_async_user_extractor`](Self::with_async_user_extractor).  The
+    /// default is 100 milliseconds.
+    pub fn async_user_timeout(mut self, timeout: Duration) -> Self {
+        self.config.async_user_timeout = timeout;
+        self
+    }
+
+    /// Sets when the middleware's event processor runs relative to other processors.
+    ///
+    /// The default is [`ProcessorPosition::First`].
+    pub fn processor_position(mut self, position: ProcessorPosition) -> Self {
+        self.config.processor_position = position;
+        self
+    }
+
+    /// Limits the number of errors captured at the same time.
+    ///
+    /// This protects the service and the transport when every request fails.
+    /// Errors beyond the limit are discarded and counted in
+    /// [`DiscardStats::concurrency`](crate::DiscardStats::concurrency).
+    ///
+    /// The limit only bounds deferred captures, see
+    /// [`CaptureMode::Deferred`](crate::CaptureMode::Deferred), where an error
+    /// counts until its response has been written.  Immediate captures hand their
+    /// event to the transport without waiting, so they only count while the event
+    /// is built and are practically never limited.
+    pub fn max_concurrent_captures(mut self, max: usize) -> Self {
+        self.config.capture_limit = Some(Arc::new(CaptureLimit::new(max)));
+        self
+    }
+
+    /// Discards errors while the Sentry server rate limits them.
+    ///
+    /// The errors are counted in
+    /// [`DiscardStats::rate_limited`](crate::DiscardStats::rate_limited), as the
+    /// transport would drop their events silently.  See the
+    /// [`rate_limits`](crate::rate_limits) module for how the limits are tracked.
+    #[cfg(feature = "rate-limits")]
+    pub fn rate_limits(mut self, limits: RateLimits) -> Self {
+        self.config.rate_limits = Some(limits);
+        self
+    }
+
+    /// Adds an `x-sentry-rate-limited: 1` header to responses of errors discarded
+    /// because of [`rate_limits`](Self::rate_limits).
+    ///
+    /// The header is only added in debug builds, so that synthetic monitoring of
+    /// staging deployments notices when errors stop being reported.
+    #[cfg(feature = "rate-limits")]
+    pub fn emit_rate_limited_header(mut self, emit: bool) -> Self {
+        self.config.emit_rate_limited_header = emit;
+        self
+    }
+
+    /// Captures a warning for requests that have not completed within `threshold`.
+    ///
+    /// The warning is captured at most once per request, with the request data
+    /// attached.  Requests completing in time do not capture anything.
+    pub fn hang_detection(mut self, threshold: Duration) -> Self {
+        self.config.hang_threshold = Some(threshold);
+        self
+    }
+
+    /// Appends a redactor to the request data redaction pipeline.
+    ///
+    /// Redactors run in the order they were added, after the default
+    /// [`DenylistKeys`](crate::redact::DenylistKeys) unless it was removed with
+    /// [`clear_redactors`](Self::clear_redactors).  If a redactor panics, the
+    /// value is filtered.
+    pub fn redactor<R: Redactor + 'static>(mut self, redactor: R) -> Self {
+        Arc::make_mut(&mut self.config.redactors).push(Arc::new(redactor));
+        self
+    }
+
+    /// Removes all redactors, including the default one.
+    pub fn clear_redactors(mut self) -> Self {
+        self.config.redactors = Arc::default();
+        self
+    }
+
+    /// Sets the `logger` of events captured for requests.
+    ///
+    /// Events that already have a logger keep it.  The default is `sentry_actix`.
+    pub fn logger<L: Into<Cow<'static, str>>>(mut self, logger: L) -> Self {
+        self.config.logger = Arc::from(&*logger.into());
+        self
+    }
+
+    /// Attaches a summary of the request to captured errors as the `request_summary` extra.
+    ///
+    /// The format supports a subset of the actix `Logger` format:
+    ///
+    /// - `%a`: The remote address of the client, or `-` unless PII is sent
+    /// - `%r`: The first line of the request, such as `GET /items HTTP/1.1`
+    /// - `%s`: The response status code
+    /// - `%D`: The time taken to handle the request in milliseconds
+    /// - `%%`: A literal percent sign
+    ///
+    /// Unknown tokens are rejected by [`build`](Self::build).
+    pub fn request_summary<F: Into<String>>(mut self, format: F) -> Self {
+        self.config.request_summary_format = Some(format.into());
+        self
+    }
+
+    /// Sets the fraction of requests whose events carry full request data.
+    ///
+    /// Events of the remaining requests only carry the method, the transaction and
+    /// the status of the request, without headers, body or response data.  The
+    /// decision is recorded as the `request_data` tag with a value of `full` or
+    /// `minimal`.  The default is `1.0`.
+    pub fn full_request_data_sample_rate(mut self, rate: f32) -> Self {
+        self.config.full_request_data_sample_rate = rate;
+        self
+    }
+
+    /// Sets the estimated serialized size above which events are trimmed.
+    ///
+    /// Oversized events are dropped by Sentry.  To avoid this, the request body and
+    /// then the request headers and cookies are removed from larger events, which
+    /// are tagged with `payload_trimmed` and carry their original size as the
+    /// `original_event_size` extra.  Defaults to 1MB.
+    pub fn max_event_size(mut self, max_bytes: usize) -> Self {
+        self.config.max_event_size = max_bytes;
+        self
+    }
+
+    /// Sets the maximum length of the URL of request data.
+    ///
+    /// Longer paths, queries and URLs are truncated and end with `...`, so that
+    /// crafted requests do not bloat every event.  The marker is shortened for
+    /// limits below 3 bytes, which are never exceeded.  Defaults to 2KB.
+    pub fn max_url_length(mut self, max_length: usize) -> Self {
+        self.config.url_limits.max_length = max_length;
+        self
+    }
+
+    /// Sets the maximum number of distinct query keys in request data.
+    ///
+    /// Repeated keys are always collapsed into a single pair counting their
+    /// values, as in `id=[300 values]`.  Keys beyond the limit are dropped and
+    /// replaced by a trailing `...` pair.  Defaults to 100.
+    pub fn max_query_pairs(mut self, max_pairs: usize) -> Self {
+        self.config.url_limits.max_query_pairs = max_pairs;
+        self
+    }
+
+    /// Limits the number of request body bytes attached to events.
+    ///
+    /// Request bodies are captured according to the `max_request_body_size` client
+    /// option, which this setting can only restrict further.  Bodies exceeding the
+    /// limit are truncated and flagged with the `request_body_truncated` extra.
+    /// Defaults to 100KB.
+    pub fn max_request_body_size(mut self, max_bytes: usize) -> Self {
+        self.config.max_request_body_size = max_bytes;
+        self
+    }
+}
+
+/// Raised if the middleware configuration is invalid.
+#[derive(Debug, Error, PartialEq)]
+pub enum SentryConfigError {
+    /// The error sample rate is not between `0.0` and `1.0`.
+    #[error("invalid error sample rate {0}, expected a value between 0.0 and 1.0")]
+    InvalidSampleRate(f32),
+    /// The sample rate for a status is not between `0.0` and `1.0`.
+    #[error("invalid sample rate {1} for status {0}, expected a value between 0.0 and 1.0")]
+    InvalidStatusSampleRate(StatusCode, f32),
+    /// The full request data sample rate is not between `0.0` and `1.0`.
+    #[error("invalid full request data sample rate {0}, expected a value between 0.0 and 1.0")]
+    InvalidRequestDataSampleRate(f32),
+    /// A sample rate is set for a status that is never captured.
+    #[error("sample rate for status {0} has no effect, only server errors are captured")]
+    UnsampledStatus(StatusCode),
+    /// A static tag has an empty key.
+    #[error("static tags must have a non-empty key")]
+    EmptyTagKey,
+    /// A static extra value has an empty key.
+    #[error("static extra values must have a non-empty key")]
+    EmptyExtraKey,
+    /// The request summary format contains an unknown token.
+    #[error("unknown token `{0}` in request summary format")]
+    UnknownSummaryToken(String),
+    /// An environment variable has an invalid value.
+    #[error("invalid value `{1}` for environment variable {0}")]
+    InvalidEnvVar(String, String),
+    /// Strict middleware has features without effect, see [`Sentry::check`].
+    #[error("strict middleware has features without effect: {}", .0.join("; "))]
+    InertFeatures(Vec<String>),
+}
+
+/// Checks whether a sample rate is between `0.0` and `1.0`.
+fn is_valid_sample_rate(rate: f32) -> bool {
+    (0.0..=1.0).contains(&rate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Ensures invalid configurations are rejected by the builder.
+    #[test]
+    fn test_invalid_config() {
+        let build = |builder: SentryBuilder| builder.build().err();
+
+        assert_eq!(build(Sentry::builder()), None);
+        assert_eq!(
+            build(Sentry::builder().error_sample_rate(1.5)),
+            Some(SentryConfigError::InvalidSampleRate(1.5))
+        );
+        assert!(matches!(
+            build(Sentry::builder().error_sample_rate(f32::NAN)),
+            Some(SentryConfigError::InvalidSampleRate(_))
+        ));
+        assert_eq!(
+            build(Sentry::builder().status_sample_rate(StatusCode::BAD_GATEWAY, -0.5)),
+            Some(SentryConfigError::InvalidStatusSampleRate(
+                StatusCode::BAD_GATEWAY,
+                -0.5
+            ))
+        );
+        assert_eq!(
+            build(Sentry::builder().full_request_data_sample_rate(-1.0)),
+            Some(SentryConfigError::InvalidRequestDataSampleRate(-1.0))
+        );
+        assert_eq!(
+            build(Sentry::builder().status_sample_rate(StatusCode::NOT_FOUND, 0.5)),
+            Some(SentryConfigError::UnsampledStatus(StatusCode::NOT_FOUND))
+        );
+        assert_eq!(
+            build(Sentry::builder().tag("", "value")),
+            Some(SentryConfigError::EmptyTagKey)
+        );
+        assert_eq!(
+            build(Sentry::builder().extra("", "value")),
+            Some(SentryConfigError::EmptyExtraKey)
+        );
+        assert_eq!(
+            build(Sentry::builder().request_summary("%a %u")),
+            Some(SentryConfigError::UnknownSummaryToken("%u".into()))
+        );
+        assert_eq!(
+            build(Sentry::builder().request_summary("%s %")),
+            Some(SentryConfigError::UnknownSummaryToken("%".into()))
+        );
+    }
+
+    /// Ensures `finish` panics with the validation error.
+    #[test]
+    #[should_panic(expected = "invalid error sample rate 2")]
+    fn test_finish_invalid_config() {
+        Sentry::builder().error_sample_rate(2.0).finish();
+    }
+}
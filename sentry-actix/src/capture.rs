@@ -0,0 +1,1792 @@
+//! The policy deciding which errors are captured, and how.
+
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use actix_web::dev::{BodySize, Extensions, MessageBody, ResponseBody};
+use actix_web::http::{header, HeaderMap, StatusCode, Version};
+use actix_web::web::Bytes;
+use actix_web::{error, HttpResponse, ResponseError};
+use futures_util::future::{abortable, AbortHandle};
+
+use sentry_core::protocol::{self, Breadcrumb, Event, Map, Mechanism, Value};
+use sentry_core::types::Uuid;
+use sentry_core::{event_from_error, parse_type_from_debug, Hub, Level};
+
+use crate::clock::{Clock, IdSource};
+use crate::handler::HandlerMetadata;
+use crate::middleware::{format_event_id, Sentry};
+use crate::request::{render_summary, RequestState};
+use crate::status::LastCapture;
+
+/// Determines when the middleware captures errors.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CaptureMode {
+    /// Errors are captured as soon as the service returns.
+    Immediate,
+    /// Errors are captured once the response has been fully written.
+    ///
+    /// A single event is captured per failed request with its final outcome: the
+    /// `status_code`, the `response_body_bytes` sent, the total `duration_ms` and
+    /// whether the response was `aborted` before its body completed.  The bytes
+    /// sent are also recorded as `http.response_content_length`, flagged with
+    /// `http.response_content_length_truncated` for aborted responses.
+    Deferred,
+}
+
+/// Where the middleware found an error it captures.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum CaptureSource {
+    /// The wrapped service returned an error.
+    ServiceError,
+    /// The wrapped service returned a response carrying an error.
+    ResponseError,
+    /// A panic was captured while handling the request.
+    Panic,
+    /// The body of a long-lived response failed while being streamed.
+    StreamError,
+    /// The application captured the event with [`with_request_hub`](crate::with_request_hub).
+    Manual,
+    /// An inner middleware turned the error into a response, see [`OriginalError`].
+    SwallowedError,
+}
+
+impl CaptureSource {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            CaptureSource::ServiceError => "service_error",
+            CaptureSource::ResponseError => "response_error",
+            CaptureSource::Panic => "panic",
+            CaptureSource::StreamError => "stream_error",
+            CaptureSource::Manual => "manual",
+            CaptureSource::SwallowedError => "swallowed_error",
+        }
+    }
+
+    /// Returns whether the application handled the error by turning it into a response.
+    fn handled(self) -> bool {
+        match self {
+            CaptureSource::ServiceError | CaptureSource::Panic | CaptureSource::StreamError => {
+                false
+            }
+            CaptureSource::ResponseError
+            | CaptureSource::Manual
+            | CaptureSource::SwallowedError => true,
+        }
+    }
+}
+
+/// Tags the events of panics captured while handling a request with their source.
+pub(crate) fn tag_panic(event: &mut Event<'static>) {
+    let panicked = event.exception.values.iter().any(|exception| {
+        exception
+            .mechanism
+            .as_ref()
+            .map_or(false, |mechanism| mechanism.ty == "panic")
+    });
+    if panicked {
+        event
+            .tags
+            .entry("sentry_actix.capture_source".into())
+            .or_insert_with(|| CaptureSource::Panic.as_str().into());
+    }
+}
+
+/// The outcome of the middleware deciding whether to capture an error.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum CaptureDecision {
+    /// The error was captured with the given event id.
+    Captured(Uuid),
+    /// The error will be captured with the given event id once the response is written.
+    Deferred(Uuid),
+    /// The error was captured by the handler with the given event id.
+    CapturedByHandler(Uuid),
+    /// Capturing server errors is disabled.
+    SkippedByFilter,
+    /// The error was rendered as a response without a server error status.
+    SkippedByStatus,
+    /// The error was not sampled.
+    SkippedBySampling,
+    /// Too many errors were being captured at the same time.
+    SkippedByConcurrency,
+    /// Errors are rate limited by the Sentry server.
+    #[cfg(feature = "rate-limits")]
+    SkippedByRateLimit,
+}
+
+impl CaptureDecision {
+    /// Returns the id of the event if the error is captured.
+    pub(crate) fn event_id(self) -> Option<Uuid> {
+        match self {
+            CaptureDecision::Captured(event_id)
+            | CaptureDecision::Deferred(event_id)
+            | CaptureDecision::CapturedByHandler(event_id) => Some(event_id),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for CaptureDecision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CaptureDecision::Captured(event_id) => write!(f, "captured {}", event_id),
+            CaptureDecision::Deferred(event_id) => write!(f, "deferred {}", event_id),
+            CaptureDecision::CapturedByHandler(event_id) => {
+                write!(f, "captured_by_handler {}", event_id)
+            }
+            CaptureDecision::SkippedByFilter => f.write_str("skipped_by_filter"),
+            CaptureDecision::SkippedByStatus => f.write_str("skipped_by_status"),
+            CaptureDecision::SkippedBySampling => f.write_str("skipped_by_sampling"),
+            CaptureDecision::SkippedByConcurrency => f.write_str("skipped_by_concurrency"),
+            #[cfg(feature = "rate-limits")]
+            CaptureDecision::SkippedByRateLimit => f.write_str("skipped_by_rate_limit"),
+        }
+    }
+}
+
+/// Marks a request for which nothing should be reported.
+///
+/// Middleware running before the Sentry middleware, or guards, can insert this
+/// marker into the request extensions.  Events of marked requests are dropped,
+/// including events captured explicitly by the handler.  Markers inserted after
+/// the Sentry middleware was called only prevent capturing error responses.
+///
+/// # Example
+///
+/// ```
+/// use actix_web::{dev::Service, App, HttpMessage};
+/// use sentry_actix::{DisableCapture, Sentry};
+///
+/// let app = App::new()
+///     .wrap(Sentry::new())
+///     .wrap_fn(|req, srv| {
+///         if req.headers().contains_key("x-internal-scraper") {
+///             req.extensions_mut().insert(DisableCapture);
+///         }
+///         srv.call(req)
+///     });
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DisableCapture;
+
+/// Marks a request for which no Release Health Session should be started.
+///
+/// The marker must be inserted into the request extensions before the Sentry
+/// middleware is called.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DisableSessions;
+
+/// Marks a request whose errors should be captured regardless of their status.
+///
+/// Errors of marked requests are captured even without a server error status.
+/// [`DisableCapture`] takes precedence over this marker.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ForceCapture;
+
+/// An error an inner middleware turned into a response itself.
+///
+/// Middleware that handles errors by responding without them, such as an
+/// authentication middleware answering `401 Unauthorized` when the identity
+/// provider fails, can keep the original error in the extensions of the
+/// response.  The Sentry middleware removes it and captures it following the
+/// capture policy for the status of the error, not that of the response, with
+/// the `swallowed_by_middleware` tag.
+///
+/// # Example
+///
+/// ```
+/// use actix_web::dev::{Service, ServiceRequest};
+/// use actix_web::{error, App, Error, HttpResponse};
+/// use futures_util::future::{ok, Either};
+/// use sentry_actix::{OriginalError, Sentry};
+///
+/// fn authenticate(req: &ServiceRequest) -> Result<(), Error> {
+///     Err(error::ErrorBadGateway("Identity provider unavailable"))
+/// }
+///
+/// // The Sentry middleware must be registered last to run outermost
+/// let app = App::new()
+///     .wrap_fn(|req, srv| match authenticate(&req) {
+///         Ok(()) => Either::Left(srv.call(req)),
+///         Err(e) => {
+///             let mut res = req.into_response(HttpResponse::Unauthorized().finish());
+///             res.response_mut().extensions_mut().insert(OriginalError(e));
+///             Either::Right(ok(res))
+///         }
+///     })
+///     .wrap(Sentry::new());
+/// ```
+#[derive(Debug)]
+pub struct OriginalError(pub actix_web::Error);
+
+/// How the markers of a request override the capture policy.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum CaptureOverride {
+    None,
+    Disable,
+    Force,
+}
+
+impl CaptureOverride {
+    pub(crate) fn from_extensions(extensions: &Extensions) -> Self {
+        if extensions.contains::<DisableCapture>() {
+            CaptureOverride::Disable
+        } else if extensions.contains::<ForceCapture>() {
+            CaptureOverride::Force
+        } else {
+            CaptureOverride::None
+        }
+    }
+}
+
+/// The number of errors the middleware discarded, by reason.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DiscardStats {
+    /// Errors discarded because capturing server errors is disabled, or the
+    /// request is marked with [`DisableCapture`].
+    pub event_processor: u64,
+    /// Errors discarded because their response does not have a server error status.
+    pub status: u64,
+    /// Errors discarded by `error_sample_rate` or `status_sample_rate`.
+    pub sample_rate: u64,
+    /// Errors discarded because `max_concurrent_captures` errors were already
+    /// being captured.
+    pub concurrency: u64,
+    /// Errors discarded because the Sentry server rate limits errors, as tracked
+    /// by the `rate-limits` feature.
+    pub rate_limited: u64,
+}
+
+/// Counters of discarded errors shared between clones of the middleware.
+#[derive(Default)]
+pub(crate) struct DiscardCounters {
+    event_processor: AtomicU64,
+    status: AtomicU64,
+    sample_rate: AtomicU64,
+    concurrency: AtomicU64,
+    rate_limited: AtomicU64,
+}
+
+impl DiscardCounters {
+    pub(crate) fn stats(&self) -> DiscardStats {
+        DiscardStats {
+            event_processor: self.event_processor.load(Ordering::Relaxed),
+            status: self.status.load(Ordering::Relaxed),
+            sample_rate: self.sample_rate.load(Ordering::Relaxed),
+            concurrency: self.concurrency.load(Ordering::Relaxed),
+            rate_limited: self.rate_limited.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Limits the number of errors captured at the same time.
+///
+/// Permits are counted with an atomic, so acquiring one never blocks.
+#[derive(Debug)]
+pub(crate) struct CaptureLimit {
+    max: usize,
+    in_flight: AtomicUsize,
+}
+
+impl CaptureLimit {
+    pub(crate) fn new(max: usize) -> Self {
+        CaptureLimit {
+            max,
+            in_flight: AtomicUsize::new(0),
+        }
+    }
+
+    /// Acquires a permit, unless `max` permits are held.
+    pub(crate) fn try_acquire(self: &Arc<Self>) -> Option<CapturePermit> {
+        let max = self.max;
+        self.in_flight
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |in_flight| {
+                if in_flight < max {
+                    Some(in_flight + 1)
+                } else {
+                    None
+                }
+            })
+            .ok()?;
+        Some(CapturePermit(self.clone()))
+    }
+}
+
+/// A permit to capture an error, released when dropped.
+pub(crate) struct CapturePermit(Arc<CaptureLimit>);
+
+impl Drop for CapturePermit {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Release);
+    }
+}
+
+/// Classifies errors of a registered type, see [`SentryClassify`].
+pub(crate) type ErrorClassifier =
+    dyn Fn(&actix_web::Error) -> Option<(Level, BTreeMap<String, String>)> + Send + Sync;
+
+/// Creates a classifier for errors of type `E`, including those wrapped in a [`SentryError`].
+pub(crate) fn classifier<E>() -> Arc<ErrorClassifier>
+where
+    E: SentryClassify + ResponseError + 'static,
+{
+    Arc::new(|error: &actix_web::Error| {
+        let classified = downcast_error::<E>(error)?;
+        Some((classified.level(), classified.tags()))
+    })
+}
+
+/// Returns the error of type `E` of an actix error, including when wrapped in a
+/// [`SentryError`].
+fn downcast_error<E: ResponseError + 'static>(error: &actix_web::Error) -> Option<&E> {
+    error.as_error::<E>().or_else(|| {
+        error
+            .as_error::<SentryError>()
+            .and_then(|sentry_error| sentry_error.inner().as_error::<E>())
+    })
+}
+
+/// Names the concrete type of errors of a registered type.
+pub(crate) type ErrorTypeFn = dyn Fn(&actix_web::Error) -> Option<String> + Send + Sync;
+
+/// Creates a function naming errors of type `E`, as `AppError::OutOfStock` for
+/// the `OutOfStock` variant of an `AppError` enum.
+pub(crate) fn error_type_fn<E>() -> Arc<ErrorTypeFn>
+where
+    E: ResponseError + 'static,
+{
+    Arc::new(concrete_error_type::<E>)
+}
+
+fn concrete_error_type<E: ResponseError + 'static>(error: &actix_web::Error) -> Option<String> {
+    let concrete = downcast_error::<E>(error)?;
+    let type_name = std::any::type_name::<E>();
+    let type_name = type_name.split('<').next().unwrap_or(type_name);
+    let name = type_name.rsplit("::").next().unwrap_or(type_name);
+    // The variant of an enum leads its derived `Debug` output
+    let debug = format!("{:?}", concrete);
+    let variant = parse_type_from_debug(&debug);
+    let is_ident = variant.starts_with(|c: char| c.is_ascii_alphabetic())
+        && variant
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if is_ident && variant != name {
+        Some(format!("{}::{}", name, variant))
+    } else {
+        Some(name.to_owned())
+    }
+}
+
+/// Names the concrete type of errors of the standard library and actix-web.
+fn builtin_error_type(error: &actix_web::Error) -> Option<String> {
+    if downcast_error::<io::Error>(error).is_some() {
+        // The variants of `io::Error` are representations rather than causes
+        return Some("io::Error".into());
+    }
+    concrete_error_type::<error::JsonPayloadError>(error)
+        .or_else(|| concrete_error_type::<error::UrlencodedError>(error))
+        .or_else(|| concrete_error_type::<error::QueryPayloadError>(error))
+        .or_else(|| concrete_error_type::<error::PathError>(error))
+        .or_else(|| concrete_error_type::<error::ReadlinesError>(error))
+        .or_else(|| concrete_error_type::<error::UrlGenerationError>(error))
+}
+
+/// The signature of predicates selecting errors to flush before responding.
+pub(crate) type FlushPredicate = dyn Fn(&actix_web::Error, StatusCode) -> bool + Send + Sync;
+
+/// Flushes the client of the hub without blocking the worker thread.
+///
+/// This waits for at most `timeout`, even if the transport does not honor it.
+pub(crate) async fn flush_client(hub: &Hub, timeout: Duration) {
+    if let Some(client) = hub.client() {
+        let flush = actix_rt::task::spawn_blocking(move || client.flush(Some(timeout)));
+        actix_rt::time::timeout(timeout, flush).await.ok();
+    }
+}
+
+/// Cancels the watchdog of a request when dropped.
+pub(crate) struct Watchdog(AbortHandle);
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Captures a warning if the request has not completed within `threshold`.
+///
+/// The watchdog only holds a weak reference to the hub, so it does not keep the
+/// hub alive after the response has been returned.
+pub(crate) fn spawn_watchdog(hub: &Arc<Hub>, route: &str, threshold: Duration) -> Watchdog {
+    let hub = Arc::downgrade(hub);
+    let route = route.to_owned();
+    let (timer, handle) = abortable(actix_rt::time::sleep(threshold));
+    actix_rt::spawn(async move {
+        if timer.await.is_err() {
+            return;
+        }
+        if let Some(hub) = hub.upgrade() {
+            let mut extra = Map::new();
+            extra.insert("route".into(), route.into());
+            extra.insert(
+                "hang_threshold_ms".into(),
+                (threshold.as_millis() as u64).into(),
+            );
+            if let Some(thread) = std::thread::current().name() {
+                extra.insert("thread".into(), thread.into());
+            }
+            let mut tags = BTreeMap::new();
+            tags.insert("sentry_actix.capture_source".into(), "watchdog".into());
+            hub.capture_event(Event {
+                level: Level::Warning,
+                message: Some(format!(
+                    "Request exceeded {:?} without completing",
+                    threshold
+                )),
+                tags,
+                extra,
+                ..Default::default()
+            });
+        }
+    });
+    Watchdog(handle)
+}
+
+/// The minimum interval between events for `poll_ready` errors.
+pub(crate) const POLL_READY_CAPTURE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The minimum interval between events for aborted requests.
+pub(crate) const ABORTED_CAPTURE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Captures a warning if the handling of a request is dropped while its service
+/// is still pending.
+///
+/// The guard is disarmed once the service completes, so it only fires for
+/// futures dropped before that, as happens to in-flight requests when workers
+/// stop during a shutdown.
+pub(crate) struct AbortGuard {
+    pub(crate) hub: Arc<Hub>,
+    pub(crate) route: String,
+    pub(crate) rate_limit: Arc<CaptureRateLimit>,
+    pub(crate) clock: Arc<dyn Clock>,
+    pub(crate) ids: Arc<dyn IdSource>,
+    pub(crate) started: Instant,
+    pub(crate) armed: bool,
+}
+
+impl Drop for AbortGuard {
+    fn drop(&mut self) {
+        let now = self.clock.now();
+        if !self.armed || !self.rate_limit.try_acquire(now) {
+            return;
+        }
+        let mut tags = BTreeMap::new();
+        tags.insert("sentry_actix.capture_source".into(), "aborted".into());
+        let mut extra = Map::new();
+        extra.insert("route".into(), self.route.clone().into());
+        extra.insert(
+            "duration_ms".into(),
+            (now.saturating_duration_since(self.started).as_millis() as u64).into(),
+        );
+        self.hub.capture_event(Event {
+            event_id: self.ids.event_id(),
+            level: Level::Warning,
+            message: Some("Request aborted during shutdown/worker drop".into()),
+            tags,
+            extra,
+            ..Default::default()
+        });
+    }
+}
+
+/// Allows one capture per interval, shared between clones of the middleware.
+///
+/// The instant of the last capture is stored as microseconds since the creation
+/// of the limiter, offset by one so that zero means none.
+#[derive(Debug)]
+pub(crate) struct CaptureRateLimit {
+    epoch: Instant,
+    interval: Duration,
+    last: AtomicU64,
+}
+
+impl CaptureRateLimit {
+    /// Creates a limiter measuring instants from `epoch` on.
+    pub(crate) fn new(interval: Duration, epoch: Instant) -> Self {
+        CaptureRateLimit {
+            epoch,
+            interval,
+            last: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns whether a capture is allowed at `now`, recording it if so.
+    pub(crate) fn try_acquire(&self, now: Instant) -> bool {
+        let offset = now.saturating_duration_since(self.epoch).as_micros() as u64 + 1;
+        let interval = self.interval.as_micros() as u64;
+        self.last
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |last| {
+                if last == 0 || offset.saturating_sub(last) >= interval {
+                    Some(offset)
+                } else {
+                    None
+                }
+            })
+            .is_ok()
+    }
+}
+
+/// The events captured on a request hub, added as breadcrumbs to later events.
+///
+/// The trail is kept by the event processor of the request rather than in the
+/// scope, as the scope cannot be modified while an event is being captured.
+#[derive(Debug, Default)]
+pub(crate) struct CaptureTrail(Mutex<Vec<Breadcrumb>>);
+
+impl CaptureTrail {
+    /// Adds the breadcrumbs of earlier events to an event and records it.
+    pub(crate) fn apply_and_record(&self, event: &mut Event<'static>) {
+        let mut trail = self.0.lock().unwrap();
+        if !trail.is_empty() {
+            event.breadcrumbs.values.extend(trail.iter().cloned());
+            event
+                .breadcrumbs
+                .values
+                .sort_by_key(|breadcrumb| breadcrumb.timestamp);
+        }
+
+        let subject = match (event.exception.values.last(), &event.message) {
+            (Some(exception), _) => match exception.value {
+                Some(ref value) => format!("{}: {}", exception.ty, value),
+                None => exception.ty.clone(),
+            },
+            (None, Some(message)) => message.clone(),
+            (None, None) => "event".into(),
+        };
+        let mut data = Map::new();
+        data.insert("event_id".into(), event.event_id.to_string().into());
+        trail.push(Breadcrumb {
+            category: Some("sentry".into()),
+            message: Some(format!(
+                "captured event {} for {}",
+                format_event_id(event.event_id, false),
+                subject
+            )),
+            level: event.level,
+            data,
+            ..Default::default()
+        });
+    }
+}
+
+/// The level, message and fingerprint identifying repeated messages.
+type MessageKey = (Level, String, Vec<Cow<'static, str>>);
+
+/// The message events held back during a request, with their number of occurrences.
+#[derive(Debug, Default)]
+struct HeldMessages {
+    closed: bool,
+    flushing: Option<Uuid>,
+    messages: Vec<(MessageKey, Event<'static>, u64)>,
+}
+
+/// Collapses identical messages captured on a request hub into single events.
+///
+/// Like the [`CaptureTrail`], the held messages are kept by the event processor
+/// of the request, which holds them before processing them.  Events with
+/// exceptions, and events captured by the middleware itself, are never held.
+#[derive(Debug, Default)]
+pub(crate) struct MessageAggregator(Mutex<HeldMessages>);
+
+impl MessageAggregator {
+    fn key(event: &Event<'static>) -> Option<MessageKey> {
+        let captured_by_middleware = event
+            .tags
+            .get("sentry_actix.capture_source")
+            .map_or(false, |source| source != CaptureSource::Manual.as_str());
+        if !event.exception.is_empty() || captured_by_middleware {
+            return None;
+        }
+        let message = event.message.clone()?;
+        Some((event.level, message, event.fingerprint.to_vec()))
+    }
+
+    /// Holds a message event until the request completes, or counts it if an
+    /// identical message is held, passing other events through.
+    pub(crate) fn hold(&self, event: Event<'static>) -> Option<Event<'static>> {
+        let mut held = self.0.lock().unwrap();
+        let key = match Self::key(&event) {
+            Some(key) if !held.closed => key,
+            _ => return Some(event),
+        };
+        match held.messages.iter_mut().find(|(held, _, _)| *held == key) {
+            Some((_, _, occurrences)) => *occurrences += 1,
+            None => held.messages.push((key, event, 1)),
+        }
+        None
+    }
+
+    /// Returns whether an event is a held message being captured by [`flush`](Self::flush).
+    pub(crate) fn is_flushed(&self, event: &Event<'static>) -> bool {
+        self.0.lock().unwrap().flushing == Some(event.event_id)
+    }
+
+    /// Captures the held messages, with the `occurrences` extra for repeated ones.
+    ///
+    /// The messages are captured with the scope of the request, which processes
+    /// them now, and were sampled when they were held.  Messages captured
+    /// afterwards are passed through.
+    pub(crate) fn flush(&self, hub: &Hub) {
+        let messages = {
+            let mut held = self.0.lock().unwrap();
+            held.closed = true;
+            std::mem::take(&mut held.messages)
+        };
+        for (_, mut event, occurrences) in messages {
+            if occurrences > 1 {
+                event.extra.insert("occurrences".into(), occurrences.into());
+            }
+            // The scope adds its breadcrumbs again
+            event.breadcrumbs.values.clear();
+            self.0.lock().unwrap().flushing = Some(event.event_id);
+            hub.capture_sampled_event(event);
+        }
+        self.0.lock().unwrap().flushing = None;
+    }
+}
+
+/// Captures an error returned by the `poll_ready` of the wrapped service.
+///
+/// There is no request at this point, so the event is captured on the base hub.
+pub(crate) fn capture_poll_ready_error(
+    hub: &Hub,
+    event_id: Uuid,
+    error: &actix_web::Error,
+    service: &str,
+) -> Uuid {
+    let mut event = event_from_error(error);
+    event.event_id = event_id;
+    event.message = Some("Service not ready".into());
+    event
+        .tags
+        .insert("sentry_actix.capture_source".into(), "poll_ready".into());
+    event.extra.insert("service".into(), service.into());
+    hub.capture_event(event)
+}
+
+/// The exception types of wrappers erasing the type of the error they wrap, as
+/// parsed from their `Debug` output.
+const OPAQUE_ERROR_TYPES: &[&str] = &["Custom", "Error", "InternalError", "Other"];
+
+/// The number of routes remembered by [`OpaqueRoutes`], beyond which routes are
+/// no longer diagnosed.
+const MAX_OPAQUE_ROUTES: usize = 1000;
+
+/// Returns the type of the exception of an event if it is an opaque wrapper
+/// without a source chain.
+fn opaque_error_type<'a>(event: &'a Event<'_>) -> Option<&'a str> {
+    match event.exception.values[..] {
+        [ref exception] if OPAQUE_ERROR_TYPES.contains(&exception.ty.as_str()) => {
+            Some(&exception.ty)
+        }
+        _ => None,
+    }
+}
+
+/// The routes for which opaque errors have been diagnosed, shared between clones
+/// of the middleware.
+#[derive(Debug, Default)]
+pub(crate) struct OpaqueRoutes(Mutex<HashSet<String>>);
+
+impl OpaqueRoutes {
+    /// Remembers a route, returning whether it was diagnosed for the first time.
+    fn insert(&self, route: &str) -> bool {
+        let mut routes = self.0.lock().unwrap();
+        if routes.len() >= MAX_OPAQUE_ROUTES || routes.contains(route) {
+            return false;
+        }
+        routes.insert(route.to_owned())
+    }
+}
+
+/// Captures an info event for a route whose errors are captured as opaque wrappers.
+fn capture_opaque_error_diagnostic(hub: &Hub, event_id: Uuid, route: &str, ty: &str) -> Uuid {
+    let mut tags = BTreeMap::new();
+    tags.insert(
+        "sentry_actix.capture_source".to_owned(),
+        "diagnostic".to_owned(),
+    );
+    tags.insert("route".to_owned(), route.to_owned());
+    hub.capture_event(Event {
+        event_id,
+        level: Level::Info,
+        message: Some(format!(
+            "Errors on {} are captured as opaque `{}` errors without a source",
+            route, ty
+        )),
+        tags,
+        ..Default::default()
+    })
+}
+
+/// Captures an info event for a request rejected with `405 Method Not Allowed`.
+pub(crate) fn capture_method_not_allowed(
+    hub: &Hub,
+    event_id: Uuid,
+    route: &str,
+    method: &str,
+    headers: &HeaderMap,
+) -> Uuid {
+    let mut tags = BTreeMap::new();
+    tags.insert("http.method".to_owned(), method.to_owned());
+    tags.insert("http.status_code".to_owned(), "405".to_owned());
+    if let Some(allow) = headers
+        .get(header::ALLOW)
+        .and_then(|value| value.to_str().ok())
+    {
+        tags.insert("http.allowed_methods".to_owned(), allow.to_owned());
+    }
+    hub.capture_event(Event {
+        event_id,
+        level: Level::Info,
+        message: Some(format!("Method {} not allowed on {}", method, route)),
+        tags,
+        ..Default::default()
+    })
+}
+
+/// The sink of the summaries logged with
+/// [`debug_log_captures`](crate::SentryBuilder::debug_log_captures).
+pub(crate) type DebugLog = dyn Fn(&str) + Send + Sync;
+
+/// Returns the message of a panic payload, if it is a string.
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("Box<dyn Any>")
+}
+
+/// Summarizes an event for the debug log, without data beyond the event's.
+///
+/// The summary lists the request, as it is recorded in the request data of the
+/// event, and the chain of errors, outermost first.
+fn summarize_event(event: &Event<'static>, route: &str, status: StatusCode) -> String {
+    let mut summary = format!(
+        "event {} on {} with status {}",
+        event.event_id,
+        route,
+        status.as_u16()
+    );
+    if let Some(ref request) = event.request {
+        let method = request.method.as_deref().unwrap_or("-");
+        match request.url {
+            Some(ref url) => summary.push_str(&format!("\n  request: {} {}", method, url)),
+            None => summary.push_str(&format!("\n  request: {}", method)),
+        }
+    }
+    for (i, exception) in event.exception.values.iter().rev().enumerate() {
+        let label = if i == 0 { "error" } else { "caused by" };
+        summary.push_str(&format!("\n  {}: {}", label, exception.ty));
+        if let Some(ref value) = exception.value {
+            summary.push_str(&format!(": {}", value));
+        }
+    }
+    if !event.tags.is_empty() {
+        let tags: Vec<_> = event
+            .tags
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect();
+        summary.push_str(&format!("\n  tags: {}", tags.join(", ")));
+    }
+    summary
+}
+
+/// An error wrapper that carries Sentry metadata from a handler to the middleware.
+///
+/// Handlers can return this instead of configuring the scope imperatively.  The
+/// middleware honors the attached level, tags, extras and fingerprint when it
+/// captures the error, without leaking them to other events on the hub.
+///
+/// # Example
+///
+/// ```
+/// use std::io;
+///
+/// use actix_web::{get, Error};
+/// use sentry::Level;
+/// use sentry_actix::SentryError;
+///
+/// #[get("/checkout")]
+/// async fn checkout() -> Result<String, Error> {
+///     let err = io::Error::new(io::ErrorKind::Other, "card declined");
+///     Err(SentryError::new(err)
+///         .level(Level::Warning)
+///         .tag("domain", "billing")
+///         .fingerprint(&["billing", "declined"])
+///         .into())
+/// }
+/// ```
+pub struct SentryError {
+    error: actix_web::Error,
+    source: Option<SourceFn>,
+    status: Option<StatusCode>,
+    metadata: ErrorMetadata,
+}
+
+/// Event metadata attached to an error, applied through a pushed scope.
+#[derive(Clone, Default)]
+pub(crate) struct ErrorMetadata {
+    level: Option<Level>,
+    tags: BTreeMap<String, String>,
+    extra: BTreeMap<String, Value>,
+    fingerprint: Option<Vec<String>>,
+}
+
+impl ErrorMetadata {
+    fn apply_to_scope(&self, scope: &mut sentry_core::Scope) {
+        if let Some(level) = self.level {
+            scope.set_level(Some(level));
+        }
+        for (key, value) in &self.tags {
+            scope.set_tag(key, value);
+        }
+        for (key, value) in &self.extra {
+            scope.set_extra(key, value.clone());
+        }
+        if let Some(ref fingerprint) = self.fingerprint {
+            let fingerprint: Vec<&str> = fingerprint.iter().map(String::as_str).collect();
+            scope.set_fingerprint(Some(&fingerprint));
+        }
+    }
+}
+
+/// Returns the source of the error of type `E` of an actix error.
+type SourceFn = fn(&actix_web::Error) -> Option<&(dyn std::error::Error + 'static)>;
+
+fn error_source<E>(error: &actix_web::Error) -> Option<&(dyn std::error::Error + 'static)>
+where
+    E: ResponseError + std::error::Error + 'static,
+{
+    std::error::Error::source(error.as_error::<E>()?)
+}
+
+impl SentryError {
+    /// Wraps an error that can be converted into an actix error.
+    ///
+    /// The sources of the error are those of the actix error, which does not
+    /// expose them.  Use [`with_sources`](Self::with_sources) to keep them.
+    pub fn new<E: Into<actix_web::Error>>(error: E) -> Self {
+        SentryError {
+            error: error.into(),
+            source: None,
+            status: None,
+            metadata: ErrorMetadata::default(),
+        }
+    }
+
+    /// Wraps an error, keeping its chain of sources for the captured event.
+    pub fn with_sources<E>(error: E) -> Self
+    where
+        E: ResponseError + std::error::Error + 'static,
+    {
+        SentryError {
+            source: Some(error_source::<E>),
+            ..SentryError::new(error)
+        }
+    }
+
+    /// Overrides the status code of the response rendered for this error.
+    ///
+    /// By default the status and body are those of the wrapped error.
+    pub fn status(mut self, status: StatusCode) -> Self {
+        self.status = Some(status);
+        self
+    }
+
+    /// Sets the level of the captured event.
+    pub fn level(mut self, level: Level) -> Self {
+        self.metadata.level = Some(level);
+        self
+    }
+
+    /// Adds a tag to the captured event.
+    pub fn tag<K: Into<String>, V: ToString>(mut self, key: K, value: V) -> Self {
+        self.metadata.tags.insert(key.into(), value.to_string());
+        self
+    }
+
+    /// Adds an extra value to the captured event.
+    pub fn extra<K: Into<String>, V: Into<Value>>(mut self, key: K, value: V) -> Self {
+        self.metadata.extra.insert(key.into(), value.into());
+        self
+    }
+
+    /// Sets the fingerprint of the captured event.
+    pub fn fingerprint<I, S>(mut self, fingerprint: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.metadata.fingerprint = Some(
+            fingerprint
+                .into_iter()
+                .map(|part| part.as_ref().to_owned())
+                .collect(),
+        );
+        self
+    }
+
+    /// Returns a reference to the wrapped actix error.
+    pub fn inner(&self) -> &actix_web::Error {
+        &self.error
+    }
+}
+
+impl fmt::Debug for SentryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.error, f)
+    }
+}
+
+impl fmt::Display for SentryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.error, f)
+    }
+}
+
+impl std::error::Error for SentryError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self.source {
+            Some(source) => source(&self.error),
+            None => std::error::Error::source(&self.error),
+        }
+    }
+}
+
+impl ResponseError for SentryError {
+    fn status_code(&self) -> StatusCode {
+        self.status
+            .unwrap_or_else(|| self.error.as_response_error().status_code())
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut res = self.error.as_response_error().error_response();
+        if let Some(status) = self.status {
+            *res.status_mut() = status;
+        }
+        res
+    }
+}
+
+/// Classifies errors for the events captured by the middleware.
+///
+/// Implement this for errors that are expected despite their server error status,
+/// such as domain errors that keep a legacy status code, and register the types with
+/// [`SentryBuilder::classify_error`](crate::SentryBuilder::classify_error).  Events
+/// captured for them get the returned level and tags.
+///
+/// # Example
+///
+/// ```
+/// use std::collections::BTreeMap;
+/// use std::fmt;
+///
+/// use actix_web::ResponseError;
+/// use sentry::Level;
+/// use sentry_actix::{Sentry, SentryClassify};
+///
+/// #[derive(Debug)]
+/// struct OutOfStock;
+///
+/// impl fmt::Display for OutOfStock {
+///     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+///         f.write_str("out of stock")
+///     }
+/// }
+///
+/// impl ResponseError for OutOfStock {}
+///
+/// impl SentryClassify for OutOfStock {
+///     fn level(&self) -> Level {
+///         Level::Info
+///     }
+///
+///     fn tags(&self) -> BTreeMap<String, String> {
+///         vec![("expected".into(), "true".into())].into_iter().collect()
+///     }
+/// }
+///
+/// let middleware = Sentry::builder().classify_error::<OutOfStock>().finish();
+/// ```
+pub trait SentryClassify {
+    /// Returns the level of events captured for the error.
+    fn level(&self) -> Level;
+
+    /// Returns the tags added to events captured for the error.
+    fn tags(&self) -> BTreeMap<String, String> {
+        BTreeMap::new()
+    }
+}
+
+/// Creates an event for an actix error along with any [`SentryError`] metadata.
+fn error_event(
+    error: &actix_web::Error,
+    generic_messages: &[String],
+) -> (Event<'static>, Option<ErrorMetadata>) {
+    let sentry_error = error.as_error::<SentryError>();
+    let mut event = match sentry_error {
+        Some(sentry_error) => event_from_error(sentry_error),
+        None => event_from_error(error),
+    };
+    if is_generic_message(&error.to_string(), generic_messages) {
+        mark_generic_error(&mut event, error, generic_messages);
+    }
+    (event, sentry_error.map(|e| e.metadata.clone()))
+}
+
+/// Captures an event on the hub, applying error metadata through a pushed scope.
+pub(crate) fn capture_event(
+    hub: &Hub,
+    event: Event<'static>,
+    metadata: Option<&ErrorMetadata>,
+) -> Uuid {
+    match metadata {
+        Some(metadata) => hub.with_scope(
+            |scope| metadata.apply_to_scope(scope),
+            || hub.capture_event(event),
+        ),
+        None => hub.capture_event(event),
+    }
+}
+
+/// An error event whose capture is deferred until the response has been written.
+///
+/// The event is captured with the final outcome of the request once the response
+/// body completes.  If it is dropped before that, for instance because the client
+/// disconnected, the event is captured with an `aborted` marker.  Responses with a
+/// `Content-Encoding`, such as those of the `Compress` middleware, are marked with
+/// `body_compressed`, as their size is that of the encoded body.
+pub(crate) struct DeferredCapture {
+    hub: Arc<Hub>,
+    event: Option<Event<'static>>,
+    metadata: Option<ErrorMetadata>,
+    clock: Arc<dyn Clock>,
+    started: Instant,
+    status: StatusCode,
+    body_bytes: usize,
+    content_encoding: Option<String>,
+    permit: Option<CapturePermit>,
+    last_capture: Arc<LastCapture>,
+}
+
+impl DeferredCapture {
+    /// Records the encoding of the response body, unless it is `identity`.
+    pub(crate) fn set_content_encoding(&mut self, headers: &HeaderMap) {
+        self.content_encoding = headers
+            .get(header::CONTENT_ENCODING)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.trim().to_ascii_lowercase())
+            .filter(|value| !value.is_empty() && value != "identity");
+    }
+
+    pub(crate) fn complete(&mut self, aborted: bool) {
+        if let Some(mut event) = self.event.take() {
+            let duration = self.clock.since(self.started);
+            event
+                .extra
+                .insert("status_code".into(), self.status.as_u16().into());
+            event
+                .extra
+                .insert("response_body_bytes".into(), self.body_bytes.into());
+            event
+                .extra
+                .insert("duration_ms".into(), (duration.as_millis() as u64).into());
+            event.extra.insert("aborted".into(), aborted.into());
+            event.extra.insert(
+                "http.response_content_length".into(),
+                self.body_bytes.into(),
+            );
+            if aborted {
+                event
+                    .extra
+                    .insert("http.response_content_length_truncated".into(), true.into());
+            }
+            if let Some(ref encoding) = self.content_encoding {
+                event.extra.insert("body_compressed".into(), true.into());
+                event
+                    .extra
+                    .insert("content_encoding".into(), encoding.clone().into());
+            }
+            let event_id = capture_event(&self.hub, event, self.metadata.as_ref());
+            self.last_capture.record(event_id);
+            self.permit = None;
+        }
+    }
+}
+
+impl Drop for DeferredCapture {
+    fn drop(&mut self) {
+        self.complete(true);
+    }
+}
+
+/// A response body observed until it has been fully written.
+///
+/// The body is passed through unchanged.
+pub(crate) struct SentryBody<B> {
+    pub(crate) body: Pin<Box<ResponseBody<B>>>,
+    pub(crate) deferred: Option<DeferredCapture>,
+}
+
+impl<B: MessageBody> MessageBody for SentryBody<B> {
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, actix_web::Error>>> {
+        let this = self.get_mut();
+        let poll = this.body.as_mut().poll_next(cx);
+        if let Some(ref mut deferred) = this.deferred {
+            match poll {
+                Poll::Ready(Some(Ok(ref chunk))) => deferred.body_bytes += chunk.len(),
+                Poll::Ready(None) => deferred.complete(false),
+                _ => {}
+            }
+        }
+        poll
+    }
+}
+
+/// Why the stream of a response body failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum StreamError {
+    /// The client went away, by closing the connection or resetting the HTTP/2 stream.
+    ClientCancelled,
+    /// The server failed to produce or write the body.
+    WriteFailed,
+}
+
+impl StreamError {
+    fn as_str(self) -> &'static str {
+        match self {
+            StreamError::ClientCancelled => "client_cancelled",
+            StreamError::WriteFailed => "write_failed",
+        }
+    }
+
+    fn level(self) -> Level {
+        match self {
+            StreamError::ClientCancelled => Level::Info,
+            StreamError::WriteFailed => Level::Error,
+        }
+    }
+}
+
+/// Classifies an error of a response body stream.
+///
+/// I/O errors of closed connections mean the client went away.  On HTTP/2, streams
+/// reset by the client, such as with `RST_STREAM(CANCEL)`, surface as I/O errors
+/// describing the received stream error instead.
+pub(crate) fn classify_stream_error(version: Version, error: &actix_web::Error) -> StreamError {
+    let io_error = match error.as_error::<io::Error>() {
+        Some(io_error) => io_error,
+        None => return StreamError::WriteFailed,
+    };
+    match io_error.kind() {
+        io::ErrorKind::BrokenPipe
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionAborted => StreamError::ClientCancelled,
+        _ if version == Version::HTTP_2
+            && io_error.to_string().starts_with("stream error received:") =>
+        {
+            StreamError::ClientCancelled
+        }
+        _ => StreamError::WriteFailed,
+    }
+}
+
+/// The body of a long-lived response, such as a stream of Server-Sent Events.
+///
+/// The body is passed through unchanged.  Errors of the stream are captured
+/// when they occur, tagged with `long_lived_response` and carrying the time
+/// since the request started as the `duration_ms` extra.  They are classified
+/// as the `stream_error` tag: `client_cancelled` errors are captured at the info
+/// level and `write_failed` errors at the error level.
+pub(crate) struct LongLivedBody<B> {
+    pub(crate) body: Pin<Box<ResponseBody<B>>>,
+    pub(crate) hub: Arc<Hub>,
+    pub(crate) route: String,
+    pub(crate) version: Version,
+    pub(crate) clock: Arc<dyn Clock>,
+    pub(crate) ids: Arc<dyn IdSource>,
+    pub(crate) started: Instant,
+}
+
+impl<B: MessageBody> MessageBody for LongLivedBody<B> {
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, actix_web::Error>>> {
+        let this = self.get_mut();
+        let poll = this.body.as_mut().poll_next(cx);
+        if let Poll::Ready(Some(Err(ref error))) = poll {
+            let source = CaptureSource::StreamError;
+            let stream_error = classify_stream_error(this.version, error);
+            let mut event = event_from_error(error);
+            event.event_id = this.ids.event_id();
+            event.level = stream_error.level();
+            event
+                .tags
+                .insert("sentry_actix.capture_source".into(), source.as_str().into());
+            event
+                .tags
+                .insert("long_lived_response".into(), "true".into());
+            event
+                .tags
+                .insert("stream_error".into(), stream_error.as_str().into());
+            event
+                .tags
+                .insert("http.version".into(), format!("{:?}", this.version));
+            event.extra.insert(
+                "duration_ms".into(),
+                (this.clock.since(this.started).as_millis() as u64).into(),
+            );
+            if let Some(exception) = event.exception.values.last_mut() {
+                let mut data = Map::new();
+                data.insert("route".into(), this.route.clone().into());
+                exception.mechanism = Some(Mechanism {
+                    ty: "actix".into(),
+                    handled: Some(source.handled()),
+                    data,
+                    ..Default::default()
+                });
+            }
+            this.hub.capture_event(event);
+        }
+        poll
+    }
+}
+
+/// The default messages considered too generic to identify an error.
+pub(crate) const GENERIC_ERROR_MESSAGES: &[&str] = &[
+    "",
+    "Internal Server Error",
+    "InternalError",
+    "internal error",
+    "unknown error",
+];
+
+/// Checks whether an error message is one of the given generic messages.
+fn is_generic_message(message: &str, generic_messages: &[String]) -> bool {
+    let message = message.trim();
+    generic_messages
+        .iter()
+        .any(|generic| generic.eq_ignore_ascii_case(message))
+}
+
+/// Tags an event for a generic error and attaches whatever details can be recovered.
+///
+/// The source chain is searched for the first error with a meaningful message,
+/// and the `Debug` representation is attached if it carries more information.
+fn mark_generic_error(
+    event: &mut Event<'static>,
+    error: &actix_web::Error,
+    generic_messages: &[String],
+) {
+    event.tags.insert("generic_error".into(), "true".into());
+
+    // Exceptions are sorted oldest to newest, the last one is the captured error.
+    let source = event
+        .exception
+        .values
+        .iter()
+        .rev()
+        .skip(1)
+        .find(|exception| {
+            let value = exception.value.as_deref().unwrap_or_default();
+            !is_generic_message(value, generic_messages)
+        });
+    if let Some(source) = source {
+        let value = source.value.as_deref().unwrap_or_default();
+        event.extra.insert(
+            "error_source".into(),
+            format!("{}: {}", source.ty, value).into(),
+        );
+    }
+
+    let debug = format!("{:?}", error);
+    if !is_generic_message(&debug, generic_messages) && debug != error.to_string() {
+        event.extra.insert("error_debug".into(), debug.into());
+    }
+}
+
+impl Sentry {
+    /// Logs the capture decision for an error if captures are logged, or the
+    /// client has `debug` enabled.
+    pub(crate) fn log_decision(
+        &self,
+        hub: &Arc<Hub>,
+        route: &str,
+        status: StatusCode,
+        decision: CaptureDecision,
+    ) {
+        let debug = hub.client().map_or(false, |client| client.options().debug);
+        if !debug && !self.config.debug_log_captures {
+            return;
+        }
+        let line = format!(
+            "actix error on {} with status {}: {}",
+            route,
+            status.as_u16(),
+            decision
+        );
+        self.write_debug_log(hub, &line);
+    }
+
+    /// Writes a line to the sink of
+    /// [`debug_log_captures_with`](crate::SentryBuilder::debug_log_captures_with),
+    /// or to the debug log of the SDK with the client of `hub`.
+    pub(crate) fn write_debug_log(&self, hub: &Arc<Hub>, line: &str) {
+        match self.config.debug_log {
+            Some(ref debug_log) => {
+                self.run_hook("debug_log_captures_with", || debug_log(line));
+            }
+            None => Hub::run(hub.clone(), || {
+                sentry_core::sentry_debug!("{}", line);
+            }),
+        }
+    }
+
+    /// Runs a hook provided by the user, catching its panics.
+    ///
+    /// A panic is logged to the debug log, counted in [`Sentry::hook_panics`],
+    /// and yields `None`, so that the request is handled as if the hook was not
+    /// configured.
+    pub(crate) fn run_hook<R>(&self, hook: &str, f: impl FnOnce() -> R) -> Option<R> {
+        // Hooks only borrow the request or the error, and nothing they could leave
+        // inconsistent is used after they panicked
+        let payload = match panic::catch_unwind(AssertUnwindSafe(f)) {
+            Ok(value) => return Some(value),
+            Err(payload) => payload,
+        };
+        self.config.hook_panics.fetch_add(1, Ordering::Relaxed);
+        let line = format!("{} panicked: {}", hook, panic_message(&*payload));
+        match self.config.debug_log {
+            // The panic of a panicking debug log has already been counted
+            Some(ref debug_log) => {
+                let _ = panic::catch_unwind(AssertUnwindSafe(|| debug_log(&line)));
+            }
+            None => {
+                sentry_core::sentry_debug!("{}", line);
+            }
+        }
+        None
+    }
+
+    /// Names the exception of an error event after the concrete type of the
+    /// error, keeping the type parsed from its `Debug` output as the
+    /// `actix_error_kind` extra.
+    fn apply_concrete_error_type(&self, error: &actix_web::Error, event: &mut Event<'static>) {
+        let concrete = self
+            .config
+            .error_types
+            .iter()
+            .find_map(|error_type| error_type(error))
+            .or_else(|| builtin_error_type(error));
+        if let (Some(concrete), Some(exception)) = (concrete, event.exception.values.last_mut()) {
+            let kind = std::mem::replace(&mut exception.ty, concrete);
+            event.extra.insert("actix_error_kind".into(), kind.into());
+        }
+    }
+
+    /// Captures an error according to the configuration of the middleware.
+    ///
+    /// In deferred capture mode, the event is returned for capture once the response
+    /// has been written instead.  The source of the error is recorded as the
+    /// `sentry_actix.capture_source` tag, the status of the response rendered for
+    /// it as the `http.status_code` and `http.status_text` tags, and the content
+    /// type of the response, if any, as the `response.content_type` tag.
+    ///
+    /// With `max_concurrent_captures`, a permit is held while the event is built
+    /// and captured.  Only for deferred captures does this last beyond the call,
+    /// until the response has been written.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn capture_error(
+        &self,
+        request: &RequestState,
+        source: CaptureSource,
+        error: &actix_web::Error,
+        status: StatusCode,
+        response_content_type: Option<String>,
+        capture_override: CaptureOverride,
+        handler: Option<&HandlerMetadata>,
+    ) -> (CaptureDecision, Option<DeferredCapture>) {
+        if !self.config.capture_server_errors || capture_override == CaptureOverride::Disable {
+            self.config
+                .discards
+                .event_processor
+                .fetch_add(1, Ordering::Relaxed);
+            return (CaptureDecision::SkippedByFilter, None);
+        }
+        // Service errors are captured regardless of the status they render with
+        if source != CaptureSource::ServiceError
+            && !status.is_server_error()
+            && capture_override != CaptureOverride::Force
+        {
+            self.config.discards.status.fetch_add(1, Ordering::Relaxed);
+            return (CaptureDecision::SkippedByStatus, None);
+        }
+        if !self.sample_error(status) {
+            self.config
+                .discards
+                .sample_rate
+                .fetch_add(1, Ordering::Relaxed);
+            return (CaptureDecision::SkippedBySampling, None);
+        }
+        #[cfg(feature = "rate-limits")]
+        if let Some(ref limits) = self.config.rate_limits {
+            if limits.errors_limited() {
+                self.config
+                    .discards
+                    .rate_limited
+                    .fetch_add(1, Ordering::Relaxed);
+                return (CaptureDecision::SkippedByRateLimit, None);
+            }
+        }
+        let permit = match self.config.capture_limit {
+            Some(ref limit) => match limit.try_acquire() {
+                Some(permit) => Some(permit),
+                None => {
+                    self.config
+                        .discards
+                        .concurrency
+                        .fetch_add(1, Ordering::Relaxed);
+                    return (CaptureDecision::SkippedByConcurrency, None);
+                }
+            },
+            None => None,
+        };
+
+        let (mut event, metadata) = error_event(error, &self.config.generic_error_messages);
+        event.event_id = self.config.ids.event_id();
+        if let Some(handler) = handler {
+            handler.apply_to_event(&mut event);
+        }
+        if source == CaptureSource::SwallowedError {
+            event
+                .tags
+                .insert("swallowed_by_middleware".into(), "true".into());
+        }
+        if let Some((level, tags)) = self.config.classifiers.iter().find_map(|classify| {
+            self.run_hook("classify_error", || classify(error))
+                .flatten()
+        }) {
+            event.level = level;
+            event.tags.extend(tags);
+        }
+        if self.config.use_concrete_error_types {
+            self.apply_concrete_error_type(error, &mut event);
+        }
+        if let Some(ref opaque_routes) = self.config.opaque_routes {
+            if let Some(ty) = opaque_error_type(&event) {
+                if opaque_routes.insert(&request.route) {
+                    let event_id = self.config.ids.event_id();
+                    capture_opaque_error_diagnostic(&request.hub, event_id, &request.route, ty);
+                }
+                event
+                    .extra
+                    .insert("capture_quality".into(), "opaque".into());
+            }
+        }
+        event
+            .tags
+            .insert("sentry_actix.capture_source".into(), source.as_str().into());
+        event
+            .tags
+            .insert("http.status_code".into(), status.as_str().into());
+        if let Some(reason) = status.canonical_reason() {
+            event.tags.insert("http.status_text".into(), reason.into());
+        }
+        if let Some(exception) = event.exception.values.last_mut() {
+            let mut data = Map::new();
+            data.insert("status_code".into(), status.as_u16().into());
+            data.insert("route".into(), request.route.clone().into());
+            exception.mechanism = Some(Mechanism {
+                ty: "actix".into(),
+                handled: Some(source.handled()),
+                data,
+                ..Default::default()
+            });
+        }
+        if request.full_data {
+            if let Some(content_type) = response_content_type {
+                event
+                    .tags
+                    .insert("response.content_type".into(), content_type);
+            }
+            request.body.apply_incomplete_to_event(&mut event);
+        } else {
+            event
+                .extra
+                .insert("status_code".into(), status.as_u16().into());
+        }
+        if let Some(ref routing) = request.routing {
+            event
+                .contexts
+                .insert("routing".into(), protocol::Context::Other(routing.clone()));
+        }
+        if let Some(ref tokens) = self.config.request_summary {
+            let summary = render_summary(tokens, request, status);
+            event.extra.insert("request_summary".into(), summary.into());
+        }
+        if self.config.debug_log_captures {
+            let mut event = event.clone();
+            event.request = Some((*request.data.request()).clone());
+            let summary = summarize_event(&event, &request.route, status);
+            self.write_debug_log(&request.hub, &summary);
+        }
+        match self.config.capture_mode {
+            CaptureMode::Immediate => {
+                let event_id = capture_event(&request.hub, event, metadata.as_ref());
+                self.config.last_capture.record(event_id);
+                (CaptureDecision::Captured(event_id), None)
+            }
+            CaptureMode::Deferred => {
+                let event_id = event.event_id;
+                let deferred = DeferredCapture {
+                    hub: request.hub.clone(),
+                    event: Some(event),
+                    metadata,
+                    clock: request.clock.clone(),
+                    started: request.started,
+                    status,
+                    body_bytes: 0,
+                    content_encoding: None,
+                    permit,
+                    last_capture: self.config.last_capture.clone(),
+                };
+                (CaptureDecision::Deferred(event_id), Some(deferred))
+            }
+        }
+    }
+
+    /// Decides whether full request data should be attached to events of a request.
+    pub(crate) fn sample_full_request_data(&self) -> bool {
+        let rate = self.config.full_request_data_sample_rate;
+        rate >= 1.0 || (rate > 0.0 && rand::random::<f32>() < rate)
+    }
+
+    /// Returns the flush timeout if the captured error should be flushed before responding.
+    pub(crate) fn flush_timeout(
+        &self,
+        error: &actix_web::Error,
+        status: StatusCode,
+    ) -> Option<Duration> {
+        let (predicate, timeout) = self.config.flush_on_capture.as_ref()?;
+        if self
+            .run_hook("flush_on_capture", || predicate(error, status))
+            .unwrap_or(false)
+        {
+            Some(*timeout)
+        } else {
+            None
+        }
+    }
+
+    /// Decides whether an error with the given status should be captured.
+    pub(crate) fn sample_error(&self, status: StatusCode) -> bool {
+        let rate = self
+            .config
+            .status_sample_rates
+            .get(&status)
+            .copied()
+            .unwrap_or(self.config.error_sample_rate);
+        rate >= 1.0 || (rate > 0.0 && rand::random::<f32>() < rate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::SystemClock;
+    use crate::request::{CapturedRequest, EnvProfile, IpPolicy, UrlLimits};
+
+    #[test]
+    fn test_capture_rate_limit() {
+        let start = Instant::now();
+        let limit = CaptureRateLimit::new(Duration::from_secs(60), start);
+        assert!(limit.try_acquire(start));
+        assert!(!limit.try_acquire(start + Duration::from_secs(59)));
+        assert!(limit.try_acquire(start + Duration::from_secs(60)));
+        assert!(!limit.try_acquire(start + Duration::from_secs(61)));
+    }
+
+    #[test]
+    fn test_classify_stream_error() {
+        let io_error = |kind, message| actix_web::Error::from(io::Error::new(kind, message));
+        let reset = io_error(io::ErrorKind::ConnectionReset, "connection reset");
+        for &version in &[Version::HTTP_11, Version::HTTP_2] {
+            assert_eq!(
+                classify_stream_error(version, &reset),
+                StreamError::ClientCancelled
+            );
+        }
+
+        // Streams reset by HTTP/2 clients
+        let cancelled = io_error(
+            io::ErrorKind::Other,
+            "stream error received: stream no longer needed",
+        );
+        assert_eq!(
+            classify_stream_error(Version::HTTP_2, &cancelled),
+            StreamError::ClientCancelled
+        );
+        assert_eq!(
+            classify_stream_error(Version::HTTP_11, &cancelled),
+            StreamError::WriteFailed
+        );
+
+        let failed = actix_web::error::ErrorInternalServerError("upstream closed");
+        assert_eq!(
+            classify_stream_error(Version::HTTP_2, &failed),
+            StreamError::WriteFailed
+        );
+    }
+
+    /// Ensures events of wrapped errors keep the chain of sources.
+    #[test]
+    fn test_sentry_error_sources() {
+        #[derive(Debug, thiserror::Error)]
+        #[error("payment failed")]
+        struct PaymentError(#[source] io::Error);
+
+        impl ResponseError for PaymentError {}
+
+        let payment_error = || PaymentError(io::Error::new(io::ErrorKind::Other, "card declined"));
+        let event = event_from_error(&SentryError::with_sources(payment_error()));
+        let exceptions: Vec<_> = event
+            .exception
+            .iter()
+            .map(|exception| (exception.ty.as_str(), exception.value.as_deref()))
+            .collect();
+        assert_eq!(
+            exceptions,
+            [
+                ("Custom", Some("card declined")),
+                ("PaymentError", Some("payment failed")),
+            ]
+        );
+
+        let event = event_from_error(&SentryError::new(payment_error()));
+        assert_eq!(event.exception.len(), 1);
+    }
+
+    #[test]
+    fn test_capture_limit() {
+        let limit = Arc::new(CaptureLimit::new(2));
+        let first = limit.try_acquire().unwrap();
+        let second = limit.try_acquire().unwrap();
+        assert!(limit.try_acquire().is_none());
+
+        drop(first);
+        let third = limit.try_acquire().unwrap();
+        assert!(limit.try_acquire().is_none());
+
+        drop((second, third));
+        assert_eq!(limit.in_flight.load(Ordering::SeqCst), 0);
+    }
+
+    /// Ensures the capture decision reflects the middleware configuration.
+    #[test]
+    fn test_capture_decision() {
+        let decide = |middleware: Sentry, status: StatusCode| {
+            let request = RequestState {
+                hub: Arc::new(Hub::new_from_top(Hub::current())),
+                clock: Arc::new(SystemClock),
+                started: Instant::now(),
+                full_data: true,
+                body: Default::default(),
+                data: Arc::new(CapturedRequest::new(
+                    &actix_web::test::TestRequest::default().to_http_request(),
+                    true,
+                    IpPolicy::None,
+                    EnvProfile::Minimal,
+                    Arc::default(),
+                    UrlLimits::default(),
+                )),
+                route: "/".into(),
+                request_line: None,
+                remote_addr: None,
+                routing: None,
+            };
+            let error = actix_web::error::InternalError::new("boom", status).into();
+            middleware
+                .capture_error(
+                    &request,
+                    CaptureSource::ResponseError,
+                    &error,
+                    status,
+                    None,
+                    CaptureOverride::None,
+                    None,
+                )
+                .0
+        };
+
+        let events = sentry::test::with_captured_events(|| {
+            let decision = decide(Sentry::new(), StatusCode::INTERNAL_SERVER_ERROR);
+            assert!(matches!(decision, CaptureDecision::Captured(_)));
+
+            let middleware = Sentry::builder()
+                .capture_mode(CaptureMode::Deferred)
+                .finish();
+            let decision = decide(middleware, StatusCode::INTERNAL_SERVER_ERROR);
+            assert!(matches!(decision, CaptureDecision::Deferred(_)));
+
+            let decision = decide(Sentry::new(), StatusCode::NOT_FOUND);
+            assert_eq!(decision, CaptureDecision::SkippedByStatus);
+
+            let middleware = Sentry::builder().capture_server_errors(false).finish();
+            let decision = decide(middleware, StatusCode::INTERNAL_SERVER_ERROR);
+            assert_eq!(decision, CaptureDecision::SkippedByFilter);
+
+            let middleware = Sentry::builder().error_sample_rate(0.0).finish();
+            let decision = decide(middleware, StatusCode::INTERNAL_SERVER_ERROR);
+            assert_eq!(decision, CaptureDecision::SkippedBySampling);
+        });
+
+        // The deferred event is captured when it is dropped
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1].extra.get("aborted"), Some(&true.into()));
+    }
+
+    #[test]
+    fn test_is_generic_message() {
+        let generic: Vec<String> = GENERIC_ERROR_MESSAGES
+            .iter()
+            .map(|message| (*message).to_owned())
+            .collect();
+        assert!(is_generic_message("Internal Server Error", &generic));
+        assert!(is_generic_message("  internal server error ", &generic));
+        assert!(!is_generic_message("Database unavailable", &generic));
+        assert!(!is_generic_message("Internal Server Error", &[]));
+    }
+}
@@ -0,0 +1,183 @@
+//! Breadcrumbs for outgoing requests made with the actix-web client.
+//!
+//! Requests sent with [`SentryClientRequestExt::send_traced`] record an `http`
+//! breadcrumb with the method, url, status and duration of the call on the
+//! current hub.  Inside a handler wrapped by the middleware, this is the hub of
+//! the request, so the breadcrumbs end up on errors captured for the request.
+//! When the call fails, the error is additionally attached to the request scope
+//! as the `upstream_error` and `upstream_url` extras.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use actix_web::{error, Error};
+//! use sentry_actix::client::SentryClientRequestExt;
+//!
+//! async fn index() -> Result<&'static str, Error> {
+//!     let response = awc::Client::new()
+//!         .get("http://upstream.internal/items")
+//!         .send_traced()
+//!         .await
+//!         .map_err(error::ErrorBadGateway)?;
+//!     if response.status().is_server_error() {
+//!         return Err(error::ErrorInternalServerError("upstream failed"));
+//!     }
+//!     Ok("done")
+//! }
+//! ```
+
+use std::future::Future;
+use std::time::Instant;
+
+use awc::{ClientRequest, SendClientRequest};
+use futures_util::future::{FutureExt, LocalBoxFuture};
+
+use sentry_core::protocol::{Breadcrumb, Map};
+use sentry_core::{Hub, Level};
+
+/// The result of sending a request with the actix-web client.
+pub type SendResult = <SendClientRequest as Future>::Output;
+
+/// Extension trait to send actix-web client requests with breadcrumbs.
+pub trait SentryClientRequestExt {
+    /// Sends the request, recording it as a breadcrumb on the current hub.
+    fn send_traced(self) -> LocalBoxFuture<'static, SendResult>;
+}
+
+impl SentryClientRequestExt for ClientRequest {
+    fn send_traced(self) -> LocalBoxFuture<'static, SendResult> {
+        let hub = Hub::current();
+        let method = self.get_method().to_string();
+        let url = self.get_uri().to_string();
+        let started = Instant::now();
+
+        self.send()
+            .map(move |result| {
+                let mut data = Map::new();
+                data.insert("method".into(), method.into());
+                data.insert("url".into(), url.clone().into());
+                data.insert(
+                    "duration_ms".into(),
+                    (started.elapsed().as_millis() as u64).into(),
+                );
+
+                let level = match result {
+                    Ok(ref response) => {
+                        data.insert("status_code".into(), response.status().as_u16().into());
+                        if response.status().is_server_error() {
+                            Level::Error
+                        } else {
+                            Level::Info
+                        }
+                    }
+                    Err(ref err) => {
+                        let err = err.to_string();
+                        hub.configure_scope(|scope| {
+                            scope.set_extra("upstream_error", err.clone().into());
+                            scope.set_extra("upstream_url", url.into());
+                        });
+                        data.insert("reason".into(), err.into());
+                        Level::Error
+                    }
+                };
+
+                hub.add_breadcrumb(Breadcrumb {
+                    ty: "http".into(),
+                    category: Some("http".into()),
+                    level,
+                    data,
+                    ..Default::default()
+                });
+                result
+            })
+            .boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::sync::Arc;
+
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{error, web, App, Error, HttpServer};
+    use sentry::test::TestTransport;
+
+    use super::*;
+    use crate::Sentry;
+
+    async fn call_upstream(upstream: web::Data<String>) -> Result<&'static str, Error> {
+        let response = awc::Client::new()
+            .get(upstream.as_str())
+            .send_traced()
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+        if response.status().is_server_error() {
+            return Err(error::ErrorInternalServerError("Upstream Error"));
+        }
+        Ok("done")
+    }
+
+    /// Ensures failing upstream calls are recorded on the event of the handler.
+    #[actix_rt::test]
+    async fn test_send_traced() {
+        let server = HttpServer::new(|| {
+            App::new().route(
+                "/upstream",
+                web::get()
+                    .to(|| async { Err::<String, _>(error::ErrorServiceUnavailable("Down")) }),
+            )
+        })
+        .workers(1)
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let upstream = format!("http://{}/upstream", server.addrs()[0]);
+        let server = server.run();
+
+        // A closed port makes the call itself fail
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap();
+        let unreachable = format!("http://{}/upstream", closed.local_addr().unwrap());
+        drop(closed);
+
+        let transport = TestTransport::new();
+        let client = sentry::Client::from(sentry::ClientOptions {
+            dsn: Some("https://public@sentry.invalid/1".parse().unwrap()),
+            transport: Some(Arc::new(transport.clone())),
+            ..Default::default()
+        });
+        let hub = Arc::new(Hub::new(Some(Arc::new(client)), Default::default()));
+
+        for upstream in &[&upstream, &unreachable] {
+            let app = init_service(
+                App::new()
+                    .wrap(Sentry::builder().with_hub(hub.clone()).finish())
+                    .data(upstream.to_string())
+                    .service(web::resource("/test").to(call_upstream)),
+            )
+            .await;
+
+            let req = TestRequest::get().uri("/test").to_request();
+            let res = call_service(&app, req).await;
+            assert!(res.status().is_server_error());
+        }
+        let events = transport.fetch_and_clear_events();
+        server.stop(true).await;
+        assert_eq!(events.len(), 2);
+
+        let breadcrumb = &events[0].breadcrumbs[0];
+        assert_eq!(breadcrumb.ty, "http");
+        assert_eq!(breadcrumb.level, Level::Error);
+        assert_eq!(breadcrumb.data["method"], "GET");
+        assert_eq!(breadcrumb.data["url"], upstream.as_str());
+        assert_eq!(breadcrumb.data["status_code"], 503);
+        assert!(breadcrumb.data.contains_key("duration_ms"));
+        assert!(!events[0].extra.contains_key("upstream_error"));
+
+        let breadcrumb = &events[1].breadcrumbs[0];
+        assert_eq!(breadcrumb.data["url"], unreachable.as_str());
+        assert!(!breadcrumb.data.contains_key("status_code"));
+        assert!(breadcrumb.data.contains_key("reason"));
+        assert!(events[1].extra.contains_key("upstream_error"));
+        assert_eq!(events[1].extra["upstream_url"], unreachable.as_str());
+    }
+}
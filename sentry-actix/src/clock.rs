@@ -0,0 +1,129 @@
+//! Sources of time and event ids used by the middleware.
+//!
+//! The middleware reads the time for durations, rate limits and the tracking of
+//! unready services, and assigns the ids of the events it captures, through
+//! these sources.  With the `test-util` feature, [`ManualClock`] and
+//! [`SequentialIds`] make them deterministic in tests.
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+#[cfg(any(test, feature = "test-util"))]
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(any(test, feature = "test-util"))]
+use std::sync::{Arc, Mutex};
+
+use sentry_core::types::Uuid;
+
+/// A source of the current time.
+pub(crate) trait Clock: fmt::Debug + Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// Returns the time elapsed since an earlier instant of this clock.
+    fn since(&self, earlier: Instant) -> Duration {
+        self.now().saturating_duration_since(earlier)
+    }
+}
+
+/// A source of ids for captured events.
+pub(crate) trait IdSource: fmt::Debug + Send + Sync {
+    fn event_id(&self) -> Uuid;
+}
+
+/// The clock of the operating system.
+#[derive(Debug)]
+pub(crate) struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Random ids, as generated for events by the client.
+#[derive(Debug)]
+pub(crate) struct RandomIds;
+
+impl IdSource for RandomIds {
+    fn event_id(&self) -> Uuid {
+        Uuid::new_v4()
+    }
+}
+
+/// A clock that only advances when told to.
+///
+/// Clones share the same time, so a clone can be passed to
+/// [`SentryBuilder::with_clock`](crate::SentryBuilder::with_clock) and advanced
+/// by the test.
+///
+/// # Example
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use sentry_actix::{ManualClock, Sentry};
+///
+/// let clock = ManualClock::new();
+/// let middleware = Sentry::builder().with_clock(clock.clone()).finish();
+/// // Requests handled now take exactly 250ms
+/// clock.advance(Duration::from_millis(250));
+/// ```
+#[cfg(any(test, feature = "test-util"))]
+#[derive(Clone, Debug)]
+pub struct ManualClock {
+    now: Arc<Mutex<Instant>>,
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl ManualClock {
+    /// Creates a clock starting at the current time.
+    pub fn new() -> Self {
+        ManualClock {
+            now: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    /// Advances the clock by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl Default for ManualClock {
+    fn default() -> Self {
+        ManualClock::new()
+    }
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+}
+
+/// Event ids counting up from `00000000-0000-0000-0000-000000000001`.
+///
+/// Clones share the same counter.
+#[cfg(any(test, feature = "test-util"))]
+#[derive(Clone, Debug, Default)]
+pub struct SequentialIds {
+    next: Arc<AtomicU64>,
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl SequentialIds {
+    /// Creates a source starting at the first id.
+    pub fn new() -> Self {
+        SequentialIds::default()
+    }
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl IdSource for SequentialIds {
+    fn event_id(&self) -> Uuid {
+        let id = self.next.fetch_add(1, Ordering::Relaxed) + 1;
+        Uuid::from_u128(u128::from(id))
+    }
+}
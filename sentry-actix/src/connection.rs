@@ -0,0 +1,252 @@
+//! Tracking the keep-alive connections requests are received on.
+
+use std::any::Any;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::dev::Extensions;
+use actix_web::HttpRequest;
+
+use sentry_core::Scope;
+
+/// The number of connections tracked at the same time.
+const MAX_CONNECTIONS: usize = 4096;
+
+/// How long a connection can be idle before it is no longer tracked, which is
+/// longer than any reasonable keep-alive timeout.
+const MAX_IDLE: Duration = Duration::from_secs(600);
+
+/// Marks the first request of a connection, with the time it was accepted.
+struct ConnectionStarted(Instant);
+
+/// Creates a callback for `HttpServer::on_connect` tracking connections.
+///
+/// With the callback installed, events of requests handled by the
+/// [`Sentry`](crate::Sentry) middleware carry the number of requests served on
+/// their connection, including themselves, as the `connection.requests_served`
+/// extra, and the seconds since the connection was accepted as the
+/// `connection.age_secs` extra.  This helps to find failures that only occur on
+/// long-lived keep-alive connections.
+///
+/// Data attached by `on_connect` only reaches the first request of a connection
+/// in this version of actix-web, so the middleware recognizes later requests by
+/// their peer address.  Requests without a peer address, such as those received
+/// on Unix sockets, are only tracked on their first request.
+///
+/// # Example
+///
+/// ```no_run
+/// use actix_web::{App, HttpServer};
+///
+/// # async fn run() -> std::io::Result<()> {
+/// HttpServer::new(|| App::new().wrap(sentry_actix::Sentry::new()))
+///     .on_connect(sentry_actix::connection_tracker())
+///     .bind("127.0.0.1:8080")?
+///     .run()
+///     .await
+/// # }
+/// ```
+pub fn connection_tracker() -> impl Fn(&dyn Any, &mut Extensions) + Send + Sync + 'static {
+    |_io: &dyn Any, extensions: &mut Extensions| {
+        extensions.insert(ConnectionStarted(Instant::now()));
+    }
+}
+
+struct Connection {
+    started: Instant,
+    requests_served: u64,
+    last_seen: Instant,
+}
+
+/// The connections of requests, by peer address, shared between clones of the
+/// middleware.
+///
+/// Connections are only tracked once their first request carried the data of
+/// [`connection_tracker`], so this stays empty without it.
+#[derive(Default)]
+pub(crate) struct ConnectionTable {
+    tracking: AtomicBool,
+    connections: Mutex<HashMap<SocketAddr, Connection>>,
+}
+
+impl ConnectionTable {
+    /// Counts a request on its connection, if it is tracked.
+    pub(crate) fn record(&self, req: &HttpRequest, now: Instant) -> Option<ConnectionStats> {
+        let started = req
+            .extensions()
+            .get::<ConnectionStarted>()
+            .map(|started| started.0);
+        // Avoid locking the table for every request without the tracker
+        if started.is_some() {
+            self.tracking.store(true, Ordering::Relaxed);
+        } else if !self.tracking.load(Ordering::Relaxed) {
+            return None;
+        }
+        let peer_addr = req.peer_addr();
+        let mut connections = self.connections.lock().unwrap();
+        let connection = match (started, peer_addr) {
+            (Some(started), peer_addr) => {
+                let connection = Connection {
+                    started,
+                    requests_served: 1,
+                    last_seen: now,
+                };
+                let stats = ConnectionStats::new(&connection, now);
+                if let Some(peer_addr) = peer_addr {
+                    if connections.len() >= MAX_CONNECTIONS {
+                        connections.retain(|_, connection| {
+                            now.saturating_duration_since(connection.last_seen) < MAX_IDLE
+                        });
+                    }
+                    if connections.len() < MAX_CONNECTIONS || connections.contains_key(&peer_addr) {
+                        connections.insert(peer_addr, connection);
+                    }
+                }
+                return Some(stats);
+            }
+            (None, Some(peer_addr)) => connections.get_mut(&peer_addr)?,
+            (None, None) => return None,
+        };
+        connection.requests_served += 1;
+        connection.last_seen = now;
+        Some(ConnectionStats::new(connection, now))
+    }
+}
+
+/// The state of the connection of a request.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ConnectionStats {
+    requests_served: u64,
+    age: Duration,
+}
+
+impl ConnectionStats {
+    fn new(connection: &Connection, now: Instant) -> Self {
+        ConnectionStats {
+            requests_served: connection.requests_served,
+            age: now.saturating_duration_since(connection.started),
+        }
+    }
+
+    pub(crate) fn apply_to_scope(self, scope: &mut Scope) {
+        scope.set_extra("connection.requests_served", self.requests_served.into());
+        scope.set_extra("connection.age_secs", self.age.as_secs_f64().into());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{error, web, App, HttpResponse, HttpServer};
+    use sentry::test::TestTransport;
+    use sentry_core::protocol::Value;
+    use sentry_core::Hub;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    use super::*;
+    use crate::Sentry;
+
+    async fn failing() -> Result<HttpResponse, actix_web::Error> {
+        Err(error::ErrorInternalServerError("Stale session"))
+    }
+
+    fn hub(transport: &Arc<TestTransport>) -> Arc<Hub> {
+        let client = sentry::Client::from(sentry::ClientOptions {
+            dsn: Some("https://public@sentry.invalid/1".parse().unwrap()),
+            transport: Some(Arc::new(transport.clone())),
+            ..Default::default()
+        });
+        Arc::new(Hub::new(Some(Arc::new(client)), Default::default()))
+    }
+
+    /// Reads a response with a `Content-Length` from the stream.
+    async fn read_response(stream: &mut TcpStream) -> String {
+        let mut response = Vec::new();
+        let mut buf = [0; 1024];
+        loop {
+            let read = stream.read(&mut buf).await.unwrap();
+            assert!(read > 0, "connection closed");
+            response.extend_from_slice(&buf[..read]);
+            let text = String::from_utf8_lossy(&response).into_owned();
+            if let Some(end) = text.find("\r\n\r\n") {
+                let length = text[..end]
+                    .lines()
+                    .find_map(|line| line.strip_prefix("content-length: "))
+                    .map_or(0, |length| length.parse().unwrap());
+                if response.len() >= end + 4 + length {
+                    return text;
+                }
+            }
+        }
+    }
+
+    /// Ensures events carry the state of their keep-alive connection.
+    #[actix_rt::test]
+    async fn test_connection_tracker() {
+        let transport = TestTransport::new();
+        let hub = hub(&transport);
+        let server = HttpServer::new(move || {
+            App::new()
+                .wrap(Sentry::builder().with_hub(hub.clone()).finish())
+                .route("/failing", web::get().to(failing))
+        })
+        .workers(1)
+        .on_connect(connection_tracker())
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let addr = server.addrs()[0];
+        let server = server.run();
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        for _ in 0..3 {
+            stream
+                .write_all(b"GET /failing HTTP/1.1\r\nhost: localhost\r\n\r\n")
+                .await
+                .unwrap();
+            let response = read_response(&mut stream).await;
+            assert!(response.starts_with("HTTP/1.1 500"));
+        }
+        drop(stream);
+        server.stop(true).await;
+
+        let events = transport.fetch_and_clear_events();
+        assert_eq!(events.len(), 3);
+        let served: Vec<_> = events
+            .iter()
+            .map(|event| event.extra["connection.requests_served"].as_u64().unwrap())
+            .collect();
+        assert_eq!(served, [1, 2, 3]);
+        let ages: Vec<_> = events
+            .iter()
+            .map(|event| event.extra["connection.age_secs"].as_f64().unwrap())
+            .collect();
+        assert!(ages.windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+
+    /// Ensures events of requests without connection data are left alone.
+    #[actix_rt::test]
+    async fn test_untracked_connection() {
+        let transport = TestTransport::new();
+        let app = init_service(
+            App::new()
+                .wrap(Sentry::builder().with_hub(hub(&transport)).finish())
+                .route("/failing", web::get().to(failing)),
+        )
+        .await;
+
+        let req = TestRequest::get().uri("/failing").to_request();
+        let res = call_service(&app, req).await;
+        assert!(res.status().is_server_error());
+
+        let events = transport.fetch_and_clear_events();
+        assert_eq!(events.len(), 1);
+        assert!(!events[0].extra.contains_key("connection.requests_served"));
+        assert_eq!(events[0].extra.get("connection.age_secs"), None::<&Value>);
+    }
+}
@@ -0,0 +1,269 @@
+//! Configuring the middleware from environment variables.
+
+use std::env;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::builder::{SentryBuilder, SentryConfigError};
+use crate::capture::CaptureMode;
+use crate::middleware::Sentry;
+use crate::request::{EnvProfile, IpPolicy};
+
+/// The prefix of the environment variables read by the middleware.
+const PREFIX: &str = "SENTRY_ACTIX_";
+
+impl Sentry {
+    /// Creates a middleware configured from `SENTRY_ACTIX_*` environment variables.
+    ///
+    /// Invalid values are written to the debug log of the SDK and ignored.  See
+    /// [`SentryBuilder::override_from_env`] for the supported variables.
+    pub fn from_env() -> Self {
+        Sentry::builder().override_from_env().finish()
+    }
+
+    /// Creates a middleware configured from `SENTRY_ACTIX_*` environment variables.
+    ///
+    /// Returns an error for the first invalid value.  See
+    /// [`SentryBuilder::override_from_env`] for the supported variables.
+    pub fn try_from_env() -> Result<Self, SentryConfigError> {
+        Sentry::builder().try_override_from_env()?.build()
+    }
+}
+
+impl SentryBuilder {
+    /// Overrides the configuration with `SENTRY_ACTIX_*` environment variables.
+    ///
+    /// Variables that are set replace the values configured before, so that
+    /// deployments can adjust the programmatic configuration without rebuilding.
+    /// Invalid values are written to the debug log of the SDK and ignored, with the
+    /// hub configured so far.  The supported variables are:
+    ///
+    /// - `SENTRY_ACTIX_EMIT_HEADER`, see [`emit_header`](Self::emit_header)
+    /// - `SENTRY_ACTIX_EMIT_HEADER_HYPHENATED`, see
+    ///   [`emit_header_hyphenated`](Self::emit_header_hyphenated)
+    /// - `SENTRY_ACTIX_EMIT_TRACE_HEADERS`, see [`emit_trace_headers`](Self::emit_trace_headers)
+    /// - `SENTRY_ACTIX_CAPTURE_SERVER_ERRORS`, see
+    ///   [`capture_server_errors`](Self::capture_server_errors)
+    /// - `SENTRY_ACTIX_CAPTURE_METHOD_NOT_ALLOWED`, see
+    ///   [`capture_method_not_allowed`](Self::capture_method_not_allowed)
+    /// - `SENTRY_ACTIX_CAPTURE_MODE`, `immediate` or `deferred`, see
+    ///   [`capture_mode`](Self::capture_mode)
+    /// - `SENTRY_ACTIX_ERROR_SAMPLE_RATE`, see [`error_sample_rate`](Self::error_sample_rate)
+    /// - `SENTRY_ACTIX_FULL_REQUEST_DATA_SAMPLE_RATE`, see
+    ///   [`full_request_data_sample_rate`](Self::full_request_data_sample_rate)
+    /// - `SENTRY_ACTIX_TRACK_REQUEST_BODY`, see [`track_request_body`](Self::track_request_body)
+    /// - `SENTRY_ACTIX_MAX_REQUEST_BODY_SIZE`, see
+    ///   [`max_request_body_size`](Self::max_request_body_size)
+    /// - `SENTRY_ACTIX_MAX_EVENT_SIZE`, see [`max_event_size`](Self::max_event_size)
+    /// - `SENTRY_ACTIX_PROBLEM_JSON`, see [`problem_json`](Self::problem_json)
+    /// - `SENTRY_ACTIX_ENV_PROFILE`, `minimal`, `standard` or `full`, see
+    ///   [`env_profile`](Self::env_profile)
+    /// - `SENTRY_ACTIX_IP_POLICY`, `full`, `truncated` or `none`, see
+    ///   [`ip_policy`](Self::ip_policy)
+    /// - `SENTRY_ACTIX_LOGGER`, see [`logger`](Self::logger)
+    ///
+    /// Booleans accept `1`, `true`, `yes` and `on`, or `0`, `false`, `no` and `off`.
+    pub fn override_from_env(self) -> Self {
+        let middleware = Sentry::from_config(self.config.clone());
+        self.apply_env(|error| middleware.debug_log(format_args!("ignoring {}", error)))
+    }
+
+    /// Overrides the configuration with `SENTRY_ACTIX_*` environment variables.
+    ///
+    /// Returns an error for the first invalid value.  See
+    /// [`override_from_env`](Self::override_from_env) for the supported variables.
+    pub fn try_override_from_env(self) -> Result<Self, SentryConfigError> {
+        let mut first_error = None;
+        let builder = self.apply_env(|error| {
+            first_error.get_or_insert(error);
+        });
+        match first_error {
+            Some(error) => Err(error),
+            None => Ok(builder),
+        }
+    }
+
+    /// Applies the set variables, reporting invalid values to `on_error`.
+    fn apply_env<F: FnMut(SentryConfigError)>(mut self, mut on_error: F) -> Self {
+        let mut var = |name: &str, apply: &mut dyn FnMut(&str) -> bool| {
+            let name = format!("{}{}", PREFIX, name);
+            if let Ok(value) = env::var(&name) {
+                if !apply(value.trim()) {
+                    on_error(SentryConfigError::InvalidEnvVar(name, value));
+                }
+            }
+        };
+        let config = &mut self.config;
+        var("EMIT_HEADER", &mut |v| {
+            set(&mut config.emit_header, parse_bool(v))
+        });
+        var("EMIT_HEADER_HYPHENATED", &mut |v| {
+            set(&mut config.emit_header_hyphenated, parse_bool(v))
+        });
+        var("EMIT_TRACE_HEADERS", &mut |v| {
+            set(&mut config.emit_trace_headers, parse_bool(v))
+        });
+        var("CAPTURE_SERVER_ERRORS", &mut |v| {
+            set(&mut config.capture_server_errors, parse_bool(v))
+        });
+        var("CAPTURE_METHOD_NOT_ALLOWED", &mut |v| {
+            set(&mut config.capture_method_not_allowed, parse_bool(v))
+        });
+        var("CAPTURE_MODE", &mut |v| {
+            let mode = match v.to_ascii_lowercase().as_str() {
+                "immediate" => Some(CaptureMode::Immediate),
+                "deferred" => Some(CaptureMode::Deferred),
+                _ => None,
+            };
+            set(&mut config.capture_mode, mode)
+        });
+        var("ERROR_SAMPLE_RATE", &mut |v| {
+            set(&mut config.error_sample_rate, parse_rate(v))
+        });
+        var("FULL_REQUEST_DATA_SAMPLE_RATE", &mut |v| {
+            set(&mut config.full_request_data_sample_rate, parse_rate(v))
+        });
+        var("TRACK_REQUEST_BODY", &mut |v| {
+            set(&mut config.track_request_body, parse_bool(v))
+        });
+        var("MAX_REQUEST_BODY_SIZE", &mut |v| {
+            set(&mut config.max_request_body_size, v.parse().ok())
+        });
+        var("MAX_EVENT_SIZE", &mut |v| {
+            set(&mut config.max_event_size, v.parse().ok())
+        });
+        var("PROBLEM_JSON", &mut |v| {
+            set(&mut config.problem_json, parse_bool(v))
+        });
+        var("ENV_PROFILE", &mut |v| {
+            let profile = match v.to_ascii_lowercase().as_str() {
+                "minimal" => Some(EnvProfile::Minimal),
+                "standard" => Some(EnvProfile::Standard),
+                "full" => Some(EnvProfile::Full),
+                _ => None,
+            };
+            set(&mut config.env_profile, profile)
+        });
+        var("IP_POLICY", &mut |v| {
+            let policy = match v.to_ascii_lowercase().as_str() {
+                "full" => Some(IpPolicy::Full),
+                "truncated" => Some(IpPolicy::Truncated),
+                "none" => Some(IpPolicy::None),
+                _ => None,
+            };
+            set(&mut config.ip_policy, policy)
+        });
+        var("LOGGER", &mut |v| {
+            let logger = Some(v).filter(|v| !v.is_empty());
+            set(&mut config.logger, logger.map(Arc::from))
+        });
+        self
+    }
+}
+
+/// Stores a parsed value, returning whether it was valid.
+fn set<T>(target: &mut T, value: Option<T>) -> bool {
+    match value {
+        Some(value) => {
+            *target = value;
+            true
+        }
+        None => false,
+    }
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Some(true),
+        "0" | "false" | "no" | "off" => Some(false),
+        _ => None,
+    }
+}
+
+/// Parses a sample rate between `0.0` and `1.0`.
+fn parse_rate(value: &str) -> Option<f32> {
+    f32::from_str(value)
+        .ok()
+        .filter(|rate| (0.0..=1.0).contains(rate))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{web, App};
+    use futures::executor::block_on;
+    use sentry_core::Hub;
+
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// Ensures variables override the configuration, with invalid values reported.
+    ///
+    /// All assertions on the environment are made in this single test, as tests
+    /// run in parallel and the environment is shared by the process.
+    #[actix_rt::test]
+    async fn test_override_from_env() {
+        env::set_var("SENTRY_ACTIX_EMIT_HEADER", "1");
+        env::set_var("SENTRY_ACTIX_ERROR_SAMPLE_RATE", " 0.5 ");
+        env::set_var("SENTRY_ACTIX_CAPTURE_MODE", "sometimes");
+
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let middleware = Sentry::builder()
+            .emit_trace_headers(true)
+            .debug_log_captures_with({
+                let lines = lines.clone();
+                move |line| lines.lock().unwrap().push(line.to_owned())
+            })
+            .override_from_env()
+            .finish();
+        assert_eq!(
+            *lines.lock().unwrap(),
+            ["ignoring invalid value `sometimes` for environment variable SENTRY_ACTIX_CAPTURE_MODE"]
+        );
+        assert!(middleware.config.emit_header);
+        assert!(middleware.config.emit_trace_headers);
+        assert_eq!(middleware.config.error_sample_rate, 0.5);
+        assert_eq!(middleware.config.capture_mode, CaptureMode::Immediate);
+
+        assert_eq!(
+            Sentry::try_from_env().err(),
+            Some(SentryConfigError::InvalidEnvVar(
+                "SENTRY_ACTIX_CAPTURE_MODE".into(),
+                "sometimes".into()
+            ))
+        );
+
+        env::set_var("SENTRY_ACTIX_CAPTURE_MODE", "Deferred");
+        env::remove_var("SENTRY_ACTIX_ERROR_SAMPLE_RATE");
+        let events = sentry::test::with_captured_events(|| {
+            block_on(async {
+                async fn failing() -> Result<String, actix_web::Error> {
+                    Err(actix_web::error::ErrorInternalServerError("Test Error"))
+                }
+
+                let middleware = Sentry::builder()
+                    .with_hub(Hub::current())
+                    .try_override_from_env()
+                    .unwrap()
+                    .finish();
+                let app = init_service(
+                    App::new()
+                        .wrap(middleware)
+                        .service(web::resource("/failing").to(failing)),
+                )
+                .await;
+
+                let req = TestRequest::get().uri("/failing").to_request();
+                let res = call_service(&app, req).await;
+                assert!(res.headers().contains_key("x-sentry-event"));
+                actix_web::test::read_body(res).await;
+            })
+        });
+        assert_eq!(events.len(), 1);
+        assert!(events[0].extra.contains_key("response_body_bytes"));
+
+        env::remove_var("SENTRY_ACTIX_EMIT_HEADER");
+        env::remove_var("SENTRY_ACTIX_CAPTURE_MODE");
+    }
+}
@@ -0,0 +1,114 @@
+//! Linking responses to the events captured for them.
+
+use sentry_core::types::{Dsn, Scheme, Uuid};
+
+use crate::middleware::format_event_id;
+
+/// The format of the `X-Sentry-Event-Url` header linking to a captured event.
+///
+/// The organization of a project is not part of its DSN, so only
+/// [`EventLink::Url`] links to the event in the Sentry UI.  The host of the UI
+/// is derived from the DSN: events of `sentry.io`, which are ingested by hosts
+/// such as `o1.ingest.sentry.io`, link to `https://sentry.io`, while events of
+/// self-hosted Sentry link to the host, port and path of the DSN.
+///
+/// # Example
+///
+/// ```
+/// use sentry_actix::{EventLink, Sentry};
+///
+/// let middleware = Sentry::builder()
+///     .emit_event_link(Some(EventLink::Url {
+///         organization: "acme".into(),
+///     }))
+///     .finish();
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EventLink {
+    /// The project and the event, as in `42:fc6d8c0c43fc4630ad850ee518f1b9d0`.
+    ProjectEvent,
+    /// A search for the event in the issues of an organization, as in
+    /// `https://sentry.io/organizations/acme/issues/?project=42&query=fc6d8c0c43fc4630ad850ee518f1b9d0`.
+    Url {
+        /// The slug of the organization of the project.
+        organization: String,
+    },
+}
+
+impl EventLink {
+    /// Formats the link to an event of the project of the DSN.
+    pub(crate) fn format(&self, dsn: &Dsn, event_id: Uuid) -> String {
+        let event_id = format_event_id(event_id, false);
+        match self {
+            EventLink::ProjectEvent => format!("{}:{}", dsn.project_id(), event_id),
+            EventLink::Url { organization } => format!(
+                "{}/organizations/{}/issues/?project={}&query={}",
+                base_url(dsn),
+                organization,
+                dsn.project_id(),
+                event_id
+            ),
+        }
+    }
+}
+
+/// Returns the URL of the Sentry UI of the DSN, without a trailing slash.
+fn base_url(dsn: &Dsn) -> String {
+    let host = dsn.host();
+    if host == "sentry.io" || host.ends_with(".sentry.io") {
+        return "https://sentry.io".into();
+    }
+    let port = if dsn.port() == dsn.scheme().default_port() {
+        String::new()
+    } else {
+        format!(":{}", dsn.port())
+    };
+    let scheme = match dsn.scheme() {
+        Scheme::Http => "http",
+        Scheme::Https => "https",
+    };
+    format!(
+        "{}://{}{}{}",
+        scheme,
+        host,
+        port,
+        dsn.path().trim_end_matches('/')
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(link: &EventLink, dsn: &str) -> String {
+        let event_id = "fc6d8c0c-43fc-4630-ad85-0ee518f1b9d0".parse().unwrap();
+        link.format(&dsn.parse().unwrap(), event_id)
+    }
+
+    #[test]
+    fn test_format() {
+        let url = EventLink::Url {
+            organization: "acme".into(),
+        };
+
+        assert_eq!(
+            format(
+                &EventLink::ProjectEvent,
+                "https://public@o1.ingest.sentry.io/42"
+            ),
+            "42:fc6d8c0c43fc4630ad850ee518f1b9d0"
+        );
+        assert_eq!(
+            format(&url, "https://public@o1.ingest.sentry.io/42"),
+            "https://sentry.io/organizations/acme/issues/?project=42&query=fc6d8c0c43fc4630ad850ee518f1b9d0"
+        );
+        assert_eq!(
+            format(&url, "http://public@sentry.example.com:9000/sentry/42"),
+            "http://sentry.example.com:9000/sentry/organizations/acme/issues/?project=42&query=fc6d8c0c43fc4630ad850ee518f1b9d0"
+        );
+        assert_eq!(
+            format(&url, "https://public@sentry.example.com:443/42"),
+            "https://sentry.example.com/organizations/acme/issues/?project=42&query=fc6d8c0c43fc4630ad850ee518f1b9d0"
+        );
+    }
+}
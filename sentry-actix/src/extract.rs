@@ -0,0 +1,192 @@
+//! Instrumenting the extractors of handlers.
+
+use std::any::type_name;
+use std::ops::{Deref, DerefMut};
+use std::time::Instant;
+
+use actix_web::dev::Payload;
+use actix_web::{FromRequest, HttpRequest};
+use futures_util::future::LocalBoxFuture;
+use futures_util::FutureExt;
+
+use sentry_core::protocol::{Breadcrumb, Map};
+use sentry_core::{event_from_error, Hub, Level};
+
+use crate::capture::CaptureOverride;
+use crate::middleware::Handled;
+
+/// An extractor recording the extraction of `T`.
+///
+/// Wrapping an extractor in a handler signature records how long it took on
+/// the current hub, as a breadcrumb with the `extractor` category named after
+/// the type of the extractor and its `duration_ms`.  Extraction errors are
+/// captured, with the type of the extractor as the `extractor` tag, before
+/// being returned as usual.  Requests that are not handled by the
+/// [`Sentry`](crate::Sentry) middleware are left alone.
+///
+/// This version of the SDK has no performance API, so extractions are recorded
+/// as breadcrumbs rather than spans of the request transaction.
+///
+/// # Example
+///
+/// ```
+/// use actix_web::web::Json;
+/// use sentry_actix::Instrumented;
+/// use serde_json::Value;
+///
+/// async fn create(order: Instrumented<Json<Value>>) -> String {
+///     format!("created {}", order["id"])
+/// }
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Instrumented<T>(pub T);
+
+impl<T> Instrumented<T> {
+    /// Returns the extracted value.
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Deref for Instrumented<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for Instrumented<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<T> FromRequest for Instrumented<T>
+where
+    T: FromRequest + 'static,
+{
+    type Config = T::Config;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let fut = T::from_request(req, payload);
+        if !req.extensions().contains::<Handled>() {
+            return async move { fut.await.map(Instrumented).map_err(Into::into) }.boxed_local();
+        }
+        let capture =
+            CaptureOverride::from_extensions(&req.extensions()) != CaptureOverride::Disable;
+        let started = Instant::now();
+
+        // The request hub is only bound while the future is polled
+        async move {
+            let result = fut.await.map_err(Into::into);
+            let hub = Hub::current();
+            let extractor = type_name::<T>();
+            let mut data = Map::new();
+            let duration = started.elapsed();
+            data.insert(
+                "duration_ms".into(),
+                (duration.as_secs_f64() * 1000.0).into(),
+            );
+            hub.add_breadcrumb(Breadcrumb {
+                category: Some("extractor".into()),
+                message: Some(extractor.into()),
+                level: if result.is_ok() {
+                    Level::Info
+                } else {
+                    Level::Error
+                },
+                data,
+                ..Default::default()
+            });
+            if let (Err(ref error), true) = (&result, capture) {
+                let mut event = event_from_error(error);
+                event.tags.insert("extractor".into(), extractor.into());
+                event
+                    .tags
+                    .insert("sentry_actix.capture_source".into(), "extractor".into());
+                hub.capture_event(event);
+            }
+            result.map(Instrumented)
+        }
+        .boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::web::{self, Json};
+    use actix_web::App;
+    use futures::executor::block_on;
+    use serde_json::Value;
+
+    use super::*;
+    use crate::Sentry;
+
+    async fn create(order: Instrumented<Json<Value>>) -> Result<String, actix_web::Error> {
+        match order["items"].as_array() {
+            Some(items) if !items.is_empty() => Ok(format!("{} items", items.len())),
+            _ => Err(actix_web::error::ErrorInternalServerError("Empty order")),
+        }
+    }
+
+    /// Ensures extractions are recorded, and their errors captured with the extractor.
+    #[actix_rt::test]
+    async fn test_instrumented_extractor() {
+        let events = sentry::test::with_captured_events(|| {
+            block_on(async {
+                let middleware = Sentry::builder().with_hub(Hub::current()).finish();
+                let app = init_service(
+                    App::new()
+                        .wrap(middleware)
+                        .service(web::resource("/orders").to(create)),
+                )
+                .await;
+
+                let req = TestRequest::post()
+                    .uri("/orders")
+                    .set_json(&serde_json::json!({ "items": [1, 2] }))
+                    .to_request();
+                assert!(call_service(&app, req).await.status().is_success());
+
+                let req = TestRequest::post()
+                    .uri("/orders")
+                    .set_json(&serde_json::json!({ "items": [] }))
+                    .to_request();
+                assert!(call_service(&app, req).await.status().is_server_error());
+
+                let req = TestRequest::post()
+                    .uri("/orders")
+                    .insert_header(("content-type", "application/json"))
+                    .set_payload("{")
+                    .to_request();
+                assert!(call_service(&app, req).await.status().is_client_error());
+            })
+        });
+        assert_eq!(events.len(), 2);
+
+        // The extraction is recorded for errors of the handler
+        let event = &events[0];
+        let breadcrumb = event.breadcrumbs.values.last().unwrap();
+        assert_eq!(breadcrumb.category.as_deref(), Some("extractor"));
+        assert_eq!(
+            breadcrumb.message.as_deref(),
+            Some(type_name::<Json<Value>>())
+        );
+        assert_eq!(breadcrumb.level, Level::Info);
+        assert!(breadcrumb.data["duration_ms"].is_f64());
+
+        // Extraction errors are captured with the extractor
+        let event = &events[1];
+        assert_eq!(event.tags["extractor"], type_name::<Json<Value>>());
+        assert_eq!(event.tags["sentry_actix.capture_source"], "extractor");
+        assert_eq!(event.breadcrumbs.values.last().unwrap().level, Level::Error);
+        assert_eq!(
+            event.request.as_ref().unwrap().url.as_ref().unwrap().path(),
+            "/orders"
+        );
+    }
+}
@@ -0,0 +1,131 @@
+//! Naming events of GraphQL requests after their operation.
+
+use serde_json::Value;
+
+use sentry_core::protocol::Event;
+
+use crate::redact::FILTERED;
+
+/// The operation of a GraphQL request.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Operation {
+    pub(crate) name: Option<String>,
+    pub(crate) ty: &'static str,
+}
+
+impl Operation {
+    /// Parses the operation from the JSON body of a GraphQL request.
+    ///
+    /// The name is taken from `operationName`, or the query text if it is missing.
+    pub(crate) fn from_body(body: &[u8]) -> Option<Self> {
+        let body: Value = serde_json::from_slice(body).ok()?;
+        let query = body.get("query")?.as_str()?.trim_start();
+        let (ty, rest) = if query.starts_with('{') {
+            ("query", "")
+        } else {
+            let ty = ["query", "mutation", "subscription"]
+                .iter()
+                .find(|ty| query.starts_with(*ty))?;
+            (*ty, &query[ty.len()..])
+        };
+        let name = match body.get("operationName").and_then(Value::as_str) {
+            Some(name) => Some(name.to_owned()),
+            None => {
+                let name: String = rest
+                    .trim_start()
+                    .chars()
+                    .take_while(|c| c.is_alphanumeric() || *c == '_')
+                    .collect();
+                Some(name).filter(|name| !name.is_empty())
+            }
+        };
+        Some(Operation { name, ty })
+    }
+
+    /// Tags the event with the operation and names its transaction after it.
+    ///
+    /// The transaction is only renamed if it is still the default of the route.
+    pub(crate) fn apply_to_event(&self, event: &mut Event<'static>, method: &str, route: &str) {
+        event
+            .tags
+            .insert("graphql.operation_type".into(), self.ty.into());
+        if let Some(ref name) = self.name {
+            event.tags.insert("graphql.operation".into(), name.clone());
+            if event.transaction.as_deref() == Some(route) {
+                event.transaction = Some(format!("{} {} ({})", method, route, name));
+            }
+        }
+    }
+}
+
+/// Filters the variables of the GraphQL request attached to the event.
+pub(crate) fn filter_variables(event: &mut Event<'static>) {
+    let data = match event
+        .request
+        .as_mut()
+        .and_then(|request| request.data.as_mut())
+    {
+        Some(data) => data,
+        None => return,
+    };
+    if let Ok(Value::Object(mut body)) = serde_json::from_str(data) {
+        if let Some(variables) = body.get_mut("variables") {
+            if !variables.is_null() {
+                *variables = FILTERED.into();
+                *data = Value::Object(body).to_string();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sentry_core::protocol::Request;
+
+    use super::*;
+
+    #[test]
+    fn test_operation_from_body() {
+        let operation = |body: &str| Operation::from_body(body.as_bytes());
+        assert_eq!(
+            operation(r#"{"query":"query Items { items { id } }","operationName":"ListItems"}"#),
+            Some(Operation {
+                name: Some("ListItems".into()),
+                ty: "query",
+            })
+        );
+        assert_eq!(
+            operation(r#"{"query":"  mutation AddItem($id: ID!) { add(id: $id) }"}"#),
+            Some(Operation {
+                name: Some("AddItem".into()),
+                ty: "mutation",
+            })
+        );
+        assert_eq!(
+            operation(r#"{"query":"{ items { id } }"}"#),
+            Some(Operation {
+                name: None,
+                ty: "query",
+            })
+        );
+        assert_eq!(operation(r#"{"query":"fragment F on Item { id }"}"#), None);
+        assert_eq!(operation(r#"{"operationName":"ListItems"}"#), None);
+        assert_eq!(operation("query { items }"), None);
+    }
+
+    #[test]
+    fn test_filter_variables() {
+        let mut event = Event {
+            request: Some(Request {
+                data: Some(r#"{"query":"{ me }","variables":{"email":"jane@example.com"}}"#.into()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        filter_variables(&mut event);
+        let data: Value =
+            serde_json::from_str(event.request.unwrap().data.as_deref().unwrap()).unwrap();
+        assert_eq!(data["query"], "{ me }");
+        assert_eq!(data["variables"], FILTERED);
+    }
+}
@@ -0,0 +1,343 @@
+//! Naming and describing the handlers of routes.
+
+use std::borrow::Cow;
+use std::task::{Context, Poll};
+
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{HttpMessage, HttpRequest};
+use futures_util::future::{ok, LocalBoxFuture, Ready};
+use futures_util::FutureExt;
+
+use sentry_core::protocol::Event;
+use sentry_core::{Hub, Level};
+
+use crate::middleware::Handled;
+
+/// Names the handler of the wrapped resource or route.
+///
+/// Events captured for requests to the wrapped service carry the name as the
+/// `handler` extra.  Routes without a name, such as those registered with
+/// `.to()`, additionally use it as the transaction name instead of the route
+/// pattern, unless the transaction name was customized.  Requests that are not
+/// handled by the [`Sentry`](crate::Sentry) middleware are left alone.
+///
+/// # Example
+///
+/// ```
+/// use actix_web::{get, web, App};
+/// use sentry_actix::HandlerName;
+///
+/// async fn list() -> &'static str {
+///     "items"
+/// }
+///
+/// // Attribute macros only accept paths for wrappers
+/// const SHOW: HandlerName = HandlerName::from_static("my_app::handlers::show");
+///
+/// #[get("/items/{id}", wrap = "SHOW")]
+/// async fn show() -> &'static str {
+///     "item"
+/// }
+///
+/// let app = App::new()
+///     .wrap(sentry_actix::Sentry::new())
+///     .service(
+///         web::resource("/items")
+///             .to(list)
+///             .wrap(sentry_actix::named("my_app::handlers::list")),
+///     )
+///     .service(show);
+/// ```
+pub fn named<N>(name: N) -> HandlerName
+where
+    N: Into<Cow<'static, str>>,
+{
+    HandlerName { name: name.into() }
+}
+
+/// A middleware naming the handler of a service.
+///
+/// See [`named`].
+#[derive(Clone, Debug)]
+pub struct HandlerName {
+    name: Cow<'static, str>,
+}
+
+impl HandlerName {
+    /// Creates the middleware in constant contexts.
+    ///
+    /// This allows naming handlers of routes registered with attribute macros.
+    pub const fn from_static(name: &'static str) -> Self {
+        HandlerName {
+            name: Cow::Borrowed(name),
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for HandlerName
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = HandlerNameMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(HandlerNameMiddleware {
+            service,
+            name: self.name.clone(),
+        })
+    }
+}
+
+/// Metadata of a handler applied to the events of its errors.
+///
+/// Handlers attach the metadata to their request, usually with the
+/// `monitored` attribute of the `macros` feature.  Events of errors returned
+/// by the handler carry the `op` and `owner` tags, and the level of the
+/// metadata unless an error classifier sets one.
+///
+/// # Example
+///
+/// ```
+/// use std::borrow::Cow;
+///
+/// use actix_web::{error, Error, HttpRequest};
+/// use sentry::Level;
+/// use sentry_actix::HandlerMetadata;
+///
+/// // With the `macros` feature:
+/// // #[sentry_actix::monitored(op = "checkout", owner = "payments", level_on_error = "warning")]
+/// async fn checkout(req: HttpRequest) -> Result<String, Error> {
+///     HandlerMetadata {
+///         op: Some(Cow::Borrowed("checkout")),
+///         owner: Some(Cow::Borrowed("payments")),
+///         level_on_error: Some(Level::Warning),
+///     }
+///     .attach(&req);
+///     Err(error::ErrorServiceUnavailable("Payment provider unavailable"))
+/// }
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HandlerMetadata {
+    /// The operation of the handler, set as the `op` tag.
+    pub op: Option<Cow<'static, str>>,
+    /// The owner of the handler, set as the `owner` tag.
+    pub owner: Option<Cow<'static, str>>,
+    /// The level of the events of errors of the handler.
+    pub level_on_error: Option<Level>,
+}
+
+impl HandlerMetadata {
+    /// Attaches the metadata to a request, replacing metadata attached before.
+    pub fn attach(self, req: &HttpRequest) {
+        req.extensions_mut().insert(self);
+    }
+
+    /// Applies the metadata to the event of an error.
+    pub(crate) fn apply_to_event(&self, event: &mut Event<'static>) {
+        if let Some(ref op) = self.op {
+            event.tags.insert("op".into(), op.to_string());
+        }
+        if let Some(ref owner) = self.owner {
+            event.tags.insert("owner".into(), owner.to_string());
+        }
+        if let Some(level) = self.level_on_error {
+            event.level = level;
+        }
+    }
+}
+
+/// The handler naming middleware for individual services.
+pub struct HandlerNameMiddleware<S> {
+    service: S,
+    name: Cow<'static, str>,
+}
+
+impl<S, B> Service<ServiceRequest> for HandlerNameMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        if !req.extensions().contains::<Handled>() {
+            return self.service.call(req).boxed_local();
+        }
+        let name = self.name.clone();
+        let pattern = req.match_pattern();
+        let unnamed = req.match_name().is_none();
+        let fut = self.service.call(req);
+
+        // The request hub is only bound while the future is polled
+        async move {
+            Hub::current().configure_scope(|scope| {
+                scope.set_extra("handler", name.as_ref().into());
+                if unnamed && scope.transaction() == pattern.as_deref() {
+                    scope.set_transaction(Some(&name));
+                }
+            });
+            fut.await
+        }
+        .boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{get, web, App};
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::Sentry;
+
+    async fn failing() -> Result<String, actix_web::Error> {
+        Err(io::Error::new(io::ErrorKind::Other, "Test Error").into())
+    }
+
+    /// Ensures named handlers are attached to events of `.to()` and attribute macro routes.
+    #[actix_rt::test]
+    async fn test_named_handlers() {
+        let events = sentry::test::with_captured_events(|| {
+            block_on(async {
+                const WITH_MACRO: HandlerName = HandlerName::from_static("handlers::with_macro");
+
+                #[get("/macro", wrap = "WITH_MACRO")]
+                async fn with_macro() -> Result<String, actix_web::Error> {
+                    failing().await
+                }
+
+                let app = init_service(
+                    App::new()
+                        .wrap(Sentry::builder().with_hub(Hub::current()).finish())
+                        .service(
+                            web::resource("/items/{id}")
+                                .to(failing)
+                                .wrap(named("handlers::show_item")),
+                        )
+                        .service(
+                            web::resource("/custom")
+                                .to(|| async {
+                                    Hub::current().configure_scope(|scope| {
+                                        scope.set_transaction(Some("custom"))
+                                    });
+                                    failing().await
+                                })
+                                .wrap(named("handlers::custom")),
+                        )
+                        .service(with_macro)
+                        .service(web::resource("/unnamed").to(failing)),
+                )
+                .await;
+
+                for uri in &["/items/1", "/custom", "/macro", "/unnamed"] {
+                    let req = TestRequest::get().uri(uri).to_request();
+                    let res = call_service(&app, req).await;
+                    assert!(res.status().is_server_error());
+                }
+            })
+        });
+
+        assert_eq!(events.len(), 4);
+        assert_eq!(events[0].extra["handler"], "handlers::show_item");
+        assert_eq!(
+            events[0].transaction.as_deref(),
+            Some("handlers::show_item")
+        );
+
+        // Names set by the handler take precedence
+        assert_eq!(events[1].extra["handler"], "handlers::custom");
+        assert_eq!(events[1].transaction.as_deref(), Some("custom"));
+
+        // Routes named by the macro keep their name
+        assert_eq!(events[2].extra["handler"], "handlers::with_macro");
+        assert_eq!(events[2].transaction.as_deref(), Some("with_macro"));
+
+        assert!(!events[3].extra.contains_key("handler"));
+        assert_eq!(events[3].transaction.as_deref(), Some("/unnamed"));
+    }
+
+    /// Ensures named handlers leave requests alone that are not handled by the middleware.
+    #[actix_rt::test]
+    async fn test_named_without_middleware() {
+        let events = sentry::test::with_captured_events(|| {
+            block_on(async {
+                let app = init_service(
+                    App::new().service(
+                        web::resource("/test")
+                            .to(|| async {
+                                sentry::capture_message("Message", sentry::Level::Info);
+                                "ok"
+                            })
+                            .wrap(named("handlers::test")),
+                    ),
+                )
+                .await;
+
+                let req = TestRequest::get().uri("/test").to_request();
+                let res = call_service(&app, req).await;
+                assert!(res.status().is_success());
+            })
+        });
+
+        assert_eq!(events.len(), 1);
+        assert!(!events[0].extra.contains_key("handler"));
+    }
+
+    /// Ensures monitored handlers tag their error events, whichever attribute expands first.
+    #[cfg(feature = "macros")]
+    #[actix_rt::test]
+    async fn test_monitored_handlers() {
+        let events = sentry::test::with_captured_events(|| {
+            block_on(async {
+                #[crate::monitored(op = "checkout", owner = "payments", level_on_error = "warning")]
+                #[get("/checkout")]
+                async fn checkout() -> Result<String, actix_web::Error> {
+                    failing().await
+                }
+
+                #[get("/refund")]
+                #[crate::monitored(owner = "payments")]
+                async fn refund(req: HttpRequest) -> Result<String, actix_web::Error> {
+                    assert_eq!(req.query_string(), "id=7");
+                    failing().await
+                }
+
+                let app = init_service(
+                    App::new()
+                        .wrap(Sentry::builder().with_hub(Hub::current()).finish())
+                        .service(checkout)
+                        .service(refund),
+                )
+                .await;
+
+                for uri in &["/checkout", "/refund?id=7"] {
+                    let req = TestRequest::get().uri(uri).to_request();
+                    let res = call_service(&app, req).await;
+                    assert!(res.status().is_server_error());
+                }
+            })
+        });
+
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].tags["op"], "checkout");
+        assert_eq!(events[0].tags["owner"], "payments");
+        assert_eq!(events[0].level, Level::Warning);
+        assert!(!events[1].tags.contains_key("op"));
+        assert_eq!(events[1].tags["owner"], "payments");
+        assert_eq!(events[1].level, Level::Error);
+    }
+}
@@ -0,0 +1,420 @@
+//! Helpers for handlers and the processing of captured events.
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+
+use actix_web::dev::Payload;
+use actix_web::error::ErrorInternalServerError;
+use actix_web::{FromRequest, HttpRequest};
+use futures_util::future::{ready, Ready};
+
+use sentry_core::protocol::{ClientSdkPackage, Event, IpAddress, Request, Value};
+use sentry_core::Hub;
+
+/// Records a custom measurement for the request.
+///
+/// Measurements are attached to events captured for the request as the
+/// `measurements` extra.  Recording a measurement with the same name again
+/// replaces the previous value.  Measurements recorded after the response has been
+/// returned, or for requests not handled by the middleware, are ignored.
+///
+/// # Example
+///
+/// ```
+/// use actix_web::HttpRequest;
+///
+/// async fn index(req: HttpRequest) -> &'static str {
+///     sentry_actix::set_measurement(&req, "items_processed", 42.0, "none");
+///     "done"
+/// }
+/// ```
+pub fn set_measurement(request: &HttpRequest, name: &str, value: f64, unit: &str) {
+    let measurements = request
+        .extensions()
+        .get::<Arc<Mutex<Measurements>>>()
+        .cloned();
+    if let Some(measurements) = measurements {
+        let mut measurements = measurements.lock().unwrap();
+        if !measurements.closed {
+            measurements
+                .values
+                .insert(name.to_owned(), (value, unit.to_owned()));
+        }
+    }
+}
+
+/// The transaction name the middleware computed for a request.
+///
+/// This is the name set on the request scope before the handler is called.  It
+/// is not updated if the name is changed afterwards, for instance by the handler.
+/// Extracting it fails for requests without a transaction name, such as requests
+/// not matching any route, and requests not handled by the middleware.
+///
+/// # Example
+///
+/// ```
+/// use sentry_actix::TransactionName;
+///
+/// async fn index(transaction: TransactionName) -> String {
+///     format!("handled {}", transaction.0)
+/// }
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TransactionName(pub String);
+
+impl FromRequest for TransactionName {
+    type Config = ();
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            req.extensions()
+                .get::<TransactionName>()
+                .cloned()
+                .ok_or_else(|| ErrorInternalServerError("No transaction name for the request")),
+        )
+    }
+}
+
+/// Creates a hub for background work enqueued while handling a request.
+///
+/// The returned hub starts out with a snapshot of the current hub's scope, so
+/// events captured by the background work carry the same request data, tags and
+/// transaction as events captured by the handler.  They are additionally tagged
+/// with `spawned_from`, the transaction of the originating request.  Changes to
+/// either scope after the call are not shared.
+///
+/// The hub is detached from the request's Release Health Session.  The session is
+/// ended by the middleware as soon as the response has been returned, and errors
+/// captured by background work do not update it.  Starting or ending a session on
+/// the returned hub does not affect the session of the request either.
+///
+/// # Example
+///
+/// ```
+/// use sentry_core::SentryFutureExt;
+///
+/// async fn index() -> &'static str {
+///     let hub = sentry_actix::inherit_hub_for_background();
+///     actix_rt::spawn(
+///         async {
+///             // errors captured here are linked to the request
+///         }
+///         .bind_hub(hub),
+///     );
+///     "enqueued"
+/// }
+/// ```
+pub fn inherit_hub_for_background() -> Arc<Hub> {
+    let hub = Hub::new_from_top(Hub::current());
+    hub.configure_scope(|scope| {
+        scope.detach_session();
+        if let Some(transaction) = scope.transaction().map(ToOwned::to_owned) {
+            scope.set_tag("spawned_from", transaction);
+        }
+    });
+    Arc::new(hub)
+}
+
+/// Custom measurements recorded for a request.
+#[derive(Default)]
+pub(crate) struct Measurements {
+    pub(crate) values: BTreeMap<String, (f64, String)>,
+    pub(crate) closed: bool,
+}
+
+impl Measurements {
+    /// Records the measurements as the `measurements` extra.
+    pub(crate) fn apply_to_event(&self, event: &mut Event<'static>) {
+        if self.values.is_empty() {
+            return;
+        }
+        let measurements = self
+            .values
+            .iter()
+            .map(|(name, (value, unit))| {
+                let measurement = serde_json::json!({ "value": value, "unit": unit });
+                (name.clone(), measurement)
+            })
+            .collect();
+        event
+            .extra
+            .insert("measurements".into(), Value::Object(measurements));
+    }
+}
+
+/// A writer that only counts the bytes written to it.
+struct CountingWriter(usize);
+
+impl io::Write for CountingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Estimates the size of an event serialized as JSON.
+fn estimate_event_size(event: &Event<'static>) -> usize {
+    let mut writer = CountingWriter(0);
+    serde_json::to_writer(&mut writer, event).ok();
+    writer.0
+}
+
+/// Removes optional request data from events exceeding the given size.
+///
+/// The request body is removed first, then the headers and cookies.
+pub(crate) fn trim_event(event: &mut Event<'static>, max_size: usize) {
+    let original_size = estimate_event_size(event);
+    if original_size <= max_size {
+        return;
+    }
+
+    let trim_steps: [fn(&mut Request); 2] = [
+        |request| request.data = None,
+        |request| {
+            request.headers.clear();
+            request.cookies = None;
+        },
+    ];
+    for trim in trim_steps.iter() {
+        match event.request {
+            Some(ref mut request) => trim(request),
+            None => break,
+        }
+        if estimate_event_size(event) <= max_size {
+            break;
+        }
+    }
+
+    event.tags.insert("payload_trimmed".into(), "true".into());
+    event
+        .extra
+        .insert("original_event_size".into(), original_size.into());
+}
+
+/// Parses an IP address that may carry a port and brackets, as in `[::1]:8080`.
+pub(crate) fn parse_ip(addr: &str) -> Option<IpAddr> {
+    addr.parse::<SocketAddr>()
+        .map(|addr| addr.ip())
+        .or_else(|_| addr.trim_start_matches('[').trim_end_matches(']').parse())
+        .ok()
+}
+
+/// Returns the lowercased essence of a mime type, without parameters.
+///
+/// Returns `None` if the value is not a valid `type/subtype` mime type.
+pub(crate) fn mime_essence(value: &str) -> Option<String> {
+    let essence = value.split(';').next()?.trim();
+    let mut parts = essence.splitn(2, '/');
+    let is_token = |part: &str| {
+        !part.is_empty()
+            && part
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "!#$&-^_.+".contains(c))
+    };
+    match (parts.next(), parts.next()) {
+        (Some(ty), Some(subty)) if is_token(ty) && is_token(subty) => {
+            Some(essence.to_ascii_lowercase())
+        }
+        _ => None,
+    }
+}
+
+/// Determines when the middleware's event processor runs.
+///
+/// The processor attaches the request data and other context of the request to
+/// events captured on the request hub.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProcessorPosition {
+    /// The processor runs before processors added to the scope by handlers, which
+    /// can inspect and modify the request data it attached.
+    First,
+    /// The processor runs after all other processors, which do not see the
+    /// request data.  Values they set are kept, as the processor only fills in
+    /// what is missing.
+    Last,
+}
+
+/// Adds request data to a Sentry event.
+///
+/// This is what the middleware's event processor does for every event captured
+/// during a request: it sets the request data and `logger` unless the event already
+/// has them, tags the normalized `request.content_type`, derives the user IP from
+/// `CLIENT_IP` or `REMOTE_ADDR`, and records the `sentry-actix` SDK package.
+pub fn process_event(mut event: Event<'static>, request: &Request, logger: &str) -> Event<'static> {
+    // Request
+    if event.request.is_none() {
+        event.request = Some(request.clone());
+    }
+
+    // Logger
+    if event.logger.is_none() {
+        event.logger = Some(logger.to_owned());
+    }
+
+    // Content type
+    if let Some(content_type) = request
+        .headers
+        .get("content-type")
+        .and_then(|value| mime_essence(value))
+    {
+        event
+            .tags
+            .entry("request.content_type".into())
+            .or_insert(content_type);
+    }
+
+    // User IP, preferring the client IP over the peer address
+    let client_ip = request
+        .env
+        .get("CLIENT_IP")
+        .or_else(|| request.env.get("REMOTE_ADDR"))
+        .and_then(|addr| parse_ip(addr));
+    if let Some(ip) = client_ip {
+        let user = event.user.get_or_insert_with(Default::default);
+        if user.ip_address.is_none() {
+            user.ip_address = Some(IpAddress::Exact(ip));
+        }
+    }
+
+    // SDK
+    if let Some(sdk) = event.sdk.take() {
+        let mut sdk = sdk.into_owned();
+        sdk.packages.push(ClientSdkPackage {
+            name: "sentry-actix".into(),
+            version: env!("CARGO_PKG_VERSION").into(),
+        });
+        event.sdk = Some(Cow::Owned(sdk));
+    }
+    event
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mime_essence() {
+        assert_eq!(
+            mime_essence("application/json"),
+            Some("application/json".into())
+        );
+        assert_eq!(
+            mime_essence("Text/HTML; charset=UTF-8"),
+            Some("text/html".into())
+        );
+        assert_eq!(
+            mime_essence(" application/vnd.api+json ;version=2"),
+            Some("application/vnd.api+json".into())
+        );
+        assert_eq!(mime_essence(""), None);
+        assert_eq!(mime_essence("json"), None);
+        assert_eq!(mime_essence("application/"), None);
+        assert_eq!(mime_essence("text/html/extra"), None);
+        assert_eq!(mime_essence("text /html"), None);
+    }
+
+    #[test]
+    fn test_trim_event() {
+        let event = || Event {
+            request: Some(Request {
+                method: Some("POST".into()),
+                data: Some("x".repeat(5000)),
+                headers: (0..100)
+                    .map(|i| (format!("x-header-{}", i), "y".repeat(50)))
+                    .collect(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        // Events within the limit are unchanged
+        let mut small = event();
+        trim_event(&mut small, 100_000);
+        assert!(small.request.as_ref().unwrap().data.is_some());
+        assert!(!small.tags.contains_key("payload_trimmed"));
+
+        // The body is removed first
+        let mut trimmed = event();
+        let original_size = estimate_event_size(&trimmed);
+        trim_event(&mut trimmed, original_size - 4000);
+        let request = trimmed.request.as_ref().unwrap();
+        assert_eq!(request.data, None);
+        assert_eq!(request.headers.len(), 100);
+        assert_eq!(trimmed.tags.get("payload_trimmed"), Some(&"true".into()));
+        assert_eq!(
+            trimmed.extra.get("original_event_size"),
+            Some(&original_size.into())
+        );
+
+        // Then the headers
+        let mut trimmed = event();
+        trim_event(&mut trimmed, 1000);
+        let request = trimmed.request.as_ref().unwrap();
+        assert_eq!(request.data, None);
+        assert!(request.headers.is_empty());
+        assert_eq!(request.method, Some("POST".into()));
+        assert_eq!(trimmed.tags.get("payload_trimmed"), Some(&"true".into()));
+    }
+
+    #[test]
+    fn test_parse_ip() {
+        assert_eq!(
+            parse_ip("203.0.113.7"),
+            Some("203.0.113.7".parse().unwrap())
+        );
+        assert_eq!(
+            parse_ip("203.0.113.7:8080"),
+            Some("203.0.113.7".parse().unwrap())
+        );
+        assert_eq!(parse_ip("[::1]:8080"), Some("::1".parse().unwrap()));
+        assert_eq!(parse_ip("[::1]"), Some("::1".parse().unwrap()));
+        assert_eq!(parse_ip("unknown"), None);
+    }
+
+    #[test]
+    fn test_process_event() {
+        let mut request = Request {
+            method: Some("POST".into()),
+            ..Default::default()
+        };
+        request.headers.insert(
+            "content-type".into(),
+            "Application/JSON; charset=utf-8".into(),
+        );
+        request
+            .env
+            .insert("REMOTE_ADDR".into(), "198.51.100.1:8080".into());
+        request.env.insert("CLIENT_IP".into(), "203.0.113.7".into());
+
+        let event = process_event(Event::default(), &request, "sentry_actix");
+        assert_eq!(event.request.as_ref(), Some(&request));
+        assert_eq!(event.logger.as_deref(), Some("sentry_actix"));
+        assert_eq!(
+            event.tags.get("request.content_type").map(String::as_str),
+            Some("application/json")
+        );
+        assert_eq!(
+            event.user.unwrap().ip_address,
+            Some(IpAddress::Exact("203.0.113.7".parse().unwrap()))
+        );
+
+        // Data already present on the event is kept
+        let event = Event {
+            logger: Some("custom".into()),
+            request: Some(Request::default()),
+            ..Default::default()
+        };
+        let event = process_event(event, &request, "sentry_actix");
+        assert_eq!(event.logger.as_deref(), Some("custom"));
+        assert_eq!(event.request, Some(Request::default()));
+    }
+}
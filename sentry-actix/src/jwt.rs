@@ -0,0 +1,244 @@
+//! Deriving the user of a request from a bearer JWT.
+
+use actix_web::http::{header, HeaderMap};
+use hmac::{Hmac, Mac, NewMac};
+use serde_json::{Map, Value};
+use sha2::Sha256;
+
+use sentry_core::protocol::User;
+
+/// A field of the Sentry user filled from a JWT claim.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum UserField {
+    /// The `id` of the user.
+    Id,
+    /// The `email` of the user, only set if `send_default_pii` is enabled.
+    Email,
+    /// The `username` of the user.
+    Username,
+    /// An additional field of the user with the given name.
+    Other(String),
+}
+
+/// Configures how the user of a request is derived from its bearer JWT.
+///
+/// By default, the `sub` claim is used as the user id and the `email` claim as
+/// the email.  Tokens are decoded without verifying their signature unless a
+/// secret is configured with [`verify_hs256`](Self::verify_hs256).  Missing or
+/// malformed tokens are ignored.
+///
+/// # Example
+///
+/// ```
+/// use sentry_actix::{Sentry, UserField, UserJwtConfig};
+///
+/// let middleware = Sentry::builder()
+///     .user_from_jwt(
+///         UserJwtConfig::new()
+///             .claim("preferred_username", UserField::Username)
+///             .claim("tenant", UserField::Other("tenant".into())),
+///     )
+///     .finish();
+/// ```
+#[derive(Clone, Debug)]
+pub struct UserJwtConfig {
+    claims: Vec<(String, UserField)>,
+    secret: Option<Vec<u8>>,
+}
+
+impl UserJwtConfig {
+    /// Creates a configuration mapping `sub` to the id and `email` to the email.
+    pub fn new() -> Self {
+        UserJwtConfig {
+            claims: vec![
+                ("sub".into(), UserField::Id),
+                ("email".into(), UserField::Email),
+            ],
+            secret: None,
+        }
+    }
+
+    /// Creates a configuration without any claim mappings.
+    pub fn empty() -> Self {
+        UserJwtConfig {
+            claims: Vec::new(),
+            secret: None,
+        }
+    }
+
+    /// Maps a claim to a field of the user.
+    ///
+    /// Mappings to the same field replace earlier ones.
+    pub fn claim<C: Into<String>>(mut self, claim: C, field: UserField) -> Self {
+        self.claims.retain(|(_, mapped)| *mapped != field);
+        self.claims.push((claim.into(), field));
+        self
+    }
+
+    /// Only accepts tokens signed with HMAC-SHA256 using the given secret.
+    pub fn verify_hs256<S: Into<Vec<u8>>>(mut self, secret: S) -> Self {
+        self.secret = Some(secret.into());
+        self
+    }
+
+    /// Derives the user from the `Authorization` header, if it carries a valid token.
+    pub(crate) fn user_from_headers(&self, headers: &HeaderMap, with_pii: bool) -> Option<User> {
+        let authorization = headers.get(header::AUTHORIZATION)?.to_str().ok()?.trim();
+        let (scheme, token) = authorization.split_at(authorization.find(' ')?);
+        if !scheme.eq_ignore_ascii_case("bearer") {
+            return None;
+        }
+        let claims = self.decode(token.trim())?;
+
+        let mut user = User::default();
+        for (claim, field) in &self.claims {
+            let value = match claims.get(claim) {
+                Some(Value::String(value)) => value.clone(),
+                Some(value @ Value::Number(_)) => value.to_string(),
+                _ => continue,
+            };
+            match field {
+                UserField::Id => user.id = Some(value),
+                UserField::Email if with_pii => user.email = Some(value),
+                UserField::Email => {}
+                UserField::Username => user.username = Some(value),
+                UserField::Other(name) => {
+                    user.other.insert(name.clone(), value.into());
+                }
+            }
+        }
+        if user == User::default() {
+            return None;
+        }
+        Some(user)
+    }
+
+    /// Decodes the claims of a token, verifying its signature if configured.
+    fn decode(&self, token: &str) -> Option<Map<String, Value>> {
+        let mut parts = token.split('.');
+        let (header, payload, signature) = (parts.next()?, parts.next()?, parts.next()?);
+        if parts.next().is_some() {
+            return None;
+        }
+        if let Some(ref secret) = self.secret {
+            let decoded: Value = serde_json::from_slice(&decode_part(header)?).ok()?;
+            if decoded.get("alg")?.as_str()? != "HS256" {
+                return None;
+            }
+            let mut mac = Hmac::<Sha256>::new_varkey(secret).ok()?;
+            mac.update(&token.as_bytes()[..header.len() + 1 + payload.len()]);
+            mac.verify(&decode_part(signature)?).ok()?;
+        }
+        match serde_json::from_slice(&decode_part(payload)?).ok()? {
+            Value::Object(claims) => Some(claims),
+            _ => None,
+        }
+    }
+}
+
+impl Default for UserJwtConfig {
+    fn default() -> Self {
+        UserJwtConfig::new()
+    }
+}
+
+/// Decodes a base64url encoded part of a token.
+fn decode_part(part: &str) -> Option<Vec<u8>> {
+    base64::decode_config(part, base64::URL_SAFE_NO_PAD).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::HeaderValue;
+
+    use super::*;
+
+    /// Builds a token with the given claims, signed with `secret` if given.
+    fn token(claims: &str, secret: Option<&[u8]>) -> String {
+        let encode = |part: &[u8]| base64::encode_config(part, base64::URL_SAFE_NO_PAD);
+        let header = match secret {
+            Some(_) => r#"{"alg":"HS256","typ":"JWT"}"#,
+            None => r#"{"alg":"none","typ":"JWT"}"#,
+        };
+        let signed = format!(
+            "{}.{}",
+            encode(header.as_bytes()),
+            encode(claims.as_bytes())
+        );
+        let signature = match secret {
+            Some(secret) => {
+                let mut mac = Hmac::<Sha256>::new_varkey(secret).unwrap();
+                mac.update(signed.as_bytes());
+                encode(&mac.finalize().into_bytes())
+            }
+            None => String::new(),
+        };
+        format!("{}.{}", signed, signature)
+    }
+
+    fn user(config: &UserJwtConfig, authorization: &str, with_pii: bool) -> Option<User> {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_str(authorization).unwrap(),
+        );
+        config.user_from_headers(&headers, with_pii)
+    }
+
+    #[test]
+    fn test_user_from_jwt() {
+        let claims = r#"{"sub":"user-1","email":"jane@example.com","tenant":42}"#;
+        let bearer = format!("Bearer {}", token(claims, None));
+        let config = UserJwtConfig::new().claim("tenant", UserField::Other("tenant".into()));
+
+        let with_pii = user(&config, &bearer, true).unwrap();
+        assert_eq!(with_pii.id.as_deref(), Some("user-1"));
+        assert_eq!(with_pii.email.as_deref(), Some("jane@example.com"));
+        assert_eq!(with_pii.other["tenant"], "42");
+
+        let without_pii = user(&config, &bearer, false).unwrap();
+        assert_eq!(without_pii.id.as_deref(), Some("user-1"));
+        assert_eq!(without_pii.email, None);
+
+        // Later mappings replace earlier ones of the same field
+        let config = UserJwtConfig::new().claim("email", UserField::Id);
+        let user = user(&config, &bearer, true).unwrap();
+        assert_eq!(user.id.as_deref(), Some("jane@example.com"));
+    }
+
+    #[test]
+    fn test_malformed_jwt() {
+        let config = UserJwtConfig::new();
+        let valid = token(r#"{"sub":"user-1"}"#, None);
+        for authorization in &[
+            "Bearer".to_owned(),
+            "Bearer not-a-token".to_owned(),
+            "Bearer a.b.c".to_owned(),
+            format!("Basic {}", valid),
+            format!("Bearer {}.extra", valid),
+            format!("Bearer {}", token(r#"["user-1"]"#, None)),
+            format!("Bearer {}", token(r#"{"iss":"issuer"}"#, None)),
+        ] {
+            assert_eq!(
+                user(&config, authorization, true),
+                None,
+                "{}",
+                authorization
+            );
+        }
+        assert!(user(&config, &format!("bearer {}", valid), true).is_some());
+    }
+
+    #[test]
+    fn test_verify_hs256() {
+        let config = UserJwtConfig::new().verify_hs256("secret");
+        let claims = r#"{"sub":"user-1"}"#;
+
+        let signed = format!("Bearer {}", token(claims, Some(b"secret")));
+        assert!(user(&config, &signed, false).is_some());
+
+        for token in &[token(claims, Some(b"other")), token(claims, None)] {
+            assert_eq!(user(&config, &format!("Bearer {}", token), false), None);
+        }
+    }
+}
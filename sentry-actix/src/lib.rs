@@ -40,7 +40,8 @@
 //!
 //! The actix middleware will automatically start a new session for each request
 //! when `auto_session_tracking` is enabled and the client is configured to
-//! use `SessionMode::Request`.
+//! use `SessionMode::Request`.  The session mode can be overridden for a single
+//! middleware with [`SentryBuilder::session_mode`].
 //!
 //! ```
 //! let _sentry = sentry::init(sentry::ClientOptions {
@@ -50,6 +51,14 @@
 //! });
 //! ```
 //!
+//! # Middleware Ordering
+//!
+//! The middleware registered last with `wrap` runs outermost.  Registering the Sentry
+//! middleware last lets it observe errors of all other middleware.  Responses of
+//! middleware inside it, such as `Compress`, are observed as they leave those
+//! middleware, so the `response_body_bytes` of deferred captures is the encoded size
+//! and events of encoded responses carry `body_compressed: true`.
+//!
 //! # Reusing the Hub
 //!
 //! This integration will automatically create a new per-request Hub from the main Hub, and update the
@@ -58,6 +67,62 @@
 //! ```
 //! sentry::capture_message("Something is not well", sentry::Level::Warning);
 //! ```
+//!
+//! # Redacting Request Data
+//!
+//! Request data attached to events passes through a pipeline of redactors, which filters
+//! credentials by default.  See the [`redact`] module for the built-in redactors.
+//!
+//! # Presets
+//!
+//! [`Sentry::recommended`], [`Sentry::minimal`] and [`Sentry::strict`] return builders with
+//! opinionated configurations, which can still be adjusted before finishing them.
+//!
+//! # Tunneling Browser Envelopes
+//!
+//! With the `tunnel` feature enabled, the [`tunnel`] module provides a route that forwards
+//! envelopes posted by browser SDKs to Sentry, so that they can be sent to the same origin.
+//!
+//! # Outgoing Requests
+//!
+//! With the `client` feature enabled, the [`client`] module records requests sent with the
+//! actix-web client as breadcrumbs on the request hub.
+//!
+//! # Users from JWTs
+//!
+//! With the `jwt` feature enabled, [`SentryBuilder::user_from_jwt`] derives the user of
+//! events from the bearer JWT in the `Authorization` header of the request.
+//!
+//! # Sentry Rate Limits
+//!
+//! With the `rate-limits` feature enabled, the [`rate_limits`] module tracks the rate
+//! limits of the Sentry server, so that the middleware counts the errors it discards
+//! while they are active.
+//!
+//! # Application Errors
+//!
+//! Errors returned by handlers are converted into `actix_web::Error`, which erases
+//! their type.  Handlers wrapped with [`wrap_handler`] capture their errors before
+//! the conversion, with the type of the error as the type of the exception.
+//!
+//! # Monitored Handlers
+//!
+//! With the `macros` feature enabled, the [`monitored`] attribute marks handlers with
+//! the operation, owner and level of the events of their errors.
+//!
+//! # Debug Logs
+//!
+//! Configuration problems, such as features without effect, are written to the debug
+//! log of the SDK when the `debug` option of the client is enabled.  With the
+//! `debug-logs` feature enabled, they are logged with the `log` crate instead.  A
+//! function set with [`SentryBuilder::debug_log_captures_with`] receives them
+//! in place of the debug log of the SDK.
+//!
+//! # Deterministic Tests
+//!
+//! With the `test-util` feature enabled, [`SentryBuilder::with_clock`] and
+//! [`SentryBuilder::with_sequential_ids`] replace the system clock and the random ids of
+//! events, so that tests asserting durations, rate limits or event ids do not need to sleep.
 
 #![doc(html_favicon_url = "https://sentry-brand.storage.googleapis.com/favicon.ico")]
 #![doc(html_logo_url = "https://sentry-brand.storage.googleapis.com/sentry-glyph-black.png")]
@@ -65,453 +130,123 @@
 #![allow(deprecated)]
 #![allow(clippy::type_complexity)]
 
-use std::borrow::Cow;
-use std::pin::Pin;
-use std::sync::Arc;
-
-use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
-use futures_util::future::{ok, Future, Ready};
-use futures_util::FutureExt;
-
-use sentry_core::protocol::{ClientSdkPackage, Event, Request};
-use sentry_core::{Hub, SentryFutureExt};
-
-/// A helper construct that can be used to reconfigure and build the middleware.
-pub struct SentryBuilder {
-    middleware: Sentry,
-}
-
-impl SentryBuilder {
-    /// Finishes the building and returns a middleware
-    pub fn finish(self) -> Sentry {
-        self.middleware
-    }
-
-    /// Reconfigures the middleware so that it uses a specific hub instead of the default one.
-    pub fn with_hub(mut self, hub: Arc<Hub>) -> Self {
-        self.middleware.hub = Some(hub);
-        self
-    }
-
-    /// Reconfigures the middleware so that it uses a specific hub instead of the default one.
-    pub fn with_default_hub(mut self) -> Self {
-        self.middleware.hub = None;
-        self
-    }
-
-    /// If configured the sentry id is attached to a X-Sentry-Event header.
-    pub fn emit_header(mut self, val: bool) -> Self {
-        self.middleware.emit_header = val;
-        self
-    }
-
-    /// Enables or disables error reporting.
-    ///
-    /// The default is to report all errors.
-    pub fn capture_server_errors(mut self, val: bool) -> Self {
-        self.middleware.capture_server_errors = val;
-        self
-    }
-}
-
-/// Reports certain failures to Sentry.
-#[derive(Clone)]
-pub struct Sentry {
-    hub: Option<Arc<Hub>>,
-    emit_header: bool,
-    capture_server_errors: bool,
-}
-
-impl Sentry {
-    /// Creates a new sentry middleware.
-    pub fn new() -> Self {
-        Sentry {
-            hub: None,
-            emit_header: false,
-            capture_server_errors: true,
-        }
-    }
-
-    /// Creates a new middleware builder.
-    pub fn builder() -> SentryBuilder {
-        Sentry::new().into_builder()
-    }
-
-    /// Converts the middleware into a builder.
-    pub fn into_builder(self) -> SentryBuilder {
-        SentryBuilder { middleware: self }
-    }
-}
-
-impl Default for Sentry {
-    fn default() -> Self {
-        Sentry::new()
-    }
-}
-
-impl<S, B> Transform<S, ServiceRequest> for Sentry
-where
-    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
-    S::Future: 'static,
-{
-    type Response = ServiceResponse<B>;
-    type Error = actix_web::Error;
-    type Transform = SentryMiddleware<S>;
-    type InitError = ();
-    type Future = Ready<Result<Self::Transform, Self::InitError>>;
-
-    fn new_transform(&self, service: S) -> Self::Future {
-        ok(SentryMiddleware {
-            service,
-            inner: self.clone(),
-        })
-    }
-}
-
-/// The middleware for individual services.
-pub struct SentryMiddleware<S> {
-    service: S,
-    inner: Sentry,
-}
-
-impl<S, B> Service<ServiceRequest> for SentryMiddleware<S>
-where
-    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
-    S::Future: 'static,
-{
-    type Response = ServiceResponse<B>;
-    type Error = actix_web::Error;
-    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
-
-    fn poll_ready(
-        &self,
-        cx: &mut std::task::Context<'_>,
-    ) -> std::task::Poll<Result<(), Self::Error>> {
-        self.service.poll_ready(cx)
-    }
-
-    fn call(&self, req: ServiceRequest) -> Self::Future {
-        let inner = self.inner.clone();
-        let hub = Arc::new(Hub::new_from_top(
-            inner.hub.clone().unwrap_or_else(Hub::main),
-        ));
-        let client = hub.client();
-        let track_sessions = client.as_ref().map_or(false, |client| {
-            let options = client.options();
-            options.auto_session_tracking
-                && options.session_mode == sentry_core::SessionMode::Request
-        });
-        if track_sessions {
-            hub.start_session();
-        }
-        let with_pii = client
-            .as_ref()
-            .map_or(false, |client| client.options().send_default_pii);
-
-        let (tx, sentry_req) = sentry_request_from_http(&req, with_pii);
-        hub.configure_scope(|scope| {
-            scope.set_transaction(tx.as_deref());
-            scope.add_event_processor(Box::new(move |event| {
-                Some(process_event(event, &sentry_req))
-            }))
-        });
-
-        let fut = self.service.call(req).bind_hub(hub.clone());
-
-        async move {
-            // Service errors
-            let mut res: Self::Response = match fut.await {
-                Ok(res) => res,
-                Err(e) => {
-                    if inner.capture_server_errors {
-                        hub.capture_error(&e);
-                    }
-                    return Err(e);
-                }
-            };
-
-            // Response errors
-            if inner.capture_server_errors && res.response().status().is_server_error() {
-                if let Some(e) = res.response().error() {
-                    let event_id = hub.capture_error(e);
-
-                    if inner.emit_header {
-                        res.response_mut().headers_mut().insert(
-                            "x-sentry-event".parse().unwrap(),
-                            event_id.to_simple_ref().to_string().parse().unwrap(),
-                        );
-                    }
-                }
-            }
-
-            Ok(res)
-        }
-        .boxed_local()
-    }
+// The macros refer to this crate by name, including in its own tests
+#[cfg(feature = "macros")]
+extern crate self as sentry_actix;
+
+use actix_web::dev::ServiceRequest;
+
+mod api_version;
+mod app_error;
+mod backpressure;
+mod builder;
+mod capture;
+#[cfg(feature = "client")]
+pub mod client;
+mod clock;
+mod connection;
+mod env;
+mod event_link;
+mod extract;
+mod graphql;
+mod handler;
+mod helpers;
+#[cfg(feature = "jwt")]
+mod jwt;
+mod middleware;
+mod normalize;
+mod presets;
+mod problem;
+#[cfg(feature = "rate-limits")]
+pub mod rate_limits;
+pub mod redact;
+mod request;
+mod request_hub;
+mod sessions;
+mod snapshot;
+mod status;
+mod trace;
+#[cfg(feature = "tunnel")]
+pub mod tunnel;
+mod user;
+
+pub use crate::api_version::ApiVersionSource;
+pub use crate::app_error::{capture_app_error, wrap_handler};
+pub use crate::builder::{SentryBuilder, SentryConfigError};
+pub use crate::capture::{
+    CaptureMode, DisableCapture, DisableSessions, DiscardStats, ForceCapture, OriginalError,
+    SentryClassify, SentryError,
+};
+#[cfg(feature = "test-util")]
+pub use crate::clock::{ManualClock, SequentialIds};
+pub use crate::connection::connection_tracker;
+pub use crate::event_link::EventLink;
+pub use crate::extract::Instrumented;
+pub use crate::handler::{named, HandlerMetadata, HandlerName, HandlerNameMiddleware};
+pub use crate::helpers::{
+    inherit_hub_for_background, process_event, set_measurement, ProcessorPosition, TransactionName,
+};
+#[cfg(feature = "jwt")]
+pub use crate::jwt::{UserField, UserJwtConfig};
+pub use crate::middleware::{Sentry, SentryMiddleware};
+pub use crate::normalize::{normalize_path, PathNormalizer};
+pub use crate::request::{
+    to_sentry_request, EnvProfile, IpPolicy, RequestDataConfig, RequestDataOptions, RequestLike,
+    RequestUrl,
+};
+pub use crate::request_hub::{hub_from_error, hub_from_service_response, with_request_hub};
+pub use crate::sessions::{SessionCounts, OTHER_ROUTE};
+pub use crate::snapshot::RequestSnapshot;
+pub use crate::status::status_endpoint;
+#[cfg(feature = "macros")]
+pub use sentry_actix_macros::monitored;
+
+#[cfg(feature = "macros")]
+#[doc(hidden)]
+pub mod __private {
+    pub use actix_web::HttpRequest;
+    pub use sentry_core::Level;
 }
 
-/// Build a Sentry request struct from the HTTP request
-fn sentry_request_from_http(request: &ServiceRequest, with_pii: bool) -> (Option<String>, Request) {
-    let transaction = if let Some(name) = request.match_name() {
-        Some(String::from(name))
-    } else {
-        request.match_pattern()
-    };
-
-    let mut sentry_req = Request {
-        url: format!(
-            "{}://{}{}",
-            request.connection_info().scheme(),
-            request.connection_info().host(),
-            request.uri()
-        )
-        .parse()
-        .ok(),
-        method: Some(request.method().to_string()),
-        headers: request
-            .headers()
-            .iter()
-            .map(|(k, v)| (k.to_string(), v.to_str().unwrap_or_default().to_string()))
-            .collect(),
-        ..Default::default()
+/// Commonly needed types for configuring the middleware.
+///
+/// Hooks can be written using these re-exports only, without depending on
+/// `sentry-core` directly.
+///
+/// # Example
+///
+/// ```
+/// use sentry_actix::prelude::*;
+///
+/// let transaction_name: Box<TransactionNameFn> = Box::new(|req: &ServiceRequest| {
+///     Some(req.path().trim_start_matches("/v1").to_owned())
+/// });
+///
+/// let middleware: Sentry = Sentry::builder()
+///     .with_hub(Hub::current())
+///     .capture_mode(CaptureMode::Deferred)
+///     .transaction_name_fn(transaction_name)
+///     .build()
+///     .unwrap();
+/// ```
+pub mod prelude {
+    pub use actix_web::dev::ServiceRequest;
+    pub use sentry_core::protocol::{Event, Request};
+    pub use sentry_core::{Hub, Level};
+
+    #[cfg(feature = "regex")]
+    pub use crate::redact::RegexPatterns;
+    pub use crate::redact::{DenylistKeys, RedactionTarget, Redactor};
+    pub use crate::{
+        inherit_hub_for_background, named, set_measurement, to_sentry_request, CaptureMode,
+        DisableCapture, DisableSessions, DiscardStats, ForceCapture, OriginalError,
+        ProcessorPosition, RequestDataConfig, RequestDataOptions, RequestLike, Sentry,
+        SentryBuilder, SentryClassify, SentryConfigError, SentryError, TransactionName,
+        TransactionNameFn,
     };
-
-    // If PII is enabled, include the remote address
-    if with_pii {
-        if let Some(remote) = request.connection_info().remote_addr() {
-            sentry_req.env.insert("REMOTE_ADDR".into(), remote.into());
-        }
-    };
-
-    (transaction, sentry_req)
 }
 
-/// Add request data to a Sentry event
-fn process_event(mut event: Event<'static>, request: &Request) -> Event<'static> {
-    // Request
-    if event.request.is_none() {
-        event.request = Some(request.clone());
-    }
-
-    // SDK
-    if let Some(sdk) = event.sdk.take() {
-        let mut sdk = sdk.into_owned();
-        sdk.packages.push(ClientSdkPackage {
-            name: "sentry-actix".into(),
-            version: env!("CARGO_PKG_VERSION").into(),
-        });
-        event.sdk = Some(Cow::Owned(sdk));
-    }
-    event
-}
-
-#[cfg(test)]
-mod tests {
-    use std::io;
-
-    use actix_web::test::{call_service, init_service, TestRequest};
-    use actix_web::{get, web, App, HttpRequest, HttpResponse};
-    use futures::executor::block_on;
-
-    use sentry::Level;
-
-    use super::*;
-
-    fn _assert_hub_no_events() {
-        if Hub::current().last_event_id().is_some() {
-            panic!("Current hub should not have had any events.");
-        }
-    }
-
-    fn _assert_hub_has_events() {
-        Hub::current()
-            .last_event_id()
-            .expect("Current hub should have had events.");
-    }
-
-    /// Test explicit events sent to the current Hub inside an Actix service.
-    #[actix_rt::test]
-    async fn test_explicit_events() {
-        let events = sentry::test::with_captured_events(|| {
-            block_on(async {
-                let service = || {
-                    // Current Hub should have no events
-                    _assert_hub_no_events();
-
-                    sentry::capture_message("Message", Level::Warning);
-
-                    // Current Hub should have the event
-                    _assert_hub_has_events();
-
-                    HttpResponse::Ok()
-                };
-
-                let app = init_service(
-                    App::new()
-                        .wrap(Sentry::builder().with_hub(Hub::current()).finish())
-                        .service(web::resource("/test").to(service)),
-                )
-                .await;
-
-                // Call the service twice (sequentially) to ensure the middleware isn't sticky
-                for _ in 0..2 {
-                    let req = TestRequest::get().uri("/test").to_request();
-                    let res = call_service(&app, req).await;
-                    assert!(res.status().is_success());
-                }
-            })
-        });
-
-        assert_eq!(events.len(), 2);
-        for event in events {
-            let request = event.request.expect("Request should be set.");
-            assert_eq!(event.transaction, Some("/test".into()));
-            assert_eq!(event.message, Some("Message".into()));
-            assert_eq!(event.level, Level::Warning);
-            assert_eq!(request.method, Some("GET".into()));
-        }
-    }
-
-    /// Ensures errors returned in the Actix service trigger an event.
-    #[actix_rt::test]
-    async fn test_response_errors() {
-        let events = sentry::test::with_captured_events(|| {
-            block_on(async {
-                #[get("/test")]
-                async fn failing(_req: HttpRequest) -> Result<String, actix_web::Error> {
-                    // Current hub should have no events
-                    _assert_hub_no_events();
-
-                    Err(io::Error::new(io::ErrorKind::Other, "Test Error").into())
-                }
-
-                let app = init_service(
-                    App::new()
-                        .wrap(Sentry::builder().with_hub(Hub::current()).finish())
-                        .service(failing),
-                )
-                .await;
-
-                // Call the service twice (sequentially) to ensure the middleware isn't sticky
-                for _ in 0..2 {
-                    let req = TestRequest::get().uri("/test").to_request();
-                    let res = call_service(&app, req).await;
-                    assert!(res.status().is_server_error());
-                }
-            })
-        });
-
-        assert_eq!(events.len(), 2);
-        for event in events {
-            let request = event.request.expect("Request should be set.");
-            assert_eq!(event.transaction, Some("failing".into())); // Transaction name is the name of the function
-            assert_eq!(event.message, None);
-            assert_eq!(event.exception.values[0].ty, String::from("Custom"));
-            assert_eq!(event.exception.values[0].value, Some("Test Error".into()));
-            assert_eq!(event.level, Level::Error);
-            assert_eq!(request.method, Some("GET".into()));
-        }
-    }
-
-    /// Ensures client errors (4xx) are not captured.
-    #[actix_rt::test]
-    async fn test_client_errors_discarded() {
-        let events = sentry::test::with_captured_events(|| {
-            block_on(async {
-                let service = || HttpResponse::NotFound();
-
-                let app = init_service(
-                    App::new()
-                        .wrap(Sentry::builder().with_hub(Hub::current()).finish())
-                        .service(web::resource("/test").to(service)),
-                )
-                .await;
-
-                let req = TestRequest::get().uri("/test").to_request();
-                let res = call_service(&app, req).await;
-                assert!(res.status().is_client_error());
-            })
-        });
-
-        assert!(events.is_empty());
-    }
-
-    /// Ensures transaction name can be overridden in handler scope.
-    #[actix_rt::test]
-    async fn test_override_transaction_name() {
-        let events = sentry::test::with_captured_events(|| {
-            block_on(async {
-                #[get("/test")]
-                async fn original_transaction(
-                    _req: HttpRequest,
-                ) -> Result<String, actix_web::Error> {
-                    // Override transaction name
-                    sentry::configure_scope(|scope| scope.set_transaction(Some("new_transaction")));
-                    Err(io::Error::new(io::ErrorKind::Other, "Test Error").into())
-                }
-
-                let app = init_service(
-                    App::new()
-                        .wrap(Sentry::builder().with_hub(Hub::current()).finish())
-                        .service(original_transaction),
-                )
-                .await;
-
-                let req = TestRequest::get().uri("/test").to_request();
-                let res = call_service(&app, req).await;
-                assert!(res.status().is_server_error());
-            })
-        });
-
-        assert_eq!(events.len(), 1);
-        let event = events[0].clone();
-        let request = event.request.expect("Request should be set.");
-        assert_eq!(event.transaction, Some("new_transaction".into())); // Transaction name is overridden by handler
-        assert_eq!(event.message, None);
-        assert_eq!(event.exception.values[0].ty, String::from("Custom"));
-        assert_eq!(event.exception.values[0].value, Some("Test Error".into()));
-        assert_eq!(event.level, Level::Error);
-        assert_eq!(request.method, Some("GET".into()));
-    }
-
-    #[actix_rt::test]
-    async fn test_track_session() {
-        let envelopes = sentry::test::with_captured_envelopes_options(
-            || {
-                block_on(async {
-                    #[get("/")]
-                    async fn hello() -> impl actix_web::Responder {
-                        String::from("Hello there!")
-                    }
-
-                    let middleware = Sentry::builder().with_hub(Hub::current()).finish();
-
-                    let app = init_service(App::new().wrap(middleware).service(hello)).await;
-
-                    for _ in 0..5 {
-                        let req = TestRequest::get().uri("/").to_request();
-                        call_service(&app, req).await;
-                    }
-                })
-            },
-            sentry::ClientOptions {
-                release: Some("some-release".into()),
-                session_mode: sentry::SessionMode::Request,
-                auto_session_tracking: true,
-                ..Default::default()
-            },
-        );
-        assert_eq!(envelopes.len(), 1);
-
-        let mut items = envelopes[0].items();
-        if let Some(sentry::protocol::EnvelopeItem::SessionAggregates(aggregate)) = items.next() {
-            let aggregates = &aggregate.aggregates;
-
-            assert_eq!(aggregates[0].distinct_id, None);
-            assert_eq!(aggregates[0].exited, 5);
-        } else {
-            panic!("expected session");
-        }
-        assert_eq!(items.next(), None);
-    }
-}
+/// The signature of functions deriving transaction names from requests.
+///
+/// See [`SentryBuilder::transaction_name_fn`].
+pub type TransactionNameFn = dyn Fn(&ServiceRequest) -> Option<String> + Send + Sync;
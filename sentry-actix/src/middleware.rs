@@ -0,0 +1,5639 @@
+//! The middleware and its per-request handling.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use actix_web::dev::{
+    Body, MessageBody, ResponseBody, Service, ServiceRequest, ServiceResponse, Transform,
+};
+use actix_web::http::{header, HeaderMap, Method, StatusCode};
+use actix_web::HttpMessage;
+use futures_util::future::{err, ok, Either, Future, Ready};
+use futures_util::FutureExt;
+
+use sentry_core::protocol::Value;
+use sentry_core::types::Uuid;
+use sentry_core::{Hub, Scope, SentryFutureExt, SessionMode};
+
+use crate::api_version::ApiVersionSource;
+use crate::app_error::AppErrorCaptured;
+use crate::backpressure::Backpressure;
+use crate::builder::{SentryBuilder, SentryConfigError};
+use crate::capture::{
+    capture_method_not_allowed, capture_poll_ready_error, flush_client, spawn_watchdog, tag_panic,
+    AbortGuard, CaptureDecision, CaptureLimit, CaptureMode, CaptureOverride, CaptureRateLimit,
+    CaptureSource, CaptureTrail, DebugLog, DisableSessions, DiscardCounters, DiscardStats,
+    ErrorClassifier, ErrorTypeFn, FlushPredicate, LongLivedBody, MessageAggregator, OpaqueRoutes,
+    OriginalError, SentryBody, ABORTED_CAPTURE_INTERVAL, GENERIC_ERROR_MESSAGES,
+    POLL_READY_CAPTURE_INTERVAL,
+};
+use crate::clock::{Clock, IdSource, RandomIds, SystemClock};
+use crate::connection::ConnectionTable;
+use crate::event_link::EventLink;
+use crate::graphql::{filter_variables, Operation};
+use crate::handler::HandlerMetadata;
+use crate::helpers::{
+    mime_essence, process_event, trim_event, Measurements, ProcessorPosition, TransactionName,
+};
+#[cfg(feature = "jwt")]
+use crate::jwt::UserJwtConfig;
+use crate::normalize::PathNormalizer;
+use crate::problem::{accepts_json, rewrite_response, ProblemError};
+#[cfg(feature = "rate-limits")]
+use crate::rate_limits::RateLimits;
+use crate::redact::{DenylistKeys, Redactor};
+use crate::request::{
+    apply_rewritten_path, content_length, request_line, routing_context, track_payload,
+    CapturedRequest, EnvProfile, IpPolicy, RequestBody, RequestDataConfig, RequestState,
+    RequestUrl, SummaryToken, UrlLimits,
+};
+use crate::request_hub::{RequestHub, ScopedError};
+use crate::sessions::{RouteSessionCounters, SessionCounts, SessionTracker};
+use crate::snapshot::SnapshotContext;
+use crate::status::LastCapture;
+use crate::trace::{Trace, SENTRY_TRACE_HEADER, TRACEPARENT_HEADER};
+use crate::user::{AsyncUserExtractor, DEFAULT_USER_TIMEOUT};
+use crate::TransactionNameFn;
+
+/// The name of the header carrying the id of a captured event.
+const SENTRY_EVENT_HEADER: &str = "x-sentry-event";
+
+/// The header linking to the captured event, see [`EventLink`].
+const SENTRY_EVENT_URL_HEADER: &str = "x-sentry-event-url";
+
+/// The name of the header marking errors discarded because of Sentry rate limits.
+#[cfg(feature = "rate-limits")]
+const SENTRY_RATE_LIMITED_HEADER: &str = "x-sentry-rate-limited";
+
+/// The default size above which events are trimmed.
+const DEFAULT_MAX_EVENT_SIZE: usize = 1_000_000;
+
+/// The default logger of events captured for requests.
+const DEFAULT_LOGGER: &str = "sentry_actix";
+
+/// The default maximum number of request body bytes attached to events.
+const DEFAULT_MAX_REQUEST_BODY_SIZE: usize = 100_000;
+
+/// Reports certain failures to Sentry.
+///
+/// The middleware can wrap apps, scopes and resources.  Responses keep the body
+/// type of the wrapped service, except when an error capture is deferred until
+/// the response is written, in which case the body is boxed.
+///
+/// The futures of wrapped services must be `'static`, see [`Sentry::wrap_fn`]
+/// for services borrowing data in their futures.
+///
+/// Clones share the configuration and the state of the middleware, so cloning it
+/// for each worker, or for each request, is cheap.
+#[derive(Clone)]
+pub struct Sentry {
+    pub(crate) config: Arc<Config>,
+}
+
+/// The configuration and the state of a [`Sentry`] middleware, shared by its clones.
+#[derive(Clone)]
+pub(crate) struct Config {
+    pub(crate) hub: Option<Arc<Hub>>,
+    pub(crate) hub_fn: Option<Arc<dyn Fn() -> Arc<Hub> + Send + Sync>>,
+    pub(crate) base_scope: Option<Arc<Scope>>,
+    pub(crate) bind_hub: bool,
+    pub(crate) processor_position: ProcessorPosition,
+    pub(crate) emit_header: bool,
+    pub(crate) emit_header_hyphenated: bool,
+    pub(crate) event_link: Option<Arc<EventLink>>,
+    pub(crate) capture_server_errors: bool,
+    pub(crate) capture_method_not_allowed: bool,
+    pub(crate) capture_poll_ready_errors: bool,
+    pub(crate) capture_aborted_requests: bool,
+    pub(crate) opaque_routes: Option<Arc<OpaqueRoutes>>,
+    pub(crate) detect_event_streams: bool,
+    pub(crate) env_profile: EnvProfile,
+    pub(crate) ip_policy: IpPolicy,
+    pub(crate) routing_context: bool,
+    pub(crate) breadcrumb_captures: bool,
+    pub(crate) aggregate_repeated_messages: bool,
+    pub(crate) strict: bool,
+    pub(crate) route_sessions: Option<Arc<RouteSessionCounters>>,
+    pub(crate) request_url: RequestUrl,
+    pub(crate) inert_warned: Arc<AtomicBool>,
+    pub(crate) poll_ready_rate_limit: Arc<CaptureRateLimit>,
+    pub(crate) aborted_rate_limit: Arc<CaptureRateLimit>,
+    pub(crate) clock: Arc<dyn Clock>,
+    pub(crate) ids: Arc<dyn IdSource>,
+    pub(crate) tags: Arc<BTreeMap<String, String>>,
+    pub(crate) extra: Arc<BTreeMap<String, Value>>,
+    pub(crate) error_sample_rate: f32,
+    pub(crate) status_sample_rates: Arc<HashMap<StatusCode, f32>>,
+    pub(crate) generic_error_messages: Arc<Vec<String>>,
+    pub(crate) capture_mode: CaptureMode,
+    pub(crate) track_request_body: bool,
+    pub(crate) max_request_body_size: usize,
+    pub(crate) logger: Arc<str>,
+    // Parsed into `request_summary` and cleared by `SentryBuilder::build`
+    pub(crate) request_summary_format: Option<String>,
+    pub(crate) request_summary: Option<Arc<Vec<SummaryToken>>>,
+    pub(crate) full_request_data_sample_rate: f32,
+    pub(crate) max_event_size: usize,
+    pub(crate) transaction_name_fn: Option<Arc<TransactionNameFn>>,
+    pub(crate) discards: Arc<DiscardCounters>,
+    pub(crate) hook_panics: Arc<AtomicU64>,
+    pub(crate) last_capture: Arc<LastCapture>,
+    pub(crate) connections: Arc<ConnectionTable>,
+    pub(crate) redactors: Arc<Vec<Arc<dyn Redactor>>>,
+    pub(crate) url_limits: UrlLimits,
+    pub(crate) classifiers: Arc<Vec<Arc<ErrorClassifier>>>,
+    pub(crate) error_types: Arc<Vec<Arc<ErrorTypeFn>>>,
+    pub(crate) use_concrete_error_types: bool,
+    pub(crate) flush_on_capture: Option<(Arc<FlushPredicate>, Duration)>,
+    pub(crate) hang_threshold: Option<Duration>,
+    pub(crate) debug_log_captures: bool,
+    pub(crate) debug_log: Option<Arc<DebugLog>>,
+    pub(crate) max_breadcrumbs: Option<usize>,
+    pub(crate) graphql_path: Option<Arc<str>>,
+    pub(crate) problem_json: bool,
+    // `None` follows the session mode of the client
+    pub(crate) session_mode: Option<Option<SessionMode>>,
+    pub(crate) count_head_as_session: bool,
+    pub(crate) sessions_require_route_match: bool,
+    pub(crate) api_version: Option<ApiVersionSource>,
+    pub(crate) expose_hub_in_errors: bool,
+    pub(crate) emit_trace_headers: bool,
+    pub(crate) backpressure: Arc<Backpressure>,
+    pub(crate) capture_limit: Option<Arc<CaptureLimit>>,
+    pub(crate) path_normalizer: Option<Arc<PathNormalizer>>,
+    #[cfg(feature = "jwt")]
+    pub(crate) user_jwt: Option<Arc<UserJwtConfig>>,
+    pub(crate) async_user_extractor: Option<Arc<AsyncUserExtractor>>,
+    pub(crate) async_user_timeout: Duration,
+    #[cfg(feature = "rate-limits")]
+    pub(crate) rate_limits: Option<RateLimits>,
+    #[cfg(feature = "rate-limits")]
+    pub(crate) emit_rate_limited_header: bool,
+}
+
+impl Sentry {
+    /// Creates a new sentry middleware.
+    pub fn new() -> Self {
+        Sentry::from_config(Config {
+            hub: None,
+            hub_fn: None,
+            base_scope: None,
+            bind_hub: true,
+            processor_position: ProcessorPosition::First,
+            emit_header: false,
+            emit_header_hyphenated: false,
+            event_link: None,
+            capture_server_errors: true,
+            capture_method_not_allowed: false,
+            capture_poll_ready_errors: false,
+            capture_aborted_requests: false,
+            opaque_routes: None,
+            detect_event_streams: false,
+            env_profile: EnvProfile::Minimal,
+            ip_policy: IpPolicy::Full,
+            routing_context: false,
+            breadcrumb_captures: true,
+            aggregate_repeated_messages: false,
+            strict: false,
+            route_sessions: None,
+            request_url: RequestUrl::Effective,
+            inert_warned: Arc::new(AtomicBool::new(false)),
+            poll_ready_rate_limit: Arc::new(CaptureRateLimit::new(
+                POLL_READY_CAPTURE_INTERVAL,
+                Instant::now(),
+            )),
+            aborted_rate_limit: Arc::new(CaptureRateLimit::new(
+                ABORTED_CAPTURE_INTERVAL,
+                Instant::now(),
+            )),
+            clock: Arc::new(SystemClock),
+            ids: Arc::new(RandomIds),
+            tags: Arc::default(),
+            extra: Arc::default(),
+            error_sample_rate: 1.0,
+            status_sample_rates: Arc::default(),
+            generic_error_messages: Arc::new(
+                GENERIC_ERROR_MESSAGES
+                    .iter()
+                    .map(|message| (*message).to_owned())
+                    .collect(),
+            ),
+            capture_mode: CaptureMode::Immediate,
+            track_request_body: false,
+            max_request_body_size: DEFAULT_MAX_REQUEST_BODY_SIZE,
+            logger: Arc::from(DEFAULT_LOGGER),
+            request_summary_format: None,
+            request_summary: None,
+            full_request_data_sample_rate: 1.0,
+            max_event_size: DEFAULT_MAX_EVENT_SIZE,
+            transaction_name_fn: None,
+            discards: Arc::new(DiscardCounters::default()),
+            hook_panics: Arc::new(AtomicU64::new(0)),
+            last_capture: Arc::new(LastCapture::default()),
+            connections: Arc::new(ConnectionTable::default()),
+            redactors: Arc::new(vec![Arc::new(DenylistKeys::default())]),
+            url_limits: UrlLimits::default(),
+            classifiers: Arc::default(),
+            error_types: Arc::default(),
+            use_concrete_error_types: false,
+            flush_on_capture: None,
+            hang_threshold: None,
+            debug_log_captures: false,
+            debug_log: None,
+            max_breadcrumbs: None,
+            graphql_path: None,
+            problem_json: false,
+            session_mode: None,
+            count_head_as_session: true,
+            sessions_require_route_match: false,
+            api_version: None,
+            expose_hub_in_errors: false,
+            emit_trace_headers: false,
+            backpressure: Arc::new(Backpressure::new(Instant::now())),
+            capture_limit: None,
+            path_normalizer: None,
+            #[cfg(feature = "jwt")]
+            user_jwt: None,
+            async_user_extractor: None,
+            async_user_timeout: DEFAULT_USER_TIMEOUT,
+            #[cfg(feature = "rate-limits")]
+            rate_limits: None,
+            #[cfg(feature = "rate-limits")]
+            emit_rate_limited_header: false,
+        })
+    }
+
+    /// Creates a middleware sharing `config` between its clones.
+    pub(crate) fn from_config(config: Config) -> Self {
+        Sentry {
+            config: Arc::new(config),
+        }
+    }
+
+    /// Creates a new middleware builder.
+    pub fn builder() -> SentryBuilder {
+        Sentry::new().into_builder()
+    }
+
+    /// Converts the middleware into a builder.
+    pub fn into_builder(self) -> SentryBuilder {
+        let config = Arc::try_unwrap(self.config).unwrap_or_else(|config| (*config).clone());
+        SentryBuilder { config }
+    }
+
+    /// Returns the number of errors the middleware discarded instead of capturing.
+    ///
+    /// The counts are shared between clones of the middleware, so a clone can be
+    /// kept to read them after the middleware has been passed to `wrap`.
+    pub fn discard_stats(&self) -> DiscardStats {
+        self.config.discards.stats()
+    }
+
+    /// Returns the number of times a hook provided to the middleware panicked.
+    ///
+    /// Panics of hooks such as [`SentryBuilder::transaction_name_fn`] or
+    /// [`SentryBuilder::classify_error`] are caught, and the request is handled
+    /// as if the hook was not configured.  Like the discard stats, the count is
+    /// shared between clones of the middleware.
+    pub fn hook_panics(&self) -> u64 {
+        self.config.hook_panics.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of request sessions by route and outcome.
+    ///
+    /// This is empty unless enabled with
+    /// [`SentryBuilder::route_session_stats`].  Like the discard stats, the counts
+    /// are shared between clones of the middleware.
+    pub fn route_session_stats(&self) -> BTreeMap<String, SessionCounts> {
+        self.config
+            .route_sessions
+            .as_ref()
+            .map_or_else(BTreeMap::new, |counters| counters.snapshot())
+    }
+
+    /// Returns the configuration of the request data attached to events.
+    ///
+    /// This builds the same request data for requests handled outside of actix,
+    /// see [`RequestDataConfig`].  `send_default_pii` is read from the client of
+    /// the hub requests are handled with.
+    pub fn request_data_config(&self) -> RequestDataConfig {
+        let client = self.base_hub().client();
+        RequestDataConfig {
+            send_default_pii: client.map_or(false, |client| client.options().send_default_pii),
+            env_profile: self.config.env_profile,
+            ip_policy: self.config.ip_policy,
+            redactors: self.config.redactors.clone(),
+            url_limits: self.config.url_limits,
+        }
+    }
+
+    /// Checks that the middleware can create its services.
+    ///
+    /// This fails for [strict](SentryBuilder::strict) middleware with features
+    /// that have no effect with the current hub, like the creation of its
+    /// services does.  Checking before starting the server reports the error
+    /// with its details instead of a failed service creation.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use sentry_actix::{Sentry, SentryConfigError};
+    ///
+    /// // Without a bound client, the header is never emitted
+    /// let middleware = Sentry::builder().emit_header(true).strict(true).finish();
+    /// let error = middleware.check().unwrap_err();
+    /// assert_eq!(
+    ///     error.to_string(),
+    ///     "strict middleware has features without effect: \
+    ///      emit_header enabled but no Sentry client is bound"
+    /// );
+    /// ```
+    pub fn check(&self) -> Result<(), SentryConfigError> {
+        if !self.config.strict {
+            return Ok(());
+        }
+        let inert = self.inert_features();
+        if inert.is_empty() {
+            Ok(())
+        } else {
+            Err(SentryConfigError::InertFeatures(inert))
+        }
+    }
+
+    /// Describes the configured features that have no effect with the base hub.
+    pub(crate) fn inert_features(&self) -> Vec<String> {
+        let client = match self.base_hub().client() {
+            Some(client) if client.is_enabled() => client,
+            _ => {
+                let features = [
+                    ("emit_header", self.config.emit_header),
+                    ("emit_event_link", self.config.event_link.is_some()),
+                    ("problem_json", self.config.problem_json),
+                    ("session_mode", self.config.session_mode.is_some()),
+                    (
+                        "capture_method_not_allowed",
+                        self.config.capture_method_not_allowed,
+                    ),
+                    (
+                        "capture_poll_ready_errors",
+                        self.config.capture_poll_ready_errors,
+                    ),
+                    ("flush_on_capture", self.config.flush_on_capture.is_some()),
+                    ("hang_threshold", self.config.hang_threshold.is_some()),
+                    (
+                        "aggregate_repeated_messages",
+                        self.config.aggregate_repeated_messages,
+                    ),
+                ];
+                return features
+                    .iter()
+                    .filter(|(_, enabled)| *enabled)
+                    .map(|(name, _)| format!("{} enabled but no Sentry client is bound", name))
+                    .collect();
+            }
+        };
+        let mut inert = Vec::new();
+        if self.config.session_mode == Some(Some(SessionMode::Request))
+            && !client.options().auto_session_tracking
+        {
+            inert.push(
+                "session_mode enabled but auto_session_tracking is disabled on the client".into(),
+            );
+        }
+        inert
+    }
+
+    /// Handles a request with the middleware, calling `service` for its response.
+    ///
+    /// Passing the middleware to `wrap` requires the futures of the wrapped
+    /// service to be `'static`, because the middleware boxes its own futures:
+    ///
+    /// ```compile_fail
+    /// use std::task::{Context, Poll};
+    ///
+    /// use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+    /// use actix_web::{Error, HttpResponse};
+    /// use futures_util::future::LocalBoxFuture;
+    /// use futures_util::FutureExt;
+    ///
+    /// struct Greeting<'a>(&'a str);
+    ///
+    /// impl<'a> Service<ServiceRequest> for Greeting<'a> {
+    ///     type Response = ServiceResponse;
+    ///     type Error = Error;
+    ///     type Future = LocalBoxFuture<'a, Result<ServiceResponse, Error>>;
+    ///
+    ///     fn poll_ready(&self, _: &mut Context<'_>) -> Poll<Result<(), Error>> {
+    ///         Poll::Ready(Ok(()))
+    ///     }
+    ///
+    ///     fn call(&self, req: ServiceRequest) -> Self::Future {
+    ///         let greeting = self.0;
+    ///         async move { Ok(req.into_response(HttpResponse::Ok().body(greeting.to_owned()))) }
+    ///             .boxed_local()
+    ///     }
+    /// }
+    ///
+    /// let greeting = String::from("hello");
+    /// let _ = sentry_actix::Sentry::new().new_transform(Greeting(&greeting));
+    /// ```
+    ///
+    /// This is the escape hatch for such services.  The returned future owns
+    /// the future of the service without boxing it, so that it can be used with
+    /// `wrap_fn` or called by another service.  Unlike with `wrap`, the
+    /// readiness of the service is not tracked and inert features are not
+    /// reported.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use actix_web::App;
+    /// use sentry_actix::Sentry;
+    ///
+    /// let sentry = Sentry::new();
+    /// let app = App::new().wrap_fn(move |req, srv| sentry.wrap_fn(req, srv));
+    /// ```
+    pub fn wrap_fn<S, B>(
+        &self,
+        req: ServiceRequest,
+        service: &S,
+    ) -> impl Future<Output = Result<ServiceResponse<B>, actix_web::Error>>
+    where
+        S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+        B: MessageBody + 'static,
+    {
+        self.handle(req, &|req| service.call(req))
+    }
+
+    /// Handles a request, calling the wrapped service with `call`.
+    ///
+    /// The returned future only captures the future of the service, which is
+    /// `'static` for services passed to `wrap`.
+    fn handle<Fut, B>(
+        &self,
+        req: ServiceRequest,
+        call: &dyn Fn(ServiceRequest) -> Fut,
+    ) -> impl Future<Output = Result<ServiceResponse<B>, actix_web::Error>>
+    where
+        Fut: Future<Output = Result<ServiceResponse<B>, actix_web::Error>>,
+        B: MessageBody + 'static,
+    {
+        // Requests are only handled by the outermost middleware if it is nested
+        if req.extensions().contains::<Handled>() {
+            return Either::Left(call(req));
+        }
+        req.extensions_mut().insert(Handled);
+        let user_lookup = self.start_user_lookup(&req);
+        let custom_tx = self
+            .config
+            .transaction_name_fn
+            .as_ref()
+            .and_then(|transaction_name| {
+                self.run_hook("transaction_name_fn", || transaction_name(&req))
+                    .flatten()
+            });
+        let (req, mut payload) = req.into_parts();
+        let capture_override = CaptureOverride::from_extensions(&req.extensions());
+        let disable_sessions = req.extensions().contains::<DisableSessions>();
+
+        let started = self.config.clock.now();
+        let connection = self.config.connections.record(&req, started);
+        let api_version = self
+            .config
+            .api_version
+            .as_ref()
+            .and_then(|source| source.extract(req.path(), req.headers()));
+        let inner = self.clone();
+        let hub = Arc::new(match inner.config.base_scope {
+            Some(ref scope) => Hub::new(inner.base_hub().client(), scope.clone()),
+            None => Hub::new_from_top(inner.base_hub()),
+        });
+        let client = hub.client();
+        let track_sessions = client.as_ref().map_or(false, |client| {
+            let options = client.options();
+            let session_mode = inner
+                .config
+                .session_mode
+                .unwrap_or(Some(options.session_mode));
+            options.auto_session_tracking && session_mode == Some(SessionMode::Request)
+        });
+        let count_session = (inner.config.count_head_as_session || req.method() != Method::HEAD)
+            && (!inner.config.sessions_require_route_match
+                || req.match_name().is_some()
+                || req.match_pattern().is_some());
+        let session_started = track_sessions && count_session && !disable_sessions;
+        if session_started {
+            hub.start_session();
+        }
+        let with_pii = client
+            .as_ref()
+            .map_or(false, |client| client.options().send_default_pii);
+        let ip_policy = if with_pii {
+            inner.config.ip_policy
+        } else {
+            IpPolicy::None
+        };
+        let full_data = inner.sample_full_request_data();
+        let capture_limit = match client {
+            Some(ref client) if full_data => inner.request_body_limit(client.options()),
+            _ => 0,
+        };
+
+        let mut tx = match req.match_name() {
+            Some(name) => Some(name.to_owned()),
+            None => req.match_pattern(),
+        };
+        if custom_tx.is_some() {
+            tx = custom_tx;
+        }
+        if let (None, Some(normalizer)) = (&tx, &inner.config.path_normalizer) {
+            tx = inner.run_hook("normalize_unmatched_paths", || {
+                normalizer.normalize(req.path()).into_owned()
+            });
+        }
+        if let Some(ref tx) = tx {
+            req.extensions_mut().insert(TransactionName(tx.clone()));
+        }
+        // Request data is only built once an event needs it
+        let sentry_req = Arc::new(CapturedRequest::new(
+            &req,
+            full_data,
+            ip_policy,
+            inner.config.env_profile,
+            inner.config.redactors.clone(),
+            inner.config.url_limits,
+        ));
+        let route = tx.clone().unwrap_or_else(|| req.path().to_owned());
+        let original_path = req.path().to_owned();
+        let rewritten_path = Arc::new(Mutex::new(None::<String>));
+        let graphql = inner.config.graphql_path.as_deref() == Some(req.path());
+        let method = req.method().to_string();
+        let problem_json = inner.config.problem_json && accepts_json(&req);
+        let trace = Trace::from_headers(req.headers());
+        req.extensions_mut().insert(SnapshotContext {
+            request: sentry_req.clone(),
+            trace: trace.context(),
+            tags: inner.config.tags.clone(),
+        });
+        #[cfg(feature = "jwt")]
+        let user = inner
+            .config
+            .user_jwt
+            .as_ref()
+            .and_then(|config| config.user_from_headers(req.headers(), with_pii));
+        // Payloads with a declared length are tracked to detect incomplete reads
+        let declared = content_length(&req);
+        let track =
+            inner.config.track_request_body || capture_limit > 0 || declared.unwrap_or(0) > 0;
+        let body = Arc::new(RequestBody {
+            declared,
+            tracked: if track {
+                let (tracked_payload, tracked) = track_payload(payload, capture_limit);
+                payload = tracked_payload;
+                Some(tracked)
+            } else {
+                None
+            },
+            report_observed: inner.config.track_request_body,
+        });
+        let request = RequestState {
+            hub: hub.clone(),
+            clock: inner.config.clock.clone(),
+            started,
+            full_data,
+            body: body.clone(),
+            data: sentry_req.clone(),
+            route,
+            request_line: inner
+                .config
+                .request_summary
+                .as_ref()
+                .map(|_| request_line(&req)),
+            remote_addr: if inner.config.request_summary.is_some() {
+                req.connection_info()
+                    .realip_remote_addr()
+                    .and_then(|addr| ip_policy.apply(addr))
+            } else {
+                None
+            },
+            routing: if inner.config.routing_context {
+                Some(routing_context(&req, &inner.config.redactors))
+            } else {
+                None
+            },
+        };
+        let measurements = Arc::new(Mutex::new(Measurements::default()));
+        req.extensions_mut().insert(measurements.clone());
+        req.extensions_mut().insert(RequestHub(hub.clone()));
+        let aggregator = if inner.config.aggregate_repeated_messages {
+            Some(Arc::new(MessageAggregator::default()))
+        } else {
+            None
+        };
+        let recorded = measurements.clone();
+        hub.configure_scope(|scope| {
+            for (key, value) in inner.config.tags.iter() {
+                scope.set_tag(key, value);
+            }
+            for (key, value) in inner.config.extra.iter() {
+                scope.set_extra(key, value.clone());
+            }
+            scope.set_transaction(tx.as_deref());
+            scope.set_context("trace", trace.context());
+            #[cfg(feature = "jwt")]
+            if user.is_some() {
+                scope.set_user(user);
+            }
+            if inner.config.max_breadcrumbs.is_some() {
+                scope.set_max_breadcrumbs(inner.config.max_breadcrumbs);
+            }
+            scope.set_tag("request_data", if full_data { "full" } else { "minimal" });
+            if let Some(version) = api_version {
+                scope.set_tag("api_version", version);
+            }
+            if let Some(connection) = connection {
+                connection.apply_to_scope(scope);
+            }
+            let logger = inner.config.logger.clone();
+            let max_event_size = inner.config.max_event_size;
+            let redactors = inner.config.redactors.clone();
+            let route = request.route.clone();
+            let backpressure = inner.config.backpressure.clone();
+            let clock = inner.config.clock.clone();
+            let trail = if inner.config.breadcrumb_captures {
+                Some(CaptureTrail::default())
+            } else {
+                None
+            };
+            let held = aggregator.clone();
+            let (original, rewritten) = (original_path.clone(), rewritten_path.clone());
+            let request_url = inner.config.request_url;
+            let session = match inner.config.route_sessions {
+                Some(ref counters) if session_started => {
+                    Some(SessionTracker::new(counters.clone(), route.clone()))
+                }
+                _ => None,
+            };
+            let processor: Box<dyn Fn(_) -> _ + Send + Sync> = Box::new(move |event| {
+                // Sessions are updated with all events, whether they are sent or not,
+                // and held messages were counted when they were held
+                let flushed = held.as_ref().map_or(false, |held| held.is_flushed(&event));
+                if let Some(ref session) = session {
+                    if !flushed {
+                        session.record_event(&event);
+                    }
+                }
+                if capture_override == CaptureOverride::Disable {
+                    return None;
+                }
+                let event = match held {
+                    Some(ref held) => held.hold(event)?,
+                    None => event,
+                };
+                let mut event = process_event(event, &sentry_req.request(), &logger);
+                tag_panic(&mut event);
+                if let Some(ref effective) = *rewritten.lock().unwrap() {
+                    if let Some(request) = event.request.as_mut() {
+                        apply_rewritten_path(
+                            request,
+                            &original,
+                            effective,
+                            request_url,
+                            &redactors,
+                        );
+                    }
+                }
+                if let Some(unready_ms) = backpressure.unready_ms(clock.now()) {
+                    event
+                        .extra
+                        .insert("service_unready_ms".into(), unready_ms.into());
+                }
+                if full_data {
+                    body.apply_to_event(&mut event, &redactors);
+                    if graphql {
+                        if let Some(operation) = body.parse_captured(Operation::from_body) {
+                            operation.apply_to_event(&mut event, &method, &route);
+                        }
+                        if !with_pii {
+                            filter_variables(&mut event);
+                        }
+                    }
+                }
+                recorded.lock().unwrap().apply_to_event(&mut event);
+                if let Some(ref trail) = trail {
+                    trail.apply_and_record(&mut event);
+                }
+                trim_event(&mut event, max_event_size);
+                Some(event)
+            });
+            match inner.config.processor_position {
+                ProcessorPosition::First => scope.add_event_processor(processor),
+                ProcessorPosition::Last => scope.add_final_event_processor(processor),
+            }
+        });
+
+        let req = ServiceRequest::from_parts(req, payload);
+        let fut = call(req);
+        let fut = if inner.config.bind_hub {
+            Either::Left(fut.bind_hub(hub.clone()))
+        } else {
+            Either::Right(fut)
+        };
+        let watchdog = inner
+            .config
+            .hang_threshold
+            .map(|threshold| spawn_watchdog(&hub, &request.route, threshold));
+
+        Either::Right(async move {
+            let mut abort_guard = if inner.config.capture_aborted_requests {
+                Some(AbortGuard {
+                    hub: hub.clone(),
+                    route: request.route.clone(),
+                    rate_limit: inner.config.aborted_rate_limit.clone(),
+                    clock: inner.config.clock.clone(),
+                    ids: inner.config.ids.clone(),
+                    started: request.started,
+                    armed: true,
+                })
+            } else {
+                None
+            };
+            if let Some(lookup) = user_lookup {
+                if let Some(user) = inner.await_user(lookup).await {
+                    hub.configure_scope(|scope| scope.set_user(Some(user)));
+                }
+            }
+            let res = fut.await;
+            if let Some(ref mut abort_guard) = abort_guard {
+                abort_guard.armed = false;
+            }
+            drop(watchdog);
+            // Measurements recorded after the response has been returned are ignored
+            measurements.lock().unwrap().closed = true;
+            if let Some(ref aggregator) = aggregator {
+                aggregator.flush(&hub);
+            }
+
+            // Service errors
+            let mut res = match res {
+                Ok(res) => res,
+                Err(e) => {
+                    let status = e.as_response_error().status_code();
+                    let (decision, deferred) = inner.capture_error(
+                        &request,
+                        CaptureSource::ServiceError,
+                        &e,
+                        status,
+                        None,
+                        capture_override,
+                        None,
+                    );
+                    inner.log_decision(&hub, &request.route, status, decision);
+                    // There is no response to wait for, the outcome is final.
+                    if let Some(mut deferred) = deferred {
+                        deferred.complete(false);
+                    }
+                    let scoped = |error: actix_web::Error| {
+                        if inner.config.expose_hub_in_errors {
+                            let hub = hub.clone();
+                            ScopedError { error, hub }.into()
+                        } else {
+                            error
+                        }
+                    };
+                    let event_id = match decision.event_id() {
+                        Some(event_id) => event_id,
+                        None => return Err(scoped(e)),
+                    };
+                    if let Some(timeout) = inner.flush_timeout(&e, status) {
+                        flush_client(&hub, timeout).await;
+                    }
+                    if problem_json {
+                        let event_id =
+                            format_event_id(event_id, inner.config.emit_header_hyphenated);
+                        return Err(scoped(ProblemError { error: e, event_id }.into()));
+                    }
+                    return Err(scoped(e));
+                }
+            };
+
+            // Inner middlewares may have rewritten the path the service saw
+            if res.request().path() != original_path {
+                *rewritten_path.lock().unwrap() = Some(res.request().path().to_owned());
+            }
+
+            // Response errors
+            let mut deferred = None;
+            let mut flush_timeout = None;
+            let mut problem_event_id = None;
+            let mut captured = false;
+            let status = res.response().status();
+            let content_type = res
+                .response()
+                .headers()
+                .get(header::CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+                .and_then(mime_essence);
+            let long_lived = inner.config.detect_event_streams
+                && content_type.as_deref() == Some("text/event-stream");
+            if let Some(e) = res.response().error() {
+                let extensions = res.request().extensions();
+                let (decision, capture) = match extensions.get::<AppErrorCaptured>() {
+                    Some(AppErrorCaptured(event_id)) => {
+                        (CaptureDecision::CapturedByHandler(*event_id), None)
+                    }
+                    None => inner.capture_error(
+                        &request,
+                        CaptureSource::ResponseError,
+                        e,
+                        status,
+                        content_type.clone(),
+                        CaptureOverride::from_extensions(&extensions),
+                        extensions.get::<HandlerMetadata>(),
+                    ),
+                };
+                drop(extensions);
+                inner.log_decision(&hub, &request.route, status, decision);
+                if let CaptureDecision::Captured(_) = decision {
+                    flush_timeout = inner.flush_timeout(e, status);
+                }
+                #[cfg(feature = "rate-limits")]
+                if decision == CaptureDecision::SkippedByRateLimit
+                    && inner.config.emit_rate_limited_header
+                    && cfg!(debug_assertions)
+                {
+                    res.response_mut().headers_mut().insert(
+                        header::HeaderName::from_static(SENTRY_RATE_LIMITED_HEADER),
+                        header::HeaderValue::from_static("1"),
+                    );
+                }
+                deferred = capture.map(|mut deferred| {
+                    deferred.set_content_encoding(res.response().headers());
+                    deferred
+                });
+
+                if let Some(event_id) = decision.event_id() {
+                    captured = true;
+                    inner.insert_event_headers(&hub, res.response_mut().headers_mut(), event_id);
+                    if problem_json {
+                        problem_event_id = Some(format_event_id(
+                            event_id,
+                            inner.config.emit_header_hyphenated,
+                        ));
+                    }
+                }
+            }
+            // Errors inner middlewares turned into responses themselves
+            let swallowed = res
+                .response_mut()
+                .extensions_mut()
+                .remove::<OriginalError>();
+            if let Some(OriginalError(e)) = swallowed {
+                let error_status = e.as_response_error().status_code();
+                let capture_override =
+                    CaptureOverride::from_extensions(&res.request().extensions());
+                let (decision, capture) = inner.capture_error(
+                    &request,
+                    CaptureSource::SwallowedError,
+                    &e,
+                    error_status,
+                    None,
+                    capture_override,
+                    None,
+                );
+                inner.log_decision(&hub, &request.route, error_status, decision);
+                // The response does not carry the error, the outcome is final
+                if let Some(mut deferred) = capture {
+                    deferred.complete(false);
+                }
+                if let (Some(event_id), false) = (decision.event_id(), captured) {
+                    captured = true;
+                    inner.insert_event_headers(&hub, res.response_mut().headers_mut(), event_id);
+                }
+            }
+            if let Some(event_id) = problem_event_id {
+                res = rewrite_response(res, &event_id);
+            }
+            // Long-lived responses are not waited for, their request ends with the headers
+            if long_lived {
+                if let Some(mut deferred) = deferred.take() {
+                    deferred.complete(false);
+                }
+                if session_started {
+                    hub.end_session();
+                }
+            }
+            if inner.config.capture_method_not_allowed
+                && status == StatusCode::METHOD_NOT_ALLOWED
+                && !captured
+                && CaptureOverride::from_extensions(&res.request().extensions())
+                    != CaptureOverride::Disable
+            {
+                capture_method_not_allowed(
+                    &hub,
+                    inner.config.ids.event_id(),
+                    &request.route,
+                    res.request().method().as_str(),
+                    res.response().headers(),
+                );
+            }
+            if inner.config.emit_trace_headers {
+                let headers = res.response_mut().headers_mut();
+                for (name, value) in &[
+                    (SENTRY_TRACE_HEADER, trace.sentry_trace()),
+                    (TRACEPARENT_HEADER, trace.traceparent()),
+                ] {
+                    headers.insert(
+                        header::HeaderName::from_static(name),
+                        header::HeaderValue::from_str(value).unwrap(),
+                    );
+                }
+            }
+
+            if let Some(timeout) = flush_timeout {
+                flush_client(&hub, timeout).await;
+            }
+
+            // Observe the response body until it has been written to capture deferred events
+            let capture_stream = long_lived
+                && inner.config.capture_server_errors
+                && CaptureOverride::from_extensions(&res.request().extensions())
+                    != CaptureOverride::Disable;
+            let version = res.request().version();
+            Ok(match deferred {
+                Some(deferred) => res.map_body(|_, body| {
+                    ResponseBody::Other(Body::from_message(SentryBody {
+                        body: Box::pin(body),
+                        deferred: Some(deferred),
+                    }))
+                }),
+                None if capture_stream => res.map_body(|_, body| {
+                    ResponseBody::Other(Body::from_message(LongLivedBody {
+                        body: Box::pin(body),
+                        hub,
+                        route: request.route.clone(),
+                        version,
+                        clock: inner.config.clock.clone(),
+                        ids: inner.config.ids.clone(),
+                        started: request.started,
+                    }))
+                }),
+                None => res,
+            })
+        })
+    }
+
+    /// Adds the configured headers identifying a captured event to a response.
+    fn insert_event_headers(&self, hub: &Hub, headers: &mut HeaderMap, event_id: Uuid) {
+        if self.config.emit_header {
+            let event_id = format_event_id(event_id, self.config.emit_header_hyphenated);
+            headers.insert(
+                header::HeaderName::from_static(SENTRY_EVENT_HEADER),
+                header::HeaderValue::from_str(&event_id).unwrap(),
+            );
+        }
+        let link = match (&self.config.event_link, hub.client()) {
+            (Some(link), Some(client)) => client.dsn().map(|dsn| link.format(dsn, event_id)),
+            _ => None,
+        };
+        // Organizations are not validated, and may not be valid in headers
+        if let Some(value) = link.and_then(|link| header::HeaderValue::from_str(&link).ok()) {
+            headers.insert(
+                header::HeaderName::from_static(SENTRY_EVENT_URL_HEADER),
+                value,
+            );
+        }
+    }
+
+    /// Writes a message to the debug log, with the client of the base hub.
+    pub(crate) fn debug_log(&self, message: fmt::Arguments<'_>) {
+        self.write_debug_log(&self.base_hub(), &message.to_string());
+    }
+
+    /// Returns the hub that request hubs are derived from.
+    pub(crate) fn base_hub(&self) -> Arc<Hub> {
+        let hub = self
+            .config
+            .hub_fn
+            .as_ref()
+            .and_then(|hub_fn| self.run_hook("hub_fn", || hub_fn()));
+        hub.or_else(|| self.config.hub.clone())
+            .unwrap_or_else(Hub::main)
+    }
+
+    /// Returns the number of request body bytes to capture for the given client options.
+    fn request_body_limit(&self, options: &sentry_core::ClientOptions) -> usize {
+        options
+            .max_request_body_size
+            .max_bytes()
+            .map_or(self.config.max_request_body_size, |max_bytes| {
+                max_bytes.min(self.config.max_request_body_size)
+            })
+    }
+}
+
+impl Default for Sentry {
+    fn default() -> Self {
+        Sentry::new()
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for Sentry
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = SentryMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        // `wrap` requires an `InitError` of `()`, so the error is logged here
+        if let Err(error) = self.check() {
+            self.debug_log(format_args!("failed to create services: {}", error));
+            return err(());
+        }
+        let inert = self.inert_features();
+        // Services are created for each worker, only warn once
+        if !inert.is_empty() && !self.config.inert_warned.swap(true, Ordering::Relaxed) {
+            for feature in &inert {
+                self.debug_log(format_args!("{}", feature));
+            }
+        }
+        ok(SentryMiddleware {
+            service,
+            inner: self.clone(),
+        })
+    }
+}
+
+/// The middleware for individual services.
+pub struct SentryMiddleware<S> {
+    service: S,
+    inner: Sentry,
+}
+
+impl<S, B> Service<ServiceRequest> for SentryMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let poll = self.service.poll_ready(cx);
+        let now = self.inner.config.clock.now();
+        match poll {
+            Poll::Pending => self.inner.config.backpressure.record_pending(now),
+            Poll::Ready(_) => self.inner.config.backpressure.record_ready(now),
+        }
+        if let Poll::Ready(Err(ref e)) = poll {
+            if self.inner.config.capture_poll_ready_errors
+                && self.inner.config.poll_ready_rate_limit.try_acquire(now)
+            {
+                let service = std::any::type_name::<S>();
+                let event_id = self.inner.config.ids.event_id();
+                capture_poll_ready_error(&self.inner.base_hub(), event_id, e, service);
+            }
+        }
+        poll
+    }
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = &self.service;
+        self.inner
+            .handle(req, &|req| service.call(req))
+            .boxed_local()
+    }
+}
+
+/// Formats an event id in the simple or hyphenated UUID format.
+pub(crate) fn format_event_id(event_id: Uuid, hyphenated: bool) -> String {
+    let formatted = event_id.to_string();
+    if hyphenated {
+        formatted
+    } else {
+        formatted.replace('-', "")
+    }
+}
+
+/// Marks requests that are handled by a middleware.
+pub(crate) struct Handled;
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::fmt;
+    use std::io;
+    use std::rc::Rc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use actix_service::{IntoServiceFactory, ServiceFactory};
+    use actix_web::dev::AppConfig;
+    use actix_web::test::{call_service, init_service, ok_service, TestRequest};
+    use actix_web::{get, web, App, HttpRequest, HttpResponse};
+    use futures::executor::block_on;
+    use futures_util::future::LocalBoxFuture;
+    use futures_util::StreamExt;
+
+    use actix_web::dev::Payload;
+    use actix_web::middleware::{NormalizePath, TrailingSlash};
+    use actix_web::web::Bytes;
+    use actix_web::ResponseError;
+    use sentry::{Level, MaxRequestBodySize};
+    use sentry_core::protocol::{Event, Exception, IpAddress, Mechanism};
+
+    use super::*;
+    use crate::clock::{ManualClock, SequentialIds};
+    use crate::redact::{RedactionTarget, FILTERED};
+    use crate::sessions::SessionCounts;
+    use crate::{
+        inherit_hub_for_background, set_measurement, to_sentry_request, DisableCapture,
+        ForceCapture, RequestDataOptions, SentryClassify, SentryError,
+    };
+
+    fn _assert_hub_no_events() {
+        if Hub::current().last_event_id().is_some() {
+            panic!("Current hub should not have had any events.");
+        }
+    }
+
+    fn _assert_hub_has_events() {
+        Hub::current()
+            .last_event_id()
+            .expect("Current hub should have had events.");
+    }
+
+    /// Test explicit events sent to the current Hub inside an Actix service.
+    #[actix_rt::test]
+    async fn test_explicit_events() {
+        let events = sentry::test::with_captured_events(|| {
+            block_on(async {
+                let service = || {
+                    // Current Hub should have no events
+                    _assert_hub_no_events();
+
+                    sentry::capture_message("Message", Level::Warning);
+
+                    // Current Hub should have the event
+                    _assert_hub_has_events();
+
+                    HttpResponse::Ok()
+                };
+
+                let app = init_service(
+                    App::new()
+                        .wrap(Sentry::builder().with_hub(Hub::current()).finish())
+                        .service(web::resource("/test").to(service)),
+                )
+                .await;
+
+                // Call the service twice (sequentially) to ensure the middleware isn't sticky
+                for _ in 0..2 {
+                    let req = TestRequest::get().uri("/test").to_request();
+                    let res = call_service(&app, req).await;
+                    assert!(res.status().is_success());
+                }
+            })
+        });
+
+        assert_eq!(events.len(), 2);
+        for event in events {
+            let request = event.request.expect("Request should be set.");
+            assert_eq!(event.transaction, Some("/test".into()));
+            assert_eq!(event.message, Some("Message".into()));
+            assert_eq!(event.level, Level::Warning);
+            assert_eq!(request.method, Some("GET".into()));
+        }
+    }
+
+    /// Ensures request data of earlier requests does not leak into later events.
+    #[actix_rt::test]
+    async fn test_request_isolation() {
+        let events = sentry::test::with_captured_events(|| {
+            block_on(async {
+                let service = || {
+                    sentry::capture_message("Message", Level::Warning);
+                    HttpResponse::Ok()
+                };
+
+                let app = init_service(
+                    App::new()
+                        .wrap(Sentry::builder().with_hub(Hub::current()).finish())
+                        .service(web::resource("/first").to(service))
+                        .service(web::resource("/second").to(service)),
+                )
+                .await;
+
+                for uri in &["/first?a=1", "/second?b=2"] {
+                    let req = TestRequest::get().uri(uri).to_request();
+                    let res = call_service(&app, req).await;
+                    assert!(res.status().is_success());
+                }
+            })
+        });
+
+        assert_eq!(events.len(), 2);
+        for (event, (path, query)) in events.iter().zip(&[("/first", "a=1"), ("/second", "b=2")]) {
+            let request = event.request.as_ref().unwrap();
+            let url = request.url.as_ref().unwrap();
+            assert_eq!(url.path(), *path);
+            assert_eq!(url.query(), Some(*query));
+            assert_eq!(event.transaction.as_deref(), Some(*path));
+        }
+    }
+
+    /// Ensures errors returned in the Actix service trigger an event.
+    #[actix_rt::test]
+    async fn test_response_errors() {
+        let events = sentry::test::with_captured_events(|| {
+            block_on(async {
+                #[get("/test")]
+                async fn failing(_req: HttpRequest) -> Result<String, actix_web::Error> {
+                    // Current hub should have no events
+                    _assert_hub_no_events();
+
+                    Err(io::Error::new(io::ErrorKind::Other, "Test Error").into())
+                }
+
+                let app = init_service(
+                    App::new()
+                        .wrap(Sentry::builder().with_hub(Hub::current()).finish())
+                        .service(failing),
+                )
+                .await;
+
+                // Call the service twice (sequentially) to ensure the middleware isn't sticky
+                for _ in 0..2 {
+                    let req = TestRequest::get().uri("/test").to_request();
+                    let res = call_service(&app, req).await;
+                    assert!(res.status().is_server_error());
+                }
+            })
+        });
+
+        assert_eq!(events.len(), 2);
+        for event in events {
+            let request = event.request.expect("Request should be set.");
+            assert_eq!(event.transaction, Some("failing".into())); // Transaction name is the name of the function
+            assert_eq!(event.message, None);
+            assert_eq!(event.exception.values[0].ty, String::from("Custom"));
+            assert_eq!(event.exception.values[0].value, Some("Test Error".into()));
+            assert_eq!(event.level, Level::Error);
+            assert_eq!(request.method, Some("GET".into()));
+        }
+    }
+
+    /// Ensures exceptions are named after the concrete type of their error on demand.
+    #[actix_rt::test]
+    async fn test_response_errors_concrete_types() {
+        #[derive(Debug)]
+        enum InventoryError {
+            #[allow(dead_code)]
+            Missing,
+            Locked {
+                sku: u32,
+            },
+        }
+
+        impl fmt::Display for InventoryError {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                match self {
+                    InventoryError::Missing => f.write_str("item is missing"),
+                    InventoryError::Locked { sku } => write!(f, "item {} is locked", sku),
+                }
+            }
+        }
+
+        impl ResponseError for InventoryError {}
+
+        impl SentryClassify for InventoryError {
+            fn level(&self) -> Level {
+                Level::Error
+            }
+        }
+
+        async fn io_failing() -> Result<String, actix_web::Error> {
+            Err(io::Error::new(io::ErrorKind::Other, "Test Error").into())
+        }
+
+        async fn inventory_failing() -> Result<String, actix_web::Error> {
+            Err(SentryError::new(InventoryError::Locked { sku: 7 }).into())
+        }
+
+        let capture = |concrete: bool| {
+            sentry::test::with_captured_events(|| {
+                block_on(async {
+                    let app = init_service(
+                        App::new()
+                            .wrap(
+                                Sentry::builder()
+                                    .with_hub(Hub::current())
+                                    .classify_error::<InventoryError>()
+                                    .use_concrete_error_types(concrete)
+                                    .finish(),
+                            )
+                            .route("/io", web::get().to(io_failing))
+                            .route("/inventory", web::get().to(inventory_failing)),
+                    )
+                    .await;
+
+                    for uri in &["/io", "/inventory"] {
+                        let req = TestRequest::get().uri(uri).to_request();
+                        let res = call_service(&app, req).await;
+                        assert!(res.status().is_server_error());
+                    }
+                })
+            })
+        };
+
+        let events = capture(false);
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].exception.values[0].ty, "Custom");
+        assert!(!events[0].extra.contains_key("actix_error_kind"));
+        assert_eq!(events[1].exception.values.last().unwrap().ty, "Locked");
+
+        let events = capture(true);
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].exception.values[0].ty, "io::Error");
+        assert_eq!(events[0].extra["actix_error_kind"], "Custom");
+        assert_eq!(
+            events[0].exception.values[0].value.as_deref(),
+            Some("Test Error")
+        );
+        let exception = events[1].exception.values.last().unwrap();
+        assert_eq!(exception.ty, "InventoryError::Locked");
+        assert_eq!(events[1].extra["actix_error_kind"], "Locked");
+    }
+
+    /// Ensures events continue the trace of the request and responses carry it.
+    #[actix_rt::test]
+    async fn test_trace_continuation() {
+        let mut responses = Vec::new();
+        let events = sentry::test::with_captured_events(|| {
+            block_on(async {
+                async fn failing() -> Result<String, actix_web::Error> {
+                    Err(io::Error::new(io::ErrorKind::Other, "Test Error").into())
+                }
+
+                let middleware = Sentry::builder()
+                    .with_hub(Hub::current())
+                    .emit_trace_headers(true)
+                    .finish();
+                let app = init_service(
+                    App::new()
+                        .wrap(middleware)
+                        .service(web::resource("/test").to(failing)),
+                )
+                .await;
+
+                let traceparent = (
+                    "traceparent",
+                    "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+                );
+                let sentry_trace = (
+                    "sentry-trace",
+                    "4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-0",
+                );
+                for headers in &[vec![traceparent], vec![traceparent, sentry_trace]] {
+                    let mut req = TestRequest::get().uri("/test");
+                    for header in headers {
+                        req = req.insert_header(*header);
+                    }
+                    let res = call_service(&app, req.to_request()).await;
+                    let header = |name| res.headers().get(name).unwrap().to_str().unwrap();
+                    responses.push((
+                        header("sentry-trace").to_owned(),
+                        header("traceparent").to_owned(),
+                    ));
+                }
+            })
+        });
+
+        assert_eq!(events.len(), 2);
+        for (event, (sentry_trace, traceparent), trace_id, parent_span_id, sampled) in &[
+            (
+                &events[0],
+                &responses[0],
+                "0af7651916cd43dd8448eb211c80319c",
+                "b7ad6b7169203331",
+                true,
+            ),
+            (
+                &events[1],
+                &responses[1],
+                "4bf92f3577b34da6a3ce929d0e0e4736",
+                "00f067aa0ba902b7",
+                false,
+            ),
+        ] {
+            let trace = match event.contexts.get("trace") {
+                Some(sentry::protocol::Context::Trace(trace)) => trace,
+                _ => panic!("Trace context should be set."),
+            };
+            assert_eq!(trace.trace_id.to_simple().to_string(), *trace_id);
+            assert_eq!(trace.parent_span_id.as_deref(), Some(*parent_span_id));
+
+            let span_id = &trace.span_id.to_simple().to_string()[..16];
+            assert_eq!(
+                *sentry_trace,
+                format!("{}-{}-{}", trace_id, span_id, *sampled as u8)
+            );
+            assert_eq!(
+                *traceparent,
+                format!("00-{}-{}-0{}", trace_id, span_id, *sampled as u8)
+            );
+        }
+    }
+
+    /// Ensures handlers can extract the computed transaction name.
+    #[actix_rt::test]
+    async fn test_transaction_name_extractor() {
+        #[get("/macro")]
+        async fn with_macro(transaction: TransactionName) -> String {
+            transaction.0
+        }
+
+        async fn with_route(transaction: TransactionName) -> String {
+            transaction.0
+        }
+
+        let app = init_service(
+            App::new()
+                .wrap(Sentry::builder().with_hub(Hub::current()).finish())
+                .service(with_macro)
+                .route("/items/{id}", web::get().to(with_route)),
+        )
+        .await;
+
+        for (uri, transaction) in &[("/macro", "with_macro"), ("/items/1", "/items/{id}")] {
+            let req = TestRequest::get().uri(uri).to_request();
+            let res = call_service(&app, req).await;
+            assert!(res.status().is_success());
+            assert_eq!(actix_web::test::read_body(res).await, *transaction);
+        }
+
+        // Requests not handled by the middleware have no transaction name
+        let app = init_service(App::new().route("/items/{id}", web::get().to(with_route))).await;
+        let req = TestRequest::get().uri("/items/1").to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    /// Ensures service errors are captured with the status of the response
+    /// rendered for them, including client error statuses.
+    #[actix_rt::test]
+    async fn test_service_error_status() {
+        #[derive(Debug)]
+        struct StatusError(StatusCode);
+
+        impl fmt::Display for StatusError {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "Status Error {}", self.0)
+            }
+        }
+
+        impl ResponseError for StatusError {
+            fn status_code(&self) -> StatusCode {
+                self.0
+            }
+        }
+
+        let events = sentry::test::with_captured_events(|| {
+            block_on(async {
+                let service_error = |req: ServiceRequest, srv: &_| {
+                    let status = match req.path() {
+                        "/conflict" => StatusCode::CONFLICT,
+                        _ => StatusCode::INSUFFICIENT_STORAGE,
+                    };
+                    let fut = Service::call(srv, req);
+                    async move {
+                        let _: ServiceResponse = fut.await?;
+                        Err::<ServiceResponse, _>(StatusError(status).into())
+                    }
+                };
+                let app = init_service(
+                    App::new()
+                        .wrap(Sentry::builder().with_hub(Hub::current()).finish())
+                        .service(web::resource("/response").to(|| async {
+                            Err::<String, _>(StatusError(StatusCode::INSUFFICIENT_STORAGE))
+                        }))
+                        .service(
+                            web::scope("")
+                                .wrap_fn(service_error)
+                                .route("/conflict", web::get().to(HttpResponse::Ok))
+                                .route("/service", web::get().to(HttpResponse::Ok)),
+                        ),
+                )
+                .await;
+
+                let req = TestRequest::get().uri("/response").to_request();
+                assert!(app.call(req).await.is_ok());
+                for path in &["/conflict", "/service"] {
+                    let req = TestRequest::get().uri(path).to_request();
+                    assert!(app.call(req).await.is_err());
+                }
+            })
+        });
+
+        // Service errors are captured regardless of their status
+        assert_eq!(events.len(), 3);
+        let statuses: Vec<_> = events
+            .iter()
+            .map(|event| event.tags["http.status_code"].as_str())
+            .collect();
+        assert_eq!(statuses, ["507", "409", "507"]);
+        assert_eq!(events[1].tags["http.status_text"], "Conflict");
+        for event in &events[1..] {
+            assert_eq!(event.tags["sentry_actix.capture_source"], "service_error");
+        }
+    }
+
+    /// Ensures captured errors are rendered as problem details with the event id.
+    #[actix_rt::test]
+    async fn test_problem_json() {
+        #[derive(Debug)]
+        struct JsonError;
+
+        impl fmt::Display for JsonError {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("JSON Error")
+            }
+        }
+
+        impl ResponseError for JsonError {
+            fn error_response(&self) -> HttpResponse {
+                HttpResponse::InternalServerError().json(serde_json::json!({"code": 42}))
+            }
+        }
+
+        fn problem(content_type: Option<&header::HeaderValue>, body: &[u8]) -> Value {
+            assert_eq!(content_type.unwrap(), "application/problem+json");
+            serde_json::from_slice(body).unwrap()
+        }
+
+        let mut bodies = Vec::new();
+        let events = sentry::test::with_captured_events(|| {
+            block_on(async {
+                async fn failing() -> Result<String, actix_web::Error> {
+                    Err(io::Error::new(io::ErrorKind::Other, "Test Error").into())
+                }
+
+                let service_error = |req, srv: &_| {
+                    let fut = Service::call(srv, req);
+                    async move {
+                        let _: ServiceResponse = fut.await?;
+                        Err::<ServiceResponse, _>(actix_web::error::ErrorBadGateway("upstream"))
+                    }
+                };
+                let middleware = Sentry::builder()
+                    .with_hub(Hub::current())
+                    .problem_json(true)
+                    .finish();
+                let app = init_service(
+                    App::new()
+                        .wrap(middleware)
+                        .service(web::resource("/failing").to(failing))
+                        .service(
+                            web::resource("/json").to(|| async { Err::<String, _>(JsonError) }),
+                        )
+                        .service(
+                            web::resource("/service")
+                                .wrap_fn(service_error)
+                                .to(HttpResponse::Ok),
+                        ),
+                )
+                .await;
+
+                let req = TestRequest::get()
+                    .uri("/failing")
+                    .insert_header((header::ACCEPT, "application/json"))
+                    .to_request();
+                let res = call_service(&app, req).await;
+                assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+                let content_type = res.headers().get(header::CONTENT_TYPE).cloned();
+                let body = actix_web::test::read_body(res).await;
+                bodies.push(problem(content_type.as_ref(), &body));
+
+                // Clients not accepting JSON receive the default rendering
+                let req = TestRequest::get()
+                    .uri("/failing")
+                    .insert_header((header::ACCEPT, "text/html"))
+                    .to_request();
+                let res = call_service(&app, req).await;
+                assert_eq!(actix_web::test::read_body(res).await, "Test Error");
+
+                // Bodies rendered by the handler are left alone
+                let req = TestRequest::get().uri("/json").to_request();
+                let res = call_service(&app, req).await;
+                assert_eq!(actix_web::test::read_body(res).await, r#"{"code":42}"#);
+
+                let req = TestRequest::get().uri("/service").to_request();
+                let res = app
+                    .call(req)
+                    .await
+                    .unwrap_err()
+                    .as_response_error()
+                    .error_response();
+                assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+                let body = match res.body() {
+                    ResponseBody::Body(Body::Bytes(body)) => body.clone(),
+                    _ => panic!("Response should have a body."),
+                };
+                bodies.push(problem(res.headers().get(header::CONTENT_TYPE), &body));
+            })
+        });
+
+        assert_eq!(events.len(), 4);
+        for (event, body, status, title) in &[
+            (&events[0], &bodies[0], 500, "Internal Server Error"),
+            (&events[3], &bodies[1], 502, "Bad Gateway"),
+        ] {
+            assert_eq!(body["type"], "about:blank");
+            assert_eq!(body["status"], *status);
+            assert_eq!(body["title"], *title);
+            assert_eq!(
+                body["sentry_event_id"],
+                event.event_id.to_simple().to_string()
+            );
+        }
+    }
+
+    /// Ensures events record how they were captured.
+    #[actix_rt::test]
+    async fn test_capture_source() {
+        let events = sentry::test::with_captured_events(|| {
+            block_on(async {
+                async fn failing() -> Result<String, actix_web::Error> {
+                    Err(io::Error::new(io::ErrorKind::Other, "Test Error").into())
+                }
+
+                async fn manual(req: HttpRequest) -> HttpResponse {
+                    crate::with_request_hub(&req, || {
+                        sentry::capture_message("Cache miss", Level::Info);
+                    });
+                    sentry::capture_message("Cache filled", Level::Info);
+                    HttpResponse::Ok().finish()
+                }
+
+                // Captures an event like the panic integration
+                async fn panicking() -> HttpResponse {
+                    sentry::capture_event(Event {
+                        exception: vec![Exception {
+                            ty: "panic".into(),
+                            value: Some("Item missing".into()),
+                            mechanism: Some(Mechanism {
+                                ty: "panic".into(),
+                                handled: Some(false),
+                                ..Default::default()
+                            }),
+                            ..Default::default()
+                        }]
+                        .into(),
+                        level: Level::Fatal,
+                        ..Default::default()
+                    });
+                    HttpResponse::Ok().finish()
+                }
+
+                // Turns responses into service errors
+                let service_error = |req, srv: &_| {
+                    let fut = Service::call(srv, req);
+                    async move {
+                        let _: ServiceResponse = fut.await?;
+                        Err::<ServiceResponse, _>(actix_web::error::ErrorBadGateway("upstream"))
+                    }
+                };
+                let app = init_service(
+                    App::new()
+                        .wrap(Sentry::builder().with_hub(Hub::current()).finish())
+                        .service(web::resource("/response").to(failing))
+                        .service(
+                            web::resource("/service")
+                                .wrap_fn(service_error)
+                                .to(HttpResponse::Ok),
+                        )
+                        .service(web::resource("/manual").to(manual))
+                        .service(web::resource("/panic").to(panicking)),
+                )
+                .await;
+
+                for &path in ["/response", "/service", "/manual", "/panic"].iter() {
+                    let req = TestRequest::get().uri(path).to_request();
+                    let res = app.call(req).await;
+                    assert!(res.is_err() == (path == "/service"));
+                }
+            })
+        });
+
+        assert_eq!(events.len(), 5);
+        let sources: Vec<_> = events
+            .iter()
+            .map(|event| {
+                event
+                    .tags
+                    .get("sentry_actix.capture_source")
+                    .map(String::as_str)
+            })
+            .collect();
+        assert_eq!(
+            sources,
+            [
+                Some("response_error"),
+                Some("service_error"),
+                Some("manual"),
+                None,
+                Some("panic"),
+            ]
+        );
+        // The scope of manual captures only lasts for the helper
+        assert_eq!(events[3].message.as_deref(), Some("Cache filled"));
+
+        // Response errors were handled by the application, service errors were not
+        for (event, handled, route) in &[
+            (&events[0], true, "/response"),
+            (&events[1], false, "/service"),
+        ] {
+            let mechanism = event.exception.values[0].mechanism.as_ref().unwrap();
+            assert_eq!(mechanism.ty, "actix");
+            assert_eq!(mechanism.handled, Some(*handled));
+            assert_eq!(mechanism.data["route"], *route);
+        }
+        assert_eq!(
+            events[0].exception.values[0]
+                .mechanism
+                .as_ref()
+                .unwrap()
+                .data["status_code"],
+            500
+        );
+        assert_eq!(
+            events[1].exception.values[0]
+                .mechanism
+                .as_ref()
+                .unwrap()
+                .data["status_code"],
+            502
+        );
+    }
+
+    /// Ensures client errors (4xx) are not captured.
+    #[actix_rt::test]
+    async fn test_client_errors_discarded() {
+        let events = sentry::test::with_captured_events(|| {
+            block_on(async {
+                let service = || HttpResponse::NotFound();
+
+                let app = init_service(
+                    App::new()
+                        .wrap(Sentry::builder().with_hub(Hub::current()).finish())
+                        .service(web::resource("/test").to(service)),
+                )
+                .await;
+
+                let req = TestRequest::get().uri("/test").to_request();
+                let res = call_service(&app, req).await;
+                assert!(res.status().is_client_error());
+            })
+        });
+
+        assert!(events.is_empty());
+    }
+
+    /// Ensures the event id header is emitted in the configured format.
+    #[actix_rt::test]
+    async fn test_emit_header() {
+        let mut headers = Vec::new();
+        let events = sentry::test::with_captured_events(|| {
+            block_on(async {
+                async fn failing() -> Result<String, actix_web::Error> {
+                    Err(io::Error::new(io::ErrorKind::Other, "Test Error").into())
+                }
+
+                for &(hyphenated, len) in [(false, 32), (true, 36)].iter() {
+                    let middleware = Sentry::builder()
+                        .with_hub(Hub::current())
+                        .emit_header(true)
+                        .emit_header_hyphenated(hyphenated)
+                        .finish();
+                    let app = init_service(
+                        App::new()
+                            .wrap(middleware)
+                            .service(web::resource("/test").to(failing)),
+                    )
+                    .await;
+
+                    let req = TestRequest::get().uri("/test").to_request();
+                    let res = call_service(&app, req).await;
+                    let header = res.headers().get("x-sentry-event").unwrap();
+                    let header = header.to_str().unwrap();
+                    assert_eq!(header.len(), len);
+                    assert_eq!(header.contains('-'), hyphenated);
+                    headers.push(header.to_owned());
+                }
+            })
+        });
+
+        assert_eq!(events.len(), 2);
+        assert_eq!(headers[0], events[0].event_id.to_string().replace('-', ""));
+        assert_eq!(headers[1], events[1].event_id.to_string());
+    }
+
+    /// Ensures overlapping requests sharing an unbound hub keep their own request data.
+    #[actix_rt::test]
+    async fn test_shared_hub_concurrency() {
+        let (sender, receiver) = futures::channel::oneshot::channel::<()>();
+        let receiver = Rc::new(RefCell::new(Some(receiver)));
+        let sender = Rc::new(RefCell::new(Some(sender)));
+        let events = sentry::test::with_captured_events(|| {
+            block_on(async {
+                let app = init_service(
+                    App::new()
+                        .wrap(
+                            Sentry::builder()
+                                .with_hub(Hub::current())
+                                .bind_hub(false)
+                                .finish(),
+                        )
+                        .route(
+                            "/slow",
+                            web::get().to(move || {
+                                let receiver = receiver.borrow_mut().take().unwrap();
+                                async move {
+                                    // Waits until the other request has been handled
+                                    receiver.await.unwrap();
+                                    Err::<String, _>(actix_web::error::ErrorInternalServerError(
+                                        "Slow",
+                                    ))
+                                }
+                            }),
+                        )
+                        .route(
+                            "/fast",
+                            web::get().to(move || {
+                                sender.borrow_mut().take().unwrap().send(()).unwrap();
+                                async {
+                                    Err::<String, _>(actix_web::error::ErrorInternalServerError(
+                                        "Fast",
+                                    ))
+                                }
+                            }),
+                        ),
+                )
+                .await;
+
+                let slow = call_service(&app, TestRequest::get().uri("/slow").to_request());
+                let fast = call_service(&app, TestRequest::get().uri("/fast").to_request());
+                let (slow, fast) = futures::join!(slow, fast);
+                assert!(slow.status().is_server_error());
+                assert!(fast.status().is_server_error());
+            })
+        });
+
+        assert_eq!(events.len(), 2);
+        for (event, path) in events.iter().zip(&["/fast", "/slow"]) {
+            let request = event.request.as_ref().expect("Request should be set.");
+            assert_eq!(request.url.as_ref().unwrap().path(), *path);
+            assert_eq!(event.transaction.as_deref(), Some(*path));
+        }
+    }
+
+    /// Ensures the event link header is derived from the DSN of the client.
+    #[actix_rt::test]
+    async fn test_emit_event_link() {
+        let mut links = Vec::new();
+        let events = sentry::test::with_captured_events(|| {
+            block_on(async {
+                async fn failing() -> Result<String, actix_web::Error> {
+                    Err(io::Error::new(io::ErrorKind::Other, "Test Error").into())
+                }
+
+                let formats = [
+                    EventLink::ProjectEvent,
+                    EventLink::Url {
+                        organization: "acme".into(),
+                    },
+                ];
+                for link in formats.iter() {
+                    let middleware = Sentry::builder()
+                        .with_hub(Hub::current())
+                        .emit_event_link(Some(link.clone()))
+                        .finish();
+                    let app = init_service(
+                        App::new()
+                            .wrap(middleware)
+                            .service(web::resource("/test").to(failing)),
+                    )
+                    .await;
+
+                    let req = TestRequest::get().uri("/test").to_request();
+                    let res = call_service(&app, req).await;
+                    assert!(res.headers().get("x-sentry-event").is_none());
+                    let header = res.headers().get("x-sentry-event-url").unwrap();
+                    links.push(header.to_str().unwrap().to_owned());
+                }
+            })
+        });
+
+        assert_eq!(events.len(), 2);
+        assert_eq!(links[0], format!("1:{}", events[0].event_id.to_simple()));
+        assert_eq!(
+            links[1],
+            format!(
+                "https://sentry.invalid/organizations/acme/issues/?project=1&query={}",
+                events[1].event_id.to_simple()
+            )
+        );
+    }
+
+    /// Ensures measurements recorded by handlers are attached to events.
+    #[actix_rt::test]
+    async fn test_measurements() {
+        let late_request = Rc::new(RefCell::new(None));
+        let events = sentry::test::with_captured_events(|| {
+            block_on(async {
+                let late = late_request.clone();
+                let measured = move |req: HttpRequest| {
+                    set_measurement(&req, "items_processed", 42.0, "none");
+                    set_measurement(&req, "cache_hits", 0.5, "ratio");
+                    *late.borrow_mut() = Some(req);
+                    async { Err::<String, _>(io::Error::new(io::ErrorKind::Other, "Test Error")) }
+                };
+
+                let app = init_service(
+                    App::new()
+                        .wrap(Sentry::builder().with_hub(Hub::current()).finish())
+                        .service(web::resource("/test").to(measured)),
+                )
+                .await;
+
+                let req = TestRequest::get().uri("/test").to_request();
+                let res = call_service(&app, req).await;
+                assert!(res.status().is_server_error());
+            })
+        });
+
+        // Measurements recorded after the response are ignored
+        let req = late_request.borrow_mut().take().unwrap();
+        set_measurement(&req, "late", 1.0, "none");
+
+        assert_eq!(events.len(), 1);
+        let measurements = events[0].extra.get("measurements").unwrap();
+        assert_eq!(measurements["items_processed"]["value"], 42.0);
+        assert_eq!(measurements["items_processed"]["unit"], "none");
+        assert_eq!(measurements["cache_hits"]["value"], 0.5);
+        assert_eq!(measurements["cache_hits"]["unit"], "ratio");
+
+        let measurements = req.extensions().get::<Arc<Mutex<Measurements>>>().cloned();
+        assert!(!measurements
+            .unwrap()
+            .lock()
+            .unwrap()
+            .values
+            .contains_key("late"));
+    }
+
+    /// Ensures background work is linked to the request without touching its session.
+    #[actix_rt::test]
+    async fn test_inherit_hub_for_background() {
+        let background_hub = Rc::new(RefCell::new(None));
+        let envelopes = sentry::test::with_captured_envelopes_options(
+            || {
+                block_on(async {
+                    let background = background_hub.clone();
+                    let enqueue = move || {
+                        *background.borrow_mut() = Some(inherit_hub_for_background());
+                        async { "enqueued" }
+                    };
+
+                    let app = init_service(
+                        App::new()
+                            .wrap(Sentry::builder().with_hub(Hub::current()).finish())
+                            .service(web::resource("/test").to(enqueue)),
+                    )
+                    .await;
+
+                    let req = TestRequest::get().uri("/test").to_request();
+                    let res = call_service(&app, req).await;
+                    assert!(res.status().is_success());
+
+                    // The background work fails after the response went out
+                    let hub = background_hub.borrow_mut().take().unwrap();
+                    async {
+                        let err = io::Error::new(io::ErrorKind::Other, "Background Error");
+                        Hub::current().capture_error(&err);
+                    }
+                    .bind_hub(hub)
+                    .await;
+                })
+            },
+            sentry::ClientOptions {
+                release: Some("some-release".into()),
+                session_mode: sentry::SessionMode::Request,
+                auto_session_tracking: true,
+                ..Default::default()
+            },
+        );
+        assert_eq!(envelopes.len(), 2);
+
+        let event = envelopes[0].event().unwrap();
+        assert_eq!(event.transaction.as_deref(), Some("/test"));
+        assert_eq!(event.tags.get("spawned_from").unwrap(), "/test");
+        assert_eq!(
+            event.request.as_ref().unwrap().method.as_deref(),
+            Some("GET")
+        );
+
+        let mut items = envelopes[1].items();
+        if let Some(sentry::protocol::EnvelopeItem::SessionAggregates(aggregate)) = items.next() {
+            let aggregates = &aggregate.aggregates;
+            assert_eq!(aggregates[0].exited, 1);
+            assert_eq!(aggregates[0].errored, 0);
+        } else {
+            panic!("expected session");
+        }
+        assert_eq!(items.next(), None);
+    }
+
+    /// Ensures events captured for requests receive the configured logger.
+    #[actix_rt::test]
+    async fn test_logger() {
+        let events = sentry::test::with_captured_events(|| {
+            block_on(async {
+                async fn explicit() -> HttpResponse {
+                    sentry::capture_message("Message", Level::Warning);
+                    sentry::capture_event(Event {
+                        logger: Some("custom".into()),
+                        ..Default::default()
+                    });
+                    HttpResponse::Ok().finish()
+                }
+
+                async fn failing() -> Result<String, actix_web::Error> {
+                    Err(io::Error::new(io::ErrorKind::Other, "Test Error").into())
+                }
+
+                for &logger in [None, Some("checkout")].iter() {
+                    let mut builder = Sentry::builder().with_hub(Hub::current());
+                    if let Some(logger) = logger {
+                        builder = builder.logger(logger);
+                    }
+                    let app = init_service(
+                        App::new()
+                            .wrap(builder.finish())
+                            .service(web::resource("/explicit").to(explicit))
+                            .service(web::resource("/failing").to(failing)),
+                    )
+                    .await;
+
+                    for &path in ["/explicit", "/failing"].iter() {
+                        let req = TestRequest::get().uri(path).to_request();
+                        call_service(&app, req).await;
+                    }
+                }
+            })
+        });
+
+        assert_eq!(events.len(), 6);
+        let loggers: Vec<_> = events.iter().map(|e| e.logger.as_deref()).collect();
+        assert_eq!(
+            loggers,
+            vec![
+                Some("sentry_actix"),
+                Some("custom"),
+                Some("sentry_actix"),
+                Some("checkout"),
+                Some("custom"),
+                Some("checkout"),
+            ]
+        );
+    }
+
+    /// Ensures manually created request data matches the data attached by the middleware.
+    #[actix_rt::test]
+    async fn test_to_sentry_request() {
+        for &send_default_pii in [false, true].iter() {
+            let manual = Rc::new(RefCell::new(None));
+            let options = sentry::ClientOptions {
+                send_default_pii,
+                ..Default::default()
+            };
+            let events = sentry::test::with_captured_events_options(
+                || {
+                    block_on(async {
+                        let manual = manual.clone();
+                        let failing = move |req: HttpRequest| {
+                            let options = RequestDataOptions {
+                                send_default_pii,
+                                ..Default::default()
+                            };
+                            *manual.borrow_mut() = Some(to_sentry_request(&req, &options));
+                            async {
+                                Err::<String, _>(io::Error::new(io::ErrorKind::Other, "Test Error"))
+                            }
+                        };
+
+                        let app = init_service(
+                            App::new()
+                                .wrap(Sentry::builder().with_hub(Hub::current()).finish())
+                                .service(web::resource("/items/{id}").to(failing)),
+                        )
+                        .await;
+
+                        let req = TestRequest::post()
+                            .uri("/items/42?expand=owner")
+                            .insert_header((header::CONTENT_LENGTH, "0"))
+                            .insert_header(("x-forwarded-for", "203.0.113.7"))
+                            .peer_addr("198.51.100.1:8080".parse().unwrap())
+                            .to_request();
+                        call_service(&app, req).await;
+                    })
+                },
+                options,
+            );
+
+            assert_eq!(events.len(), 1);
+            let manual = manual.borrow_mut().take().unwrap();
+            assert_eq!(events[0].request.as_ref(), Some(&manual));
+            assert_eq!(manual.env.contains_key("REMOTE_ADDR"), send_default_pii);
+        }
+    }
+
+    /// Ensures transaction name can be overridden in handler scope.
+    #[actix_rt::test]
+    async fn test_override_transaction_name() {
+        let events = sentry::test::with_captured_events(|| {
+            block_on(async {
+                #[get("/test")]
+                async fn original_transaction(
+                    _req: HttpRequest,
+                ) -> Result<String, actix_web::Error> {
+                    // Override transaction name
+                    sentry::configure_scope(|scope| scope.set_transaction(Some("new_transaction")));
+                    Err(io::Error::new(io::ErrorKind::Other, "Test Error").into())
+                }
+
+                let app = init_service(
+                    App::new()
+                        .wrap(Sentry::builder().with_hub(Hub::current()).finish())
+                        .service(original_transaction),
+                )
+                .await;
+
+                let req = TestRequest::get().uri("/test").to_request();
+                let res = call_service(&app, req).await;
+                assert!(res.status().is_server_error());
+            })
+        });
+
+        assert_eq!(events.len(), 1);
+        let event = events[0].clone();
+        let request = event.request.expect("Request should be set.");
+        assert_eq!(event.transaction, Some("new_transaction".into())); // Transaction name is overridden by handler
+        assert_eq!(event.message, None);
+        assert_eq!(event.exception.values[0].ty, String::from("Custom"));
+        assert_eq!(event.exception.values[0].value, Some("Test Error".into()));
+        assert_eq!(event.level, Level::Error);
+        assert_eq!(request.method, Some("GET".into()));
+    }
+
+    /// Ensures the transaction includes the prefix of the scope the middleware is mounted on.
+    #[actix_rt::test]
+    async fn test_scope_transaction_name() {
+        let events = sentry::test::with_captured_events(|| {
+            block_on(async {
+                async fn failing() -> Result<String, actix_web::Error> {
+                    Err(io::Error::new(io::ErrorKind::Other, "Test Error").into())
+                }
+
+                let middleware = || Sentry::builder().with_hub(Hub::current()).finish();
+                let scope = || {
+                    web::scope("/api")
+                        .wrap(middleware())
+                        .route("/items/{id}", web::get().to(failing))
+                };
+
+                // Mounted on the scope only
+                let app = init_service(App::new().service(scope())).await;
+                let req = TestRequest::get().uri("/api/items/42").to_request();
+                let res = call_service(&app, req).await;
+                assert!(res.status().is_server_error());
+
+                // Mounted on both the app and the scope
+                let app = init_service(App::new().wrap(middleware()).service(scope())).await;
+                let req = TestRequest::get().uri("/api/items/42").to_request();
+                let res = call_service(&app, req).await;
+                assert!(res.status().is_server_error());
+            })
+        });
+
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].transaction, Some("/api/items/{id}".into()));
+        assert_eq!(events[1].transaction, Some("/api/items/{id}".into()));
+    }
+
+    /// Ensures the request and response content types are recorded as tags.
+    #[actix_rt::test]
+    async fn test_content_type_tags() {
+        let events = sentry::test::with_captured_events(|| {
+            block_on(async {
+                async fn failing() -> HttpResponse {
+                    let error = io::Error::new(io::ErrorKind::Other, "Test Error");
+                    let mut res = HttpResponse::from_error(error.into());
+                    res.headers_mut().insert(
+                        header::CONTENT_TYPE,
+                        header::HeaderValue::from_static("Application/Problem+JSON; charset=utf-8"),
+                    );
+                    res
+                }
+
+                let app = init_service(
+                    App::new()
+                        .wrap(Sentry::builder().with_hub(Hub::current()).finish())
+                        .service(web::resource("/test").to(failing)),
+                )
+                .await;
+
+                for content_type in &["text/html; charset=UTF-8", "invalid", ""] {
+                    let mut req = TestRequest::post().uri("/test");
+                    if !content_type.is_empty() {
+                        req = req.insert_header((header::CONTENT_TYPE, *content_type));
+                    }
+                    let res = call_service(&app, req.to_request()).await;
+                    assert!(res.status().is_server_error());
+                }
+            })
+        });
+
+        assert_eq!(events.len(), 3);
+        assert_eq!(
+            events[0].tags.get("request.content_type"),
+            Some(&"text/html".into())
+        );
+        assert!(!events[1].tags.contains_key("request.content_type"));
+        assert!(!events[2].tags.contains_key("request.content_type"));
+        for event in &events {
+            assert_eq!(
+                event.tags.get("response.content_type"),
+                Some(&"application/problem+json".into())
+            );
+        }
+    }
+
+    /// Ensures a custom function can derive the transaction name.
+    #[actix_rt::test]
+    async fn test_transaction_name_fn() {
+        let events = sentry::test::with_captured_events(|| {
+            block_on(async {
+                async fn failing(req: HttpRequest) -> Result<String, actix_web::Error> {
+                    let version = req.match_info().query("version").to_owned();
+                    sentry::configure_scope(|scope| scope.set_tag("version", version));
+                    Err(io::Error::new(io::ErrorKind::Other, "Test Error").into())
+                }
+
+                let middleware = Sentry::builder()
+                    .with_hub(Hub::current())
+                    .transaction_name_fn(|req| {
+                        let pattern = req.match_pattern()?;
+                        pattern.strip_prefix("/{version}").map(ToOwned::to_owned)
+                    })
+                    .finish();
+                let app = init_service(
+                    App::new()
+                        .wrap(middleware)
+                        .route("/{version}/users/{id}", web::get().to(failing))
+                        .route("/health", web::get().to(failing)),
+                )
+                .await;
+
+                for &uri in ["/v1/users/1", "/v2/users/2", "/health"].iter() {
+                    let req = TestRequest::get().uri(uri).to_request();
+                    let res = call_service(&app, req).await;
+                    assert!(res.status().is_server_error());
+                }
+            })
+        });
+
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0].transaction, Some("/users/{id}".into()));
+        assert_eq!(events[0].tags.get("version"), Some(&"v1".into()));
+        assert_eq!(events[1].transaction, Some("/users/{id}".into()));
+        assert_eq!(events[1].tags.get("version"), Some(&"v2".into()));
+        // Falls back to the route pattern
+        assert_eq!(events[2].transaction, Some("/health".into()));
+    }
+
+    /// Ensures metadata attached through `SentryError` is applied to the captured event only.
+    #[actix_rt::test]
+    async fn test_sentry_error_metadata() {
+        let events = sentry::test::with_captured_events(|| {
+            block_on(async {
+                #[get("/test")]
+                async fn failing(_req: HttpRequest) -> Result<String, actix_web::Error> {
+                    let err = io::Error::new(io::ErrorKind::Other, "Test Error");
+                    Err(SentryError::new(err)
+                        .level(Level::Warning)
+                        .tag("domain", "billing")
+                        .extra("attempt", 3)
+                        .fingerprint(&["billing", "declined"])
+                        .into())
+                }
+
+                let app = init_service(
+                    App::new()
+                        .wrap(Sentry::builder().with_hub(Hub::current()).finish())
+                        .service(failing),
+                )
+                .await;
+
+                let req = TestRequest::get().uri("/test").to_request();
+                let res = call_service(&app, req).await;
+                assert!(res.status().is_server_error());
+
+                // The metadata must not leak into subsequent events
+                sentry::capture_message("Message", Level::Info);
+            })
+        });
+
+        assert_eq!(events.len(), 2);
+        let event = &events[0];
+        assert_eq!(event.level, Level::Warning);
+        assert_eq!(event.tags.get("domain"), Some(&"billing".to_owned()));
+        assert_eq!(event.extra.get("attempt"), Some(&3.into()));
+        assert_eq!(&event.fingerprint[..], &["billing", "declined"]);
+        assert_eq!(event.exception.values[0].ty, String::from("Custom"));
+        assert_eq!(event.exception.values[0].value, Some("Test Error".into()));
+
+        let event = &events[1];
+        assert_eq!(event.level, Level::Info);
+        assert!(!event.tags.contains_key("domain"));
+        assert!(!event.extra.contains_key("attempt"));
+    }
+
+    /// Ensures the request payload size is recorded on events.
+    #[actix_rt::test]
+    async fn test_request_body_size() {
+        let events = sentry::test::with_captured_events(|| {
+            block_on(async {
+                async fn upload(body: web::Bytes) -> Result<String, actix_web::Error> {
+                    Err(
+                        io::Error::new(io::ErrorKind::Other, format!("{} bytes", body.len()))
+                            .into(),
+                    )
+                }
+
+                // A request declaring its length, and a chunked one that is only tracked
+                for &track in [false, true].iter() {
+                    let middleware = Sentry::builder()
+                        .with_hub(Hub::current())
+                        .track_request_body(track)
+                        .finish();
+                    let app = init_service(
+                        App::new()
+                            .wrap(middleware)
+                            .service(web::resource("/upload").to(upload)),
+                    )
+                    .await;
+
+                    let mut req = TestRequest::post()
+                        .uri("/upload")
+                        .set_payload(vec![0u8; 1234]);
+                    if !track {
+                        req = req.insert_header((header::CONTENT_LENGTH, "1234"));
+                    }
+                    let res = call_service(&app, req.to_request()).await;
+                    assert!(res.status().is_server_error());
+                }
+            })
+        });
+
+        assert_eq!(events.len(), 2);
+        let request = events[0].request.as_ref().expect("Request should be set.");
+        assert_eq!(request.env.get("CONTENT_LENGTH"), Some(&"1234".into()));
+        assert_eq!(
+            events[0].extra.get("request_body_bytes"),
+            Some(&1234.into())
+        );
+
+        let request = events[1].request.as_ref().expect("Request should be set.");
+        assert_eq!(request.env.get("CONTENT_LENGTH"), None);
+        assert_eq!(
+            events[1].extra.get("request_body_bytes"),
+            Some(&1234.into())
+        );
+    }
+
+    /// Ensures captures are summarized in the debug log, even without a client.
+    #[actix_rt::test]
+    async fn test_debug_log_captures() {
+        async fn failing() -> Result<String, actix_web::Error> {
+            Err(io::Error::new(io::ErrorKind::Other, "Test Error").into())
+        }
+
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let log = lines.clone();
+        let app = init_service(
+            App::new()
+                .wrap(
+                    Sentry::builder()
+                        .with_hub(Arc::new(Hub::new(None, Default::default())))
+                        .debug_log_captures_with(move |line| {
+                            log.lock().unwrap().push(line.to_owned())
+                        })
+                        .finish(),
+                )
+                .route("/items/{id}", web::get().to(failing)),
+        )
+        .await;
+
+        let req = TestRequest::get()
+            .uri("/items/42?token=secret")
+            .to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        let lines = lines.lock().unwrap();
+        assert_eq!(lines.len(), 2);
+        let summary = &lines[0];
+        assert!(summary.starts_with("event "), "{}", summary);
+        assert!(summary.contains("on /items/{id} with status 500"));
+        assert!(summary.contains("request: GET http://localhost:8080/items/42?token=[Filtered]"));
+        assert!(summary.contains("error: Custom: Test Error"), "{}", summary);
+        assert!(!summary.contains("secret"));
+        assert!(lines[1].starts_with("actix error on /items/{id} with status 500: captured"));
+    }
+
+    /// Ensures the observed request and response body sizes are recorded on events.
+    #[actix_rt::test]
+    async fn test_body_content_lengths() {
+        let events = sentry::test::with_captured_events(|| {
+            block_on(async {
+                async fn upload(body: web::Bytes) -> Result<String, actix_web::Error> {
+                    Err(actix_web::error::ErrorInternalServerError(format!(
+                        "Stored {} bytes",
+                        body.len()
+                    )))
+                }
+
+                async fn reject() -> Result<String, actix_web::Error> {
+                    Err(actix_web::error::ErrorInternalServerError("Rejected"))
+                }
+
+                let app = init_service(
+                    App::new()
+                        .wrap(
+                            Sentry::builder()
+                                .with_hub(Hub::current())
+                                .capture_mode(CaptureMode::Deferred)
+                                .finish(),
+                        )
+                        .service(web::resource("/upload").to(upload))
+                        .service(web::resource("/reject").to(reject)),
+                )
+                .await;
+
+                let req = |uri| {
+                    TestRequest::post()
+                        .uri(uri)
+                        .insert_header((header::CONTENT_LENGTH, "1234"))
+                        .set_payload(vec![0u8; 1234])
+                        .to_request()
+                };
+                let res = call_service(&app, req("/upload")).await;
+                let body = actix_web::test::read_body(res).await;
+                assert_eq!(body, "Stored 1234 bytes");
+
+                // Neither body is read, as if the client disconnected early
+                drop(call_service(&app, req("/reject")).await);
+            })
+        });
+
+        assert_eq!(events.len(), 2);
+        let extra = &events[0].extra;
+        assert_eq!(extra.get("http.request_content_length"), Some(&1234.into()));
+        assert_eq!(extra.get("http.response_content_length"), Some(&17.into()));
+        assert!(!extra.contains_key("http.request_content_length_truncated"));
+        assert!(!extra.contains_key("http.response_content_length_truncated"));
+
+        let extra = &events[1].extra;
+        assert_eq!(extra.get("http.request_content_length"), Some(&0.into()));
+        assert_eq!(extra.get("http.response_content_length"), Some(&0.into()));
+        assert_eq!(
+            extra.get("http.request_content_length_truncated"),
+            Some(&true.into())
+        );
+        assert_eq!(
+            extra.get("http.response_content_length_truncated"),
+            Some(&true.into())
+        );
+    }
+
+    /// Ensures events of GraphQL requests are named after their operation.
+    #[actix_rt::test]
+    async fn test_graphql_operation() {
+        async fn graphql(_body: web::Bytes) -> Result<String, actix_web::Error> {
+            Err(io::Error::new(io::ErrorKind::Other, "Resolver Error").into())
+        }
+
+        let capture = |send_default_pii, body: &'static str| {
+            let options = sentry::ClientOptions {
+                max_request_body_size: MaxRequestBodySize::Always,
+                send_default_pii,
+                ..Default::default()
+            };
+            let events = sentry::test::with_captured_events_options(
+                || {
+                    block_on(async {
+                        let middleware = Sentry::builder()
+                            .with_hub(Hub::current())
+                            .graphql("/graphql")
+                            .finish();
+                        let app = init_service(
+                            App::new()
+                                .wrap(middleware)
+                                .service(web::resource("/graphql").to(graphql)),
+                        )
+                        .await;
+
+                        let req = TestRequest::post()
+                            .uri("/graphql")
+                            .set_payload(body)
+                            .to_request();
+                        let res = call_service(&app, req).await;
+                        assert!(res.status().is_server_error());
+                    })
+                },
+                options,
+            );
+            assert_eq!(events.len(), 1);
+            events.into_iter().next().unwrap()
+        };
+
+        let body = r#"{"query":"mutation AddItem($name: String!) { add(name: $name) { id } }","variables":{"name":"secret item"}}"#;
+        let event = capture(false, body);
+        assert_eq!(
+            event.transaction.as_deref(),
+            Some("POST /graphql (AddItem)")
+        );
+        assert_eq!(event.tags["graphql.operation"], "AddItem");
+        assert_eq!(event.tags["graphql.operation_type"], "mutation");
+        let data = event.request.unwrap().data.unwrap();
+        assert!(!data.contains("secret item"));
+
+        // Variables are kept with PII enabled
+        let event = capture(true, body);
+        assert!(event.request.unwrap().data.unwrap().contains("secret item"));
+
+        // Malformed bodies keep the name of the route
+        let event = capture(false, "{not graphql");
+        assert_eq!(event.transaction.as_deref(), Some("/graphql"));
+        assert!(!event.tags.contains_key("graphql.operation"));
+    }
+
+    /// Ensures request bodies are captured up to the limit of the client option.
+    #[actix_rt::test]
+    async fn test_max_request_body_size() {
+        async fn upload(body: web::Bytes) -> Result<String, actix_web::Error> {
+            Err(io::Error::new(io::ErrorKind::Other, format!("{} bytes", body.len())).into())
+        }
+
+        let capture = |max_request_body_size, builder_limit| {
+            let options = sentry::ClientOptions {
+                max_request_body_size,
+                ..Default::default()
+            };
+            let events = sentry::test::with_captured_events_options(
+                || {
+                    block_on(async {
+                        let middleware = Sentry::builder()
+                            .with_hub(Hub::current())
+                            .max_request_body_size(builder_limit)
+                            .finish();
+                        let app = init_service(
+                            App::new()
+                                .wrap(middleware)
+                                .service(web::resource("/upload").to(upload)),
+                        )
+                        .await;
+
+                        let req = TestRequest::post()
+                            .uri("/upload")
+                            .set_payload("x".repeat(2000))
+                            .to_request();
+                        let res = call_service(&app, req).await;
+                        assert!(res.status().is_server_error());
+                    })
+                },
+                options,
+            );
+            assert_eq!(events.len(), 1);
+            events.into_iter().next().unwrap()
+        };
+
+        // Bodies are not captured by default
+        let event = capture(MaxRequestBodySize::None, 100_000);
+        assert_eq!(event.request.unwrap().data, None);
+
+        // Larger bodies are truncated to the active bucket
+        let event = capture(MaxRequestBodySize::Small, 100_000);
+        assert_eq!(event.request.unwrap().data, Some("x".repeat(1000)));
+        assert_eq!(
+            event.extra.get("request_body_truncated"),
+            Some(&true.into())
+        );
+
+        // Bodies within the bucket are captured in full
+        let event = capture(MaxRequestBodySize::Medium, 100_000);
+        assert_eq!(event.request.unwrap().data, Some("x".repeat(2000)));
+        assert!(!event.extra.contains_key("request_body_truncated"));
+
+        // The builder can restrict the client option, but not loosen it
+        let event = capture(MaxRequestBodySize::Always, 500);
+        assert_eq!(event.request.unwrap().data, Some("x".repeat(500)));
+        assert_eq!(
+            event.extra.get("request_body_truncated"),
+            Some(&true.into())
+        );
+        let event = capture(MaxRequestBodySize::Small, 1500);
+        assert_eq!(event.request.unwrap().data, Some("x".repeat(1000)));
+    }
+
+    /// Ensures request data passes through the redaction pipeline.
+    #[actix_rt::test]
+    async fn test_redaction() {
+        async fn upload(_body: web::Bytes) -> Result<String, actix_web::Error> {
+            Err(io::Error::new(io::ErrorKind::Other, "Test Error").into())
+        }
+
+        let capture = |middleware: SentryBuilder| {
+            let options = sentry::ClientOptions {
+                max_request_body_size: MaxRequestBodySize::Always,
+                ..Default::default()
+            };
+            let events = sentry::test::with_captured_events_options(
+                || {
+                    block_on(async {
+                        let middleware = middleware.with_hub(Hub::current()).finish();
+                        let app = init_service(
+                            App::new()
+                                .wrap(middleware)
+                                .service(web::resource("/users/{id}").to(upload)),
+                        )
+                        .await;
+
+                        let req = TestRequest::post()
+                            .uri("/users/42?page=2&token=abc")
+                            .insert_header(("authorization", "Bearer abc"))
+                            .insert_header(("accept", "application/json"))
+                            .set_payload(r#"{"name":"jane","password":"hunter2"}"#)
+                            .to_request();
+                        let res = call_service(&app, req).await;
+                        assert!(res.status().is_server_error());
+                    })
+                },
+                options,
+            );
+            assert_eq!(events.len(), 1);
+            events.into_iter().next().unwrap().request.unwrap()
+        };
+
+        // Credentials are filtered by default
+        let request = capture(Sentry::builder());
+        assert_eq!(
+            request.url.unwrap().query(),
+            Some("page=2&token=[Filtered]")
+        );
+        assert_eq!(request.headers["authorization"], "[Filtered]");
+        assert_eq!(request.headers["accept"], "application/json");
+        let data: serde_json::Value = serde_json::from_str(&request.data.unwrap()).unwrap();
+        assert_eq!(data["name"], "jane");
+        assert_eq!(data["password"], "[Filtered]");
+
+        // Custom redactors run after the default one
+        let request = capture(Sentry::builder().redactor(
+            |target: RedactionTarget<'_>, value: &mut String| {
+                if target == RedactionTarget::Path {
+                    *value = value.replace("42", "{id}");
+                }
+            },
+        ));
+        assert_eq!(request.url.unwrap().path(), "/users/%7Bid%7D");
+
+        // Without redactors the data is attached unchanged
+        let request = capture(Sentry::builder().clear_redactors());
+        assert_eq!(request.url.unwrap().query(), Some("page=2&token=abc"));
+        assert_eq!(request.headers["authorization"], "Bearer abc");
+    }
+
+    /// Ensures errors for requests whose payload was not fully read are marked.
+    #[actix_rt::test]
+    async fn test_incomplete_request_body() {
+        let events = sentry::test::with_captured_events(|| {
+            block_on(async {
+                async fn partial(mut payload: web::Payload) -> Result<String, actix_web::Error> {
+                    let chunk = payload.next().await.unwrap()?;
+                    Err(
+                        io::Error::new(io::ErrorKind::Other, format!("{} bytes", chunk.len()))
+                            .into(),
+                    )
+                }
+
+                async fn full(body: web::Bytes) -> Result<String, actix_web::Error> {
+                    Err(
+                        io::Error::new(io::ErrorKind::Other, format!("{} bytes", body.len()))
+                            .into(),
+                    )
+                }
+
+                let app = init_service(
+                    App::new()
+                        .wrap(Sentry::builder().with_hub(Hub::current()).finish())
+                        .service(web::resource("/partial").to(partial))
+                        .service(web::resource("/full").to(full)),
+                )
+                .await;
+
+                for &path in ["/partial", "/full"].iter() {
+                    let mut req = TestRequest::post()
+                        .uri(path)
+                        .insert_header((header::CONTENT_LENGTH, "1000"))
+                        .to_request();
+                    // Only the first chunk of the payload is read by the partial handler
+                    let chunks = [200, 800]
+                        .iter()
+                        .map(|&len| Ok(Bytes::from(vec![0u8; len])))
+                        .collect::<Vec<_>>();
+                    *req.payload() = Payload::Stream(Box::pin(futures::stream::iter(chunks)));
+                    let res = call_service(&app, req).await;
+                    assert!(res.status().is_server_error());
+                }
+            })
+        });
+
+        assert_eq!(events.len(), 2);
+        assert_eq!(
+            events[0].tags.get("incomplete_request_body"),
+            Some(&"true".into())
+        );
+        assert_eq!(
+            events[0].extra.get("request_body_read_bytes"),
+            Some(&200.into())
+        );
+        assert_eq!(
+            events[0].extra.get("request_body_declared_bytes"),
+            Some(&1000.into())
+        );
+        assert!(!events[1].tags.contains_key("incomplete_request_body"));
+    }
+
+    /// Ensures a summary of the request is attached to captured errors.
+    #[actix_rt::test]
+    async fn test_request_summary() {
+        let capture = |send_default_pii| {
+            let options = sentry::ClientOptions {
+                send_default_pii,
+                ..Default::default()
+            };
+            let events = sentry::test::with_captured_events_options(
+                || {
+                    block_on(async {
+                        async fn failing() -> Result<String, actix_web::Error> {
+                            Err(io::Error::new(io::ErrorKind::Other, "Test Error").into())
+                        }
+
+                        let middleware = Sentry::builder()
+                            .with_hub(Hub::current())
+                            .request_summary("%a \"%r\" %s")
+                            .finish();
+                        let app = init_service(
+                            App::new()
+                                .wrap(middleware)
+                                .service(web::resource("/items").to(failing)),
+                        )
+                        .await;
+
+                        let req = TestRequest::get()
+                            .uri("/items?page=2")
+                            .peer_addr("198.51.100.1:8080".parse().unwrap())
+                            .to_request();
+                        call_service(&app, req).await;
+                    })
+                },
+                options,
+            );
+            assert_eq!(events.len(), 1);
+            events[0].extra["request_summary"]
+                .as_str()
+                .unwrap()
+                .to_owned()
+        };
+
+        assert_eq!(capture(false), "- \"GET /items?page=2 HTTP/1.1\" 500");
+        assert_eq!(
+            capture(true),
+            "198.51.100.1:8080 \"GET /items?page=2 HTTP/1.1\" 500"
+        );
+    }
+
+    /// Ensures only sampled requests carry full request data.
+    #[actix_rt::test]
+    async fn test_full_request_data_sample_rate() {
+        let events = sentry::test::with_captured_events_options(
+            || {
+                block_on(async {
+                    async fn upload(body: web::Bytes) -> HttpResponse {
+                        let error = io::Error::new(io::ErrorKind::Other, body.len().to_string());
+                        let mut res = HttpResponse::from_error(error.into());
+                        res.headers_mut().insert(
+                            header::CONTENT_TYPE,
+                            header::HeaderValue::from_static("text/plain"),
+                        );
+                        res
+                    }
+
+                    for &rate in [1.0, 0.0].iter() {
+                        let middleware = Sentry::builder()
+                            .with_hub(Hub::current())
+                            .full_request_data_sample_rate(rate)
+                            .finish();
+                        let app = init_service(
+                            App::new()
+                                .wrap(middleware)
+                                .service(web::resource("/upload").to(upload)),
+                        )
+                        .await;
+
+                        let req = TestRequest::post()
+                            .uri("/upload?id=1")
+                            .insert_header((header::CONTENT_TYPE, "application/json"))
+                            .insert_header((header::CONTENT_LENGTH, "7"))
+                            .set_payload("{\"a\":1}")
+                            .to_request();
+                        let res = call_service(&app, req).await;
+                        assert!(res.status().is_server_error());
+                    }
+                })
+            },
+            sentry::ClientOptions {
+                max_request_body_size: MaxRequestBodySize::Always,
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(events.len(), 2);
+        let full = &events[0];
+        let request = full.request.as_ref().unwrap();
+        assert_eq!(full.tags.get("request_data"), Some(&"full".into()));
+        assert_eq!(full.transaction, Some("/upload".into()));
+        assert!(request.url.is_some());
+        assert!(!request.headers.is_empty());
+        assert_eq!(request.data, Some("{\"a\":1}".into()));
+        assert!(full.extra.contains_key("request_body_bytes"));
+        assert!(full.tags.contains_key("request.content_type"));
+        assert!(full.tags.contains_key("response.content_type"));
+
+        let minimal = &events[1];
+        let request = minimal.request.as_ref().unwrap();
+        assert_eq!(minimal.tags.get("request_data"), Some(&"minimal".into()));
+        assert_eq!(minimal.transaction, Some("/upload".into()));
+        assert_eq!(minimal.extra.get("status_code"), Some(&500.into()));
+        assert_eq!(request.method, Some("POST".into()));
+        assert_eq!(request.url, None);
+        assert!(request.headers.is_empty());
+        assert!(request.env.is_empty());
+        assert_eq!(request.data, None);
+        assert!(!minimal.extra.contains_key("request_body_bytes"));
+        assert!(!minimal.tags.contains_key("request.content_type"));
+        assert!(!minimal.tags.contains_key("response.content_type"));
+    }
+
+    /// Ensures oversized events captured by the middleware are trimmed.
+    #[actix_rt::test]
+    async fn test_max_event_size() {
+        let events = sentry::test::with_captured_events_options(
+            || {
+                block_on(async {
+                    async fn upload(body: web::Bytes) -> Result<String, actix_web::Error> {
+                        Err(io::Error::new(io::ErrorKind::Other, body.len().to_string()).into())
+                    }
+
+                    let middleware = Sentry::builder()
+                        .with_hub(Hub::current())
+                        .max_event_size(5000)
+                        .finish();
+                    let app = init_service(
+                        App::new()
+                            .wrap(middleware)
+                            .service(web::resource("/upload").to(upload)),
+                    )
+                    .await;
+
+                    let req = TestRequest::post()
+                        .uri("/upload")
+                        .set_payload("x".repeat(8000))
+                        .to_request();
+                    let res = call_service(&app, req).await;
+                    assert!(res.status().is_server_error());
+                })
+            },
+            sentry::ClientOptions {
+                max_request_body_size: MaxRequestBodySize::Always,
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(events.len(), 1);
+        let request = events[0].request.as_ref().unwrap();
+        assert_eq!(request.data, None);
+        assert_eq!(events[0].tags.get("payload_trimmed"), Some(&"true".into()));
+        assert!(events[0].extra["original_event_size"].as_u64().unwrap() > 8000);
+    }
+
+    /// Ensures errors are sampled by rate, with per-status overrides.
+    #[actix_rt::test]
+    async fn test_error_sample_rate() {
+        let events = sentry::test::with_captured_events(|| {
+            block_on(async {
+                async fn unavailable() -> Result<String, actix_web::Error> {
+                    Err(actix_web::error::ErrorServiceUnavailable("Unavailable"))
+                }
+
+                async fn bad_gateway() -> Result<String, actix_web::Error> {
+                    Err(actix_web::error::ErrorBadGateway("Bad Gateway"))
+                }
+
+                let middleware = Sentry::builder()
+                    .with_hub(Hub::current())
+                    .error_sample_rate(0.0)
+                    .status_sample_rate(StatusCode::BAD_GATEWAY, 1.0)
+                    .finish();
+                let app = init_service(
+                    App::new()
+                        .wrap(middleware)
+                        .service(web::resource("/unavailable").to(unavailable))
+                        .service(web::resource("/bad-gateway").to(bad_gateway)),
+                )
+                .await;
+
+                for uri in &["/unavailable", "/bad-gateway"] {
+                    let req = TestRequest::get().uri(uri).to_request();
+                    let res = call_service(&app, req).await;
+                    assert!(res.status().is_server_error());
+                }
+            })
+        });
+
+        assert_eq!(events.len(), 1);
+        assert_eq!(
+            events[0].exception.values[0].value,
+            Some("Bad Gateway".into())
+        );
+    }
+
+    /// Ensures discarded errors are counted per reason.
+    #[actix_rt::test]
+    async fn test_discard_stats() {
+        let sampled = Sentry::builder()
+            .error_sample_rate(0.0)
+            .status_sample_rate(StatusCode::BAD_GATEWAY, 1.0)
+            .finish();
+        let disabled = Sentry::builder().capture_server_errors(false).finish();
+
+        let events = sentry::test::with_captured_events(|| {
+            block_on(async {
+                async fn unavailable() -> Result<String, actix_web::Error> {
+                    Err(actix_web::error::ErrorServiceUnavailable("Unavailable"))
+                }
+
+                async fn bad_gateway() -> Result<String, actix_web::Error> {
+                    Err(actix_web::error::ErrorBadGateway("Bad Gateway"))
+                }
+
+                async fn not_found() -> Result<String, actix_web::Error> {
+                    Err(actix_web::error::ErrorNotFound("Not Found"))
+                }
+
+                // Clones of the middleware share the counts
+                for middleware in &[&sampled, &disabled] {
+                    let middleware = (*middleware).clone().into_builder();
+                    let app = init_service(
+                        App::new()
+                            .wrap(middleware.with_hub(Hub::current()).finish())
+                            .service(web::resource("/unavailable").to(unavailable))
+                            .service(web::resource("/bad-gateway").to(bad_gateway))
+                            .service(web::resource("/not-found").to(not_found)),
+                    )
+                    .await;
+
+                    for uri in &["/unavailable", "/unavailable", "/bad-gateway", "/not-found"] {
+                        let req = TestRequest::get().uri(uri).to_request();
+                        call_service(&app, req).await;
+                    }
+                }
+            })
+        });
+
+        assert_eq!(events.len(), 1);
+        assert_eq!(
+            sampled.discard_stats(),
+            DiscardStats {
+                event_processor: 0,
+                status: 1,
+                sample_rate: 2,
+                concurrency: 0,
+                rate_limited: 0,
+            }
+        );
+        assert_eq!(
+            disabled.discard_stats(),
+            DiscardStats {
+                event_processor: 4,
+                status: 0,
+                sample_rate: 0,
+                concurrency: 0,
+                rate_limited: 0,
+            }
+        );
+    }
+
+    /// Ensures static tags and extras from the builder are applied to all events.
+    #[actix_rt::test]
+    async fn test_static_tags_and_extras() {
+        let events = sentry::test::with_captured_events(|| {
+            block_on(async {
+                let message = || {
+                    sentry::configure_scope(|scope| scope.set_tag("service", "overridden"));
+                    sentry::capture_message("Message", Level::Warning);
+                    HttpResponse::Ok()
+                };
+
+                #[get("/fail")]
+                async fn failing(_req: HttpRequest) -> Result<String, actix_web::Error> {
+                    Err(io::Error::new(io::ErrorKind::Other, "Test Error").into())
+                }
+
+                let middleware = Sentry::builder()
+                    .with_hub(Hub::current())
+                    .tag("service", "checkout")
+                    .extra("build", 123)
+                    .finish();
+                let app = init_service(
+                    App::new()
+                        .wrap(middleware)
+                        .service(web::resource("/message").to(message))
+                        .service(failing),
+                )
+                .await;
+
+                for uri in &["/message", "/fail"] {
+                    let req = TestRequest::get().uri(uri).to_request();
+                    call_service(&app, req).await;
+                }
+            })
+        });
+
+        assert_eq!(events.len(), 2);
+        assert_eq!(
+            events[0].tags.get("service"),
+            Some(&"overridden".to_owned())
+        );
+        assert_eq!(events[1].tags.get("service"), Some(&"checkout".to_owned()));
+        for event in events {
+            assert_eq!(event.extra.get("build"), Some(&123.into()));
+        }
+    }
+
+    /// Ensures errors with generic messages are tagged and enriched.
+    #[actix_rt::test]
+    async fn test_generic_errors() {
+        let events = sentry::test::with_captured_events(|| {
+            block_on(async {
+                async fn empty() -> Result<String, actix_web::Error> {
+                    Err(actix_web::error::ErrorInternalServerError(""))
+                }
+
+                async fn generic() -> Result<String, actix_web::Error> {
+                    let err = io::Error::new(io::ErrorKind::Other, "Internal Server Error");
+                    Err(err.into())
+                }
+
+                async fn specific() -> Result<String, actix_web::Error> {
+                    Err(actix_web::error::ErrorInternalServerError("Disk full"))
+                }
+
+                let app = init_service(
+                    App::new()
+                        .wrap(Sentry::builder().with_hub(Hub::current()).finish())
+                        .service(web::resource("/empty").to(empty))
+                        .service(web::resource("/generic").to(generic))
+                        .service(web::resource("/specific").to(specific)),
+                )
+                .await;
+
+                for uri in &["/empty", "/generic", "/specific"] {
+                    let req = TestRequest::get().uri(uri).to_request();
+                    call_service(&app, req).await;
+                }
+            })
+        });
+
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0].tags.get("generic_error"), Some(&"true".into()));
+        assert_eq!(events[1].tags.get("generic_error"), Some(&"true".into()));
+        assert_eq!(
+            events[1].extra.get("error_debug"),
+            Some(&"Custom { kind: Other, error: \"Internal Server Error\" }".into())
+        );
+        assert!(!events[2].tags.contains_key("generic_error"));
+    }
+
+    /// Ensures the peer address and the forwarded client IP are recorded separately.
+    #[actix_rt::test]
+    async fn test_forwarded_client_ip() {
+        let events = sentry::test::with_captured_events_options(
+            || {
+                block_on(async {
+                    #[get("/test")]
+                    async fn failing(_req: HttpRequest) -> Result<String, actix_web::Error> {
+                        Err(io::Error::new(io::ErrorKind::Other, "Test Error").into())
+                    }
+
+                    let middleware = Sentry::builder().with_hub(Hub::current()).finish();
+                    let app = init_service(App::new().wrap(middleware).service(failing)).await;
+
+                    let req = TestRequest::get()
+                        .uri("/test")
+                        .peer_addr("10.0.0.1:4321".parse().unwrap())
+                        .insert_header(("x-forwarded-for", "203.0.113.7, 10.0.0.1"))
+                        .to_request();
+                    call_service(&app, req).await;
+                })
+            },
+            sentry::ClientOptions {
+                send_default_pii: true,
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(events.len(), 1);
+        let event = &events[0];
+        let request = event.request.as_ref().expect("Request should be set.");
+        assert_eq!(
+            request.env.get("REMOTE_ADDR"),
+            Some(&"10.0.0.1:4321".into())
+        );
+        assert_eq!(request.env.get("CLIENT_IP"), Some(&"203.0.113.7".into()));
+        let user = event.user.as_ref().expect("User should be set.");
+        assert_eq!(
+            user.ip_address,
+            Some(IpAddress::Exact("203.0.113.7".parse().unwrap()))
+        );
+    }
+
+    /// Ensures truncated addresses are recorded in the request data and the user.
+    #[actix_rt::test]
+    async fn test_truncated_ip_policy() {
+        let events = sentry::test::with_captured_events_options(
+            || {
+                block_on(async {
+                    #[get("/test")]
+                    async fn failing(_req: HttpRequest) -> Result<String, actix_web::Error> {
+                        Err(io::Error::new(io::ErrorKind::Other, "Test Error").into())
+                    }
+
+                    let middleware = Sentry::builder()
+                        .with_hub(Hub::current())
+                        .ip_policy(IpPolicy::Truncated)
+                        .finish();
+                    let app = init_service(App::new().wrap(middleware).service(failing)).await;
+
+                    let req = TestRequest::get()
+                        .uri("/test")
+                        .peer_addr("10.0.0.1:4321".parse().unwrap())
+                        .insert_header(("x-forwarded-for", "[2001:db8:85a3:8d3::1]:443"))
+                        .to_request();
+                    call_service(&app, req).await;
+                })
+            },
+            sentry::ClientOptions {
+                send_default_pii: true,
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(events.len(), 1);
+        let event = &events[0];
+        let request = event.request.as_ref().expect("Request should be set.");
+        assert_eq!(request.env.get("REMOTE_ADDR"), Some(&"10.0.0.0".into()));
+        assert_eq!(
+            request.env.get("CLIENT_IP"),
+            Some(&"2001:db8:85a3::".into())
+        );
+        let user = event.user.as_ref().expect("User should be set.");
+        assert_eq!(
+            user.ip_address,
+            Some(IpAddress::Exact("2001:db8:85a3::".parse().unwrap()))
+        );
+    }
+
+    #[derive(Debug)]
+    struct StreamingError;
+
+    impl fmt::Display for StreamingError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("Streaming Error")
+        }
+    }
+
+    impl ResponseError for StreamingError {
+        fn error_response(&self) -> HttpResponse {
+            let chunk = || Ok::<_, actix_web::Error>(Bytes::from_static(b"Streaming "));
+            let chunks = futures::stream::iter(vec![chunk(), chunk()]);
+            HttpResponse::InternalServerError().streaming(chunks)
+        }
+    }
+
+    #[derive(Debug)]
+    struct OutOfStock;
+
+    impl fmt::Display for OutOfStock {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("Out Of Stock")
+        }
+    }
+
+    impl ResponseError for OutOfStock {}
+
+    impl SentryClassify for OutOfStock {
+        fn level(&self) -> Level {
+            Level::Info
+        }
+
+        fn tags(&self) -> BTreeMap<String, String> {
+            let mut tags = BTreeMap::new();
+            tags.insert("expected".into(), "true".into());
+            tags
+        }
+    }
+
+    /// Ensures registered error types are captured with their classification.
+    #[actix_rt::test]
+    async fn test_classify_error() {
+        let events = sentry::test::with_captured_events(|| {
+            block_on(async {
+                async fn out_of_stock() -> Result<String, actix_web::Error> {
+                    Err(OutOfStock.into())
+                }
+
+                async fn wrapped() -> Result<String, actix_web::Error> {
+                    Err(SentryError::new(OutOfStock).tag("domain", "orders").into())
+                }
+
+                async fn failing() -> Result<String, actix_web::Error> {
+                    Err(io::Error::new(io::ErrorKind::Other, "Test Error").into())
+                }
+
+                let middleware = Sentry::builder()
+                    .with_hub(Hub::current())
+                    .classify_error::<OutOfStock>()
+                    .finish();
+                let app = init_service(
+                    App::new()
+                        .wrap(middleware)
+                        .service(web::resource("/out-of-stock").to(out_of_stock))
+                        .service(web::resource("/wrapped").to(wrapped))
+                        .service(web::resource("/failing").to(failing)),
+                )
+                .await;
+
+                for uri in &["/out-of-stock", "/wrapped", "/failing"] {
+                    let req = TestRequest::get().uri(uri).to_request();
+                    let res = call_service(&app, req).await;
+                    assert!(res.status().is_server_error());
+                }
+            })
+        });
+
+        assert_eq!(events.len(), 3);
+        for event in &events[..2] {
+            assert_eq!(event.level, Level::Info);
+            assert_eq!(event.tags["expected"], "true");
+        }
+        assert_eq!(events[1].tags["domain"], "orders");
+
+        // Unregistered errors are unaffected
+        assert_eq!(events[2].level, Level::Error);
+        assert!(!events[2].tags.contains_key("expected"));
+    }
+
+    /// Ensures panics of hooks are caught, counted and logged.
+    #[actix_rt::test]
+    async fn test_hook_panics() {
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let middleware = Sentry::builder()
+            .transaction_name_fn(|_| panic!("Malformed route"))
+            .flush_on_capture(|_, _| panic!("Unknown status"), Duration::from_secs(1))
+            .redactor(|target: RedactionTarget<'_>, _value: &mut String| {
+                if target == RedactionTarget::Header("x-api-key") {
+                    panic!("Unexpected key");
+                }
+            })
+            .debug_log_captures_with({
+                let lines = lines.clone();
+                move |line| lines.lock().unwrap().push(line.to_owned())
+            })
+            .finish();
+        let stats = middleware.clone();
+
+        let events = sentry::test::with_captured_events(|| {
+            block_on(async {
+                async fn failing() -> Result<String, actix_web::Error> {
+                    Err(io::Error::new(io::ErrorKind::Other, "Test Error").into())
+                }
+
+                let app = init_service(
+                    App::new()
+                        .wrap(middleware.into_builder().with_hub(Hub::current()).finish())
+                        .route("/items/{id}", web::get().to(failing)),
+                )
+                .await;
+
+                let req = TestRequest::get()
+                    .uri("/items/42")
+                    .insert_header(("x-api-key", "secret"))
+                    .to_request();
+                let res = call_service(&app, req).await;
+                assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+            })
+        });
+
+        assert_eq!(events.len(), 1);
+        let event = &events[0];
+        assert_eq!(event.transaction.as_deref(), Some("/items/{id}"));
+        let request = event.request.as_ref().expect("Request should be set.");
+        assert_eq!(request.headers["x-api-key"], FILTERED);
+
+        assert_eq!(stats.hook_panics(), 2);
+        let lines = lines.lock().unwrap();
+        assert!(lines.contains(&"transaction_name_fn panicked: Malformed route".into()));
+        assert!(lines.contains(&"flush_on_capture panicked: Unknown status".into()));
+    }
+
+    /// Ensures the middleware composes with response body types other than `Body`.
+    #[actix_rt::test]
+    async fn test_custom_body_types() {
+        let events = sentry::test::with_captured_events(|| {
+            block_on(async {
+                async fn failing() -> Result<String, actix_web::Error> {
+                    Err(io::Error::new(io::ErrorKind::Other, "Test Error").into())
+                }
+
+                for &mode in [CaptureMode::Immediate, CaptureMode::Deferred].iter() {
+                    let middleware = || {
+                        Sentry::builder()
+                            .with_hub(Hub::current())
+                            .capture_mode(mode)
+                            .finish()
+                    };
+                    // Replaces the body with a stream, which is not a `Body`
+                    let streamed = |req, srv: &_| {
+                        let fut = Service::call(srv, req);
+                        async move {
+                            let res: ServiceResponse = fut.await?;
+                            Ok(res.map_body(|_, _| {
+                                let chunk =
+                                    Ok::<_, actix_web::Error>(Bytes::from_static(b"Streamed"));
+                                ResponseBody::Body(actix_web::body::BodyStream::new(
+                                    futures::stream::iter(vec![chunk]),
+                                ))
+                            }))
+                        }
+                    };
+                    let app = init_service(
+                        App::new()
+                            .wrap_fn(streamed)
+                            .wrap(middleware())
+                            .service(web::resource("/stream").to(failing))
+                            .service(web::resource("/resource").wrap(middleware()).to(failing)),
+                    )
+                    .await;
+
+                    for &path in ["/stream", "/resource"].iter() {
+                        let req = TestRequest::get().uri(path).to_request();
+                        let res = call_service(&app, req).await;
+                        assert!(res.status().is_server_error());
+                        assert_eq!(
+                            actix_web::test::read_body(res).await,
+                            Bytes::from_static(b"Streamed")
+                        );
+                    }
+                }
+            })
+        });
+
+        assert_eq!(events.len(), 4);
+        assert_eq!(events[3].extra.get("response_body_bytes"), Some(&8.into()));
+    }
+
+    /// Ensures deferred captures of encoded responses are marked as compressed.
+    #[actix_rt::test]
+    async fn test_compressed_response() {
+        let events = sentry::test::with_captured_events(|| {
+            block_on(async {
+                async fn failing_json() -> Result<HttpResponse, actix_web::Error> {
+                    Err(actix_web::error::InternalError::from_response(
+                        "Test Error",
+                        HttpResponse::InternalServerError()
+                            .content_type("application/json")
+                            .body(r#"{"error":"Test Error"}"#),
+                    )
+                    .into())
+                }
+
+                // Sets the header like the `Compress` middleware does when encoding
+                let encoded = |req, srv: &_| {
+                    let fut = Service::call(srv, req);
+                    async move {
+                        let mut res: ServiceResponse = fut.await?;
+                        let encoding = res.request().headers().get("x-encoding").cloned();
+                        if let Some(encoding) = encoding {
+                            res.headers_mut().insert(header::CONTENT_ENCODING, encoding);
+                        }
+                        Ok(res)
+                    }
+                };
+                let app = init_service(
+                    App::new()
+                        .wrap_fn(encoded)
+                        .wrap(
+                            Sentry::builder()
+                                .with_hub(Hub::current())
+                                .capture_mode(CaptureMode::Deferred)
+                                .finish(),
+                        )
+                        .service(web::resource("/failing").to(failing_json)),
+                )
+                .await;
+
+                for encoding in &["gzip", "identity"] {
+                    let req = TestRequest::get()
+                        .uri("/failing")
+                        .insert_header(("x-encoding", *encoding))
+                        .to_request();
+                    actix_web::test::read_body(call_service(&app, req).await).await;
+                }
+            })
+        });
+
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].extra["body_compressed"], true);
+        assert_eq!(events[0].extra["content_encoding"], "gzip");
+        assert!(!events[1].extra.contains_key("body_compressed"));
+    }
+
+    /// Ensures `405` responses are captured as info events with the allowed methods.
+    #[actix_rt::test]
+    async fn test_capture_method_not_allowed() {
+        let events = sentry::test::with_captured_events(|| {
+            block_on(async {
+                let app = init_service(
+                    App::new()
+                        .wrap(
+                            Sentry::builder()
+                                .with_hub(Hub::current())
+                                .capture_method_not_allowed(true)
+                                .finish(),
+                        )
+                        .service(
+                            web::resource("/items")
+                                .route(web::get().to(HttpResponse::Ok))
+                                .default_service(web::to(|| {
+                                    HttpResponse::MethodNotAllowed()
+                                        .insert_header((header::ALLOW, "GET"))
+                                        .finish()
+                                })),
+                        )
+                        .service(web::resource("/legacy").route(web::get().to(HttpResponse::Ok)))
+                        .default_service(web::to(HttpResponse::NotFound)),
+                )
+                .await;
+
+                for (method, uri, status) in &[
+                    (Method::POST, "/items", StatusCode::METHOD_NOT_ALLOWED),
+                    (Method::GET, "/items", StatusCode::OK),
+                    (Method::DELETE, "/legacy", StatusCode::METHOD_NOT_ALLOWED),
+                    (Method::POST, "/missing", StatusCode::NOT_FOUND),
+                ] {
+                    let req = TestRequest::default()
+                        .method(method.clone())
+                        .uri(uri)
+                        .to_request();
+                    let res = call_service(&app, req).await;
+                    assert_eq!(res.status(), *status);
+                }
+            })
+        });
+
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].level, Level::Info);
+        assert_eq!(
+            events[0].message.as_deref(),
+            Some("Method POST not allowed on /items")
+        );
+        assert_eq!(events[0].tags["http.method"], "POST");
+        assert_eq!(events[0].tags["http.allowed_methods"], "GET");
+        assert_eq!(events[0].transaction.as_deref(), Some("/items"));
+
+        // Resources without a default service respond without an `Allow` header
+        assert_eq!(events[1].tags["http.method"], "DELETE");
+        assert!(!events[1].tags.contains_key("http.allowed_methods"));
+    }
+
+    /// A transport that takes much longer to flush than it should.
+    struct StallingTransport {
+        envelopes: Mutex<Vec<sentry::Envelope>>,
+        flushes: AtomicUsize,
+    }
+
+    impl sentry::Transport for StallingTransport {
+        fn send_envelope(&self, envelope: sentry::Envelope) {
+            self.envelopes.lock().unwrap().push(envelope);
+        }
+
+        fn flush(&self, _timeout: Duration) -> bool {
+            self.flushes.fetch_add(1, Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(500));
+            true
+        }
+    }
+
+    /// Ensures matching errors are flushed without waiting longer than the timeout.
+    #[actix_rt::test]
+    async fn test_flush_on_capture() {
+        async fn unavailable() -> Result<String, actix_web::Error> {
+            Err(actix_web::error::ErrorServiceUnavailable("Unavailable"))
+        }
+
+        async fn failing() -> Result<String, actix_web::Error> {
+            Err(io::Error::new(io::ErrorKind::Other, "Test Error").into())
+        }
+
+        let transport = Arc::new(StallingTransport {
+            envelopes: Mutex::new(Vec::new()),
+            flushes: AtomicUsize::new(0),
+        });
+        let client = sentry::Client::from(sentry::ClientOptions {
+            dsn: Some("https://public@sentry.invalid/1".parse().unwrap()),
+            transport: Some(Arc::new(transport.clone())),
+            ..Default::default()
+        });
+        let hub = Arc::new(Hub::new(Some(Arc::new(client)), Default::default()));
+
+        let middleware = Sentry::builder()
+            .with_hub(hub)
+            .flush_on_capture(
+                |_, status| status == StatusCode::SERVICE_UNAVAILABLE,
+                Duration::from_millis(50),
+            )
+            .finish();
+        let app = init_service(
+            App::new()
+                .wrap(middleware)
+                .service(web::resource("/unavailable").to(unavailable))
+                .service(web::resource("/failing").to(failing)),
+        )
+        .await;
+
+        // Errors that do not match are not flushed
+        let req = TestRequest::get().uri("/failing").to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(transport.flushes.load(Ordering::SeqCst), 0);
+
+        // The response completes within the timeout although the transport stalls
+        let started = Instant::now();
+        let req = TestRequest::get().uri("/unavailable").to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(started.elapsed() < Duration::from_millis(400));
+        assert_eq!(transport.flushes.load(Ordering::SeqCst), 1);
+        assert_eq!(transport.envelopes.lock().unwrap().len(), 2);
+    }
+
+    /// Ensures later events of a request show the events captured before them.
+    #[actix_rt::test]
+    async fn test_breadcrumb_captures() {
+        let capture = |breadcrumb_captures| {
+            sentry::test::with_captured_events(|| {
+                block_on(async {
+                    async fn failing() -> Result<String, actix_web::Error> {
+                        let err = io::Error::new(io::ErrorKind::Other, "Handled Error");
+                        sentry::capture_error(&err);
+                        Err(io::Error::new(io::ErrorKind::Other, "Test Error").into())
+                    }
+
+                    let middleware = Sentry::builder()
+                        .with_hub(Hub::current())
+                        .breadcrumb_captures(breadcrumb_captures)
+                        .finish();
+                    let app = init_service(
+                        App::new()
+                            .wrap(middleware)
+                            .service(web::resource("/failing").to(failing)),
+                    )
+                    .await;
+
+                    // Captures of earlier requests are not carried over
+                    for _ in 0..2 {
+                        let req = TestRequest::get().uri("/failing").to_request();
+                        call_service(&app, req).await;
+                    }
+                })
+            })
+        };
+
+        let events = capture(true);
+        assert_eq!(events.len(), 4);
+        for pair in events.chunks(2) {
+            assert!(pair[0].breadcrumbs.is_empty());
+            assert_eq!(pair[1].breadcrumbs.len(), 1);
+            let breadcrumb = &pair[1].breadcrumbs[0];
+            assert_eq!(breadcrumb.category.as_deref(), Some("sentry"));
+            assert_eq!(
+                breadcrumb.message.as_deref(),
+                Some(
+                    format!(
+                        "captured event {} for Custom: Handled Error",
+                        pair[0].event_id.to_simple()
+                    )
+                    .as_str()
+                )
+            );
+        }
+
+        let events = capture(false);
+        assert_eq!(events.len(), 4);
+        assert!(events.iter().all(|event| event.breadcrumbs.is_empty()));
+    }
+
+    /// Ensures repeated messages of a request are sent as one event with their count.
+    #[actix_rt::test]
+    async fn test_aggregate_repeated_messages() {
+        let capture = |aggregate| {
+            sentry::test::with_captured_events(|| {
+                block_on(async {
+                    async fn noisy() -> &'static str {
+                        for _ in 0..50 {
+                            sentry::capture_message("Item skipped", Level::Warning);
+                        }
+                        sentry::capture_message("Item skipped", Level::Info);
+                        for _ in 0..2 {
+                            let err = io::Error::new(io::ErrorKind::Other, "Item failed");
+                            sentry::capture_error(&err);
+                        }
+                        "done"
+                    }
+
+                    let middleware = Sentry::builder()
+                        .with_hub(Hub::current())
+                        .aggregate_repeated_messages(aggregate)
+                        .finish();
+                    let app = init_service(
+                        App::new()
+                            .wrap(middleware)
+                            .service(web::resource("/noisy").to(noisy)),
+                    )
+                    .await;
+
+                    for _ in 0..2 {
+                        let req = TestRequest::get().uri("/noisy").to_request();
+                        call_service(&app, req).await;
+                    }
+                })
+            })
+        };
+
+        let events = capture(true);
+        assert_eq!(events.len(), 8);
+        for request in events.chunks(4) {
+            // Exceptions are captured as usual
+            assert!(request[..2].iter().all(|event| !event.exception.is_empty()));
+            let repeated = &request[2];
+            assert_eq!(repeated.message.as_deref(), Some("Item skipped"));
+            assert_eq!(repeated.level, Level::Warning);
+            assert_eq!(repeated.extra["occurrences"], 50);
+            assert_eq!(repeated.transaction.as_deref(), Some("/noisy"));
+            assert!(repeated.request.is_some());
+            assert_eq!(request[3].level, Level::Info);
+            assert!(!request[3].extra.contains_key("occurrences"));
+        }
+
+        let events = capture(false);
+        assert_eq!(events.len(), 106);
+        assert!(events
+            .iter()
+            .all(|event| !event.extra.contains_key("occurrences")));
+    }
+
+    /// Ensures repeated messages are sampled once, when they are first captured.
+    #[actix_rt::test]
+    async fn test_aggregate_sampled_messages() {
+        let events = sentry::test::with_captured_events_options(
+            || {
+                block_on(async {
+                    async fn noisy() -> &'static str {
+                        for _ in 0..200 {
+                            sentry::capture_message("Item skipped", Level::Warning);
+                        }
+                        "done"
+                    }
+
+                    let middleware = Sentry::builder()
+                        .with_hub(Hub::current())
+                        .aggregate_repeated_messages(true)
+                        .finish();
+                    let app = init_service(
+                        App::new()
+                            .wrap(middleware)
+                            .service(web::resource("/noisy").to(noisy)),
+                    )
+                    .await;
+
+                    for _ in 0..10 {
+                        let req = TestRequest::get().uri("/noisy").to_request();
+                        call_service(&app, req).await;
+                    }
+                })
+            },
+            sentry::ClientOptions {
+                sample_rate: 0.5,
+                ..Default::default()
+            },
+        );
+
+        // Sampling the aggregated events again would drop about half of them
+        assert_eq!(events.len(), 10);
+        for event in events {
+            let occurrences = event.extra["occurrences"].as_u64().unwrap();
+            assert!(occurrences > 1 && occurrences < 200, "{}", occurrences);
+        }
+    }
+
+    /// Ensures features without effect are reported once, and fail strict middleware.
+    #[actix_rt::test]
+    async fn test_inert_features() {
+        async fn index() -> &'static str {
+            "ok"
+        }
+
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let hub = Arc::new(Hub::new(None, Default::default()));
+        let middleware = Sentry::builder()
+            .with_hub(hub)
+            .emit_header(true)
+            .problem_json(true)
+            .debug_log_captures_with({
+                let lines = lines.clone();
+                move |line| lines.lock().unwrap().push(line.to_owned())
+            })
+            .finish();
+        assert_eq!(
+            middleware.inert_features(),
+            [
+                "emit_header enabled but no Sentry client is bound",
+                "problem_json enabled but no Sentry client is bound",
+            ]
+        );
+        assert!(!middleware.config.inert_warned.load(Ordering::Relaxed));
+        for _ in 0..2 {
+            init_service(
+                App::new()
+                    .wrap(middleware.clone())
+                    .route("/", web::get().to(index)),
+            )
+            .await;
+        }
+        assert!(middleware.config.inert_warned.load(Ordering::Relaxed));
+        assert_eq!(
+            lines.lock().unwrap().split_off(0),
+            middleware.inert_features()
+        );
+
+        assert_eq!(middleware.check(), Ok(()));
+        let strict = middleware.into_builder().strict(true).finish();
+        let error = strict.check().unwrap_err();
+        assert!(strict.new_transform(ok_service()).await.is_err());
+        assert_eq!(
+            lines.lock().unwrap().split_off(0),
+            [format!("failed to create services: {}", error)]
+        );
+
+        // With a client, options disabling features are reported
+        let hub_with = |auto_session_tracking| {
+            let client = sentry::Client::from(sentry::ClientOptions {
+                dsn: Some("https://public@sentry.invalid/1".parse().unwrap()),
+                transport: Some(Arc::new(sentry::test::TestTransport::new())),
+                auto_session_tracking,
+                ..Default::default()
+            });
+            Arc::new(Hub::new(Some(Arc::new(client)), Default::default()))
+        };
+        let strict = |hub| {
+            Sentry::builder()
+                .with_hub(hub)
+                .emit_header(true)
+                .session_mode(Some(SessionMode::Request))
+                .strict(true)
+                .finish()
+        };
+        assert_eq!(
+            strict(hub_with(false)).inert_features(),
+            ["session_mode enabled but auto_session_tracking is disabled on the client"]
+        );
+        let middleware = strict(hub_with(true));
+        assert!(middleware.inert_features().is_empty());
+        assert_eq!(middleware.check(), Ok(()));
+        assert!(middleware.new_transform(ok_service()).await.is_ok());
+    }
+
+    /// Ensures misconfigured strict middleware fails the startup of apps without panicking.
+    #[actix_rt::test]
+    async fn test_strict_startup() {
+        let middleware = Sentry::builder()
+            .with_hub(Arc::new(Hub::new(None, Default::default())))
+            .emit_header(true)
+            .strict(true)
+            .finish();
+        assert_eq!(
+            middleware.check(),
+            Err(SentryConfigError::InertFeatures(vec![
+                "emit_header enabled but no Sentry client is bound".into()
+            ]))
+        );
+        assert_eq!(
+            middleware.check().unwrap_err().to_string(),
+            "strict middleware has features without effect: \
+             emit_header enabled but no Sentry client is bound"
+        );
+
+        let app = App::new()
+            .configure(|cfg| {
+                cfg.route("/", web::get().to(HttpResponse::Ok));
+            })
+            .wrap(middleware);
+        let service = app.into_factory().new_service(AppConfig::default()).await;
+        assert!(service.is_err());
+    }
+
+    /// Ensures services registered with `App::configure` are handled, at any level.
+    #[actix_rt::test]
+    async fn test_configure() {
+        async fn failing() -> Result<String, actix_web::Error> {
+            Err(io::Error::new(io::ErrorKind::Other, "Test Error").into())
+        }
+
+        fn api(cfg: &mut web::ServiceConfig) {
+            cfg.route("/app", web::get().to(failing)).service(
+                web::scope("/scoped")
+                    .wrap(Sentry::builder().with_hub(Hub::current()).finish())
+                    .route("", web::get().to(failing)),
+            );
+        }
+
+        let events = sentry::test::with_captured_events(|| {
+            block_on(async {
+                let app = init_service(
+                    App::new()
+                        .configure(api)
+                        .wrap(Sentry::builder().with_hub(Hub::current()).finish()),
+                )
+                .await;
+                for uri in &["/app", "/scoped"] {
+                    let req = TestRequest::get().uri(uri).to_request();
+                    let res = call_service(&app, req).await;
+                    assert!(res.status().is_server_error());
+                }
+            })
+        });
+
+        // The nested middleware leaves the request to the outermost one
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].transaction.as_deref(), Some("/app"));
+        assert_eq!(events[1].transaction.as_deref(), Some("/scoped"));
+    }
+
+    /// Ensures the breadcrumbs of a request are limited and dropped ones are counted.
+    #[actix_rt::test]
+    async fn test_max_breadcrumbs() {
+        let events = sentry::test::with_captured_events(|| {
+            block_on(async {
+                let service = |n: web::Path<usize>| {
+                    for i in 0..*n {
+                        sentry::add_breadcrumb(sentry::Breadcrumb {
+                            message: Some(format!("query {}", i)),
+                            ..Default::default()
+                        });
+                    }
+                    sentry::capture_message("Message", Level::Warning);
+                    HttpResponse::Ok()
+                };
+
+                let app = init_service(
+                    App::new()
+                        .wrap(
+                            Sentry::builder()
+                                .with_hub(Hub::current())
+                                .max_breadcrumbs(150)
+                                .finish(),
+                        )
+                        .service(web::resource("/test/{n}").to(service)),
+                )
+                .await;
+
+                for uri in &["/test/200", "/test/10"] {
+                    let req = TestRequest::get().uri(uri).to_request();
+                    let res = call_service(&app, req).await;
+                    assert!(res.status().is_success());
+                }
+            })
+        });
+
+        assert_eq!(events.len(), 2);
+        // The limit exceeds the default of the client option
+        assert_eq!(events[0].breadcrumbs.len(), 150);
+        assert_eq!(
+            events[0].breadcrumbs[0].message.as_deref(),
+            Some("query 50")
+        );
+        assert_eq!(events[0].extra["breadcrumbs_dropped"], 50);
+
+        assert_eq!(events[1].breadcrumbs.len(), 10);
+        assert!(!events[1].extra.contains_key("breadcrumbs_dropped"));
+    }
+
+    /// Ensures client options are read for every request, also after replacing the client.
+    #[actix_rt::test]
+    async fn test_replaced_client() {
+        fn client(
+            send_default_pii: bool,
+        ) -> (Arc<sentry::test::TestTransport>, Arc<sentry::Client>) {
+            let transport = sentry::test::TestTransport::new();
+            let client = sentry::Client::from(sentry::ClientOptions {
+                dsn: Some("https://public@sentry.invalid/1".parse().unwrap()),
+                transport: Some(Arc::new(transport.clone())),
+                send_default_pii,
+                ..Default::default()
+            });
+            (transport, Arc::new(client))
+        }
+
+        async fn failing() -> Result<String, actix_web::Error> {
+            Err(io::Error::new(io::ErrorKind::Other, "Test Error").into())
+        }
+
+        async fn remote_addr(middleware: Sentry, transport: &sentry::test::TestTransport) -> bool {
+            let app = init_service(
+                App::new()
+                    .wrap(middleware)
+                    .service(web::resource("/test").to(failing)),
+            )
+            .await;
+            let req = TestRequest::get()
+                .uri("/test")
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .to_request();
+            assert!(call_service(&app, req).await.status().is_server_error());
+
+            let events = transport.fetch_and_clear_events();
+            assert_eq!(events.len(), 1);
+            events[0]
+                .request
+                .as_ref()
+                .unwrap()
+                .env
+                .contains_key("REMOTE_ADDR")
+        }
+
+        // Binding a new client to the hub of the middleware
+        let (first_transport, first_client) = client(false);
+        let (second_transport, second_client) = client(true);
+        let hub = Arc::new(Hub::new(Some(first_client), Default::default()));
+        let middleware = Sentry::builder().with_hub(hub.clone()).finish();
+        assert!(!remote_addr(middleware.clone(), &first_transport).await);
+        hub.bind_client(Some(second_client));
+        assert!(remote_addr(middleware, &second_transport).await);
+
+        // Selecting a different hub
+        let (first_transport, first_client) = client(false);
+        let (second_transport, second_client) = client(true);
+        let selected = Arc::new(Mutex::new(Arc::new(Hub::new(
+            Some(first_client),
+            Default::default(),
+        ))));
+        let selected_hub = selected.clone();
+        let middleware = Sentry::builder()
+            .with_hub_fn(move || selected_hub.lock().unwrap().clone())
+            .finish();
+        assert!(!remote_addr(middleware.clone(), &first_transport).await);
+        *selected.lock().unwrap() = Arc::new(Hub::new(Some(second_client), Default::default()));
+        assert!(remote_addr(middleware, &second_transport).await);
+    }
+
+    /// Ensures a warning is captured once for requests exceeding the hang threshold.
+    #[actix_rt::test]
+    async fn test_hang_detection() {
+        tokio::time::pause();
+
+        async fn slow() -> Result<String, actix_web::Error> {
+            actix_rt::time::sleep(Duration::from_millis(150)).await;
+            Err(io::Error::new(io::ErrorKind::Other, "Test Error").into())
+        }
+
+        let transport = sentry::test::TestTransport::new();
+        let client = sentry::Client::from(sentry::ClientOptions {
+            dsn: Some("https://public@sentry.invalid/1".parse().unwrap()),
+            transport: Some(Arc::new(transport.clone())),
+            ..Default::default()
+        });
+        let hub = Arc::new(Hub::new(Some(Arc::new(client)), Default::default()));
+
+        let middleware = Sentry::builder()
+            .with_hub(hub)
+            .hang_detection(Duration::from_millis(30))
+            .finish();
+        let app = init_service(
+            App::new()
+                .wrap(middleware)
+                .service(web::resource("/slow").to(slow))
+                .service(web::resource("/fast").to(|| async { "ok" })),
+        )
+        .await;
+
+        let req = TestRequest::get().uri("/slow").to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        // Watchdogs of completed requests are cancelled
+        let req = TestRequest::get().uri("/fast").to_request();
+        let res = call_service(&app, req).await;
+        assert!(res.status().is_success());
+        actix_rt::time::sleep(Duration::from_millis(60)).await;
+
+        let events = transport.fetch_and_clear_events();
+        assert_eq!(events.len(), 2);
+
+        let watchdog = &events[0];
+        assert_eq!(watchdog.level, Level::Warning);
+        assert_eq!(
+            watchdog.message.as_deref(),
+            Some("Request exceeded 30ms without completing")
+        );
+        assert_eq!(watchdog.extra["hang_threshold_ms"], 30);
+        assert_eq!(watchdog.transaction.as_deref(), Some("/slow"));
+        let request = watchdog.request.as_ref().expect("Request should be set.");
+        assert_eq!(request.method.as_deref(), Some("GET"));
+
+        // The request completes normally afterwards
+        assert_eq!(events[1].level, Level::Error);
+        assert_eq!(
+            events[1].exception.values[0].value.as_deref(),
+            Some("Test Error")
+        );
+    }
+
+    /// Compares immediate and deferred capture for a handler with a streaming error body.
+    #[actix_rt::test]
+    async fn test_deferred_capture() {
+        let events = sentry::test::with_captured_events(|| {
+            block_on(async {
+                async fn failing() -> Result<String, actix_web::Error> {
+                    Err(StreamingError.into())
+                }
+
+                for &mode in [CaptureMode::Immediate, CaptureMode::Deferred].iter() {
+                    let middleware = Sentry::builder()
+                        .with_hub(Hub::current())
+                        .capture_mode(mode)
+                        .finish();
+                    let app = init_service(
+                        App::new()
+                            .wrap(middleware)
+                            .service(web::resource("/test").to(failing)),
+                    )
+                    .await;
+
+                    let req = TestRequest::get().uri("/test").to_request();
+                    let res = call_service(&app, req).await;
+                    assert!(res.status().is_server_error());
+                    let body = actix_web::test::read_body(res).await;
+                    assert_eq!(body.len(), 20);
+
+                    // The client disconnects before the body is written
+                    let req = TestRequest::get().uri("/test").to_request();
+                    drop(call_service(&app, req).await);
+                }
+            })
+        });
+
+        assert_eq!(events.len(), 4);
+        for event in &events[..2] {
+            assert_eq!(
+                event.exception.values[0].value,
+                Some("Streaming Error".into())
+            );
+            assert!(!event.extra.contains_key("aborted"));
+        }
+
+        let event = &events[2];
+        assert_eq!(
+            event.exception.values[0].value,
+            Some("Streaming Error".into())
+        );
+        assert_eq!(event.extra.get("status_code"), Some(&500.into()));
+        assert_eq!(event.extra.get("response_body_bytes"), Some(&20.into()));
+        assert_eq!(event.extra.get("aborted"), Some(&false.into()));
+        assert!(event.extra.contains_key("duration_ms"));
+
+        let event = &events[3];
+        assert_eq!(event.extra.get("response_body_bytes"), Some(&0.into()));
+        assert_eq!(event.extra.get("aborted"), Some(&true.into()));
+    }
+
+    #[actix_rt::test]
+    async fn test_track_session() {
+        let envelopes = sentry::test::with_captured_envelopes_options(
+            || {
+                block_on(async {
+                    #[get("/")]
+                    async fn hello() -> impl actix_web::Responder {
+                        String::from("Hello there!")
+                    }
+
+                    let middleware = Sentry::builder().with_hub(Hub::current()).finish();
+
+                    let app = init_service(App::new().wrap(middleware).service(hello)).await;
+
+                    for _ in 0..5 {
+                        let req = TestRequest::get().uri("/").to_request();
+                        call_service(&app, req).await;
+                    }
+                })
+            },
+            sentry::ClientOptions {
+                release: Some("some-release".into()),
+                session_mode: sentry::SessionMode::Request,
+                auto_session_tracking: true,
+                ..Default::default()
+            },
+        );
+        assert_eq!(envelopes.len(), 1);
+
+        let mut items = envelopes[0].items();
+        if let Some(sentry::protocol::EnvelopeItem::SessionAggregates(aggregate)) = items.next() {
+            let aggregates = &aggregate.aggregates;
+
+            assert_eq!(aggregates[0].distinct_id, None);
+            assert_eq!(aggregates[0].exited, 5);
+        } else {
+            panic!("expected session");
+        }
+        assert_eq!(items.next(), None);
+    }
+
+    /// Ensures errors of clients going away are told apart from failures of the stream.
+    #[actix_rt::test]
+    async fn test_stream_error_classification() {
+        let events = sentry::test::with_captured_events(|| {
+            block_on(async {
+                async fn events() -> HttpResponse {
+                    let chunks = futures_util::stream::iter(vec![
+                        Ok(Bytes::from_static(b"data: 1\n\n")),
+                        Err(io::Error::new(io::ErrorKind::BrokenPipe, "Broken Pipe")),
+                    ]);
+                    HttpResponse::Ok()
+                        .content_type("text/event-stream")
+                        .streaming(chunks)
+                }
+
+                let middleware = Sentry::builder()
+                    .with_hub(Hub::current())
+                    .detect_event_streams(true)
+                    .finish();
+                let app = init_service(
+                    App::new()
+                        .wrap(middleware)
+                        .service(web::resource("/events").to(events)),
+                )
+                .await;
+
+                let req = TestRequest::get().uri("/events").to_request();
+                let mut res = call_service(&app, req).await;
+                let mut body = res.take_body();
+                assert!(body.next().await.unwrap().is_ok());
+                assert!(body.next().await.unwrap().is_err());
+            })
+        });
+
+        assert_eq!(events.len(), 1);
+        let event = &events[0];
+        assert_eq!(event.level, Level::Info);
+        assert_eq!(event.tags["stream_error"], "client_cancelled");
+        assert_eq!(event.tags["http.version"], "HTTP/1.1");
+    }
+
+    /// Ensures the standard env profile describes the server and the mount point.
+    #[actix_rt::test]
+    async fn test_env_profile() {
+        let events = sentry::test::with_captured_events(|| {
+            block_on(async {
+                async fn failing() -> Result<String, actix_web::Error> {
+                    Err(io::Error::new(io::ErrorKind::Other, "Test Error").into())
+                }
+
+                let middleware = Sentry::builder()
+                    .with_hub(Hub::current())
+                    .env_profile(EnvProfile::Standard)
+                    .finish();
+                let app = init_service(
+                    App::new().service(
+                        web::scope("/api")
+                            .wrap(middleware)
+                            .service(web::resource("/items/{id}").to(failing)),
+                    ),
+                )
+                .await;
+
+                let req = TestRequest::post()
+                    .uri("/api/items/42?page=2")
+                    .insert_header((header::HOST, "example.com:8443"))
+                    .to_request();
+                let res = call_service(&app, req).await;
+                assert!(res.status().is_server_error());
+            })
+        });
+
+        assert_eq!(events.len(), 1);
+        let env: Vec<_> = events[0]
+            .request
+            .as_ref()
+            .unwrap()
+            .env
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        assert_eq!(
+            env,
+            [
+                ("HTTP_HOST", "example.com:8443"),
+                ("REQUEST_METHOD", "POST"),
+                ("SCRIPT_NAME", "/api"),
+                ("SERVER_NAME", "example.com"),
+                ("SERVER_PORT", "8443"),
+            ]
+        );
+    }
+
+    /// Ensures captured errors describe how their request was routed.
+    #[actix_rt::test]
+    async fn test_routing_context() {
+        let events = sentry::test::with_captured_events(|| {
+            block_on(async {
+                async fn failing() -> Result<String, actix_web::Error> {
+                    Err(io::Error::new(io::ErrorKind::Other, "Test Error").into())
+                }
+
+                let items = || {
+                    web::scope("/api")
+                        .service(web::resource("/items/{id}").name("item").to(failing))
+                };
+                let middleware = Sentry::builder()
+                    .with_hub(Hub::current())
+                    .routing_context(true)
+                    .finish();
+                let app = init_service(
+                    App::new()
+                        .wrap(middleware.clone())
+                        .service(items())
+                        .default_service(web::to(failing)),
+                )
+                .await;
+                for uri in &["/api/items/42?token=secret", "/missing"] {
+                    let req = TestRequest::get().uri(uri).to_request();
+                    call_service(&app, req).await;
+                }
+
+                // The middleware wraps the scope
+                let app = init_service(App::new().service(items().wrap(middleware))).await;
+                let req = TestRequest::get().uri("/api/items/42").to_request();
+                call_service(&app, req).await;
+            })
+        });
+
+        assert_eq!(events.len(), 3);
+        let routing = |event: &Event<'static>| match event.contexts.get("routing") {
+            Some(sentry_core::protocol::Context::Other(routing)) => routing.clone(),
+            context => panic!("unexpected routing context {:?}", context),
+        };
+
+        let matched = routing(&events[0]);
+        assert_eq!(matched["pattern"], "/api/items/{id}");
+        assert_eq!(matched["name"], "item");
+        assert_eq!(matched["mount_path"], "");
+        assert_eq!(matched["path"], "/api/items/42");
+        assert_eq!(matched["query"], "token=[Filtered]");
+        assert_eq!(matched["default_service"], false);
+
+        let unmatched = routing(&events[1]);
+        assert_eq!(unmatched["default_service"], true);
+        assert_eq!(unmatched["path"], "/missing");
+        assert!(!unmatched.contains_key("pattern"));
+        assert!(!unmatched.contains_key("query"));
+
+        let scoped = routing(&events[2]);
+        assert_eq!(scoped["pattern"], "/api/items/{id}");
+        assert_eq!(scoped["mount_path"], "/api");
+    }
+
+    /// Ensures errors of event streams are captured while they stream.
+    #[actix_rt::test]
+    async fn test_detect_event_streams() {
+        let envelopes = sentry::test::with_captured_envelopes_options(
+            || {
+                block_on(async {
+                    async fn events() -> HttpResponse {
+                        let chunks = futures_util::stream::iter(vec![
+                            Ok(Bytes::from_static(b"data: 1\n\n")),
+                            Ok(Bytes::from_static(b"data: 2\n\n")),
+                            Err(actix_web::error::ErrorInternalServerError("Stream Broke")),
+                        ]);
+                        HttpResponse::Ok()
+                            .content_type("text/event-stream")
+                            .streaming(chunks)
+                    }
+
+                    let clock = ManualClock::new();
+                    let middleware = Sentry::builder()
+                        .with_hub(Hub::current())
+                        .with_clock(clock.clone())
+                        .capture_mode(CaptureMode::Deferred)
+                        .detect_event_streams(true)
+                        .finish();
+                    let app = init_service(
+                        App::new()
+                            .wrap(middleware)
+                            .service(web::resource("/events").to(events)),
+                    )
+                    .await;
+
+                    let req = TestRequest::get().uri("/events").to_request();
+                    let mut res = call_service(&app, req).await;
+                    assert!(res.status().is_success());
+                    let mut body = res.take_body();
+                    for _ in 0..2 {
+                        assert!(body.next().await.unwrap().is_ok());
+                    }
+                    clock.advance(Duration::from_millis(20));
+                    assert!(body.next().await.unwrap().is_err());
+                })
+            },
+            sentry::ClientOptions {
+                release: Some("some-release".into()),
+                session_mode: sentry::SessionMode::Request,
+                auto_session_tracking: true,
+                ..Default::default()
+            },
+        );
+        assert_eq!(envelopes.len(), 2);
+
+        let event = envelopes[0].event().unwrap();
+        assert_eq!(
+            event.exception.values[0].value.as_deref(),
+            Some("Stream Broke")
+        );
+        assert_eq!(event.tags["long_lived_response"], "true");
+        assert_eq!(event.tags["sentry_actix.capture_source"], "stream_error");
+        assert_eq!(event.transaction.as_deref(), Some("/events"));
+        assert_eq!(event.extra["duration_ms"], 20);
+        assert_eq!(event.tags["stream_error"], "write_failed");
+        assert_eq!(event.level, Level::Error);
+
+        // The session ended with the headers, before the stream failed
+        let mut items = envelopes[1].items();
+        if let Some(sentry::protocol::EnvelopeItem::SessionAggregates(aggregate)) = items.next() {
+            let aggregates = &aggregate.aggregates;
+            assert_eq!(aggregates[0].exited, 1);
+            assert_eq!(aggregates[0].errored, 0);
+        } else {
+            panic!("expected session");
+        }
+    }
+
+    /// Ensures the session mode of the middleware overrides the one of the client.
+    #[actix_rt::test]
+    async fn test_session_mode_override() {
+        let capture = |client_mode, session_mode| {
+            sentry::test::with_captured_envelopes_options(
+                || {
+                    block_on(async {
+                        let middleware = Sentry::builder()
+                            .with_hub(Hub::current())
+                            .session_mode(session_mode)
+                            .finish();
+                        let app = init_service(
+                            App::new()
+                                .wrap(middleware)
+                                .service(web::resource("/").to(|| async { "Hello there!" })),
+                        )
+                        .await;
+
+                        for _ in 0..2 {
+                            let req = TestRequest::get().uri("/").to_request();
+                            call_service(&app, req).await;
+                        }
+                    })
+                },
+                sentry::ClientOptions {
+                    release: Some("some-release".into()),
+                    session_mode: client_mode,
+                    auto_session_tracking: true,
+                    ..Default::default()
+                },
+            )
+        };
+
+        // Sessions of applications are sent individually instead of aggregated
+        let envelopes = capture(SessionMode::Application, Some(SessionMode::Request));
+        let sessions: Vec<_> = envelopes
+            .iter()
+            .flat_map(|envelope| envelope.items())
+            .map(|item| match item {
+                sentry::protocol::EnvelopeItem::SessionUpdate(session) => session,
+                _ => panic!("expected session"),
+            })
+            .collect();
+        assert_eq!(sessions.len(), 2);
+        assert_ne!(sessions[0].session_id, sessions[1].session_id);
+        assert!(sessions
+            .iter()
+            .all(|session| session.status == sentry::protocol::SessionStatus::Exited));
+
+        for session_mode in &[Some(SessionMode::Application), None] {
+            let envelopes = capture(SessionMode::Request, *session_mode);
+            assert!(envelopes.is_empty());
+        }
+    }
+
+    /// Ensures paths rewritten by inner middlewares are recorded with the original ones.
+    #[actix_rt::test]
+    async fn test_rewritten_path() {
+        let capture = |request_url| {
+            sentry::test::with_captured_events(|| {
+                block_on(async {
+                    async fn failing() -> Result<String, actix_web::Error> {
+                        Err(io::Error::new(io::ErrorKind::Other, "Test Error").into())
+                    }
+
+                    let middleware = Sentry::builder()
+                        .with_hub(Hub::current())
+                        .request_url(request_url)
+                        .finish();
+                    let app = init_service(
+                        App::new()
+                            .wrap(NormalizePath::new(TrailingSlash::MergeOnly))
+                            .wrap(middleware)
+                            .service(web::resource("/items/{id}").to(failing)),
+                    )
+                    .await;
+
+                    for uri in &["//items//7?page=2", "/items/8"] {
+                        let req = TestRequest::get().uri(uri).to_request();
+                        let res = call_service(&app, req).await;
+                        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+                    }
+                })
+            })
+        };
+
+        let events = capture(RequestUrl::Effective);
+        assert_eq!(events.len(), 2);
+        let request = events[0].request.as_ref().unwrap();
+        assert_eq!(
+            request.url.as_ref().unwrap().as_str(),
+            "http://localhost:8080/items/7?page=2"
+        );
+        assert_eq!(request.env["ORIGINAL_PATH"], "//items//7");
+        assert!(!request.env.contains_key("EFFECTIVE_PATH"));
+        let request = events[1].request.as_ref().unwrap();
+        assert_eq!(request.url.as_ref().unwrap().path(), "/items/8");
+        assert!(!request.env.contains_key("ORIGINAL_PATH"));
+
+        let events = capture(RequestUrl::Original);
+        let request = events[0].request.as_ref().unwrap();
+        assert_eq!(request.url.as_ref().unwrap().path(), "//items//7");
+        assert_eq!(request.env["EFFECTIVE_PATH"], "/items/7");
+        assert!(!request.env.contains_key("ORIGINAL_PATH"));
+    }
+
+    /// Ensures clones share the configuration and state of the middleware, and
+    /// rebuilt middleware shares its state.
+    #[test]
+    fn test_clones_share_state() {
+        let sentry = Sentry::builder()
+            .tag("service", "orders")
+            .extra("region", "eu")
+            .status_sample_rate(StatusCode::BAD_GATEWAY, 0.5)
+            .generic_error_messages(vec!["Oops"])
+            .classify_error::<OutOfStock>()
+            .redactor(|_: RedactionTarget<'_>, _: &mut String| {})
+            .request_summary("%r %s")
+            .logger("orders")
+            .graphql("/graphql")
+            .emit_event_link(Some(EventLink::Url {
+                organization: "acme".into(),
+            }))
+            .diagnose_opaque_errors(true)
+            .route_session_stats(10)
+            .finish();
+        assert!(sentry.config.request_summary_format.is_none());
+        assert!(Arc::ptr_eq(&sentry.config, &sentry.clone().config));
+
+        // Middleware rebuilt from a clone copies the configuration without its contents
+        let (a, b) = (sentry.clone(), sentry.clone().into_builder().finish());
+        assert!(!Arc::ptr_eq(&a.config, &b.config));
+
+        assert!(Arc::ptr_eq(&a.config.tags, &b.config.tags));
+        assert!(Arc::ptr_eq(&a.config.extra, &b.config.extra));
+        assert!(Arc::ptr_eq(
+            &a.config.status_sample_rates,
+            &b.config.status_sample_rates
+        ));
+        assert!(Arc::ptr_eq(
+            &a.config.generic_error_messages,
+            &b.config.generic_error_messages
+        ));
+        assert!(Arc::ptr_eq(&a.config.classifiers, &b.config.classifiers));
+        assert!(Arc::ptr_eq(&a.config.error_types, &b.config.error_types));
+        assert!(Arc::ptr_eq(&a.config.redactors, &b.config.redactors));
+        assert!(Arc::ptr_eq(
+            a.config.request_summary.as_ref().unwrap(),
+            b.config.request_summary.as_ref().unwrap()
+        ));
+        assert!(Arc::ptr_eq(
+            a.config.opaque_routes.as_ref().unwrap(),
+            b.config.opaque_routes.as_ref().unwrap()
+        ));
+        assert!(Arc::ptr_eq(
+            a.config.route_sessions.as_ref().unwrap(),
+            b.config.route_sessions.as_ref().unwrap()
+        ));
+        assert!(Arc::ptr_eq(&a.config.logger, &b.config.logger));
+        assert!(Arc::ptr_eq(
+            a.config.graphql_path.as_ref().unwrap(),
+            b.config.graphql_path.as_ref().unwrap()
+        ));
+        assert!(Arc::ptr_eq(
+            a.config.event_link.as_ref().unwrap(),
+            b.config.event_link.as_ref().unwrap()
+        ));
+        assert!(Arc::ptr_eq(&a.config.discards, &b.config.discards));
+        assert!(Arc::ptr_eq(&a.config.connections, &b.config.connections));
+        assert!(Arc::ptr_eq(&a.config.last_capture, &b.config.last_capture));
+    }
+
+    /// Ensures session outcomes are counted by route, with routes beyond the limit bucketed.
+    #[actix_rt::test]
+    async fn test_route_session_stats() {
+        let middleware = Sentry::builder().route_session_stats(2).finish();
+        let stats = middleware.clone();
+        sentry::test::with_captured_envelopes_options(
+            || {
+                block_on(async {
+                    async fn failing() -> Result<String, actix_web::Error> {
+                        Err(io::Error::new(io::ErrorKind::Other, "Test Error").into())
+                    }
+
+                    async fn crashed() -> &'static str {
+                        let err = io::Error::new(io::ErrorKind::Other, "Worker crashed");
+                        let mut event = sentry::event_from_error(&err);
+                        event.exception.values[0].mechanism = Some(Mechanism {
+                            ty: "worker".into(),
+                            handled: Some(false),
+                            ..Default::default()
+                        });
+                        sentry::capture_event(event);
+                        "done"
+                    }
+
+                    let app = init_service(
+                        App::new()
+                            .wrap(middleware.into_builder().with_hub(Hub::current()).finish())
+                            .service(web::resource("/").to(|| async { "Hello there!" }))
+                            .service(web::resource("/failing").to(failing))
+                            .service(web::resource("/crashed").to(crashed)),
+                    )
+                    .await;
+
+                    for uri in &["/", "/failing", "/", "/crashed", "/failing", "/"] {
+                        let req = TestRequest::get().uri(uri).to_request();
+                        call_service(&app, req).await;
+                    }
+                })
+            },
+            sentry::ClientOptions {
+                release: Some("some-release".into()),
+                session_mode: sentry::SessionMode::Request,
+                auto_session_tracking: true,
+                ..Default::default()
+            },
+        );
+
+        let counts = |ok, errored, crashed| SessionCounts {
+            ok,
+            errored,
+            crashed,
+        };
+        let stats: Vec<_> = stats.route_session_stats().into_iter().collect();
+        assert_eq!(
+            stats,
+            [
+                ("/".to_owned(), counts(3, 0, 0)),
+                ("/failing".to_owned(), counts(0, 2, 0)),
+                ("other".to_owned(), counts(0, 0, 1)),
+            ]
+        );
+
+        // Without sessions, nothing is counted
+        let middleware = Sentry::builder()
+            .with_hub(Hub::current())
+            .route_session_stats(2)
+            .finish();
+        let app = init_service(
+            App::new()
+                .wrap(middleware.clone())
+                .service(web::resource("/").to(|| async { "Hello there!" })),
+        )
+        .await;
+        call_service(&app, TestRequest::get().uri("/").to_request()).await;
+        assert!(middleware.route_session_stats().is_empty());
+    }
+
+    /// Ensures HEAD requests can be excluded from sessions while their errors are captured.
+    #[actix_rt::test]
+    async fn test_count_head_as_session() {
+        let envelopes = sentry::test::with_captured_envelopes_options(
+            || {
+                block_on(async {
+                    async fn failing() -> Result<String, actix_web::Error> {
+                        Err(io::Error::new(io::ErrorKind::Other, "Test Error").into())
+                    }
+
+                    let middleware = Sentry::builder()
+                        .with_hub(Hub::current())
+                        .count_head_as_session(false)
+                        .finish();
+                    let app = init_service(
+                        App::new()
+                            .wrap(middleware)
+                            .service(web::resource("/").to(|| async { "Hello there!" }))
+                            .service(web::resource("/failing").to(failing)),
+                    )
+                    .await;
+
+                    for (method, uri) in &[
+                        (Method::GET, "/"),
+                        (Method::HEAD, "/"),
+                        (Method::HEAD, "/"),
+                        (Method::GET, "/"),
+                        (Method::HEAD, "/failing"),
+                    ] {
+                        let req = TestRequest::default()
+                            .method(method.clone())
+                            .uri(uri)
+                            .to_request();
+                        call_service(&app, req).await;
+                    }
+                })
+            },
+            sentry::ClientOptions {
+                release: Some("some-release".into()),
+                session_mode: sentry::SessionMode::Request,
+                auto_session_tracking: true,
+                ..Default::default()
+            },
+        );
+
+        let mut exited = 0;
+        let mut events = 0;
+        for item in envelopes.iter().flat_map(|envelope| envelope.items()) {
+            match item {
+                sentry::protocol::EnvelopeItem::SessionAggregates(aggregate) => {
+                    exited += aggregate
+                        .aggregates
+                        .iter()
+                        .map(|aggregate| aggregate.exited)
+                        .sum::<u32>();
+                }
+                sentry::protocol::EnvelopeItem::Event(_) => events += 1,
+                _ => panic!("unexpected item"),
+            }
+        }
+        assert_eq!(exited, 2);
+        assert_eq!(events, 1);
+    }
+
+    /// Ensures unmatched requests can be excluded from sessions while their errors are captured.
+    #[actix_rt::test]
+    async fn test_sessions_require_route_match() {
+        let envelopes = sentry::test::with_captured_envelopes_options(
+            || {
+                block_on(async {
+                    async fn failing() -> Result<String, actix_web::Error> {
+                        Err(io::Error::new(io::ErrorKind::Other, "Test Error").into())
+                    }
+
+                    let middleware = Sentry::builder()
+                        .with_hub(Hub::current())
+                        .sessions_require_route_match(true)
+                        .finish();
+                    let app = init_service(
+                        App::new()
+                            .wrap(middleware)
+                            .route("/items/{id}", web::get().to(|| async { "Item" }))
+                            .default_service(web::to(failing)),
+                    )
+                    .await;
+
+                    for uri in &[
+                        "/wp-admin.php",
+                        "/items/1",
+                        "/.env",
+                        "/items/2",
+                        "/phpmyadmin/",
+                    ] {
+                        let req = TestRequest::get().uri(uri).to_request();
+                        call_service(&app, req).await;
+                    }
+                })
+            },
+            sentry::ClientOptions {
+                release: Some("some-release".into()),
+                session_mode: sentry::SessionMode::Request,
+                auto_session_tracking: true,
+                ..Default::default()
+            },
+        );
+
+        let mut exited = 0;
+        let mut events = 0;
+        for item in envelopes.iter().flat_map(|envelope| envelope.items()) {
+            match item {
+                sentry::protocol::EnvelopeItem::SessionAggregates(aggregate) => {
+                    exited += aggregate
+                        .aggregates
+                        .iter()
+                        .map(|aggregate| aggregate.exited)
+                        .sum::<u32>();
+                }
+                sentry::protocol::EnvelopeItem::Event(_) => events += 1,
+                _ => panic!("unexpected item"),
+            }
+        }
+        assert_eq!(exited, 2);
+        assert_eq!(events, 3);
+    }
+
+    /// Ensures events carry how long the service was recently unready.
+    #[actix_rt::test]
+    async fn test_service_unready() {
+        let events = sentry::test::with_captured_events(|| {
+            block_on(async {
+                async fn failing() -> Result<String, actix_web::Error> {
+                    Err(io::Error::new(io::ErrorKind::Other, "Test Error").into())
+                }
+
+                let sentry = Sentry::builder().with_hub(Hub::current()).finish();
+                let app = init_service(
+                    App::new()
+                        .wrap(sentry.clone())
+                        .service(web::resource("/failing").to(failing)),
+                )
+                .await;
+
+                let req = TestRequest::get().uri("/failing").to_request();
+                call_service(&app, req).await;
+
+                let now = Instant::now();
+                sentry.config.backpressure.record_pending(now);
+                sentry
+                    .config
+                    .backpressure
+                    .record_ready(now + Duration::from_millis(250));
+                let req = TestRequest::get().uri("/failing").to_request();
+                call_service(&app, req).await;
+            })
+        });
+
+        assert_eq!(events.len(), 2);
+        assert!(!events[0].extra.contains_key("service_unready_ms"));
+        assert_eq!(events[1].extra["service_unready_ms"], 250);
+    }
+
+    /// Ensures request scopes start from the base scope, with request values winning.
+    #[actix_rt::test]
+    async fn test_base_scope() {
+        let events = sentry::test::with_captured_events_options(
+            || {
+                block_on(async {
+                    async fn failing() -> Result<String, actix_web::Error> {
+                        Err(io::Error::new(io::ErrorKind::Other, "Test Error").into())
+                    }
+
+                    async fn with_user() -> Result<String, actix_web::Error> {
+                        Hub::current().configure_scope(|scope| {
+                            scope.set_user(Some(sentry::User {
+                                id: Some("7".into()),
+                                ..Default::default()
+                            }))
+                        });
+                        failing().await
+                    }
+
+                    Hub::current().configure_scope(|scope| scope.set_tag("parent", "yes"));
+                    let mut base = Scope::default();
+                    base.set_tag("env", "base");
+                    base.set_tag("shared", "base");
+                    base.set_extra("origin", "base".into());
+                    base.set_extra("shared", "base".into());
+                    base.set_user(Some(sentry::User {
+                        id: Some("42".into()),
+                        ..Default::default()
+                    }));
+                    let app = init_service(
+                        App::new()
+                            .wrap(
+                                Sentry::builder()
+                                    .with_hub(Hub::current())
+                                    .with_base_scope(base)
+                                    .tag("shared", "middleware")
+                                    .extra("shared", "middleware")
+                                    .finish(),
+                            )
+                            .service(web::resource("/failing").to(failing))
+                            .service(web::resource("/user").to(with_user)),
+                    )
+                    .await;
+
+                    for uri in &["/failing", "/user"] {
+                        let req = TestRequest::get()
+                            .uri(uri)
+                            .peer_addr("198.51.100.1:8080".parse().unwrap())
+                            .to_request();
+                        call_service(&app, req).await;
+                    }
+                })
+            },
+            sentry::ClientOptions {
+                send_default_pii: true,
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(events.len(), 2);
+        let event = &events[0];
+        // The base scope replaces the scope of the hub
+        assert!(!event.tags.contains_key("parent"));
+        assert_eq!(event.tags["env"], "base");
+        assert_eq!(event.tags["shared"], "middleware");
+        assert_eq!(event.extra["origin"], "base");
+        assert_eq!(event.extra["shared"], "middleware");
+        assert_eq!(event.transaction.as_deref(), Some("/failing"));
+        let user = event.user.as_ref().unwrap();
+        assert_eq!(user.id.as_deref(), Some("42"));
+        assert_eq!(user.ip_address, Some("198.51.100.1".parse().unwrap()));
+
+        assert_eq!(events[1].user.as_ref().unwrap().id.as_deref(), Some("7"));
+    }
+
+    /// Ensures errors beyond the concurrency limit are discarded and counted.
+    #[actix_rt::test]
+    async fn test_max_concurrent_captures() {
+        let middleware = Sentry::builder()
+            .capture_mode(CaptureMode::Deferred)
+            .max_concurrent_captures(1)
+            .finish();
+        let events = sentry::test::with_captured_events(|| {
+            block_on(async {
+                async fn failing() -> Result<String, actix_web::Error> {
+                    Err(io::Error::new(io::ErrorKind::Other, "Test Error").into())
+                }
+
+                let app = init_service(
+                    App::new()
+                        .wrap(
+                            middleware
+                                .clone()
+                                .into_builder()
+                                .with_hub(Hub::current())
+                                .finish(),
+                        )
+                        .service(web::resource("/failing").to(failing)),
+                )
+                .await;
+
+                // Deferred captures are in progress until their response is read
+                let mut pending = Vec::new();
+                for _ in 0..3 {
+                    let req = TestRequest::get().uri("/failing").to_request();
+                    pending.push(call_service(&app, req).await);
+                }
+                for res in pending {
+                    actix_web::test::read_body(res).await;
+                }
+
+                let req = TestRequest::get().uri("/failing").to_request();
+                actix_web::test::read_body(call_service(&app, req).await).await;
+            })
+        });
+
+        assert_eq!(events.len(), 2);
+        assert_eq!(middleware.discard_stats().concurrency, 2);
+    }
+
+    /// Ensures errors swallowed by inner middlewares are captured by their own status.
+    #[actix_rt::test]
+    async fn test_swallowed_errors() {
+        fn authenticate(req: &ServiceRequest) -> Result<(), actix_web::Error> {
+            match req
+                .headers()
+                .get("authorization")
+                .map(|value| value.as_bytes())
+            {
+                Some(b"Bearer valid") => Ok(()),
+                Some(b"Bearer revoked") => Err(actix_web::error::ErrorForbidden("Token revoked")),
+                _ => Err(actix_web::error::ErrorBadGateway(
+                    "Identity provider unavailable",
+                )),
+            }
+        }
+
+        let events = sentry::test::with_captured_events(|| {
+            block_on(async {
+                let app = init_service(
+                    App::new()
+                        .wrap_fn(|req, srv| match authenticate(&req) {
+                            Ok(()) => Either::Left(srv.call(req)),
+                            Err(e) => {
+                                let mut res =
+                                    req.into_response(HttpResponse::Unauthorized().finish());
+                                res.response_mut().extensions_mut().insert(OriginalError(e));
+                                Either::Right(ok(res))
+                            }
+                        })
+                        .wrap(
+                            Sentry::builder()
+                                .with_hub(Hub::current())
+                                .emit_header(true)
+                                .finish(),
+                        )
+                        .service(web::resource("/account").to(HttpResponse::Ok)),
+                )
+                .await;
+
+                for (token, status) in &[
+                    ("Bearer valid", StatusCode::OK),
+                    ("Bearer revoked", StatusCode::UNAUTHORIZED),
+                    ("Bearer unknown", StatusCode::UNAUTHORIZED),
+                ] {
+                    let req = TestRequest::get()
+                        .uri("/account")
+                        .insert_header(("authorization", *token))
+                        .to_request();
+                    let res = call_service(&app, req).await;
+                    assert_eq!(res.status(), *status);
+                    assert_eq!(
+                        res.headers().contains_key("x-sentry-event"),
+                        *token == "Bearer unknown"
+                    );
+                }
+            })
+        });
+
+        assert_eq!(events.len(), 1);
+        let event = &events[0];
+        assert_eq!(
+            event.exception.values[0].value.as_deref(),
+            Some("Identity provider unavailable")
+        );
+        assert_eq!(event.tags["swallowed_by_middleware"], "true");
+        assert_eq!(event.tags["sentry_actix.capture_source"], "swallowed_error");
+        assert_eq!(event.tags["http.status_code"], "502");
+    }
+
+    /// A service with futures borrowing from the request handler.
+    struct Lookup<'a> {
+        accounts: &'a [&'a str],
+    }
+
+    impl<'a> Service<ServiceRequest> for Lookup<'a> {
+        type Response = ServiceResponse;
+        type Error = actix_web::Error;
+        type Future = LocalBoxFuture<'a, Result<Self::Response, Self::Error>>;
+
+        fn poll_ready(&self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&self, req: ServiceRequest) -> Self::Future {
+            let accounts = self.accounts;
+            async move {
+                let name = req.match_info().query("name").to_owned();
+                match accounts.iter().find(|account| **account == name) {
+                    Some(account) => {
+                        Ok(req.into_response(HttpResponse::Ok().body((*account).to_owned())))
+                    }
+                    None => Err(actix_web::error::ErrorInternalServerError(
+                        "Account store out of sync",
+                    )),
+                }
+            }
+            .boxed_local()
+        }
+    }
+
+    /// Ensures services with non-`'static` futures can be handled with `wrap_fn`.
+    #[actix_rt::test]
+    async fn test_wrap_fn() {
+        let events = sentry::test::with_captured_events(|| {
+            block_on(async {
+                let accounts = [String::from("alice")];
+                let accounts: Vec<&str> = accounts.iter().map(String::as_str).collect();
+                let service = Lookup {
+                    accounts: &accounts,
+                };
+                let middleware = Sentry::builder()
+                    .with_hub(Hub::current())
+                    .emit_header(true)
+                    .finish();
+
+                let lookup = |name: &'static str| {
+                    let mut req = TestRequest::get()
+                        .uri(&format!("/accounts/{}", name))
+                        .to_srv_request();
+                    req.match_info_mut().add_static("name", name);
+                    middleware.wrap_fn(req, &service)
+                };
+                assert_eq!(lookup("alice").await.unwrap().status(), StatusCode::OK);
+                assert!(lookup("bob").await.is_err());
+
+                async fn failing() -> Result<String, actix_web::Error> {
+                    Err(actix_web::error::ErrorBadGateway("Upstream unavailable"))
+                }
+
+                let app = init_service(
+                    App::new()
+                        .wrap_fn(move |req, srv| middleware.wrap_fn(req, srv))
+                        .route("/failing", web::get().to(failing)),
+                )
+                .await;
+                let res = call_service(&app, TestRequest::get().uri("/failing").to_request()).await;
+                assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+                assert!(res.headers().contains_key("x-sentry-event"));
+            })
+        });
+
+        assert_eq!(events.len(), 2);
+        assert_eq!(
+            events[0].exception.values[0].value.as_deref(),
+            Some("Account store out of sync")
+        );
+        assert_eq!(
+            events[1].exception.values[0].value.as_deref(),
+            Some("Upstream unavailable")
+        );
+    }
+
+    /// Ensures errors are discarded and counted while Sentry rate limits them.
+    #[cfg(feature = "rate-limits")]
+    #[actix_rt::test]
+    async fn test_rate_limited_captures() {
+        let limits = RateLimits::new();
+        let middleware = Sentry::builder()
+            .rate_limits(limits.clone())
+            .emit_rate_limited_header(true)
+            .finish();
+        let events = sentry::test::with_captured_events(|| {
+            block_on(async {
+                async fn failing() -> Result<String, actix_web::Error> {
+                    Err(io::Error::new(io::ErrorKind::Other, "Test Error").into())
+                }
+
+                let app = init_service(
+                    App::new()
+                        .wrap(
+                            middleware
+                                .clone()
+                                .into_builder()
+                                .with_hub(Hub::current())
+                                .finish(),
+                        )
+                        .service(web::resource("/failing").to(failing)),
+                )
+                .await;
+
+                let req = TestRequest::get().uri("/failing").to_request();
+                let res = call_service(&app, req).await;
+                assert!(!res.headers().contains_key("x-sentry-rate-limited"));
+
+                limits.update_from_sentry_header("60:error;transaction:organization");
+                for _ in 0..2 {
+                    let req = TestRequest::get().uri("/failing").to_request();
+                    let res = call_service(&app, req).await;
+                    assert!(res.status().is_server_error());
+                    assert_eq!(res.headers().get("x-sentry-rate-limited").unwrap(), "1");
+                }
+            })
+        });
+
+        assert_eq!(events.len(), 1);
+        assert_eq!(middleware.discard_stats().rate_limited, 2);
+    }
+
+    /// Ensures the user is derived from the bearer JWT, respecting PII settings.
+    #[cfg(feature = "jwt")]
+    #[actix_rt::test]
+    async fn test_user_from_jwt() {
+        // An unsigned token with the `sub` "user-1" and `email` "jane@example.com"
+        const TOKEN: &str = "eyJhbGciOiJub25lIn0.\
+            eyJzdWIiOiJ1c2VyLTEiLCJlbWFpbCI6ImphbmVAZXhhbXBsZS5jb20ifQ.";
+
+        let capture = |send_default_pii: bool, authorization: String| {
+            let events = sentry::test::with_captured_events_options(
+                || {
+                    block_on(async {
+                        async fn failing() -> Result<String, actix_web::Error> {
+                            Err(io::Error::new(io::ErrorKind::Other, "Test Error").into())
+                        }
+
+                        let app = init_service(
+                            App::new()
+                                .wrap(
+                                    Sentry::builder()
+                                        .with_hub(Hub::current())
+                                        .user_from_jwt(crate::UserJwtConfig::new())
+                                        .finish(),
+                                )
+                                .service(web::resource("/failing").to(failing)),
+                        )
+                        .await;
+
+                        let req = TestRequest::get()
+                            .uri("/failing")
+                            .insert_header(("authorization", authorization))
+                            .to_request();
+                        call_service(&app, req).await;
+                    })
+                },
+                sentry::ClientOptions {
+                    send_default_pii,
+                    ..Default::default()
+                },
+            );
+            assert_eq!(events.len(), 1);
+            let event = events.into_iter().next().unwrap();
+            // The token is never attached to the event
+            let request = event.request.as_ref().unwrap();
+            assert!(!request.headers.values().any(|value| value.contains(TOKEN)));
+            event.user
+        };
+
+        let bearer = format!("Bearer {}", TOKEN);
+        let user = capture(true, bearer.clone()).unwrap();
+        assert_eq!(user.id.as_deref(), Some("user-1"));
+        assert_eq!(user.email.as_deref(), Some("jane@example.com"));
+
+        let user = capture(false, bearer).unwrap();
+        assert_eq!(user.id.as_deref(), Some("user-1"));
+        assert_eq!(user.email, None);
+
+        assert_eq!(capture(false, "Bearer not.a.token".into()), None);
+    }
+
+    /// Ensures only path-derived transaction names are normalized.
+    #[actix_rt::test]
+    async fn test_normalize_unmatched_paths() {
+        let events = sentry::test::with_captured_events(|| {
+            block_on(async {
+                async fn failing() -> Result<String, actix_web::Error> {
+                    Err(io::Error::new(io::ErrorKind::Other, "Test Error").into())
+                }
+
+                let app = init_service(
+                    App::new()
+                        .wrap(
+                            Sentry::builder()
+                                .with_hub(Hub::current())
+                                .normalize_unmatched_paths(crate::PathNormalizer::new())
+                                .finish(),
+                        )
+                        .service(web::resource("/releases/2021").to(failing))
+                        .default_service(web::to(failing)),
+                )
+                .await;
+
+                for uri in &[
+                    "/releases/2021",
+                    "/users/12345/orders/3f2504e0-4f89-11d3-9a0c-0305e82c3301",
+                ] {
+                    let req = TestRequest::get().uri(uri).to_request();
+                    call_service(&app, req).await;
+                }
+            })
+        });
+
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].transaction.as_deref(), Some("/releases/2021"));
+        assert_eq!(
+            events[1].transaction.as_deref(),
+            Some("/users/{id}/orders/{uuid}")
+        );
+    }
+
+    /// Ensures the middleware captures on the request hub without binding it.
+    #[actix_rt::test]
+    async fn test_without_binding_hub() {
+        let events = sentry::test::with_captured_events(|| {
+            block_on(async {
+                async fn failing() -> Result<String, actix_web::Error> {
+                    sentry::capture_message("Message", Level::Warning);
+                    Err(io::Error::new(io::ErrorKind::Other, "Test Error").into())
+                }
+
+                let app = init_service(
+                    App::new()
+                        .wrap(
+                            Sentry::builder()
+                                .with_hub(Hub::current())
+                                .bind_hub(false)
+                                .finish(),
+                        )
+                        .service(web::resource("/failing").to(failing)),
+                )
+                .await;
+
+                let req = TestRequest::get().uri("/failing").to_request();
+                call_service(&app, req).await;
+            })
+        });
+
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].message.as_deref(), Some("Message"));
+        assert_eq!(events[0].transaction, None);
+        assert!(events[0].request.is_none());
+
+        assert_eq!(events[1].transaction.as_deref(), Some("/failing"));
+        assert!(events[1].request.is_some());
+    }
+
+    /// Ensures processors added by handlers run after or before the middleware's.
+    #[actix_rt::test]
+    async fn test_processor_position() {
+        let capture = |position: ProcessorPosition| {
+            let events = sentry::test::with_captured_events(|| {
+                block_on(async {
+                    async fn failing() -> Result<String, actix_web::Error> {
+                        Hub::current().configure_scope(|scope| {
+                            scope.add_event_processor(Box::new(|mut event| {
+                                if let Some(ref mut request) = event.request {
+                                    request.url = "https://example.com/rewritten".parse().ok();
+                                }
+                                Some(event)
+                            }))
+                        });
+                        Err(io::Error::new(io::ErrorKind::Other, "Test Error").into())
+                    }
+
+                    let app = init_service(
+                        App::new()
+                            .wrap(
+                                Sentry::builder()
+                                    .with_hub(Hub::current())
+                                    .processor_position(position)
+                                    .finish(),
+                            )
+                            .service(web::resource("/failing").to(failing)),
+                    )
+                    .await;
+
+                    let req = TestRequest::get().uri("/failing").to_request();
+                    call_service(&app, req).await;
+                })
+            });
+            assert_eq!(events.len(), 1);
+            let request = events[0].request.as_ref().unwrap();
+            request.url.as_ref().unwrap().to_string()
+        };
+
+        assert_eq!(
+            capture(ProcessorPosition::First),
+            "https://example.com/rewritten"
+        );
+        assert_eq!(
+            capture(ProcessorPosition::Last),
+            "http://localhost:8080/failing"
+        );
+    }
+
+    /// A service that is never ready.
+    struct ExhaustedService;
+
+    impl Service<ServiceRequest> for ExhaustedService {
+        type Response = ServiceResponse;
+        type Error = actix_web::Error;
+        type Future = Ready<Result<Self::Response, Self::Error>>;
+
+        fn poll_ready(&self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Err(actix_web::error::ErrorServiceUnavailable(
+                "Connection pool exhausted",
+            )))
+        }
+
+        fn call(&self, req: ServiceRequest) -> Self::Future {
+            ok(req.into_response(HttpResponse::Ok().finish()))
+        }
+    }
+
+    /// Ensures requests dropped while their service is pending are captured, rate-limited.
+    #[actix_rt::test]
+    async fn test_capture_aborted_requests() {
+        let events = sentry::test::with_captured_events(|| {
+            block_on(async {
+                async fn stalled() -> &'static str {
+                    futures::future::pending::<()>().await;
+                    "unreachable"
+                }
+
+                let app = init_service(
+                    App::new()
+                        .wrap(
+                            Sentry::builder()
+                                .with_hub(Hub::current())
+                                .capture_aborted_requests(true)
+                                .finish(),
+                        )
+                        .route("/", web::get().to(HttpResponse::Ok))
+                        .route("/stalled", web::get().to(stalled)),
+                )
+                .await;
+
+                let res = call_service(&app, TestRequest::get().uri("/").to_request()).await;
+                assert_eq!(res.status(), StatusCode::OK);
+                drop(res);
+
+                // The worker stops while the handlers are pending
+                for _ in 0..2 {
+                    let mut fut =
+                        Box::pin(app.call(TestRequest::get().uri("/stalled").to_request()));
+                    assert!(futures::poll!(&mut fut).is_pending());
+                    drop(fut);
+                }
+            })
+        });
+
+        assert_eq!(events.len(), 1);
+        let event = &events[0];
+        assert_eq!(event.level, Level::Warning);
+        assert_eq!(
+            event.message.as_deref(),
+            Some("Request aborted during shutdown/worker drop")
+        );
+        assert_eq!(event.tags["sentry_actix.capture_source"], "aborted");
+        assert_eq!(event.extra["route"], "/stalled");
+        let request = event.request.as_ref().expect("Request should be set.");
+        assert_eq!(request.url.as_ref().unwrap().path(), "/stalled");
+    }
+
+    /// Ensures `poll_ready` errors are captured on the base hub, rate-limited.
+    #[actix_rt::test]
+    async fn test_capture_poll_ready_errors() {
+        let events = sentry::test::with_captured_events(|| {
+            block_on(async {
+                let clock = ManualClock::new();
+                let middleware = Sentry::builder()
+                    .with_hub(Hub::current())
+                    .with_clock(clock.clone())
+                    .with_sequential_ids(SequentialIds::new())
+                    .capture_poll_ready_errors(true)
+                    .finish();
+                let service = middleware.new_transform(ExhaustedService).await.unwrap();
+                for _ in 0..3 {
+                    let ready = futures_util::future::poll_fn(|cx| service.poll_ready(cx)).await;
+                    assert!(ready.is_err());
+                    clock.advance(Duration::from_secs(20));
+                }
+                // The interval has elapsed since the first capture
+                let ready = futures_util::future::poll_fn(|cx| service.poll_ready(cx)).await;
+                assert!(ready.is_err());
+
+                // Disabled by default
+                let service = Sentry::builder()
+                    .with_hub(Hub::current())
+                    .finish()
+                    .new_transform(ExhaustedService)
+                    .await
+                    .unwrap();
+                let ready = futures_util::future::poll_fn(|cx| service.poll_ready(cx)).await;
+                assert!(ready.is_err());
+            })
+        });
+
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1].event_id, Uuid::from_u128(2));
+        let event = &events[0];
+        assert_eq!(event.event_id, Uuid::from_u128(1));
+        assert_eq!(event.message.as_deref(), Some("Service not ready"));
+        assert_eq!(
+            event.exception.values[0].value.as_deref(),
+            Some("Connection pool exhausted")
+        );
+        assert_eq!(event.tags["sentry_actix.capture_source"], "poll_ready");
+        assert!(event.extra["service"]
+            .as_str()
+            .unwrap()
+            .ends_with("ExhaustedService"));
+        assert!(event.request.is_none());
+    }
+
+    /// Ensures earlier middleware can disable or force capturing and sessions.
+    #[actix_rt::test]
+    async fn test_capture_markers() {
+        let envelopes = sentry::test::with_captured_envelopes_options(
+            || {
+                block_on(async {
+                    async fn failing() -> Result<String, actix_web::Error> {
+                        Err(io::Error::new(io::ErrorKind::Other, "Test Error").into())
+                    }
+
+                    async fn not_found() -> Result<String, actix_web::Error> {
+                        Err(actix_web::error::ErrorNotFound("Not Found"))
+                    }
+
+                    async fn message() -> &'static str {
+                        sentry::capture_message("Message", Level::Warning);
+                        "ok"
+                    }
+
+                    let markers = |req: ServiceRequest, srv: &_| {
+                        let header = req
+                            .headers()
+                            .get("x-markers")
+                            .and_then(|value| value.to_str().ok())
+                            .unwrap_or_default()
+                            .to_owned();
+                        for marker in header.split(',') {
+                            let mut extensions = req.extensions_mut();
+                            match marker {
+                                "disable" => {
+                                    extensions.insert(DisableCapture);
+                                }
+                                "sessions" => {
+                                    extensions.insert(DisableSessions);
+                                }
+                                "force" => {
+                                    extensions.insert(ForceCapture);
+                                }
+                                _ => {}
+                            }
+                        }
+                        Service::call(srv, req)
+                    };
+                    let app = init_service(
+                        App::new()
+                            .wrap(Sentry::builder().with_hub(Hub::current()).finish())
+                            .wrap_fn(markers)
+                            .service(web::resource("/failing").to(failing))
+                            .service(web::resource("/not-found").to(not_found))
+                            .service(web::resource("/message").to(message)),
+                    )
+                    .await;
+
+                    for (uri, markers) in &[
+                        ("/failing", "disable"),
+                        ("/failing", "sessions"),
+                        ("/not-found", "force"),
+                        ("/not-found", ""),
+                        ("/failing", "disable,force"),
+                        ("/message", "disable"),
+                    ] {
+                        let req = TestRequest::get()
+                            .uri(uri)
+                            .insert_header(("x-markers", *markers))
+                            .to_request();
+                        call_service(&app, req).await;
+                    }
+                })
+            },
+            sentry::ClientOptions {
+                release: Some("some-release".into()),
+                session_mode: sentry::SessionMode::Request,
+                auto_session_tracking: true,
+                ..Default::default()
+            },
+        );
+
+        let mut events = Vec::new();
+        let mut sessions = 0;
+        for item in envelopes.iter().flat_map(|envelope| envelope.items()) {
+            match item {
+                sentry::protocol::EnvelopeItem::Event(event) => events.push(event),
+                sentry::protocol::EnvelopeItem::SessionAggregates(aggregates) => {
+                    for aggregate in &aggregates.aggregates {
+                        sessions += aggregate.exited + aggregate.errored;
+                    }
+                }
+                _ => panic!("unexpected item"),
+            }
+        }
+
+        // Only the request with disabled sessions has no session
+        assert_eq!(sessions, 5);
+        assert_eq!(events.len(), 2);
+        assert_eq!(
+            events[0].exception.values[0].value.as_deref(),
+            Some("Test Error")
+        );
+        assert_eq!(events[1].tags["http.status_code"], "404");
+    }
+
+    #[test]
+    fn test_format_event_id() {
+        let event_id: Uuid = "67e55044-10b1-426f-9247-bb680e5fe0c8".parse().unwrap();
+        assert_eq!(
+            format_event_id(event_id, false),
+            "67e5504410b1426f9247bb680e5fe0c8"
+        );
+        assert_eq!(
+            format_event_id(event_id, true),
+            "67e55044-10b1-426f-9247-bb680e5fe0c8"
+        );
+    }
+
+    /// Ensures opaque errors are marked, and diagnosed once per route.
+    #[actix_rt::test]
+    async fn test_diagnose_opaque_errors() {
+        let events = sentry::test::with_captured_events(|| {
+            block_on(async {
+                async fn opaque() -> Result<String, actix_web::Error> {
+                    Err(io::Error::new(io::ErrorKind::Other, "Test Error").into())
+                }
+
+                async fn described() -> Result<String, actix_web::Error> {
+                    Err(io::Error::from(io::ErrorKind::ConnectionReset).into())
+                }
+
+                let app = init_service(
+                    App::new()
+                        .wrap(
+                            Sentry::builder()
+                                .with_hub(Hub::current())
+                                .diagnose_opaque_errors(true)
+                                .finish(),
+                        )
+                        .route("/opaque/{id}", web::get().to(opaque))
+                        .route("/described", web::get().to(described)),
+                )
+                .await;
+
+                for uri in &["/opaque/1", "/opaque/2", "/described"] {
+                    let req = TestRequest::get().uri(uri).to_request();
+                    let res = call_service(&app, req).await;
+                    assert!(res.status().is_server_error());
+                }
+            })
+        });
+
+        assert_eq!(events.len(), 4);
+        let diagnostic = &events[0];
+        assert_eq!(diagnostic.level, Level::Info);
+        assert_eq!(
+            diagnostic.message.as_deref(),
+            Some("Errors on /opaque/{id} are captured as opaque `Custom` errors without a source")
+        );
+        assert_eq!(diagnostic.tags["sentry_actix.capture_source"], "diagnostic");
+        for event in &events[1..3] {
+            assert_eq!(event.exception.values[0].ty, "Custom");
+            assert_eq!(event.extra["capture_quality"], "opaque");
+        }
+        assert!(!events[3].extra.contains_key("capture_quality"));
+    }
+}
@@ -0,0 +1,174 @@
+//! Normalizing transaction names derived from raw request paths.
+
+use std::borrow::Cow;
+use std::fmt;
+use std::sync::Arc;
+
+/// The signature of functions classifying path segments.
+type Classifier = dyn Fn(&str) -> bool + Send + Sync;
+
+/// Replaces identifiers in request paths with placeholders.
+///
+/// Requests that did not match a route pattern have no transaction name.  With
+/// [`SentryBuilder::normalize_unmatched_paths`](crate::SentryBuilder::normalize_unmatched_paths),
+/// their path is used instead, normalized to keep the number of distinct names
+/// bounded.  Names derived from route patterns are never normalized.
+///
+/// Each segment is replaced by the placeholder of the first classifier matching
+/// it.  By default, numeric segments become `{id}`, UUIDs become `{uuid}` and
+/// hexadecimal hashes of at least 16 digits become `{hash}`.
+///
+/// # Example
+///
+/// ```
+/// use sentry_actix::PathNormalizer;
+///
+/// let normalizer = PathNormalizer::new().classifier("{slug}", |segment| segment.contains('-'));
+/// assert_eq!(
+///     normalizer.normalize("/users/42/posts/hello-world"),
+///     "/users/{id}/posts/{slug}"
+/// );
+/// ```
+#[derive(Clone)]
+pub struct PathNormalizer {
+    classifiers: Vec<(Cow<'static, str>, Arc<Classifier>)>,
+}
+
+impl PathNormalizer {
+    /// Creates a normalizer with the default classifiers.
+    pub fn new() -> Self {
+        PathNormalizer::empty()
+            .classifier("{id}", is_numeric)
+            .classifier("{uuid}", is_uuid)
+            .classifier("{hash}", is_hex_hash)
+    }
+
+    /// Creates a normalizer without any classifiers.
+    pub fn empty() -> Self {
+        PathNormalizer {
+            classifiers: Vec::new(),
+        }
+    }
+
+    /// Replaces segments matching the classifier with the placeholder.
+    ///
+    /// Classifiers are tried in the order they were added.
+    pub fn classifier<P, F>(mut self, placeholder: P, f: F) -> Self
+    where
+        P: Into<Cow<'static, str>>,
+        F: Fn(&str) -> bool + Send + Sync + 'static,
+    {
+        self.classifiers.push((placeholder.into(), Arc::new(f)));
+        self
+    }
+
+    /// Normalizes the segments of a path.
+    pub fn normalize<'a>(&self, path: &'a str) -> Cow<'a, str> {
+        let placeholder = |segment: &str| {
+            self.classifiers
+                .iter()
+                .find(|(_, classify)| !segment.is_empty() && classify(segment))
+                .map(|(placeholder, _)| placeholder.as_ref())
+        };
+        if path
+            .split('/')
+            .all(|segment| placeholder(segment).is_none())
+        {
+            return Cow::Borrowed(path);
+        }
+        let segments: Vec<&str> = path
+            .split('/')
+            .map(|segment| placeholder(segment).unwrap_or(segment))
+            .collect();
+        Cow::Owned(segments.join("/"))
+    }
+}
+
+impl Default for PathNormalizer {
+    fn default() -> Self {
+        PathNormalizer::new()
+    }
+}
+
+impl fmt::Debug for PathNormalizer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let placeholders: Vec<_> = self.classifiers.iter().map(|(p, _)| p).collect();
+        f.debug_struct("PathNormalizer")
+            .field("placeholders", &placeholders)
+            .finish()
+    }
+}
+
+/// Normalizes the segments of a path with the default classifiers.
+///
+/// See [`PathNormalizer`].
+///
+/// # Example
+///
+/// ```
+/// assert_eq!(
+///     sentry_actix::normalize_path("/users/12345/avatar"),
+///     "/users/{id}/avatar"
+/// );
+/// ```
+pub fn normalize_path(path: &str) -> Cow<'_, str> {
+    PathNormalizer::new().normalize(path)
+}
+
+fn is_numeric(segment: &str) -> bool {
+    segment.bytes().all(|b| b.is_ascii_digit())
+}
+
+fn is_uuid(segment: &str) -> bool {
+    segment.len() == 36
+        && segment.bytes().enumerate().all(|(i, b)| match i {
+            8 | 13 | 18 | 23 => b == b'-',
+            _ => b.is_ascii_hexdigit(),
+        })
+}
+
+fn is_hex_hash(segment: &str) -> bool {
+    segment.len() >= 16
+        && segment.bytes().all(|b| b.is_ascii_hexdigit())
+        && segment.bytes().any(|b| b.is_ascii_digit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_path() {
+        assert_eq!(normalize_path("/users/12345"), "/users/{id}");
+        assert_eq!(
+            normalize_path("/orders/3f2504e0-4f89-11d3-9a0c-0305e82c3301/items/7"),
+            "/orders/{uuid}/items/{id}"
+        );
+        assert_eq!(
+            normalize_path("/blobs/d41d8cd98f00b204e9800998ecf8427e"),
+            "/blobs/{hash}"
+        );
+
+        // Mixed segments are kept
+        assert_eq!(
+            normalize_path("/orders/order-123/v2"),
+            "/orders/order-123/v2"
+        );
+        assert_eq!(normalize_path("/deadbeefcafebabe"), "/deadbeefcafebabe");
+        assert_eq!(normalize_path("/users/"), "/users/");
+        assert!(matches!(normalize_path("/health"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn test_custom_classifiers() {
+        let normalizer = PathNormalizer::empty().classifier("{n}", is_numeric);
+        assert_eq!(
+            normalizer.normalize("/users/42/d41d8cd98f00b204e9800998ecf8427e"),
+            "/users/{n}/d41d8cd98f00b204e9800998ecf8427e"
+        );
+
+        // Earlier classifiers take precedence
+        let normalizer = PathNormalizer::new().classifier("{short}", |s| s.len() < 3);
+        assert_eq!(normalizer.normalize("/a/12"), "/{short}/{id}");
+    }
+}
@@ -0,0 +1,194 @@
+//! Opinionated configurations of the middleware.
+
+use actix_web::dev::ServiceRequest;
+use actix_web::http::header;
+
+use crate::builder::SentryBuilder;
+use crate::middleware::Sentry;
+use crate::redact::{RedactionTarget, FILTERED};
+use crate::request::{EnvProfile, IpPolicy};
+
+/// The headers kept by [`Sentry::strict`], which describe the payload rather
+/// than the client.
+const STRICT_HEADERS: &[header::HeaderName] =
+    &[header::ACCEPT, header::CONTENT_LENGTH, header::CONTENT_TYPE];
+
+/// Names transactions after the method and the route pattern, as in `GET /items/{id}`.
+fn method_transaction_name(req: &ServiceRequest) -> Option<String> {
+    let pattern = req.match_pattern()?;
+    Some(format!("{} {}", req.method(), pattern))
+}
+
+/// Filters all query parameters and the headers not describing the payload.
+fn strict_redactor(target: RedactionTarget<'_>, value: &mut String) {
+    let filtered = match target {
+        RedactionTarget::QueryParam(_) => true,
+        RedactionTarget::Header(name) => !STRICT_HEADERS
+            .iter()
+            .any(|kept| kept.as_str().eq_ignore_ascii_case(name)),
+        RedactionTarget::Path | RedactionTarget::Body => false,
+    };
+    if filtered {
+        *value = FILTERED.into();
+    }
+}
+
+impl Sentry {
+    /// Creates a builder with the configuration recommended for most apps.
+    ///
+    /// Server errors are captured with the default redaction of credentials,
+    /// events of a request are added as breadcrumbs to its later events, and
+    /// transactions are named after the method and the route pattern, as in
+    /// `GET /items/{id}`.
+    pub fn recommended() -> SentryBuilder {
+        Sentry::builder()
+            .capture_server_errors(true)
+            .breadcrumb_captures(true)
+            .transaction_name_fn(method_transaction_name)
+    }
+
+    /// Creates a builder that only captures errors.
+    ///
+    /// Events carry the method of the request, but no headers, URL, body or
+    /// addresses, and no breadcrumbs of earlier events of the request.
+    pub fn minimal() -> SentryBuilder {
+        Sentry::builder()
+            .capture_server_errors(true)
+            .breadcrumb_captures(false)
+            .full_request_data_sample_rate(0.0)
+            .max_request_body_size(0)
+    }
+
+    /// Creates a builder for apps handling sensitive data.
+    ///
+    /// Addresses are never recorded, even with `send_default_pii`, the request
+    /// body is never captured, the values of all query parameters and of all
+    /// headers but `Accept`, `Content-Length` and `Content-Type` are filtered,
+    /// and the `env` of request data is minimal.
+    ///
+    /// This is unrelated to [`SentryBuilder::strict`], which fails the creation
+    /// of services with features that have no effect.
+    pub fn strict() -> SentryBuilder {
+        Sentry::builder()
+            .capture_server_errors(true)
+            .ip_policy(IpPolicy::None)
+            .env_profile(EnvProfile::Minimal)
+            .track_request_body(false)
+            .max_request_body_size(0)
+            .redactor(strict_redactor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{web, App};
+    use futures::executor::block_on;
+    use sentry::protocol::Event;
+    use sentry_core::Hub;
+
+    use super::*;
+
+    /// Captures the events of a request failing after capturing a message.
+    fn capture(builder: SentryBuilder) -> Vec<Event<'static>> {
+        async fn failing(body: web::Bytes) -> Result<String, actix_web::Error> {
+            sentry::capture_message("Slow query", sentry::Level::Warning);
+            Err(io::Error::new(io::ErrorKind::Other, format!("{} bytes", body.len())).into())
+        }
+
+        sentry::test::with_captured_events_options(
+            || {
+                block_on(async {
+                    let app = init_service(
+                        App::new()
+                            .wrap(builder.with_hub(Hub::current()).finish())
+                            .route("/items/{id}", web::post().to(failing)),
+                    )
+                    .await;
+                    let req = TestRequest::post()
+                        .uri("/items/42?token=abc&page=2")
+                        .insert_header(("authorization", "Bearer abc"))
+                        .insert_header(("x-forwarded-for", "203.0.113.7"))
+                        .insert_header((header::CONTENT_TYPE, "application/json"))
+                        .peer_addr("198.51.100.1:8080".parse().unwrap())
+                        .set_payload(r#"{"name":"widget"}"#)
+                        .to_request();
+                    let res = call_service(&app, req).await;
+                    assert!(res.status().is_server_error());
+                })
+            },
+            sentry::ClientOptions {
+                send_default_pii: true,
+                max_request_body_size: sentry::MaxRequestBodySize::Always,
+                ..Default::default()
+            },
+        )
+    }
+
+    #[test]
+    fn test_recommended() {
+        let events = capture(Sentry::recommended());
+        assert_eq!(events.len(), 2);
+        let event = &events[1];
+        assert_eq!(event.transaction.as_deref(), Some("POST /items/{id}"));
+        assert_eq!(event.breadcrumbs.len(), 1);
+        let request = event.request.as_ref().expect("Request should be set.");
+        assert_eq!(request.headers["authorization"], FILTERED);
+        assert_eq!(request.headers["x-forwarded-for"], "203.0.113.7");
+        assert_eq!(
+            request.url.as_ref().unwrap().query(),
+            Some("token=[Filtered]&page=2")
+        );
+        assert_eq!(request.data.as_deref(), Some(r#"{"name":"widget"}"#));
+        assert!(request.env.contains_key("REMOTE_ADDR"));
+    }
+
+    #[test]
+    fn test_minimal() {
+        let events = capture(Sentry::minimal());
+        assert_eq!(events.len(), 2);
+        let event = &events[1];
+        assert_eq!(event.transaction.as_deref(), Some("/items/{id}"));
+        assert!(event.breadcrumbs.is_empty());
+        let request = event.request.as_ref().expect("Request should be set.");
+        assert_eq!(request.method.as_deref(), Some("POST"));
+        assert!(request.url.is_none());
+        assert!(request.headers.is_empty());
+        assert!(request.data.is_none());
+        assert!(request.env.is_empty());
+    }
+
+    #[test]
+    fn test_strict() {
+        let events = capture(Sentry::strict());
+        assert_eq!(events.len(), 2);
+        let event = &events[1];
+        let request = event.request.as_ref().expect("Request should be set.");
+        assert_eq!(request.headers["authorization"], FILTERED);
+        assert_eq!(request.headers["x-forwarded-for"], FILTERED);
+        assert_eq!(request.headers["content-type"], "application/json");
+        assert_eq!(
+            request.url.as_ref().unwrap().query(),
+            Some("token=[Filtered]&page=[Filtered]")
+        );
+        assert!(request.data.is_none());
+        assert!(!request.env.contains_key("REMOTE_ADDR"));
+        assert!(!request.env.contains_key("CLIENT_IP"));
+    }
+
+    /// Ensures the presets can still be adjusted.
+    #[test]
+    fn test_adjusted_preset() {
+        let events = capture(Sentry::strict().redactor(
+            |target: RedactionTarget<'_>, value: &mut String| {
+                if target == RedactionTarget::Path {
+                    *value = value.replace("42", "0");
+                }
+            },
+        ));
+        let request = events[1].request.as_ref().expect("Request should be set.");
+        assert_eq!(request.url.as_ref().unwrap().path(), "/items/0");
+    }
+}
@@ -0,0 +1,129 @@
+//! Rendering of captured errors as RFC 7807 `application/problem+json` responses.
+
+use std::fmt;
+
+use actix_web::dev::{Body, ResponseBody, ServiceResponse};
+use actix_web::http::{header, StatusCode};
+use actix_web::{HttpRequest, HttpResponse, ResponseError};
+use serde_json::json;
+
+use crate::helpers::mime_essence;
+
+/// The content type of problem details.
+const PROBLEM_JSON: &str = "application/problem+json";
+
+/// Returns whether the client accepts JSON responses.
+///
+/// Requests without an `Accept` header accept any response.
+pub(crate) fn accepts_json(req: &HttpRequest) -> bool {
+    let accept = match req.headers().get(header::ACCEPT) {
+        Some(accept) => accept,
+        None => return true,
+    };
+    let accept = match accept.to_str() {
+        Ok(accept) => accept,
+        Err(_) => return false,
+    };
+    accept.split(',').any(|range| {
+        let mut params = range.split(';').map(str::trim);
+        let essence = params.next().unwrap_or_default().to_ascii_lowercase();
+        let accepted = matches!(
+            essence.as_str(),
+            "*/*" | "application/*" | "application/json" | PROBLEM_JSON
+        );
+        accepted && !params.any(|param| param == "q=0")
+    })
+}
+
+/// Renders the problem details of an error with the given status and event id.
+fn problem_body(status: StatusCode, event_id: &str) -> String {
+    json!({
+        "type": "about:blank",
+        "title": status.canonical_reason().unwrap_or("Error"),
+        "status": status.as_u16(),
+        "sentry_event_id": event_id,
+    })
+    .to_string()
+}
+
+/// An error rendered as problem details with the id of its event.
+///
+/// Service errors are wrapped in this error, as there is no response to rewrite.
+pub(crate) struct ProblemError {
+    pub(crate) error: actix_web::Error,
+    pub(crate) event_id: String,
+}
+
+impl fmt::Debug for ProblemError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.error, f)
+    }
+}
+
+impl fmt::Display for ProblemError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.error, f)
+    }
+}
+
+impl ResponseError for ProblemError {
+    fn status_code(&self) -> StatusCode {
+        self.error.as_response_error().status_code()
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let status = self.status_code();
+        HttpResponse::build(status)
+            .content_type(PROBLEM_JSON)
+            .body(problem_body(status, &self.event_id))
+    }
+}
+
+/// Replaces a plain text error response with problem details.
+///
+/// Responses with a body of another type were rendered by the handler and are
+/// returned unchanged.
+pub(crate) fn rewrite_response<B>(res: ServiceResponse<B>, event_id: &str) -> ServiceResponse<B> {
+    let is_plain_text = res
+        .response()
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(mime_essence)
+        .map_or(true, |essence| essence == "text/plain");
+    if !is_plain_text {
+        return res;
+    }
+    let body = problem_body(res.status(), event_id);
+    res.map_body(|head, _| {
+        head.headers_mut().insert(
+            header::CONTENT_TYPE,
+            header::HeaderValue::from_static(PROBLEM_JSON),
+        );
+        ResponseBody::Other(Body::from(body))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    #[test]
+    fn test_accepts_json() {
+        let accepts = |accept: Option<&str>| {
+            let mut req = TestRequest::default();
+            if let Some(accept) = accept {
+                req = req.insert_header((header::ACCEPT, accept));
+            }
+            accepts_json(&req.to_http_request())
+        };
+        assert!(accepts(None));
+        assert!(accepts(Some("*/*")));
+        assert!(accepts(Some("text/html, application/json;q=0.9")));
+        assert!(accepts(Some("application/problem+json")));
+        assert!(!accepts(Some("text/html")));
+        assert!(!accepts(Some("text/html, application/json;q=0")));
+    }
+}
@@ -0,0 +1,268 @@
+//! Tracking the rate limits of the Sentry server.
+//!
+//! The transports of the SDK drop events silently while Sentry rate limits them,
+//! without exposing that state.  [`RateLimits`] records the limits reported by
+//! responses of the Sentry server, so that the middleware can count the errors
+//! it discards because of them, in
+//! [`DiscardStats::rate_limited`](crate::DiscardStats::rate_limited).
+//!
+//! Limits are recorded by the [`tunnel`](crate::tunnel) route for forwarded
+//! envelopes, or with the `update_from_*` methods by custom transports seeing
+//! the responses.  [`RateLimitedTransport`] applies them to another transport.
+//!
+//! # Example
+//!
+//! ```
+//! use std::sync::Arc;
+//!
+//! use sentry_actix::rate_limits::{RateLimitedTransport, RateLimits};
+//! use sentry_actix::Sentry;
+//!
+//! let limits = RateLimits::new();
+//! let transport = Arc::new(RateLimitedTransport::new(
+//!     sentry::test::TestTransport::new(),
+//!     limits.clone(),
+//! ));
+//! let _sentry = sentry::init(sentry::ClientOptions {
+//!     transport: Some(Arc::new(transport)),
+//!     ..Default::default()
+//! });
+//!
+//! let middleware = Sentry::builder().rate_limits(limits).finish();
+//! ```
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use actix_web::http::{HeaderMap, StatusCode};
+use sentry_core::protocol::EnvelopeItem;
+use sentry_core::{Envelope, Transport};
+
+/// The header of responses of the Sentry server listing its rate limits.
+const SENTRY_RATE_LIMITS_HEADER: &str = "x-sentry-rate-limits";
+
+/// How long a `429` response without a valid `Retry-After` header limits events.
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+/// The rate limits reported by the Sentry server.
+///
+/// Clones share the same limits.
+#[derive(Clone, Default)]
+pub struct RateLimits {
+    inner: Arc<Mutex<Limits>>,
+}
+
+#[derive(Default)]
+struct Limits {
+    /// The end of the limit of all categories.
+    all: Option<Instant>,
+    /// The end of the limits of single categories.
+    categories: HashMap<String, Instant>,
+}
+
+impl RateLimits {
+    /// Creates limits without any active limit.
+    pub fn new() -> Self {
+        RateLimits::default()
+    }
+
+    /// Records the limits of a response of the Sentry server.
+    ///
+    /// The `X-Sentry-Rate-Limits` header takes precedence.  Without it, a `429`
+    /// response limits all categories for its `Retry-After` seconds, or a minute.
+    pub fn update_from_response(&self, status: StatusCode, headers: &HeaderMap) {
+        let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+        if let Some(limits) = header(SENTRY_RATE_LIMITS_HEADER) {
+            self.update_from_sentry_header(limits);
+        } else if status == StatusCode::TOO_MANY_REQUESTS {
+            let retry_after = header("retry-after").unwrap_or_default();
+            self.update_from_retry_after(retry_after);
+        }
+    }
+
+    /// Records a limit of all categories from a `Retry-After` header in seconds.
+    ///
+    /// Invalid values limit all categories for a minute.
+    pub fn update_from_retry_after(&self, header: &str) {
+        let duration = header
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .filter(|seconds| seconds.is_finite() && *seconds >= 0.0)
+            .map_or(DEFAULT_RETRY_AFTER, |seconds| {
+                Duration::from_secs(seconds.ceil() as u64)
+            });
+        self.inner.lock().unwrap().all = Some(Instant::now() + duration);
+    }
+
+    /// Records the limits of an `X-Sentry-Rate-Limits` header.
+    ///
+    /// Each comma separated limit lists its seconds, its categories separated by
+    /// semicolons, all of them if empty, and its scope.
+    pub fn update_from_sentry_header(&self, header: &str) {
+        let now = Instant::now();
+        let mut limits = self.inner.lock().unwrap();
+        for limit in header.split(',') {
+            let mut parts = limit.trim().split(':');
+            let seconds = match parts.next().and_then(|seconds| seconds.parse::<f64>().ok()) {
+                Some(seconds) if seconds.is_finite() && seconds >= 0.0 => seconds,
+                _ => continue,
+            };
+            let categories = match (parts.next(), parts.next()) {
+                (Some(categories), Some(_scope)) => categories,
+                _ => continue,
+            };
+            let until = now + Duration::from_secs(seconds.ceil() as u64);
+            if categories.is_empty() {
+                limits.all = Some(until);
+            }
+            for category in categories.split(';').filter(|c| !c.is_empty()) {
+                limits.categories.insert(category.to_owned(), until);
+            }
+        }
+    }
+
+    /// Returns how long events of a category, such as `error`, remain limited.
+    pub fn limited_for(&self, category: &str) -> Option<Duration> {
+        let now = Instant::now();
+        let limits = self.inner.lock().unwrap();
+        let remaining = |until: Option<&Instant>| {
+            until
+                .map(|until| until.saturating_duration_since(now))
+                .filter(|remaining| *remaining > Duration::from_secs(0))
+        };
+        match (
+            remaining(limits.all.as_ref()),
+            remaining(limits.categories.get(category)),
+        ) {
+            (Some(all), Some(category)) => Some(all.max(category)),
+            (all, category) => all.or(category),
+        }
+    }
+
+    /// Returns whether error events are limited.
+    pub fn errors_limited(&self) -> bool {
+        self.limited_for("error").is_some()
+    }
+}
+
+impl fmt::Debug for RateLimits {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RateLimits")
+            .field("errors_limited", &self.errors_limited())
+            .finish()
+    }
+}
+
+/// A transport dropping envelopes with events while errors are rate limited.
+///
+/// The limits are shared with the middleware through [`RateLimits`], and updated
+/// by whoever sees the responses of the Sentry server.  Envelopes without
+/// events, such as session updates, are always sent.
+pub struct RateLimitedTransport {
+    inner: Arc<dyn Transport>,
+    limits: RateLimits,
+}
+
+impl RateLimitedTransport {
+    /// Wraps a transport.
+    pub fn new(inner: Arc<dyn Transport>, limits: RateLimits) -> Self {
+        RateLimitedTransport { inner, limits }
+    }
+
+    /// Returns the limits of the transport.
+    pub fn limits(&self) -> &RateLimits {
+        &self.limits
+    }
+}
+
+impl fmt::Debug for RateLimitedTransport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RateLimitedTransport")
+            .field("limits", &self.limits)
+            .finish()
+    }
+}
+
+impl Transport for RateLimitedTransport {
+    fn send_envelope(&self, envelope: Envelope) {
+        let has_event = envelope
+            .items()
+            .any(|item| matches!(item, EnvelopeItem::Event(_)));
+        if has_event && self.limits.errors_limited() {
+            return;
+        }
+        self.inner.send_envelope(envelope)
+    }
+
+    fn flush(&self, timeout: Duration) -> bool {
+        self.inner.flush(timeout)
+    }
+
+    fn shutdown(&self, timeout: Duration) -> bool {
+        self.inner.shutdown(timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::HeaderValue;
+
+    use super::*;
+
+    #[test]
+    fn test_sentry_header() {
+        let limits = RateLimits::new();
+        limits.update_from_sentry_header("60:transaction:key, invalid, 0:error:organization");
+        assert!(!limits.errors_limited());
+        assert!(limits.limited_for("transaction").is_some());
+
+        limits.update_from_sentry_header("120:error;session:organization:quota_exceeded");
+        assert!(limits.limited_for("error").unwrap() > Duration::from_secs(60));
+        assert!(limits.limited_for("session").is_some());
+        assert!(limits.limited_for("attachment").is_none());
+
+        limits.update_from_sentry_header("30::organization");
+        assert!(limits.limited_for("attachment").is_some());
+    }
+
+    #[test]
+    fn test_response() {
+        let limits = RateLimits::new();
+        let mut headers = HeaderMap::new();
+        limits.update_from_response(StatusCode::OK, &headers);
+        assert!(!limits.errors_limited());
+
+        headers.insert(
+            "retry-after".parse().unwrap(),
+            HeaderValue::from_static("5"),
+        );
+        limits.update_from_response(StatusCode::TOO_MANY_REQUESTS, &headers);
+        let remaining = limits.limited_for("error").unwrap();
+        assert!(remaining > Duration::from_secs(4) && remaining <= Duration::from_secs(5));
+
+        let limits = RateLimits::new();
+        headers.insert(
+            SENTRY_RATE_LIMITS_HEADER.parse().unwrap(),
+            HeaderValue::from_static("60:transaction:key"),
+        );
+        limits.update_from_response(StatusCode::TOO_MANY_REQUESTS, &headers);
+        assert!(!limits.errors_limited());
+    }
+
+    #[test]
+    fn test_transport() {
+        let inner = sentry::test::TestTransport::new();
+        let transport = RateLimitedTransport::new(inner.clone(), RateLimits::new());
+        let envelope = || Envelope::from(sentry_core::protocol::Event::default());
+
+        transport.send_envelope(envelope());
+        transport
+            .limits()
+            .update_from_sentry_header("60:error:organization");
+        transport.send_envelope(envelope());
+        assert_eq!(inner.fetch_and_clear_envelopes().len(), 1);
+    }
+}
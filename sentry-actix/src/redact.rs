@@ -0,0 +1,344 @@
+//! Redaction of request data attached to events.
+//!
+//! The middleware passes every header, query parameter, the URL path and the
+//! captured request body through an ordered pipeline of [`Redactor`]s before
+//! attaching them to events.  Each redactor sees the value as left by the previous
+//! one.  By default, the pipeline consists of [`DenylistKeys::default`], which
+//! filters credentials from common headers, query parameters and JSON bodies.
+//!
+//! With the `regex` feature enabled, [`RegexPatterns`] filters all values matching
+//! regular expressions, such as credit card numbers and email addresses.
+//!
+//! # Example
+//!
+//! ```
+//! use sentry_actix::redact::{DenylistKeys, RedactionTarget};
+//! use sentry_actix::Sentry;
+//!
+//! let middleware = Sentry::builder()
+//!     .redactor(DenylistKeys::new(vec!["x-tenant-secret", "ssn"]))
+//!     .redactor(|target: RedactionTarget<'_>, value: &mut String| {
+//!         if target == RedactionTarget::Path {
+//!             *value = value.replace("/internal/", "/");
+//!         }
+//!     })
+//!     .finish();
+//! ```
+
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+
+use serde_json::Value;
+
+/// The replacement of redacted values.
+pub const FILTERED: &str = "[Filtered]";
+
+/// The keys filtered by [`DenylistKeys::default`].
+const DEFAULT_DENYLIST: &[&str] = &[
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+    "x-api-key",
+    "api_key",
+    "password",
+    "secret",
+    "token",
+];
+
+/// The part of the request data a value belongs to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RedactionTarget<'a> {
+    /// The value of the header with the given name.
+    Header(&'a str),
+    /// The value of the query parameter with the given name.
+    QueryParam(&'a str),
+    /// The path of the URL.
+    Path,
+    /// The captured request body.
+    Body,
+}
+
+/// Redacts values of the request data attached to events.
+///
+/// This is implemented for closures taking the target and the value.
+pub trait Redactor: Send + Sync {
+    /// Redacts the value in place.
+    fn redact(&self, target: RedactionTarget<'_>, value: &mut String);
+}
+
+impl<F> Redactor for F
+where
+    F: Fn(RedactionTarget<'_>, &mut String) + Send + Sync,
+{
+    fn redact(&self, target: RedactionTarget<'_>, value: &mut String) {
+        self(target, value)
+    }
+}
+
+/// Filters headers, query parameters and JSON body keys by name.
+///
+/// Names are compared case-insensitively.  The values of matching headers and
+/// query parameters are replaced entirely.  Bodies that are valid JSON have the
+/// values of matching object keys replaced at any depth.
+#[derive(Clone, Debug)]
+pub struct DenylistKeys {
+    keys: Vec<String>,
+}
+
+impl DenylistKeys {
+    /// Creates a redactor filtering the given keys.
+    pub fn new<I, K>(keys: I) -> Self
+    where
+        I: IntoIterator<Item = K>,
+        K: Into<String>,
+    {
+        DenylistKeys {
+            keys: keys.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    fn is_denied(&self, key: &str) -> bool {
+        self.keys
+            .iter()
+            .any(|denied| denied.eq_ignore_ascii_case(key))
+    }
+
+    /// Filters denied keys in the JSON value, returning whether anything changed.
+    fn redact_json(&self, value: &mut Value) -> bool {
+        match value {
+            Value::Object(map) => {
+                let mut changed = false;
+                for (key, value) in map.iter_mut() {
+                    if self.is_denied(key) {
+                        *value = FILTERED.into();
+                        changed = true;
+                    } else {
+                        changed |= self.redact_json(value);
+                    }
+                }
+                changed
+            }
+            Value::Array(values) => {
+                let mut changed = false;
+                for value in values {
+                    changed |= self.redact_json(value);
+                }
+                changed
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Default for DenylistKeys {
+    /// Filters credentials such as `Authorization` and `Cookie` headers and
+    /// `password` or `token` parameters.
+    fn default() -> Self {
+        DenylistKeys::new(DEFAULT_DENYLIST.iter().copied())
+    }
+}
+
+impl Redactor for DenylistKeys {
+    fn redact(&self, target: RedactionTarget<'_>, value: &mut String) {
+        match target {
+            RedactionTarget::Header(key) | RedactionTarget::QueryParam(key) => {
+                if self.is_denied(key) {
+                    *value = FILTERED.into();
+                }
+            }
+            RedactionTarget::Body => {
+                if let Ok(mut json) = serde_json::from_str::<Value>(value) {
+                    if self.redact_json(&mut json) {
+                        *value = json.to_string();
+                    }
+                }
+            }
+            RedactionTarget::Path => {}
+        }
+    }
+}
+
+/// Filters all parts of values matching regular expressions.
+#[cfg(feature = "regex")]
+#[derive(Clone, Debug)]
+pub struct RegexPatterns {
+    patterns: Vec<regex::Regex>,
+}
+
+#[cfg(feature = "regex")]
+impl RegexPatterns {
+    /// Creates a redactor filtering matches of the given patterns.
+    pub fn new<I>(patterns: I) -> Self
+    where
+        I: IntoIterator<Item = regex::Regex>,
+    {
+        RegexPatterns {
+            patterns: patterns.into_iter().collect(),
+        }
+    }
+
+    /// Creates a redactor filtering numbers that look like credit card numbers.
+    pub fn credit_cards() -> Self {
+        RegexPatterns::new(vec![regex::Regex::new(r"\b(?:\d[ -]?){12,18}\d\b").unwrap()])
+    }
+
+    /// Creates a redactor filtering email addresses.
+    pub fn emails() -> Self {
+        RegexPatterns::new(vec![regex::Regex::new(
+            r"[A-Za-z0-9._%+-]+@[A-Za-z0-9-]+(?:\.[A-Za-z0-9-]+)*\.[A-Za-z]{2,}",
+        )
+        .unwrap()])
+    }
+}
+
+#[cfg(feature = "regex")]
+impl Redactor for RegexPatterns {
+    fn redact(&self, _target: RedactionTarget<'_>, value: &mut String) {
+        for pattern in &self.patterns {
+            if let std::borrow::Cow::Owned(redacted) = pattern.replace_all(value, FILTERED) {
+                *value = redacted;
+            }
+        }
+    }
+}
+
+/// Runs the value through the redactors in order.
+///
+/// The value is filtered if a redactor panics, as it may not have been redacted.
+pub(crate) fn redact(
+    redactors: &[Arc<dyn Redactor>],
+    target: RedactionTarget<'_>,
+    value: &mut String,
+) {
+    for redactor in redactors {
+        let redacted =
+            panic::catch_unwind(AssertUnwindSafe(|| redactor.redact(target, &mut *value)));
+        if redacted.is_err() {
+            *value = FILTERED.into();
+            return;
+        }
+    }
+}
+
+/// Runs the values of all parameters in a query string through the redactors.
+pub(crate) fn redact_query(redactors: &[Arc<dyn Redactor>], query: &str) -> String {
+    let mut redacted = String::with_capacity(query.len());
+    for (i, pair) in query.split('&').enumerate() {
+        if i > 0 {
+            redacted.push('&');
+        }
+        match pair.find('=') {
+            Some(index) => {
+                let (key, value) = (&pair[..index], &pair[index + 1..]);
+                let mut value = value.to_owned();
+                redact(redactors, RedactionTarget::QueryParam(key), &mut value);
+                redacted.push_str(key);
+                redacted.push('=');
+                redacted.push_str(&value);
+            }
+            None => redacted.push_str(pair),
+        }
+    }
+    redacted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pipeline(
+        redactors: Vec<Arc<dyn Redactor>>,
+        target: RedactionTarget<'_>,
+        value: &str,
+    ) -> String {
+        let mut value = value.to_owned();
+        redact(&redactors, target, &mut value);
+        value
+    }
+
+    #[test]
+    fn test_denylist_keys() {
+        let denylist: Vec<Arc<dyn Redactor>> = vec![Arc::new(DenylistKeys::default())];
+        let header = |name, value| pipeline(denylist.clone(), RedactionTarget::Header(name), value);
+        assert_eq!(header("Authorization", "Bearer secret"), FILTERED);
+        assert_eq!(header("cookie", "session=1"), FILTERED);
+        assert_eq!(header("accept", "text/html"), "text/html");
+
+        assert_eq!(
+            redact_query(&denylist, "page=2&token=abc&flag"),
+            "page=2&token=[Filtered]&flag"
+        );
+
+        let body = pipeline(
+            denylist.clone(),
+            RedactionTarget::Body,
+            r#"{"user":"jane","password":"hunter2","nested":[{"Token":"abc"}]}"#,
+        );
+        let body: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["user"], "jane");
+        assert_eq!(body["password"], FILTERED);
+        assert_eq!(body["nested"][0]["Token"], FILTERED);
+
+        // Bodies that are not JSON and paths are left alone
+        assert_eq!(
+            pipeline(denylist.clone(), RedactionTarget::Body, "password=1"),
+            "password=1"
+        );
+        assert_eq!(
+            pipeline(denylist, RedactionTarget::Path, "/token"),
+            "/token"
+        );
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn test_regex_patterns() {
+        let cards: Vec<Arc<dyn Redactor>> = vec![Arc::new(RegexPatterns::credit_cards())];
+        assert_eq!(
+            pipeline(
+                cards.clone(),
+                RedactionTarget::Body,
+                "card 4111 1111 1111 1111 ok"
+            ),
+            "card [Filtered] ok"
+        );
+        assert_eq!(
+            pipeline(cards, RedactionTarget::QueryParam("q"), "order-42"),
+            "order-42"
+        );
+
+        let emails: Vec<Arc<dyn Redactor>> = vec![Arc::new(RegexPatterns::emails())];
+        assert_eq!(
+            pipeline(
+                emails,
+                RedactionTarget::Path,
+                "/users/jane.doe@example.com/orders"
+            ),
+            "/users/[Filtered]/orders"
+        );
+    }
+
+    #[test]
+    fn test_ordering() {
+        let append = |suffix: &'static str| -> Arc<dyn Redactor> {
+            Arc::new(move |_: RedactionTarget<'_>, value: &mut String| value.push_str(suffix))
+        };
+        assert_eq!(
+            pipeline(vec![append("a"), append("b")], RedactionTarget::Path, "/"),
+            "/ab"
+        );
+
+        // Later redactors see the values filtered by earlier ones
+        let filtered: Arc<dyn Redactor> = Arc::new(DenylistKeys::new(vec!["secret"]));
+        let target = RedactionTarget::Header("secret");
+        assert_eq!(
+            pipeline(vec![append("!"), filtered.clone()], target, "x"),
+            FILTERED
+        );
+        assert_eq!(
+            pipeline(vec![filtered, append("!")], target, "x"),
+            "[Filtered]!"
+        );
+    }
+}
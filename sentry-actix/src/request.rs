@@ -0,0 +1,1793 @@
+//! Extraction of Sentry request data from actix requests.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use actix_web::dev::{Path, Payload, ServiceRequest, Url};
+use actix_web::http::{header, HeaderMap, HeaderValue, Method, StatusCode, Uri, Version};
+use actix_web::HttpRequest;
+use futures_util::StreamExt;
+
+use sentry_core::protocol::{Event, Map, Request, Value};
+use sentry_core::Hub;
+
+use crate::builder::SentryConfigError;
+use crate::clock::Clock;
+use crate::helpers::parse_ip;
+use crate::redact::{self, DenylistKeys, RedactionTarget, Redactor};
+
+/// Determines which CGI-style variables are added to the `env` of request data.
+///
+/// The peer address and the client IP are only added as `REMOTE_ADDR` and
+/// `CLIENT_IP` if `send_default_pii` is enabled, whatever the profile.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EnvProfile {
+    /// Only `CONTENT_LENGTH`, and the addresses of the client.
+    Minimal,
+    /// Additionally `SERVER_NAME`, `SERVER_PORT`, `HTTP_HOST`, `REQUEST_METHOD` and
+    /// `SCRIPT_NAME`.
+    ///
+    /// `SCRIPT_NAME` is the prefix of the path matched by the scopes enclosing the
+    /// middleware, so it is empty unless the middleware wraps a scope.
+    Standard,
+    /// Additionally `QUERY_STRING`, redacted like the query of the URL, and
+    /// `SERVER_PROTOCOL`.
+    ///
+    /// If `send_default_pii` is enabled, also `VIA_PROXY`, which is `true` if the
+    /// client IP reported by proxies differs from the IP of the peer, and with
+    /// [`IpPolicy::Full`], the port of the peer as `REMOTE_PORT`.  Requests
+    /// without a peer address, such as those received on Unix sockets, have
+    /// neither.
+    Full,
+}
+
+impl Default for EnvProfile {
+    fn default() -> Self {
+        EnvProfile::Minimal
+    }
+}
+
+/// Determines how the addresses of clients are recorded.
+///
+/// Addresses are only recorded if `send_default_pii` is enabled.  The policy
+/// applies to the `REMOTE_ADDR` and `CLIENT_IP` variables of the request data,
+/// and so to the IP address of the user derived from them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IpPolicy {
+    /// Records addresses as they are.
+    Full,
+    /// Records the network prefix of addresses only, `/24` for IPv4 and `/48`
+    /// for IPv6, without the port.  Malformed addresses are not recorded.
+    Truncated,
+    /// Never records addresses.
+    None,
+}
+
+impl IpPolicy {
+    /// Applies the policy to an address, which may include a port.
+    pub(crate) fn apply(self, addr: &str) -> Option<String> {
+        match self {
+            IpPolicy::Full => Some(addr.to_owned()),
+            IpPolicy::Truncated => parse_ip(addr).map(|ip| truncate_ip(ip).to_string()),
+            IpPolicy::None => None,
+        }
+    }
+}
+
+impl Default for IpPolicy {
+    fn default() -> Self {
+        IpPolicy::Full
+    }
+}
+
+/// Returns the `/24` network of IPv4 addresses and the `/48` network of IPv6 addresses.
+fn truncate_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            Ipv4Addr::new(a, b, c, 0).into()
+        }
+        IpAddr::V6(ip) => {
+            let [a, b, c, ..] = ip.segments();
+            Ipv6Addr::new(a, b, c, 0, 0, 0, 0, 0).into()
+        }
+    }
+}
+
+/// Determines the path in the URL of request data when middlewares rewrote it.
+///
+/// Middlewares wrapped inside the [`Sentry`](crate::Sentry) middleware, such as
+/// `NormalizePath`, can rewrite the path of requests before it reaches the
+/// service.  The path that is not in the URL is recorded as the `ORIGINAL_PATH`
+/// or `EFFECTIVE_PATH` variable of the `env`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RequestUrl {
+    /// The URL has the path of the request as it was received.
+    Original,
+    /// The URL has the path seen by the wrapped service.
+    Effective,
+}
+
+impl Default for RequestUrl {
+    fn default() -> Self {
+        RequestUrl::Effective
+    }
+}
+
+/// The default maximum length of the URL of request data.
+pub(crate) const DEFAULT_MAX_URL_LENGTH: usize = 2048;
+
+/// The default maximum number of distinct query keys in request data.
+pub(crate) const DEFAULT_MAX_QUERY_PAIRS: usize = 100;
+
+/// Caps on the URL of request data, so that crafted URLs do not bloat events.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct UrlLimits {
+    pub(crate) max_length: usize,
+    pub(crate) max_query_pairs: usize,
+}
+
+impl Default for UrlLimits {
+    fn default() -> Self {
+        UrlLimits {
+            max_length: DEFAULT_MAX_URL_LENGTH,
+            max_query_pairs: DEFAULT_MAX_QUERY_PAIRS,
+        }
+    }
+}
+
+/// Options controlling the request data created by [`to_sentry_request`].
+#[derive(Clone, Debug, Default)]
+pub struct RequestDataOptions {
+    /// Includes the peer address and the client IP as `REMOTE_ADDR` and `CLIENT_IP`.
+    ///
+    /// This corresponds to the `send_default_pii` client option honored by the middleware.
+    pub send_default_pii: bool,
+    /// The variables added to the `env` of the request data.
+    pub env_profile: EnvProfile,
+    /// How the addresses are recorded if `send_default_pii` is enabled.
+    pub ip_policy: IpPolicy,
+}
+
+/// Creates Sentry request data from an actix request.
+///
+/// This creates the same data the middleware attaches to events, for events that
+/// are captured manually without the middleware's event processor.
+///
+/// # Example
+///
+/// ```
+/// use actix_web::HttpRequest;
+/// use sentry::protocol::Event;
+/// use sentry_actix::{to_sentry_request, RequestDataOptions};
+///
+/// async fn index(req: HttpRequest) -> &'static str {
+///     let options = RequestDataOptions::default();
+///     sentry::capture_event(Event {
+///         message: Some("Manually captured".into()),
+///         request: Some(to_sentry_request(&req, &options)),
+///         ..Default::default()
+///     });
+///     "done"
+/// }
+/// ```
+pub fn to_sentry_request(request: &HttpRequest, options: &RequestDataOptions) -> Request {
+    RequestDataConfig::new()
+        .send_default_pii(options.send_default_pii)
+        .env_profile(options.env_profile)
+        .ip_policy(options.ip_policy)
+        .build_request(request)
+}
+
+/// The parts of a request that make up Sentry request data.
+///
+/// This is implemented for actix requests, and can be implemented for other
+/// types carrying HTTP-like metadata, such as the messages of a queue consumer,
+/// so that their events carry request data built like that of the middleware.
+/// See [`RequestDataConfig::build_request`].
+pub trait RequestLike {
+    /// Returns the method, as in `GET`.
+    fn method(&self) -> &str;
+
+    /// Returns the scheme of the URL, as in `https`.
+    fn scheme(&self) -> Cow<'_, str>;
+
+    /// Returns the host of the URL, including the port if any.
+    fn host(&self) -> Cow<'_, str>;
+
+    /// Returns the path of the URL.
+    fn path(&self) -> &str;
+
+    /// Returns the query of the URL, without the leading `?`.
+    fn query(&self) -> Option<&str> {
+        None
+    }
+
+    /// Returns the headers as pairs of names and values.
+    ///
+    /// Later values of repeated headers replace earlier ones.
+    fn headers(&self) -> Vec<(&str, &str)> {
+        Vec::new()
+    }
+
+    /// Returns the first value of a header, looked up case-insensitively.
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers()
+            .into_iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v)
+    }
+
+    /// Returns the protocol, as in `HTTP/1.1`, for the `SERVER_PROTOCOL` variable.
+    fn protocol(&self) -> Option<Cow<'_, str>> {
+        None
+    }
+
+    /// Returns the prefix of the path the handler is mounted at, for the
+    /// `SCRIPT_NAME` variable.
+    fn mount_path(&self) -> &str {
+        ""
+    }
+
+    /// Returns the address of the peer, for the `REMOTE_ADDR` variable.
+    fn peer_addr(&self) -> Option<String> {
+        None
+    }
+
+    /// Returns the address of the client if it differs from the peer, as reported
+    /// by proxies, for the `CLIENT_IP` variable.
+    fn client_ip(&self) -> Option<String> {
+        None
+    }
+}
+
+impl RequestLike for HttpRequest {
+    fn method(&self) -> &str {
+        HttpRequest::method(self).as_str()
+    }
+
+    fn scheme(&self) -> Cow<'_, str> {
+        Cow::Owned(self.connection_info().scheme().to_owned())
+    }
+
+    fn host(&self) -> Cow<'_, str> {
+        Cow::Owned(self.connection_info().host().to_owned())
+    }
+
+    fn path(&self) -> &str {
+        self.uri().path()
+    }
+
+    fn query(&self) -> Option<&str> {
+        self.uri().query()
+    }
+
+    fn headers(&self) -> Vec<(&str, &str)> {
+        HttpRequest::headers(self)
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.to_str().unwrap_or_default()))
+            .collect()
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        header_value(HttpRequest::headers(self), name)
+    }
+
+    fn protocol(&self) -> Option<Cow<'_, str>> {
+        Some(Cow::Owned(format!("{:?}", self.version())))
+    }
+
+    fn mount_path(&self) -> &str {
+        mount_path(self)
+    }
+
+    fn peer_addr(&self) -> Option<String> {
+        HttpRequest::peer_addr(self).map(|addr| addr.to_string())
+    }
+
+    fn client_ip(&self) -> Option<String> {
+        self.connection_info()
+            .realip_remote_addr()
+            .map(ToOwned::to_owned)
+    }
+}
+
+impl RequestLike for ServiceRequest {
+    fn method(&self) -> &str {
+        ServiceRequest::method(self).as_str()
+    }
+
+    fn scheme(&self) -> Cow<'_, str> {
+        Cow::Owned(self.connection_info().scheme().to_owned())
+    }
+
+    fn host(&self) -> Cow<'_, str> {
+        Cow::Owned(self.connection_info().host().to_owned())
+    }
+
+    fn path(&self) -> &str {
+        self.uri().path()
+    }
+
+    fn query(&self) -> Option<&str> {
+        self.uri().query()
+    }
+
+    fn headers(&self) -> Vec<(&str, &str)> {
+        ServiceRequest::headers(self)
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.to_str().unwrap_or_default()))
+            .collect()
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        header_value(ServiceRequest::headers(self), name)
+    }
+
+    fn protocol(&self) -> Option<Cow<'_, str>> {
+        Some(Cow::Owned(format!("{:?}", self.version())))
+    }
+
+    fn mount_path(&self) -> &str {
+        matched_prefix(self.match_info())
+    }
+
+    fn peer_addr(&self) -> Option<String> {
+        ServiceRequest::peer_addr(self).map(|addr| addr.to_string())
+    }
+
+    fn client_ip(&self) -> Option<String> {
+        self.connection_info()
+            .realip_remote_addr()
+            .map(ToOwned::to_owned)
+    }
+}
+
+impl<T: RequestLike + ?Sized> RequestLike for &T {
+    fn method(&self) -> &str {
+        (**self).method()
+    }
+
+    fn scheme(&self) -> Cow<'_, str> {
+        (**self).scheme()
+    }
+
+    fn host(&self) -> Cow<'_, str> {
+        (**self).host()
+    }
+
+    fn path(&self) -> &str {
+        (**self).path()
+    }
+
+    fn query(&self) -> Option<&str> {
+        (**self).query()
+    }
+
+    fn headers(&self) -> Vec<(&str, &str)> {
+        (**self).headers()
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        (**self).header(name)
+    }
+
+    fn protocol(&self) -> Option<Cow<'_, str>> {
+        (**self).protocol()
+    }
+
+    fn mount_path(&self) -> &str {
+        (**self).mount_path()
+    }
+
+    fn peer_addr(&self) -> Option<String> {
+        (**self).peer_addr()
+    }
+
+    fn client_ip(&self) -> Option<String> {
+        (**self).client_ip()
+    }
+}
+
+/// Builds Sentry request data from any [`RequestLike`] request.
+///
+/// The configuration mirrors the options of the middleware governing request
+/// data: `send_default_pii`, the [`EnvProfile`], the [`IpPolicy`] and the
+/// redactors, which default to [`DenylistKeys`].  The middleware builds its request data the same way, so
+/// that events captured outside of actix carry identical data.
+///
+/// # Example
+///
+/// ```
+/// use std::borrow::Cow;
+///
+/// use sentry_actix::{EnvProfile, RequestDataConfig, RequestLike};
+///
+/// struct Delivery {
+///     routing_key: String,
+///     headers: Vec<(String, String)>,
+/// }
+///
+/// impl RequestLike for Delivery {
+///     fn method(&self) -> &str {
+///         "CONSUME"
+///     }
+///     fn scheme(&self) -> Cow<'_, str> {
+///         "amqp".into()
+///     }
+///     fn host(&self) -> Cow<'_, str> {
+///         "broker".into()
+///     }
+///     fn path(&self) -> &str {
+///         &self.routing_key
+///     }
+///     fn headers(&self) -> Vec<(&str, &str)> {
+///         self.headers.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect()
+///     }
+/// }
+///
+/// let config = RequestDataConfig::new().env_profile(EnvProfile::Standard);
+/// let request = config.build_request(&Delivery {
+///     routing_key: "/orders/created".into(),
+///     headers: vec![("authorization".into(), "Bearer secret".into())],
+/// });
+/// assert_eq!(request.headers["authorization"], "[Filtered]");
+/// ```
+#[derive(Clone)]
+pub struct RequestDataConfig {
+    pub(crate) send_default_pii: bool,
+    pub(crate) env_profile: EnvProfile,
+    pub(crate) ip_policy: IpPolicy,
+    pub(crate) redactors: Arc<Vec<Arc<dyn Redactor>>>,
+    pub(crate) url_limits: UrlLimits,
+}
+
+impl RequestDataConfig {
+    /// Creates a configuration without PII, with the minimal profile and the
+    /// default redactor.
+    pub fn new() -> Self {
+        RequestDataConfig {
+            send_default_pii: false,
+            env_profile: EnvProfile::default(),
+            ip_policy: IpPolicy::default(),
+            redactors: Arc::new(vec![Arc::new(DenylistKeys::default())]),
+            url_limits: UrlLimits::default(),
+        }
+    }
+
+    /// Includes the peer address and the client IP as `REMOTE_ADDR` and `CLIENT_IP`.
+    pub fn send_default_pii(mut self, send_default_pii: bool) -> Self {
+        self.send_default_pii = send_default_pii;
+        self
+    }
+
+    /// Sets the variables added to the `env` of the request data.
+    pub fn env_profile(mut self, env_profile: EnvProfile) -> Self {
+        self.env_profile = env_profile;
+        self
+    }
+
+    /// Sets how the addresses are recorded if `send_default_pii` is enabled.
+    pub fn ip_policy(mut self, ip_policy: IpPolicy) -> Self {
+        self.ip_policy = ip_policy;
+        self
+    }
+
+    /// Appends a redactor to the redaction pipeline.
+    pub fn redactor<R: Redactor + 'static>(mut self, redactor: R) -> Self {
+        Arc::make_mut(&mut self.redactors).push(Arc::new(redactor));
+        self
+    }
+
+    /// Removes all redactors, including the default one.
+    pub fn clear_redactors(mut self) -> Self {
+        self.redactors = Arc::default();
+        self
+    }
+
+    /// Sets the maximum length of the URL, see
+    /// [`SentryBuilder::max_url_length`](crate::SentryBuilder::max_url_length).
+    pub fn max_url_length(mut self, max_length: usize) -> Self {
+        self.url_limits.max_length = max_length;
+        self
+    }
+
+    /// Sets the maximum number of distinct query keys, see
+    /// [`SentryBuilder::max_query_pairs`](crate::SentryBuilder::max_query_pairs).
+    pub fn max_query_pairs(mut self, max_pairs: usize) -> Self {
+        self.url_limits.max_query_pairs = max_pairs;
+        self
+    }
+
+    /// Builds the request data of a request.
+    pub fn build_request(&self, request: impl RequestLike) -> Request {
+        let ip_policy = if self.send_default_pii {
+            self.ip_policy
+        } else {
+            IpPolicy::None
+        };
+        build_request(
+            &request,
+            ip_policy,
+            self.env_profile,
+            &self.redactors,
+            self.url_limits,
+        )
+    }
+}
+
+impl Default for RequestDataConfig {
+    fn default() -> Self {
+        RequestDataConfig::new()
+    }
+}
+
+impl fmt::Debug for RequestDataConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RequestDataConfig")
+            .field("send_default_pii", &self.send_default_pii)
+            .field("env_profile", &self.env_profile)
+            .field("ip_policy", &self.ip_policy)
+            .field("redactors", &self.redactors.len())
+            .field("url_limits", &self.url_limits)
+            .finish()
+    }
+}
+
+/// The parts of a request the middleware builds request data from.
+///
+/// The headers, the URI and the scheme, host and addresses read from them share
+/// their buffers with the request where possible, so that requests without
+/// events do not pay for converting them to strings.  The request data
+/// is built the first time an event needs it, and reused for later events.
+pub(crate) struct CapturedRequest {
+    method: Method,
+    uri: Uri,
+    version: Version,
+    headers: HeaderMap,
+    scheme: RequestStr,
+    host: RequestStr,
+    // The length of the mount path if it is a prefix of the path of the URI
+    mount_path: Result<usize, String>,
+    // Formatted once by the connection info of the request
+    peer_addr: Option<String>,
+    client_ip: Option<RequestStr>,
+    full_data: bool,
+    ip_policy: IpPolicy,
+    env_profile: EnvProfile,
+    redactors: Arc<Vec<Arc<dyn Redactor>>>,
+    url_limits: UrlLimits,
+    built: Mutex<Option<Arc<Request>>>,
+}
+
+/// A string of a request, kept without copying it where possible.
+enum RequestStr {
+    Static(&'static str),
+    /// The value of a header, sharing the buffer of the request.
+    Header(HeaderValue),
+    /// The authority of the URI.
+    Authority,
+    /// The address of the peer.
+    PeerAddr,
+    Owned(String),
+}
+
+impl RequestStr {
+    /// Keeps a string of `request`, sharing the buffer of `header` if it is its value.
+    fn new(value: &str, request: &HttpRequest, header: header::HeaderName) -> Self {
+        match value {
+            "" => return RequestStr::Static(""),
+            "http" => return RequestStr::Static("http"),
+            "https" => return RequestStr::Static("https"),
+            _ => {}
+        }
+        if let Some(header) = request
+            .headers()
+            .get_all(header)
+            .find(|header| header.as_bytes() == value.as_bytes())
+        {
+            return RequestStr::Header(header.clone());
+        }
+        if request
+            .uri()
+            .authority()
+            .map(|authority| authority.as_str())
+            == Some(value)
+        {
+            return RequestStr::Authority;
+        }
+        if request.connection_info().remote_addr() == Some(value) {
+            return RequestStr::PeerAddr;
+        }
+        RequestStr::Owned(value.to_owned())
+    }
+
+    fn get<'a>(&'a self, request: &'a CapturedRequest) -> Cow<'a, str> {
+        match self {
+            RequestStr::Static(value) => Cow::Borrowed(value),
+            RequestStr::Header(value) => Cow::Borrowed(value.to_str().unwrap_or_default()),
+            RequestStr::Authority => Cow::Borrowed(
+                request
+                    .uri
+                    .authority()
+                    .map_or("", |authority| authority.as_str()),
+            ),
+            RequestStr::PeerAddr => Cow::Borrowed(request.peer_addr.as_deref().unwrap_or_default()),
+            RequestStr::Owned(value) => Cow::Borrowed(value),
+        }
+    }
+}
+
+impl CapturedRequest {
+    /// Keeps the parts of a request.
+    ///
+    /// Addresses are recorded following `ip_policy`, which must be [`IpPolicy::None`]
+    /// unless `send_default_pii` is enabled.  Without `full_data`, the request data
+    /// only holds the method, and only the method is kept.
+    pub(crate) fn new(
+        request: &HttpRequest,
+        full_data: bool,
+        ip_policy: IpPolicy,
+        env_profile: EnvProfile,
+        redactors: Arc<Vec<Arc<dyn Redactor>>>,
+        url_limits: UrlLimits,
+    ) -> Self {
+        let mut captured = CapturedRequest {
+            method: request.method().clone(),
+            uri: request.uri().clone(),
+            version: request.version(),
+            headers: HeaderMap::new(),
+            scheme: RequestStr::Static(""),
+            host: RequestStr::Static(""),
+            mount_path: Ok(0),
+            peer_addr: None,
+            client_ip: None,
+            full_data,
+            ip_policy,
+            env_profile,
+            redactors,
+            url_limits,
+            built: Mutex::new(None),
+        };
+        if !full_data {
+            return captured;
+        }
+        let info = request.connection_info();
+        // The header values share the buffer of the request
+        captured.headers = request.headers().clone();
+        captured.scheme = RequestStr::new(
+            info.scheme(),
+            request,
+            header::HeaderName::from_static("x-forwarded-proto"),
+        );
+        captured.host = RequestStr::new(info.host(), request, header::HOST);
+        let mount_path = mount_path(request);
+        captured.mount_path = if request.uri().path().starts_with(mount_path) {
+            Ok(mount_path.len())
+        } else {
+            Err(mount_path.to_owned())
+        };
+        captured.peer_addr = info.remote_addr().map(ToOwned::to_owned);
+        if ip_policy != IpPolicy::None {
+            captured.client_ip = info.realip_remote_addr().map(|client_ip| {
+                RequestStr::new(
+                    client_ip,
+                    request,
+                    header::HeaderName::from_static("x-forwarded-for"),
+                )
+            });
+        }
+        captured
+    }
+
+    /// Returns the request data, building it on first use.
+    pub(crate) fn request(&self) -> Arc<Request> {
+        let mut built = self.built.lock().unwrap();
+        built
+            .get_or_insert_with(|| {
+                Arc::new(if self.full_data {
+                    build_request(
+                        self,
+                        self.ip_policy,
+                        self.env_profile,
+                        &self.redactors,
+                        self.url_limits,
+                    )
+                } else {
+                    Request {
+                        method: Some(self.method.to_string()),
+                        ..Default::default()
+                    }
+                })
+            })
+            .clone()
+    }
+}
+
+impl RequestLike for CapturedRequest {
+    fn method(&self) -> &str {
+        self.method.as_str()
+    }
+
+    fn scheme(&self) -> Cow<'_, str> {
+        self.scheme.get(self)
+    }
+
+    fn host(&self) -> Cow<'_, str> {
+        self.host.get(self)
+    }
+
+    fn path(&self) -> &str {
+        self.uri.path()
+    }
+
+    fn query(&self) -> Option<&str> {
+        self.uri.query()
+    }
+
+    fn headers(&self) -> Vec<(&str, &str)> {
+        self.headers
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.to_str().unwrap_or_default()))
+            .collect()
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        header_value(&self.headers, name)
+    }
+
+    fn protocol(&self) -> Option<Cow<'_, str>> {
+        Some(Cow::Owned(format!("{:?}", self.version)))
+    }
+
+    fn mount_path(&self) -> &str {
+        match self.mount_path {
+            Ok(len) => &self.uri.path()[..len],
+            Err(ref mount_path) => mount_path,
+        }
+    }
+
+    fn peer_addr(&self) -> Option<String> {
+        self.peer_addr.clone()
+    }
+
+    fn client_ip(&self) -> Option<String> {
+        self.client_ip
+            .as_ref()
+            .map(|client_ip| client_ip.get(self).into_owned())
+    }
+}
+
+/// Builds the request data of a request, as done by the middleware.
+fn build_request<R: RequestLike + ?Sized>(
+    request: &R,
+    ip_policy: IpPolicy,
+    env_profile: EnvProfile,
+    redactors: &[Arc<dyn Redactor>],
+    url_limits: UrlLimits,
+) -> Request {
+    let query = request.query().map(|query| {
+        let query = limit_query(query, url_limits.max_query_pairs);
+        let mut query = redact::redact_query(redactors, &query);
+        truncate_marked(&mut query, url_limits.max_length);
+        query
+    });
+    let url = {
+        let (scheme, host) = (request.scheme(), request.host());
+        let mut path = request.path().to_owned();
+        redact::redact(redactors, RedactionTarget::Path, &mut path);
+        truncate_marked(&mut path, url_limits.max_length);
+        let query_len = query.as_ref().map_or(0, |query| query.len() + 1);
+        let mut url = String::with_capacity(scheme.len() + 3 + host.len() + path.len() + query_len);
+        url.push_str(&scheme);
+        url.push_str("://");
+        url.push_str(&host);
+        url.push_str(&path);
+        if let Some(ref query) = query {
+            url.push('?');
+            url.push_str(query);
+        }
+        truncate_marked(&mut url, url_limits.max_length);
+        url
+    };
+
+    let mut sentry_req = Request {
+        url: url.parse().ok(),
+        method: Some(request.method().to_owned()),
+        headers: request
+            .headers()
+            .into_iter()
+            .map(|(k, v)| {
+                let mut value = v.to_owned();
+                redact::redact(redactors, RedactionTarget::Header(k), &mut value);
+                (k.to_owned(), value)
+            })
+            .collect(),
+        ..Default::default()
+    };
+
+    if let Some(length) = request
+        .header(header::CONTENT_LENGTH.as_str())
+        .and_then(|length| length.parse::<usize>().ok())
+    {
+        sentry_req
+            .env
+            .insert("CONTENT_LENGTH".into(), length.to_string());
+    }
+    if env_profile != EnvProfile::Minimal {
+        add_server_env(request, &mut sentry_req);
+    }
+    if env_profile == EnvProfile::Full {
+        if let Some(query) = query {
+            sentry_req.env.insert("QUERY_STRING".into(), query);
+        }
+        if let Some(protocol) = request.protocol() {
+            sentry_req
+                .env
+                .insert("SERVER_PROTOCOL".into(), protocol.into_owned());
+        }
+    }
+
+    // If PII is enabled, include the peer address and the client IP if it differs
+    if ip_policy != IpPolicy::None {
+        let raw_peer_addr = request.peer_addr();
+        let peer_addr = raw_peer_addr
+            .as_ref()
+            .and_then(|addr| ip_policy.apply(addr));
+        if let Some(ref peer_addr) = peer_addr {
+            sentry_req
+                .env
+                .insert("REMOTE_ADDR".into(), peer_addr.clone());
+        }
+        if let Some(client_ip) = request.client_ip().and_then(|addr| ip_policy.apply(&addr)) {
+            if peer_addr.as_ref() != Some(&client_ip) {
+                sentry_req.env.insert("CLIENT_IP".into(), client_ip);
+            }
+        }
+        if env_profile == EnvProfile::Full {
+            add_peer_env(
+                request,
+                raw_peer_addr.as_deref(),
+                ip_policy,
+                &mut sentry_req,
+            );
+        }
+    };
+
+    sentry_req
+}
+
+/// Collapses the repeated keys of a query and caps its number of distinct keys.
+///
+/// Repeated keys are kept once, at their first position, with the number of
+/// their values, as in `id=[300 values]`.  Keys beyond `max_pairs` are dropped
+/// and marked with a trailing `...` pair.
+fn limit_query(query: &str, max_pairs: usize) -> Cow<'_, str> {
+    let mut pairs: Vec<(&str, &str, usize)> = Vec::new();
+    let mut indices: HashMap<&str, usize> = HashMap::new();
+    let mut dropped = false;
+    for pair in query.split('&') {
+        let key = pair.split('=').next().unwrap_or_default();
+        match indices.get(key) {
+            Some(&index) => pairs[index].2 += 1,
+            None if pairs.len() < max_pairs => {
+                indices.insert(key, pairs.len());
+                pairs.push((key, pair, 1));
+            }
+            None => dropped = true,
+        }
+    }
+    if !dropped && pairs.iter().all(|&(_, _, count)| count == 1) {
+        return Cow::Borrowed(query);
+    }
+
+    let mut limited = String::new();
+    for (key, pair, count) in pairs {
+        if !limited.is_empty() {
+            limited.push('&');
+        }
+        if count == 1 {
+            limited.push_str(pair);
+        } else {
+            limited.push_str(&format!("{}=%5B{}%20values%5D", key, count));
+        }
+    }
+    if dropped {
+        if !limited.is_empty() {
+            limited.push('&');
+        }
+        limited.push_str("...");
+    }
+    Cow::Owned(limited)
+}
+
+/// Truncates a string to at most `max_length` bytes, marked with a trailing `...`
+/// as far as it fits.
+fn truncate_marked(value: &mut String, max_length: usize) {
+    if value.len() <= max_length {
+        return;
+    }
+    let marker = &"..."[..max_length.min(3)];
+    let mut end = max_length - marker.len();
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+    value.truncate(end);
+    value.push_str(marker);
+}
+
+/// Returns the first value of a header of a header map.
+fn header_value<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get(name)
+        .map(|value| value.to_str().unwrap_or_default())
+}
+
+/// Adds the variables describing the peer of the [`EnvProfile::Full`] profile.
+fn add_peer_env<R: RequestLike + ?Sized>(
+    request: &R,
+    peer_addr: Option<&str>,
+    ip_policy: IpPolicy,
+    sentry_req: &mut Request,
+) {
+    let peer_ip = match peer_addr.and_then(parse_ip) {
+        Some(peer_ip) => peer_ip,
+        None => return,
+    };
+    // The truncated address has no port either
+    if ip_policy == IpPolicy::Full {
+        if let Ok(addr) = peer_addr.unwrap_or_default().parse::<SocketAddr>() {
+            sentry_req
+                .env
+                .insert("REMOTE_PORT".into(), addr.port().to_string());
+        }
+    }
+    let client_ip = request.client_ip().as_deref().and_then(parse_ip);
+    let via_proxy = client_ip.map_or(false, |client_ip| client_ip != peer_ip);
+    sentry_req
+        .env
+        .insert("VIA_PROXY".into(), via_proxy.to_string());
+}
+
+/// Adds the variables of the [`EnvProfile::Standard`] profile.
+fn add_server_env<R: RequestLike + ?Sized>(request: &R, sentry_req: &mut Request) {
+    let env = &mut sentry_req.env;
+    env.insert("REQUEST_METHOD".into(), request.method().into());
+    if let Some(host) = request.header(header::HOST.as_str()) {
+        env.insert("HTTP_HOST".into(), host.into());
+    }
+
+    let (scheme, host) = (request.scheme(), request.host());
+    let (name, port) = split_host(&host);
+    let port = port.or_else(|| match scheme.as_ref() {
+        "https" => Some("443"),
+        "http" => Some("80"),
+        _ => None,
+    });
+    env.insert("SERVER_NAME".into(), name.into());
+    if let Some(port) = port {
+        env.insert("SERVER_PORT".into(), port.into());
+    }
+
+    env.insert("SCRIPT_NAME".into(), request.mount_path().into());
+}
+
+/// Records the path seen by the service of a request whose path was rewritten.
+pub(crate) fn apply_rewritten_path(
+    request: &mut Request,
+    original: &str,
+    effective: &str,
+    request_url: RequestUrl,
+    redactors: &[Arc<dyn Redactor>],
+) {
+    let redacted = |path: &str| {
+        let mut path = path.to_owned();
+        redact::redact(redactors, RedactionTarget::Path, &mut path);
+        path
+    };
+    match request_url {
+        RequestUrl::Original => {
+            request
+                .env
+                .insert("EFFECTIVE_PATH".into(), redacted(effective));
+        }
+        RequestUrl::Effective => {
+            if let Some(url) = request.url.as_mut() {
+                url.set_path(&redacted(effective));
+            }
+            request
+                .env
+                .insert("ORIGINAL_PATH".into(), redacted(original));
+        }
+    }
+}
+
+/// Returns the prefix of the path matched by the scopes enclosing the current service.
+fn mount_path(request: &HttpRequest) -> &str {
+    matched_prefix(request.match_info())
+}
+
+/// Returns the prefix of the path matched so far by the router.
+fn matched_prefix(match_info: &Path<Url>) -> &str {
+    let path = match_info.get_ref().path();
+    let unprocessed = match_info.unprocessed();
+    path[..path.len().saturating_sub(unprocessed.len())].trim_end_matches('/')
+}
+
+/// Describes how a request was routed, for the `routing` context of events.
+///
+/// The pattern and name are looked up in the resource map of the app, which does
+/// not evaluate guards.  Requests without a matching resource are marked as
+/// handled by a `default_service`.
+pub(crate) fn routing_context(
+    request: &HttpRequest,
+    redactors: &[Arc<dyn Redactor>],
+) -> Map<String, Value> {
+    let mut context = Map::new();
+    let pattern = request.match_pattern();
+    context.insert("default_service".into(), pattern.is_none().into());
+    if let Some(pattern) = pattern {
+        context.insert("pattern".into(), pattern.into());
+    }
+    if let Some(name) = request.match_name() {
+        context.insert("name".into(), name.into());
+    }
+    context.insert("mount_path".into(), mount_path(request).into());
+    let mut path = request.uri().path().to_owned();
+    redact::redact(redactors, RedactionTarget::Path, &mut path);
+    context.insert("path".into(), path.into());
+    if let Some(query) = request.uri().query() {
+        context.insert(
+            "query".into(),
+            redact::redact_query(redactors, query).into(),
+        );
+    }
+    context
+}
+
+/// Splits a host into its name and port, if any, handling bracketed IPv6 addresses.
+fn split_host(host: &str) -> (&str, Option<&str>) {
+    let name_end = match host.strip_prefix('[') {
+        Some(rest) => rest.find(']').map_or(host.len(), |end| end + 2),
+        None => host.find(':').unwrap_or(host.len()),
+    };
+    let (name, rest) = host.split_at(name_end);
+    (name, rest.strip_prefix(':').filter(|port| !port.is_empty()))
+}
+
+/// Returns the length declared in the `Content-Length` header, if it is valid.
+pub(crate) fn content_length(request: &HttpRequest) -> Option<usize> {
+    request
+        .headers()
+        .get(header::CONTENT_LENGTH)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+}
+
+/// The bytes read by the handler from a tracked request payload.
+pub(crate) struct TrackedPayload {
+    read: AtomicUsize,
+    captured: Mutex<Vec<u8>>,
+    capture_limit: usize,
+}
+
+impl TrackedPayload {
+    fn record(&self, chunk: &[u8]) {
+        let read = self.read.fetch_add(chunk.len(), Ordering::Relaxed);
+        if read < self.capture_limit {
+            let remaining = self.capture_limit - read;
+            let mut captured = self.captured.lock().unwrap();
+            captured.extend_from_slice(&chunk[..chunk.len().min(remaining)]);
+        }
+    }
+}
+
+/// Wraps the request payload so that the bytes read by the handler are counted,
+/// and captured up to the given limit.
+pub(crate) fn track_payload(
+    payload: Payload,
+    capture_limit: usize,
+) -> (Payload, Arc<TrackedPayload>) {
+    let tracked = Arc::new(TrackedPayload {
+        read: AtomicUsize::new(0),
+        captured: Mutex::new(Vec::new()),
+        capture_limit,
+    });
+    let recorder = tracked.clone();
+    let payload = payload.inspect(move |chunk| {
+        if let Ok(bytes) = chunk {
+            recorder.record(bytes);
+        }
+    });
+    (Payload::Stream(Box::pin(payload)), tracked)
+}
+
+/// The state of a request handled by the middleware.
+pub(crate) struct RequestState {
+    pub(crate) hub: Arc<Hub>,
+    pub(crate) clock: Arc<dyn Clock>,
+    pub(crate) started: Instant,
+    pub(crate) full_data: bool,
+    pub(crate) body: Arc<RequestBody>,
+    pub(crate) data: Arc<CapturedRequest>,
+    pub(crate) route: String,
+    pub(crate) request_line: Option<String>,
+    pub(crate) remote_addr: Option<String>,
+    pub(crate) routing: Option<Map<String, Value>>,
+}
+
+/// A token of the request summary format.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum SummaryToken {
+    Literal(String),
+    RemoteAddr,
+    RequestLine,
+    Status,
+    DurationMs,
+}
+
+/// Parses a request summary format into tokens.
+pub(crate) fn parse_summary_format(format: &str) -> Result<Vec<SummaryToken>, SentryConfigError> {
+    let mut tokens = Vec::new();
+    let mut literal = String::new();
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            literal.push(c);
+            continue;
+        }
+        let token = match chars.next() {
+            Some('%') => {
+                literal.push('%');
+                continue;
+            }
+            Some('a') => SummaryToken::RemoteAddr,
+            Some('r') => SummaryToken::RequestLine,
+            Some('s') => SummaryToken::Status,
+            Some('D') => SummaryToken::DurationMs,
+            Some(other) => {
+                return Err(SentryConfigError::UnknownSummaryToken(format!(
+                    "%{}",
+                    other
+                )))
+            }
+            None => return Err(SentryConfigError::UnknownSummaryToken("%".into())),
+        };
+        if !literal.is_empty() {
+            tokens.push(SummaryToken::Literal(std::mem::take(&mut literal)));
+        }
+        tokens.push(token);
+    }
+    if !literal.is_empty() {
+        tokens.push(SummaryToken::Literal(literal));
+    }
+    Ok(tokens)
+}
+
+/// Renders the request summary for a request that resulted in the given status.
+pub(crate) fn render_summary(
+    tokens: &[SummaryToken],
+    request: &RequestState,
+    status: StatusCode,
+) -> String {
+    let mut summary = String::new();
+    for token in tokens {
+        match token {
+            SummaryToken::Literal(literal) => summary.push_str(literal),
+            SummaryToken::RemoteAddr => {
+                summary.push_str(request.remote_addr.as_deref().unwrap_or("-"))
+            }
+            SummaryToken::RequestLine => {
+                summary.push_str(request.request_line.as_deref().unwrap_or("-"))
+            }
+            SummaryToken::Status => summary.push_str(status.as_str()),
+            SummaryToken::DurationMs => {
+                let duration = request.clock.since(request.started);
+                summary.push_str(&format!("{:.3}", duration.as_secs_f64() * 1000.0))
+            }
+        }
+    }
+    summary
+}
+
+/// Returns the first line of the request, as in `GET /items?page=2 HTTP/1.1`.
+pub(crate) fn request_line(request: &HttpRequest) -> String {
+    let path = request
+        .uri()
+        .path_and_query()
+        .map_or("/", |path| path.as_str());
+    format!("{} {} {:?}", request.method(), path, request.version())
+}
+
+/// The request payload, as declared and as read by the handler.
+#[derive(Default)]
+pub(crate) struct RequestBody {
+    pub(crate) declared: Option<usize>,
+    pub(crate) tracked: Option<Arc<TrackedPayload>>,
+    pub(crate) report_observed: bool,
+}
+
+impl RequestBody {
+    /// Marks the event if the handler did not read the full declared payload.
+    ///
+    /// This happens when clients abort uploads, for instance after sending an
+    /// `Expect: 100-continue` header, or when handlers fail before reading the body.
+    pub(crate) fn apply_incomplete_to_event(&self, event: &mut Event<'static>) {
+        let (declared, tracked) = match (self.declared, self.tracked.as_ref()) {
+            (Some(declared), Some(tracked)) => (declared, tracked),
+            _ => return,
+        };
+        let read = tracked.read.load(Ordering::Relaxed);
+        if read < declared {
+            event
+                .tags
+                .insert("incomplete_request_body".into(), "true".into());
+            event
+                .extra
+                .insert("request_body_read_bytes".into(), read.into());
+            event
+                .extra
+                .insert("request_body_declared_bytes".into(), declared.into());
+        }
+    }
+
+    /// Parses the captured body, if any, with `f`.
+    pub(crate) fn parse_captured<R>(&self, f: impl FnOnce(&[u8]) -> Option<R>) -> Option<R> {
+        let captured = self.tracked.as_ref()?.captured.lock().unwrap();
+        if captured.is_empty() {
+            return None;
+        }
+        f(&captured)
+    }
+
+    /// Records the payload size as the `request_body_bytes` extra and attaches
+    /// the captured body, passed through the redactors, as request data.
+    ///
+    /// Observed bytes take precedence when the payload size is tracked, and are
+    /// also recorded as the `http.request_content_length` extra, flagged with
+    /// `http.request_content_length_truncated` if fewer than declared were read.
+    pub(crate) fn apply_to_event(
+        &self,
+        event: &mut Event<'static>,
+        redactors: &[Arc<dyn Redactor>],
+    ) {
+        let tracked = self.tracked.as_ref();
+        let size = match tracked {
+            Some(tracked) if self.report_observed => Some(tracked.read.load(Ordering::Relaxed)),
+            _ => self.declared,
+        };
+        if let Some(size) = size {
+            event
+                .extra
+                .entry("request_body_bytes".into())
+                .or_insert_with(|| size.into());
+        }
+        if let Some(tracked) = tracked {
+            let read = tracked.read.load(Ordering::Relaxed);
+            event
+                .extra
+                .insert("http.request_content_length".into(), read.into());
+            if self.declared.map_or(false, |declared| read < declared) {
+                event
+                    .extra
+                    .insert("http.request_content_length_truncated".into(), true.into());
+            }
+        }
+
+        let tracked = match tracked {
+            Some(tracked) => tracked,
+            None => return,
+        };
+        let captured = tracked.captured.lock().unwrap();
+        if captured.is_empty() {
+            return;
+        }
+        if let Some(request) = event.request.as_mut() {
+            if request.data.is_none() {
+                let mut data = String::from_utf8_lossy(&captured).into_owned();
+                redact::redact(redactors, RedactionTarget::Body, &mut data);
+                request.data = Some(data);
+                if tracked.read.load(Ordering::Relaxed) > captured.len() {
+                    event
+                        .extra
+                        .insert("request_body_truncated".into(), true.into());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+    use crate::clock::SystemClock;
+
+    /// Ensures the strings of captured requests share the buffers of the request.
+    #[test]
+    fn test_captured_request_shares_strings() {
+        let capture = |req: &HttpRequest, full_data| {
+            CapturedRequest::new(
+                req,
+                full_data,
+                IpPolicy::Full,
+                EnvProfile::Minimal,
+                Arc::default(),
+                UrlLimits::default(),
+            )
+        };
+        let req = TestRequest::get()
+            .uri("/items/42")
+            .insert_header((header::HOST, "api.example.com"))
+            .insert_header(("x-forwarded-proto", "https"))
+            .insert_header(("x-forwarded-for", "203.0.113.7"))
+            .peer_addr("10.0.0.1:4711".parse().unwrap())
+            .to_http_request();
+
+        let captured = capture(&req, true);
+        assert!(matches!(captured.scheme, RequestStr::Static("https")));
+        assert!(matches!(captured.host, RequestStr::Header(_)));
+        assert!(matches!(captured.client_ip, Some(RequestStr::Header(_))));
+        assert_eq!(captured.mount_path, Ok(0));
+        assert_eq!(captured.host(), "api.example.com");
+        assert_eq!(captured.client_ip().as_deref(), Some("203.0.113.7"));
+        let data = captured.request();
+        assert_eq!(
+            data.url.as_ref().map(|url| url.as_str()),
+            Some("https://api.example.com/items/42")
+        );
+        assert_eq!(data.env["CLIENT_IP"], "203.0.113.7");
+        assert_eq!(captured.header("Host"), Some("api.example.com"));
+
+        // Without proxies, the client address is the peer address formatted once
+        let req = TestRequest::get()
+            .peer_addr("10.0.0.1:4711".parse().unwrap())
+            .to_http_request();
+        let captured = capture(&req, true);
+        assert!(matches!(captured.client_ip, Some(RequestStr::PeerAddr)));
+        assert_eq!(captured.peer_addr.as_deref(), Some("10.0.0.1:4711"));
+        assert_eq!(captured.client_ip().as_deref(), Some("10.0.0.1:4711"));
+
+        let captured = capture(&req, false);
+        assert!(captured.headers.is_empty());
+        assert!(captured.client_ip.is_none());
+        assert!(captured.request().url.is_none());
+    }
+
+    #[test]
+    fn test_parse_summary_format() {
+        assert_eq!(
+            parse_summary_format("%a \"%r\" %s %Dms 100%%").unwrap(),
+            vec![
+                SummaryToken::RemoteAddr,
+                SummaryToken::Literal(" \"".into()),
+                SummaryToken::RequestLine,
+                SummaryToken::Literal("\" ".into()),
+                SummaryToken::Status,
+                SummaryToken::Literal(" ".into()),
+                SummaryToken::DurationMs,
+                SummaryToken::Literal("ms 100%".into()),
+            ]
+        );
+        assert_eq!(parse_summary_format("").unwrap(), vec![]);
+    }
+
+    /// Ensures the duration is rendered in milliseconds.
+    #[test]
+    fn test_render_summary() {
+        let tokens = parse_summary_format("%Dms").unwrap();
+        let request = RequestState {
+            hub: Arc::new(Hub::new(None, Default::default())),
+            clock: Arc::new(SystemClock),
+            started: Instant::now(),
+            full_data: true,
+            body: Default::default(),
+            data: Arc::new(CapturedRequest::new(
+                &TestRequest::default().to_http_request(),
+                true,
+                IpPolicy::None,
+                EnvProfile::Minimal,
+                Arc::default(),
+                UrlLimits::default(),
+            )),
+            route: "/".into(),
+            request_line: None,
+            remote_addr: None,
+            routing: None,
+        };
+        let summary = render_summary(&tokens, &request, StatusCode::OK);
+        assert!(summary.ends_with("ms"));
+        assert!(summary.trim_end_matches("ms").parse::<f64>().is_ok());
+    }
+
+    #[test]
+    fn test_request_line_and_content_length() {
+        let req = TestRequest::post()
+            .uri("/items?page=2")
+            .insert_header((header::CONTENT_LENGTH, "42"))
+            .to_http_request();
+        assert_eq!(request_line(&req), "POST /items?page=2 HTTP/1.1");
+        assert_eq!(content_length(&req), Some(42));
+
+        let req = TestRequest::get()
+            .insert_header((header::CONTENT_LENGTH, "invalid"))
+            .to_http_request();
+        assert_eq!(content_length(&req), None);
+    }
+
+    fn sentry_request(
+        req: &HttpRequest,
+        ip_policy: IpPolicy,
+        env_profile: EnvProfile,
+        redactors: &[Arc<dyn Redactor>],
+    ) -> Request {
+        let captured = CapturedRequest::new(
+            req,
+            true,
+            ip_policy,
+            env_profile,
+            Arc::new(redactors.to_vec()),
+            UrlLimits::default(),
+        );
+        (*captured.request()).clone()
+    }
+
+    #[test]
+    fn test_sentry_request_from_http() {
+        let req = TestRequest::get()
+            .uri("/items/42?page=2&token=abc")
+            .insert_header(("authorization", "Bearer abc"))
+            .peer_addr("198.51.100.1:8080".parse().unwrap())
+            .to_http_request();
+        let redactors: [Arc<dyn Redactor>; 1] = [Arc::new(DenylistKeys::default())];
+
+        let request = sentry_request(&req, IpPolicy::None, EnvProfile::Minimal, &redactors);
+        assert_eq!(request.method.as_deref(), Some("GET"));
+        let url = request.url.unwrap();
+        assert_eq!(url.path(), "/items/42");
+        assert_eq!(url.query(), Some("page=2&token=[Filtered]"));
+        assert_eq!(request.headers["authorization"], "[Filtered]");
+        assert!(request.env.is_empty());
+
+        // The peer address is only included with PII enabled
+        let request = sentry_request(&req, IpPolicy::Full, EnvProfile::Minimal, &[]);
+        assert_eq!(request.env["REMOTE_ADDR"], "198.51.100.1:8080");
+        assert_eq!(request.headers["authorization"], "Bearer abc");
+    }
+
+    #[test]
+    fn test_peer_env() {
+        let config = RequestDataConfig::new()
+            .send_default_pii(true)
+            .env_profile(EnvProfile::Full);
+        let forwarded = TestRequest::get()
+            .insert_header(("x-forwarded-for", "203.0.113.7"))
+            .peer_addr("198.51.100.1:8080".parse().unwrap())
+            .to_http_request();
+        let request = config.build_request(&forwarded);
+        assert_eq!(request.env["REMOTE_ADDR"], "198.51.100.1:8080");
+        assert_eq!(request.env["REMOTE_PORT"], "8080");
+        assert_eq!(request.env["CLIENT_IP"], "203.0.113.7");
+        assert_eq!(request.env["VIA_PROXY"], "true");
+
+        let direct = TestRequest::get()
+            .peer_addr("198.51.100.1:8080".parse().unwrap())
+            .to_http_request();
+        let request = config.build_request(&direct);
+        assert_eq!(request.env["REMOTE_PORT"], "8080");
+        assert_eq!(request.env["VIA_PROXY"], "false");
+
+        // The port is not recorded with truncated addresses
+        let request = config
+            .clone()
+            .ip_policy(IpPolicy::Truncated)
+            .build_request(&forwarded);
+        assert!(!request.env.contains_key("REMOTE_PORT"));
+        assert_eq!(request.env["VIA_PROXY"], "true");
+
+        // Requests on Unix sockets have no peer address
+        let unix = TestRequest::get()
+            .insert_header(("x-forwarded-for", "203.0.113.7"))
+            .to_http_request();
+        let request = config.build_request(&unix);
+        assert!(!request.env.contains_key("REMOTE_PORT"));
+        assert!(!request.env.contains_key("VIA_PROXY"));
+
+        // Neither is recorded without PII or with other profiles
+        let request = config
+            .clone()
+            .send_default_pii(false)
+            .build_request(&forwarded);
+        assert!(!request.env.contains_key("VIA_PROXY"));
+        let request = config
+            .env_profile(EnvProfile::Standard)
+            .build_request(&forwarded);
+        assert!(!request.env.contains_key("VIA_PROXY"));
+    }
+
+    #[test]
+    fn test_limit_query() {
+        assert_eq!(limit_query("a=1&b=2", 2), "a=1&b=2");
+        assert_eq!(
+            limit_query("a=1&b=2&a=3&a=4&c", 100),
+            "a=%5B3%20values%5D&b=2&c"
+        );
+        assert_eq!(
+            limit_query("a=1&b=2&c=3&b=4", 2),
+            "a=1&b=%5B2%20values%5D&..."
+        );
+        assert_eq!(limit_query("a=1", 0), "...");
+    }
+
+    /// Ensures truncated values never exceed their maximum length.
+    #[test]
+    fn test_truncate_marked() {
+        let truncate = |value: &str, max_length| {
+            let mut value = value.to_owned();
+            truncate_marked(&mut value, max_length);
+            value
+        };
+        assert_eq!(truncate("/items", 6), "/items");
+        assert_eq!(truncate("/items/42", 6), "/it...");
+        assert_eq!(truncate("/élan", 5), "/...");
+        assert_eq!(truncate("/items", 2), "..");
+        assert_eq!(truncate("/items", 0), "");
+
+        let req = TestRequest::get().uri("/items?a=1&b=2").to_http_request();
+        let request = RequestDataConfig::new()
+            .env_profile(EnvProfile::Full)
+            .max_url_length(1)
+            .max_query_pairs(0)
+            .build_request(&req);
+        assert_eq!(request.env["QUERY_STRING"], ".");
+        assert!(request.url.is_none());
+    }
+
+    /// Ensures crafted URLs do not bloat serialized events.
+    #[test]
+    fn test_adversarial_urls() {
+        let long_value = "x".repeat(50 * 1024);
+        let repeated = vec!["a=1"; 500].join("&");
+        let distinct: Vec<_> = (0..5000).map(|i| format!("k{}=v", i)).collect();
+        let uris = [
+            format!("/search?q={}", long_value),
+            format!("/search?{}", repeated),
+            format!("/search?{}", distinct.join("&")),
+            format!("/{}?q=1", long_value),
+        ];
+        for uri in &uris {
+            let req = TestRequest::get().uri(uri).to_http_request();
+            let request = sentry_request(&req, IpPolicy::None, EnvProfile::Full, &[]);
+            let url = request.url.as_ref().expect("URL should be parsed.");
+            assert!(url.as_str().len() <= DEFAULT_MAX_URL_LENGTH, "{}", url);
+            assert!(request.env["QUERY_STRING"].len() <= DEFAULT_MAX_URL_LENGTH);
+
+            let event = Event {
+                request: Some(request),
+                ..Default::default()
+            };
+            let size = serde_json::to_string(&event).unwrap().len();
+            assert!(size < 3 * DEFAULT_MAX_URL_LENGTH, "{} bytes", size);
+        }
+
+        let req = TestRequest::get().uri(&uris[1]).to_http_request();
+        let request = sentry_request(&req, IpPolicy::None, EnvProfile::Minimal, &[]);
+        assert_eq!(request.url.unwrap().query(), Some("a=%5B500%20values%5D"));
+
+        let req = TestRequest::get().uri(&uris[0]).to_http_request();
+        let request = RequestDataConfig::new()
+            .max_url_length(64)
+            .build_request(&req);
+        let url = request.url.unwrap();
+        assert_eq!(url.as_str().len(), 64);
+        assert!(url.as_str().ends_with("xxx..."));
+    }
+
+    #[test]
+    fn test_env_profiles() {
+        let req = TestRequest::get()
+            .uri("/items?page=2&token=abc")
+            .insert_header((header::HOST, "example.com"))
+            .to_http_request();
+        let redactors: [Arc<dyn Redactor>; 1] = [Arc::new(DenylistKeys::default())];
+
+        let request = sentry_request(&req, IpPolicy::None, EnvProfile::Full, &redactors);
+        let env: Vec<_> = request
+            .env
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        assert_eq!(
+            env,
+            [
+                ("HTTP_HOST", "example.com"),
+                ("QUERY_STRING", "page=2&token=[Filtered]"),
+                ("REQUEST_METHOD", "GET"),
+                ("SCRIPT_NAME", ""),
+                ("SERVER_NAME", "example.com"),
+                ("SERVER_PORT", "80"),
+                ("SERVER_PROTOCOL", "HTTP/1.1"),
+            ]
+        );
+    }
+
+    /// A request of a queue consumer, equivalent to the actix request of the test.
+    struct Delivery {
+        headers: Vec<(String, String)>,
+    }
+
+    impl RequestLike for Delivery {
+        fn method(&self) -> &str {
+            "POST"
+        }
+
+        fn scheme(&self) -> Cow<'_, str> {
+            "http".into()
+        }
+
+        fn host(&self) -> Cow<'_, str> {
+            "example.com".into()
+        }
+
+        fn path(&self) -> &str {
+            "/orders/7"
+        }
+
+        fn query(&self) -> Option<&str> {
+            Some("token=abc")
+        }
+
+        fn headers(&self) -> Vec<(&str, &str)> {
+            self.headers
+                .iter()
+                .map(|(k, v)| (k.as_str(), v.as_str()))
+                .collect()
+        }
+
+        fn protocol(&self) -> Option<Cow<'_, str>> {
+            Some("HTTP/1.1".into())
+        }
+
+        fn peer_addr(&self) -> Option<String> {
+            Some("198.51.100.1:8080".into())
+        }
+    }
+
+    /// Ensures custom requests get the same data as the middleware attaches.
+    #[test]
+    fn test_build_request_like_middleware() {
+        use actix_web::test::{call_service, init_service};
+        use actix_web::{web, App};
+        use futures::executor::block_on;
+
+        use crate::Sentry;
+
+        let middleware = || {
+            Sentry::builder()
+                .with_hub(Hub::current())
+                .env_profile(EnvProfile::Full)
+                .finish()
+        };
+        let events = sentry::test::with_captured_events_options(
+            || {
+                block_on(async {
+                    async fn failing() -> Result<String, actix_web::Error> {
+                        Err(actix_web::error::ErrorInternalServerError("Test Error"))
+                    }
+
+                    let app = init_service(
+                        App::new()
+                            .wrap(middleware())
+                            .service(web::resource("/orders/{id}").to(failing)),
+                    )
+                    .await;
+                    let req = TestRequest::post()
+                        .uri("/orders/7?token=abc")
+                        .insert_header((header::HOST, "example.com"))
+                        .insert_header((header::CONTENT_LENGTH, "0"))
+                        .insert_header((header::AUTHORIZATION, "Bearer abc"))
+                        .peer_addr("198.51.100.1:8080".parse().unwrap())
+                        .to_request();
+                    call_service(&app, req).await;
+                })
+            },
+            sentry::ClientOptions {
+                send_default_pii: true,
+                ..Default::default()
+            },
+        );
+        assert_eq!(events.len(), 1);
+        let attached = events[0].request.clone().unwrap();
+
+        let delivery = Delivery {
+            headers: vec![
+                ("host".into(), "example.com".into()),
+                ("content-length".into(), "0".into()),
+                ("authorization".into(), "Bearer abc".into()),
+            ],
+        };
+        let config = RequestDataConfig::new()
+            .send_default_pii(true)
+            .env_profile(EnvProfile::Full);
+        assert_eq!(config.build_request(&delivery), attached);
+        assert_eq!(attached.headers["authorization"], "[Filtered]");
+        assert_eq!(attached.env["REMOTE_ADDR"], "198.51.100.1:8080");
+
+        // Actix requests go through the same path
+        let req = TestRequest::post()
+            .uri("/orders/7?token=abc")
+            .insert_header((header::HOST, "example.com"))
+            .insert_header((header::CONTENT_LENGTH, "0"))
+            .insert_header((header::AUTHORIZATION, "Bearer abc"))
+            .peer_addr("198.51.100.1:8080".parse().unwrap())
+            .to_srv_request();
+        assert_eq!(config.build_request(&req), attached);
+        assert_eq!(config.build_request(req.into_parts().0), attached);
+    }
+
+    #[test]
+    fn test_ip_policy() {
+        let addrs = [
+            "198.51.100.23",
+            "198.51.100.23:8080",
+            "2001:db8:85a3:8d3:1319:8a2e:370:7348",
+            "[2001:db8:85a3:8d3::1]:443",
+            "[2001:db8::1]",
+            "unknown",
+            "198.51.100",
+        ];
+        let apply = |policy: IpPolicy| -> Vec<_> {
+            addrs
+                .iter()
+                .map(|addr| policy.apply(addr))
+                .collect::<Vec<_>>()
+        };
+        let full: Vec<_> = addrs.iter().map(|addr| Some(addr.to_string())).collect();
+        assert_eq!(apply(IpPolicy::Full), full);
+        assert_eq!(
+            apply(IpPolicy::Truncated),
+            [
+                Some("198.51.100.0".into()),
+                Some("198.51.100.0".into()),
+                Some("2001:db8:85a3::".into()),
+                Some("2001:db8:85a3::".into()),
+                Some("2001:db8::".into()),
+                None,
+                None,
+            ]
+        );
+        assert_eq!(
+            apply(IpPolicy::None),
+            [None, None, None, None, None, None, None]
+        );
+
+        // Addresses are never recorded without PII
+        let req = TestRequest::get()
+            .peer_addr("198.51.100.1:8080".parse().unwrap())
+            .to_http_request();
+        let config = RequestDataConfig::new().ip_policy(IpPolicy::Truncated);
+        assert!(config.build_request(&req).env.is_empty());
+        let request = config.send_default_pii(true).build_request(&req);
+        assert_eq!(request.env["REMOTE_ADDR"], "198.51.100.0");
+    }
+
+    #[test]
+    fn test_split_host() {
+        assert_eq!(split_host("example.com"), ("example.com", None));
+        assert_eq!(
+            split_host("example.com:8443"),
+            ("example.com", Some("8443"))
+        );
+        assert_eq!(split_host("[::1]:8080"), ("[::1]", Some("8080")));
+        assert_eq!(split_host("[::1]"), ("[::1]", None));
+        assert_eq!(split_host("example.com:"), ("example.com", None));
+    }
+}
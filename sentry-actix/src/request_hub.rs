@@ -0,0 +1,216 @@
+//! Exposing the hub of a request to outer middleware.
+
+use std::fmt;
+use std::sync::Arc;
+
+use actix_web::dev::ServiceResponse;
+use actix_web::http::StatusCode;
+use actix_web::{HttpRequest, HttpResponse, ResponseError};
+
+use sentry_core::Hub;
+
+use crate::capture::CaptureSource;
+
+/// The hub a request was handled with, kept in the extensions of the request.
+pub(crate) struct RequestHub(pub(crate) Arc<Hub>);
+
+/// A service error carrying the hub of its request, see
+/// [`SentryBuilder::expose_hub_in_errors`](crate::SentryBuilder::expose_hub_in_errors).
+pub(crate) struct ScopedError {
+    pub(crate) error: actix_web::Error,
+    pub(crate) hub: Arc<Hub>,
+}
+
+impl fmt::Debug for ScopedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.error, f)
+    }
+}
+
+impl fmt::Display for ScopedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.error, f)
+    }
+}
+
+impl ResponseError for ScopedError {
+    fn status_code(&self) -> StatusCode {
+        self.error.as_response_error().status_code()
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        self.error.as_response_error().error_response()
+    }
+}
+
+/// Returns the hub a response was handled with by the [`Sentry`](crate::Sentry)
+/// middleware.
+///
+/// The binding of the hub ends when the middleware returns the response, so
+/// middleware wrapping it should capture against this hub rather than
+/// `Hub::current()` to keep the data of the request, such as its transaction
+/// and request data.
+///
+/// # Example
+///
+/// ```
+/// use actix_web::dev::Service;
+/// use actix_web::App;
+/// use sentry::Level;
+/// use sentry_actix::{hub_from_service_response, Sentry};
+///
+/// let app = App::new().wrap(Sentry::new()).wrap_fn(|req, srv| {
+///     let fut = srv.call(req);
+///     async {
+///         let res = fut.await?;
+///         if res.status().is_client_error() {
+///             if let Some(hub) = hub_from_service_response(&res) {
+///                 hub.capture_message("Rejected request", Level::Info);
+///             }
+///         }
+///         Ok(res)
+///     }
+/// });
+/// ```
+pub fn hub_from_service_response<B>(res: &ServiceResponse<B>) -> Option<Arc<Hub>> {
+    let extensions = res.request().extensions();
+    extensions.get::<RequestHub>().map(|hub| hub.0.clone())
+}
+
+/// Returns the hub a service error was handled with by the
+/// [`Sentry`](crate::Sentry) middleware.
+///
+/// This is only set with
+/// [`SentryBuilder::expose_hub_in_errors`](crate::SentryBuilder::expose_hub_in_errors),
+/// as the error has to be wrapped to carry the hub.
+pub fn hub_from_error(error: &actix_web::Error) -> Option<Arc<Hub>> {
+    error
+        .as_error::<ScopedError>()
+        .map(|scoped| scoped.hub.clone())
+}
+
+/// Runs a function capturing events manually with the hub a request is handled
+/// with by the [`Sentry`](crate::Sentry) middleware.
+///
+/// The hub is bound while the function runs, in a scope pushed for it that tags
+/// the events with the `sentry_actix.capture_source` `manual`.  Requests not
+/// handled by the middleware use the current hub.
+///
+/// # Example
+///
+/// ```
+/// use actix_web::HttpRequest;
+/// use sentry::Level;
+///
+/// async fn index(req: HttpRequest) -> &'static str {
+///     sentry_actix::with_request_hub(&req, || {
+///         sentry::capture_message("Cache miss", Level::Info);
+///     });
+///     "ok"
+/// }
+/// ```
+pub fn with_request_hub<F, R>(req: &HttpRequest, f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let hub = req
+        .extensions()
+        .get::<RequestHub>()
+        .map_or_else(Hub::current, |hub| hub.0.clone());
+    Hub::run(hub.clone(), || {
+        hub.with_scope(
+            |scope| {
+                scope.set_tag(
+                    "sentry_actix.capture_source",
+                    CaptureSource::Manual.as_str(),
+                )
+            },
+            f,
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::dev::{Service, ServiceRequest};
+    use actix_web::test::{init_service, TestRequest};
+    use actix_web::{error, web, App};
+    use futures::executor::block_on;
+    use futures_util::future::{err, Either};
+    use sentry::protocol::Event;
+    use sentry_core::Level;
+
+    use super::*;
+    use crate::{Sentry, SentryBuilder};
+
+    /// Handles a request with middleware capturing the outcome outside of the
+    /// Sentry middleware, returning the events of that middleware.
+    fn capture_outside(builder: SentryBuilder, fail: bool) -> Vec<Event<'static>> {
+        let events = sentry::test::with_captured_events(|| {
+            block_on(async {
+                let app = init_service(
+                    App::new()
+                        .wrap_fn(move |req: ServiceRequest, srv| {
+                            if fail {
+                                Either::Left(err(error::ErrorBadGateway("Upstream failed")))
+                            } else {
+                                Either::Right(srv.call(req))
+                            }
+                        })
+                        .wrap(builder.with_hub(Hub::current()).finish())
+                        .wrap_fn(|req, srv| {
+                            let fut = srv.call(req);
+                            async {
+                                let res = fut.await;
+                                let hub = match res {
+                                    Ok(ref res) => hub_from_service_response(res),
+                                    Err(ref e) => hub_from_error(e),
+                                };
+                                let hub = hub.unwrap_or_else(Hub::current);
+                                hub.capture_message("Outer capture", Level::Info);
+                                res
+                            }
+                        })
+                        .route("/items/{id}", web::get().to(|| async { "item" })),
+                )
+                .await;
+
+                let req = TestRequest::get().uri("/items/42").to_request();
+                match app.call(req).await {
+                    Ok(res) => assert!(!fail && res.status().is_success()),
+                    Err(e) => {
+                        assert_eq!(e.as_response_error().status_code(), StatusCode::BAD_GATEWAY)
+                    }
+                }
+            })
+        });
+        events
+            .into_iter()
+            .filter(|event| event.message.as_deref() == Some("Outer capture"))
+            .collect()
+    }
+
+    /// Ensures outer middleware captures with the data of successful requests.
+    #[test]
+    fn test_hub_from_service_response() {
+        let events = capture_outside(Sentry::builder(), false);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].transaction.as_deref(), Some("/items/{id}"));
+        assert!(events[0].request.is_some());
+        assert_eq!(events[0].tags["request_data"], "full");
+    }
+
+    /// Ensures outer middleware captures with the data of failed requests once
+    /// errors carry their hub.
+    #[test]
+    fn test_hub_from_error() {
+        let events = capture_outside(Sentry::builder().expose_hub_in_errors(true), true);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].transaction.as_deref(), Some("/items/{id}"));
+        assert!(events[0].request.is_some());
+
+        let events = capture_outside(Sentry::builder(), true);
+        assert_eq!(events.len(), 1);
+        assert!(events[0].request.is_none());
+    }
+}
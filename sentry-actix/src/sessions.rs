@@ -0,0 +1,147 @@
+//! Per-route counters of the outcomes of request sessions.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+use sentry_core::protocol::Event;
+use sentry_core::Level;
+
+/// The route under which sessions of routes beyond the limit are counted.
+pub const OTHER_ROUTE: &str = "other";
+
+/// The number of request sessions of a route, by outcome.
+///
+/// See [`SentryBuilder::route_session_stats`](crate::SentryBuilder::route_session_stats).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SessionCounts {
+    /// Sessions without errors.
+    pub ok: u64,
+    /// Sessions with errors, none of them unhandled.
+    pub errored: u64,
+    /// Sessions with an unhandled error.
+    pub crashed: u64,
+}
+
+/// The outcome of a request session.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Outcome {
+    Ok,
+    Errored,
+    Crashed,
+}
+
+/// Counters of session outcomes by route, shared between clones of the middleware.
+#[derive(Debug)]
+pub(crate) struct RouteSessionCounters {
+    max_routes: usize,
+    routes: Mutex<HashMap<String, SessionCounts>>,
+}
+
+impl RouteSessionCounters {
+    pub(crate) fn new(max_routes: usize) -> Self {
+        RouteSessionCounters {
+            max_routes,
+            routes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn record(&self, route: &str, outcome: Outcome) {
+        let mut routes = self.routes.lock().unwrap();
+        let known = routes.len() - routes.contains_key(OTHER_ROUTE) as usize;
+        let route = if routes.contains_key(route) || known < self.max_routes {
+            route
+        } else {
+            OTHER_ROUTE
+        };
+        let counts = routes.entry(route.to_owned()).or_default();
+        match outcome {
+            Outcome::Ok => counts.ok += 1,
+            Outcome::Errored => counts.errored += 1,
+            Outcome::Crashed => counts.crashed += 1,
+        }
+    }
+
+    pub(crate) fn snapshot(&self) -> BTreeMap<String, SessionCounts> {
+        let routes = self.routes.lock().unwrap();
+        routes
+            .iter()
+            .map(|(route, counts)| (route.clone(), *counts))
+            .collect()
+    }
+}
+
+/// Follows the outcome of the session of a request, counted once it is dropped.
+///
+/// The tracker is kept by the event processor of the request, so it is dropped
+/// with the scope of the request, which ends its session.  Events update the
+/// outcome like they update the session.
+pub(crate) struct SessionTracker {
+    counters: Arc<RouteSessionCounters>,
+    route: String,
+    outcome: Mutex<Outcome>,
+}
+
+impl SessionTracker {
+    pub(crate) fn new(counters: Arc<RouteSessionCounters>, route: String) -> Self {
+        SessionTracker {
+            counters,
+            route,
+            outcome: Mutex::new(Outcome::Ok),
+        }
+    }
+
+    /// Updates the outcome with a captured event.
+    pub(crate) fn record_event(&self, event: &Event<'static>) {
+        let mut outcome = self.outcome.lock().unwrap();
+        if *outcome == Outcome::Crashed {
+            return;
+        }
+        let crashed = event.exception.iter().any(|exception| {
+            exception
+                .mechanism
+                .as_ref()
+                .map_or(false, |mechanism| mechanism.handled == Some(false))
+        });
+        if crashed {
+            *outcome = Outcome::Crashed;
+        } else if event.level >= Level::Error || !event.exception.is_empty() {
+            *outcome = Outcome::Errored;
+        }
+    }
+}
+
+impl Drop for SessionTracker {
+    fn drop(&mut self) {
+        let outcome = *self.outcome.lock().unwrap();
+        self.counters.record(&self.route, outcome);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_route_cardinality() {
+        let counters = RouteSessionCounters::new(2);
+        for route in &["/a", "/b", "/c", "/a", "/d"] {
+            counters.record(route, Outcome::Ok);
+        }
+        counters.record("/b", Outcome::Errored);
+        counters.record("/e", Outcome::Crashed);
+
+        let snapshot = counters.snapshot();
+        let routes: Vec<_> = snapshot.keys().map(String::as_str).collect();
+        assert_eq!(routes, ["/a", "/b", OTHER_ROUTE]);
+        assert_eq!(snapshot["/a"].ok, 2);
+        assert_eq!(snapshot["/b"].errored, 1);
+        assert_eq!(
+            snapshot[OTHER_ROUTE],
+            SessionCounts {
+                ok: 2,
+                errored: 0,
+                crashed: 1
+            }
+        );
+    }
+}
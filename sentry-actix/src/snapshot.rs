@@ -0,0 +1,233 @@
+//! Snapshots of the request context for events captured after the response.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use actix_web::HttpRequest;
+
+use sentry_core::protocol::{Event, Request, TraceContext};
+use sentry_core::types::Uuid;
+use sentry_core::{Hub, Level};
+
+use crate::helpers::TransactionName;
+use crate::request::{to_sentry_request, CapturedRequest, RequestDataOptions};
+
+/// The request context recorded by the middleware for snapshots.
+pub(crate) struct SnapshotContext {
+    pub(crate) request: Arc<CapturedRequest>,
+    pub(crate) trace: TraceContext,
+    pub(crate) tags: Arc<BTreeMap<String, String>>,
+}
+
+/// An owned copy of the context of a request.
+///
+/// Snapshots can be moved to background tasks to capture events about a request
+/// after its response has been sent, when the request hub is gone.  For requests
+/// handled by the [`Sentry`](crate::Sentry) middleware, the snapshot holds the
+/// request data, transaction name, trace and tags the middleware attaches to
+/// events.  Otherwise, it holds the request data created by
+/// [`to_sentry_request`] with the default options.
+///
+/// # Example
+///
+/// ```
+/// use actix_web::HttpRequest;
+/// use sentry::Level;
+/// use sentry_actix::RequestSnapshot;
+///
+/// async fn upload(req: HttpRequest) -> &'static str {
+///     let snapshot = RequestSnapshot::capture(&req);
+///     actix_rt::spawn(async move {
+///         // Audit the upload after responding
+///         snapshot.capture_message("Upload flagged by audit", Level::Warning);
+///     });
+///     "accepted"
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct RequestSnapshot {
+    request: Request,
+    transaction: Option<String>,
+    trace: Option<TraceContext>,
+    tags: BTreeMap<String, String>,
+}
+
+impl RequestSnapshot {
+    /// Takes a snapshot of the context of a request.
+    pub fn capture(req: &HttpRequest) -> Self {
+        let transaction = match req.extensions().get::<TransactionName>() {
+            Some(TransactionName(name)) => Some(name.clone()),
+            None => req
+                .match_name()
+                .map(ToOwned::to_owned)
+                .or_else(|| req.match_pattern()),
+        };
+        if let Some(context) = req.extensions().get::<SnapshotContext>() {
+            return RequestSnapshot {
+                request: (*context.request.request()).clone(),
+                transaction,
+                trace: Some(context.trace.clone()),
+                tags: (*context.tags).clone(),
+            };
+        }
+        // The extensions must not be borrowed while the connection info is read
+        RequestSnapshot {
+            request: to_sentry_request(req, &RequestDataOptions::default()),
+            transaction,
+            trace: None,
+            tags: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the request data of the snapshot.
+    pub fn request(&self) -> &Request {
+        &self.request
+    }
+
+    /// Returns the transaction name of the request, if any.
+    pub fn transaction(&self) -> Option<&str> {
+        self.transaction.as_deref()
+    }
+
+    /// Returns the id of the trace of the request, if it was handled by the middleware.
+    pub fn trace_id(&self) -> Option<Uuid> {
+        self.trace.as_ref().map(|trace| trace.trace_id)
+    }
+
+    /// Adds the context of the snapshot to an event, keeping values already set.
+    pub fn apply_to_event(&self, mut event: Event<'static>) -> Event<'static> {
+        if event.request.is_none() {
+            event.request = Some(self.request.clone());
+        }
+        if event.transaction.is_none() {
+            event.transaction = self.transaction.clone();
+        }
+        if let Some(ref trace) = self.trace {
+            event
+                .contexts
+                .entry("trace".into())
+                .or_insert_with(|| trace.clone().into());
+        }
+        for (key, value) in &self.tags {
+            event
+                .tags
+                .entry(key.clone())
+                .or_insert_with(|| value.clone());
+        }
+        event
+    }
+
+    /// Captures an event with the context of the snapshot.
+    ///
+    /// The event is captured with the client of the current hub, but without its
+    /// scope, so that it carries the context of the request only.
+    pub fn capture_event(&self, event: Event<'static>) -> Uuid {
+        let hub = Hub::new(Hub::current().client(), Default::default());
+        hub.capture_event(self.apply_to_event(event))
+    }
+
+    /// Captures a message with the context of the snapshot.
+    ///
+    /// See [`capture_event`](Self::capture_event).
+    pub fn capture_message(&self, message: &str, level: Level) -> Uuid {
+        self.capture_event(Event {
+            message: Some(message.to_owned()),
+            level,
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{web, App};
+    use sentry_core::SentryFutureExt;
+
+    use super::*;
+    use crate::Sentry;
+
+    /// Ensures events captured after the response carry the context of the request.
+    #[actix_rt::test]
+    async fn test_capture_after_response() {
+        tokio::time::pause();
+
+        async fn audited(req: HttpRequest) -> &'static str {
+            let snapshot = RequestSnapshot::capture(&req);
+            let hub = Hub::current();
+            actix_rt::spawn(
+                async move {
+                    actix_rt::time::sleep(Duration::from_millis(30)).await;
+                    snapshot.capture_message("Audit failed", Level::Warning);
+                }
+                .bind_hub(hub),
+            );
+            "ok"
+        }
+
+        let transport = sentry::test::TestTransport::new();
+        let client = sentry::Client::from(sentry::ClientOptions {
+            dsn: Some("https://public@sentry.invalid/1".parse().unwrap()),
+            transport: Some(Arc::new(transport.clone())),
+            ..Default::default()
+        });
+        let hub = Arc::new(Hub::new(Some(Arc::new(client)), Default::default()));
+
+        let middleware = Sentry::builder()
+            .with_hub(hub)
+            .tag("service", "uploads")
+            .finish();
+        let app = init_service(
+            App::new()
+                .wrap(middleware)
+                .service(web::resource("/uploads/{id}").to(audited)),
+        )
+        .await;
+
+        let trace_id = "4bf92f3577b34da6a3ce929d0e0e4736";
+        let req = TestRequest::post()
+            .uri("/uploads/7?token=secret")
+            .insert_header(("sentry-trace", format!("{}-00f067aa0ba902b7", trace_id)))
+            .to_request();
+        let res = call_service(&app, req).await;
+        assert!(res.status().is_success());
+        assert!(transport.fetch_and_clear_events().is_empty());
+
+        actix_rt::time::sleep(Duration::from_millis(60)).await;
+        let events = transport.fetch_and_clear_events();
+        assert_eq!(events.len(), 1);
+        let event = &events[0];
+        assert_eq!(event.message.as_deref(), Some("Audit failed"));
+        assert_eq!(event.level, Level::Warning);
+        assert_eq!(event.transaction.as_deref(), Some("/uploads/{id}"));
+        assert_eq!(event.tags["service"], "uploads");
+        match event.contexts.get("trace") {
+            Some(sentry_core::protocol::Context::Trace(trace)) => {
+                assert_eq!(trace.trace_id.to_simple().to_string(), trace_id);
+            }
+            context => panic!("unexpected trace context {:?}", context),
+        }
+
+        // The request data is redacted like that of other events
+        let request = event.request.as_ref().unwrap();
+        assert_eq!(request.method.as_deref(), Some("POST"));
+        assert_eq!(
+            request.url.as_ref().unwrap().as_str(),
+            "http://localhost:8080/uploads/7?token=[Filtered]"
+        );
+    }
+
+    #[test]
+    fn test_without_middleware() {
+        let req = TestRequest::get().uri("/items").to_http_request();
+        let snapshot = RequestSnapshot::capture(&req);
+        assert_eq!(snapshot.trace_id(), None);
+        assert_eq!(snapshot.request().method.as_deref(), Some("GET"));
+
+        let event = snapshot.apply_to_event(Event::default());
+        assert!(event.request.is_some());
+        assert!(event.tags.is_empty());
+    }
+}
@@ -0,0 +1,269 @@
+//! Continuation of traces started by upstream services.
+//!
+//! The middleware reads the trace of a request from the `sentry-trace` header,
+//! or the W3C `traceparent` header if no valid `sentry-trace` header is present.
+//! Malformed values of either header are ignored.
+
+use actix_web::http::HeaderMap;
+
+use sentry_core::protocol::TraceContext;
+use sentry_core::types::Uuid;
+
+/// The name of the Sentry trace header.
+pub(crate) const SENTRY_TRACE_HEADER: &str = "sentry-trace";
+
+/// The name of the W3C trace context header.
+pub(crate) const TRACEPARENT_HEADER: &str = "traceparent";
+
+/// The trace of a request, continued from an upstream service if it sent one.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Trace {
+    pub(crate) trace_id: Uuid,
+    pub(crate) parent_span_id: Option<String>,
+    pub(crate) span_id: Uuid,
+    pub(crate) sampled: Option<bool>,
+}
+
+impl Trace {
+    /// Continues the trace of the request headers, or starts a new one.
+    pub(crate) fn from_headers(headers: &HeaderMap) -> Self {
+        let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+        let parent = header(SENTRY_TRACE_HEADER)
+            .and_then(parse_sentry_trace)
+            .or_else(|| header(TRACEPARENT_HEADER).and_then(parse_traceparent));
+        let span_id = Uuid::new_v4();
+        match parent {
+            Some((trace_id, parent_span_id, sampled)) => Trace {
+                trace_id,
+                parent_span_id: Some(parent_span_id),
+                span_id,
+                sampled,
+            },
+            None => Trace {
+                trace_id: Uuid::new_v4(),
+                parent_span_id: None,
+                span_id,
+                sampled: None,
+            },
+        }
+    }
+
+    /// Returns the trace context attached to events of the request.
+    pub(crate) fn context(&self) -> TraceContext {
+        TraceContext {
+            span_id: self.span_id,
+            trace_id: self.trace_id,
+            parent_span_id: self.parent_span_id.clone(),
+            op: Some("http.server".into()),
+            ..Default::default()
+        }
+    }
+
+    /// Returns the span id of the request as 16 hex digits.
+    ///
+    /// Span ids of the protocol are UUIDs, of which headers carry the first half.
+    fn header_span_id(&self) -> String {
+        let mut span_id = self.span_id.to_simple().to_string();
+        span_id.truncate(16);
+        span_id
+    }
+
+    /// Formats the trace as a `sentry-trace` header value.
+    pub(crate) fn sentry_trace(&self) -> String {
+        let trace_id = self.trace_id.to_simple();
+        let span_id = self.header_span_id();
+        match self.sampled {
+            Some(sampled) => format!("{}-{}-{}", trace_id, span_id, sampled as u8),
+            None => format!("{}-{}", trace_id, span_id),
+        }
+    }
+
+    /// Formats the trace as a `traceparent` header value.
+    pub(crate) fn traceparent(&self) -> String {
+        let flags = if self.sampled == Some(true) { 1 } else { 0 };
+        format!(
+            "00-{}-{}-{:02x}",
+            self.trace_id.to_simple(),
+            self.header_span_id(),
+            flags
+        )
+    }
+}
+
+/// Returns whether the value consists of `len` lowercase hex digits.
+fn is_hex(value: &str, len: usize) -> bool {
+    value.len() == len
+        && value
+            .bytes()
+            .all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// Parses a `sentry-trace` header into its trace id, span id and sampling decision.
+fn parse_sentry_trace(value: &str) -> Option<(Uuid, String, Option<bool>)> {
+    let mut parts = value.trim().split('-');
+    let trace_id = parts.next().filter(|part| is_hex(part, 32))?;
+    let span_id = parts.next().filter(|part| is_hex(part, 16))?;
+    let sampled = match parts.next() {
+        None => None,
+        Some("1") => Some(true),
+        Some("0") => Some(false),
+        Some(_) => return None,
+    };
+    if parts.next().is_some() {
+        return None;
+    }
+    Some((trace_id.parse().ok()?, span_id.to_owned(), sampled))
+}
+
+/// Parses a W3C `traceparent` header into its trace id, span id and sampled flag.
+fn parse_traceparent(value: &str) -> Option<(Uuid, String, Option<bool>)> {
+    let parts: Vec<_> = value.trim().split('-').collect();
+    let version = parts.first().filter(|part| is_hex(part, 2))?;
+    // Later versions may append fields, version 00 must not
+    let valid_len = if *version == "00" {
+        parts.len() == 4
+    } else {
+        *version != "ff" && parts.len() >= 4
+    };
+    if !valid_len
+        || !is_hex(parts[1], 32)
+        || !is_hex(parts[2], 16)
+        || !is_hex(parts[3], 2)
+        || parts[1].bytes().all(|b| b == b'0')
+        || parts[2].bytes().all(|b| b == b'0')
+    {
+        return None;
+    }
+    let flags = u8::from_str_radix(parts[3], 16).ok()?;
+    Some((
+        parts[1].parse().ok()?,
+        parts[2].to_owned(),
+        Some(flags & 1 == 1),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::HeaderValue;
+
+    use super::*;
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+
+    #[test]
+    fn test_parse_sentry_trace() {
+        let (trace_id, span_id, sampled) =
+            parse_sentry_trace(&format!("{}-00f067aa0ba902b7-1", TRACE_ID)).unwrap();
+        assert_eq!(trace_id.to_simple().to_string(), TRACE_ID);
+        assert_eq!(span_id, "00f067aa0ba902b7");
+        assert_eq!(sampled, Some(true));
+
+        let parsed = parse_sentry_trace(&format!("{}-00f067aa0ba902b7", TRACE_ID)).unwrap();
+        assert_eq!(parsed.2, None);
+
+        assert!(parse_sentry_trace(&format!("{}-00f067aa0ba902b7-x", TRACE_ID)).is_none());
+        assert!(parse_sentry_trace("4bf92f35-00f067aa0ba902b7").is_none());
+        assert!(parse_sentry_trace("").is_none());
+    }
+
+    #[test]
+    fn test_parse_traceparent() {
+        let (trace_id, span_id, sampled) =
+            parse_traceparent(&format!("00-{}-00f067aa0ba902b7-01", TRACE_ID)).unwrap();
+        assert_eq!(trace_id.to_simple().to_string(), TRACE_ID);
+        assert_eq!(span_id, "00f067aa0ba902b7");
+        assert_eq!(sampled, Some(true));
+
+        let parsed = parse_traceparent(&format!("00-{}-00f067aa0ba902b7-00", TRACE_ID)).unwrap();
+        assert_eq!(parsed.2, Some(false));
+
+        // Later versions may carry additional fields
+        assert!(parse_traceparent(&format!("01-{}-00f067aa0ba902b7-01-extra", TRACE_ID)).is_some());
+
+        for malformed in &[
+            format!("00-{}-00f067aa0ba902b7-01-extra", TRACE_ID),
+            format!("ff-{}-00f067aa0ba902b7-01", TRACE_ID),
+            format!("00-{}-0000000000000000-01", TRACE_ID),
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01".to_owned(),
+            format!("00-{}-00F067AA0BA902B7-01", TRACE_ID),
+            format!("00-{}-00f067aa0ba902b7", TRACE_ID),
+        ] {
+            assert!(parse_traceparent(malformed).is_none(), "{}", malformed);
+        }
+    }
+
+    #[test]
+    fn test_trace_from_headers() {
+        let trace = |headers: Vec<(&'static str, String)>| {
+            let mut map = HeaderMap::new();
+            for (name, value) in &headers {
+                map.insert(name.parse().unwrap(), HeaderValue::from_str(value).unwrap());
+            }
+            Trace::from_headers(&map)
+        };
+        let sentry_trace = (
+            SENTRY_TRACE_HEADER,
+            format!("{}-00f067aa0ba902b7-0", TRACE_ID),
+        );
+        let traceparent = (
+            TRACEPARENT_HEADER,
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01".to_owned(),
+        );
+
+        // Only traceparent
+        let parent = trace(vec![traceparent.clone()]);
+        assert_eq!(
+            parent.trace_id.to_simple().to_string(),
+            "0af7651916cd43dd8448eb211c80319c"
+        );
+        assert_eq!(parent.parent_span_id.as_deref(), Some("b7ad6b7169203331"));
+        assert_eq!(parent.sampled, Some(true));
+
+        // sentry-trace takes precedence over traceparent
+        let parent = trace(vec![sentry_trace, traceparent.clone()]);
+        assert_eq!(parent.trace_id.to_simple().to_string(), TRACE_ID);
+        assert_eq!(parent.parent_span_id.as_deref(), Some("00f067aa0ba902b7"));
+        assert_eq!(parent.sampled, Some(false));
+
+        // Malformed sentry-trace headers are ignored
+        let parent = trace(vec![(SENTRY_TRACE_HEADER, "invalid".into()), traceparent]);
+        assert_eq!(parent.parent_span_id.as_deref(), Some("b7ad6b7169203331"));
+
+        // Without a valid header, a new trace is started
+        let parent = trace(vec![(TRACEPARENT_HEADER, "00-invalid".into())]);
+        assert_eq!(parent.parent_span_id, None);
+        assert_eq!(parent.sampled, None);
+        assert_ne!(parent.span_id, Uuid::nil());
+    }
+
+    #[test]
+    fn test_format_trace() {
+        let trace = Trace {
+            trace_id: TRACE_ID.parse().unwrap(),
+            parent_span_id: None,
+            span_id: "00f067aa0ba902b7a0a1a2a3a4a5a6a7".parse().unwrap(),
+            sampled: None,
+        };
+        assert_eq!(
+            trace.sentry_trace(),
+            format!("{}-00f067aa0ba902b7", TRACE_ID)
+        );
+        assert_eq!(
+            trace.traceparent(),
+            format!("00-{}-00f067aa0ba902b7-00", TRACE_ID)
+        );
+
+        let trace = Trace {
+            sampled: Some(true),
+            ..trace
+        };
+        assert_eq!(
+            trace.sentry_trace(),
+            format!("{}-00f067aa0ba902b7-1", TRACE_ID)
+        );
+        assert_eq!(
+            trace.traceparent(),
+            format!("00-{}-00f067aa0ba902b7-01", TRACE_ID)
+        );
+    }
+}
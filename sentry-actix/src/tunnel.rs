@@ -0,0 +1,248 @@
+//! A same-origin tunnel for envelopes sent by browser SDKs.
+//!
+//! Browser SDKs configured with the `tunnel` option post their envelopes to an
+//! endpoint on the application's own origin instead of sending them to Sentry
+//! directly.  This module provides a ready-made actix route for such an
+//! endpoint.  It validates the DSN found in the envelope header against an
+//! allowlist and forwards the envelope to the matching Sentry ingestion
+//! endpoint, returning the upstream status.
+//!
+//! Forwarding uses an [`awc`] client.  To forward envelopes to DSNs using
+//! `https`, enable one of the TLS features of `awc` in your own manifest.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use actix_web::App;
+//! use sentry_actix::tunnel::EnvelopeTunnel;
+//!
+//! let dsn = "https://public@o0.ingest.sentry.io/42".parse().unwrap();
+//! let app = App::new().route("/tunnel", EnvelopeTunnel::new(vec![dsn]).finish());
+//! ```
+
+use std::sync::Arc;
+
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse, Route};
+use futures_util::StreamExt;
+
+use sentry_core::types::Dsn;
+
+#[cfg(feature = "rate-limits")]
+use crate::rate_limits::RateLimits;
+
+/// The default maximum size of a tunneled envelope: 20MB.
+const DEFAULT_MAX_SIZE: usize = 20 * 1024 * 1024;
+
+/// Creates a `POST` route that tunnels envelopes for the allowed DSNs.
+///
+/// This is a shortcut for [`EnvelopeTunnel::new`] with the default settings.
+pub fn envelope_endpoint<I>(allowed_dsns: I) -> Route
+where
+    I: IntoIterator<Item = Dsn>,
+{
+    EnvelopeTunnel::new(allowed_dsns).finish()
+}
+
+/// Configures and builds a route tunneling envelopes to Sentry.
+#[derive(Clone)]
+pub struct EnvelopeTunnel {
+    allowed_dsns: Arc<Vec<Dsn>>,
+    max_size: usize,
+    client: Option<awc::Client>,
+    #[cfg(feature = "rate-limits")]
+    rate_limits: Option<RateLimits>,
+}
+
+impl EnvelopeTunnel {
+    /// Creates a new tunnel accepting envelopes for the given DSNs.
+    ///
+    /// An envelope is accepted if the host, port and project id of its DSN
+    /// match one of the allowed DSNs.
+    pub fn new<I>(allowed_dsns: I) -> Self
+    where
+        I: IntoIterator<Item = Dsn>,
+    {
+        EnvelopeTunnel {
+            allowed_dsns: Arc::new(allowed_dsns.into_iter().collect()),
+            max_size: DEFAULT_MAX_SIZE,
+            client: None,
+            #[cfg(feature = "rate-limits")]
+            rate_limits: None,
+        }
+    }
+
+    /// Sets the maximum accepted envelope size in bytes.
+    ///
+    /// Larger envelopes are rejected with `413 Payload Too Large`.
+    pub fn max_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// Uses the given client to forward envelopes.
+    ///
+    /// By default a new client is created for every forwarded envelope.
+    pub fn client(mut self, client: awc::Client) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// Records the rate limits of responses to forwarded envelopes.
+    #[cfg(feature = "rate-limits")]
+    pub fn rate_limits(mut self, limits: RateLimits) -> Self {
+        self.rate_limits = Some(limits);
+        self
+    }
+
+    /// Finishes the configuration and returns a `POST` route.
+    pub fn finish(self) -> Route {
+        web::post().to(move |payload: web::Payload| {
+            let tunnel = self.clone();
+            async move { Ok::<_, actix_web::Error>(tunnel.forward(payload).await) }
+        })
+    }
+
+    /// Returns the allowed DSN matching the DSN of the envelope, if any.
+    fn allowed_dsn(&self, dsn: &Dsn) -> Option<&Dsn> {
+        self.allowed_dsns.iter().find(|allowed| {
+            allowed.host() == dsn.host()
+                && allowed.port() == dsn.port()
+                && allowed.project_id() == dsn.project_id()
+        })
+    }
+
+    async fn forward(&self, mut payload: web::Payload) -> HttpResponse {
+        let mut body = web::BytesMut::new();
+        while let Some(chunk) = payload.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(_) => return HttpResponse::BadRequest().finish(),
+            };
+            if body.len() + chunk.len() > self.max_size {
+                return HttpResponse::PayloadTooLarge().finish();
+            }
+            body.extend_from_slice(&chunk);
+        }
+        let body = body.freeze();
+
+        let dsn = match envelope_dsn(&body) {
+            Some(dsn) => dsn,
+            None => return HttpResponse::BadRequest().finish(),
+        };
+        let url = match self.allowed_dsn(&dsn) {
+            Some(allowed) => allowed.envelope_api_url(),
+            None => return HttpResponse::Forbidden().finish(),
+        };
+
+        let client = self.client.clone().unwrap_or_default();
+        match client
+            .post(url.as_str())
+            .content_type("application/x-sentry-envelope")
+            .send_body(body)
+            .await
+        {
+            Ok(response) => {
+                #[cfg(feature = "rate-limits")]
+                if let Some(ref limits) = self.rate_limits {
+                    limits.update_from_response(response.status(), response.headers());
+                }
+                HttpResponse::build(response.status()).finish()
+            }
+            Err(_) => HttpResponse::new(StatusCode::BAD_GATEWAY),
+        }
+    }
+}
+
+/// Parses the DSN from the header line of a raw envelope.
+fn envelope_dsn(envelope: &[u8]) -> Option<Dsn> {
+    let header = envelope.split(|b| *b == b'\n').next()?;
+    let header: serde_json::Value = serde_json::from_slice(header).ok()?;
+    header.get("dsn")?.as_str()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{App, HttpServer};
+
+    use super::*;
+
+    fn envelope(dsn: &str) -> String {
+        format!(
+            "{{\"dsn\":\"{}\"}}\n{{\"type\":\"event\"}}\n{{\"message\":\"hello\"}}\n",
+            dsn
+        )
+    }
+
+    #[test]
+    fn test_envelope_dsn() {
+        let dsn = envelope_dsn(envelope("https://public@example.com/42").as_bytes());
+        assert_eq!(dsn.unwrap().project_id().value(), 42);
+        assert!(envelope_dsn(b"{}\n").is_none());
+        assert!(envelope_dsn(b"not json").is_none());
+    }
+
+    #[actix_rt::test]
+    async fn test_forward_envelope() {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let upstream_received = received.clone();
+        let server = HttpServer::new(move || {
+            let received = upstream_received.clone();
+            App::new().route(
+                "/api/42/envelope/",
+                web::post().to(move |body: web::Bytes| {
+                    received.lock().unwrap().push(body);
+                    async { "OK" }
+                }),
+            )
+        })
+        .workers(1)
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let addr = server.addrs()[0];
+        let server = server.run();
+
+        let dsn = format!("http://public@{}/42", addr);
+        let app = init_service(
+            App::new().route("/tunnel", envelope_endpoint(vec![dsn.parse().unwrap()])),
+        )
+        .await;
+
+        let req = TestRequest::post()
+            .uri("/tunnel")
+            .set_payload(envelope(&dsn))
+            .to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(received.lock().unwrap().len(), 1);
+        assert_eq!(received.lock().unwrap()[0], envelope(&dsn).as_bytes());
+
+        // DSNs that are not allowlisted are rejected without being forwarded
+        let req = TestRequest::post()
+            .uri("/tunnel")
+            .set_payload(envelope("http://public@example.com/43"))
+            .to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        assert_eq!(received.lock().unwrap().len(), 1);
+
+        server.stop(true).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_reject_oversized_envelope() {
+        let dsn = "http://public@example.com/42";
+        let tunnel = EnvelopeTunnel::new(vec![dsn.parse().unwrap()]).max_size(16);
+        let app = init_service(App::new().route("/tunnel", tunnel.finish())).await;
+
+        let req = TestRequest::post()
+            .uri("/tunnel")
+            .set_payload(envelope(dsn))
+            .to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
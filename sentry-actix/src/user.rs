@@ -0,0 +1,159 @@
+//! Looking up the user of a request asynchronously.
+
+use std::task::Poll;
+use std::time::Duration;
+
+use actix_web::dev::ServiceRequest;
+use futures_util::future::{poll_fn, LocalBoxFuture};
+
+use sentry_core::protocol::User;
+
+use crate::middleware::Sentry;
+
+/// How long the middleware waits for the user of a request by default.
+pub(crate) const DEFAULT_USER_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Looks up the user of a request, see
+/// [`SentryBuilder::with_async_user_extractor`](crate::SentryBuilder::with_async_user_extractor).
+pub(crate) type AsyncUserExtractor =
+    dyn Fn(&ServiceRequest) -> LocalBoxFuture<'static, Option<User>> + Send + Sync;
+
+impl Sentry {
+    /// Starts looking up the user of a request, if an extractor is configured.
+    pub(crate) fn start_user_lookup(
+        &self,
+        req: &ServiceRequest,
+    ) -> Option<LocalBoxFuture<'static, Option<User>>> {
+        let extractor = self.config.async_user_extractor.as_ref()?;
+        self.run_hook("with_async_user_extractor", || extractor(req))
+    }
+
+    /// Waits for the user of a request for at most the configured timeout.
+    ///
+    /// A lookup that panics is handled like one that found no user.
+    pub(crate) async fn await_user(
+        &self,
+        mut lookup: LocalBoxFuture<'static, Option<User>>,
+    ) -> Option<User> {
+        let lookup = poll_fn(|cx| {
+            self.run_hook("with_async_user_extractor", || lookup.as_mut().poll(cx))
+                .unwrap_or(Poll::Ready(None))
+        });
+        actix_rt::time::timeout(self.config.async_user_timeout, lookup)
+            .await
+            .ok()
+            .flatten()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{error, web, App, HttpResponse};
+    use futures_util::FutureExt;
+    use sentry::test::TestTransport;
+    use sentry_core::Hub;
+
+    use super::*;
+    use crate::SentryBuilder;
+
+    async fn failing() -> Result<HttpResponse, actix_web::Error> {
+        Err(error::ErrorInternalServerError("Session expired"))
+    }
+
+    /// Looks up a user in a session store answering after `delay`.
+    fn session_store(
+        delay: Duration,
+    ) -> impl Fn(&ServiceRequest) -> LocalBoxFuture<'static, Option<User>> {
+        move |req: &ServiceRequest| {
+            let session = req
+                .headers()
+                .get("x-session")
+                .and_then(|value| value.to_str().ok())
+                .map(ToOwned::to_owned);
+            async move {
+                actix_rt::time::sleep(delay).await;
+                Some(User {
+                    id: Some(session?),
+                    ..Default::default()
+                })
+            }
+            .boxed_local()
+        }
+    }
+
+    /// Handles a failing request, returning its events and how long it took.
+    async fn capture(builder: SentryBuilder) -> (Vec<sentry::protocol::Event<'static>>, Duration) {
+        let transport = TestTransport::new();
+        let client = sentry::Client::from(sentry::ClientOptions {
+            dsn: Some("https://public@sentry.invalid/1".parse().unwrap()),
+            transport: Some(Arc::new(transport.clone())),
+            ..Default::default()
+        });
+        let hub = Arc::new(Hub::new(Some(Arc::new(client)), Default::default()));
+        let app = init_service(
+            App::new()
+                .wrap(builder.with_hub(hub).finish())
+                .route("/failing", web::get().to(failing)),
+        )
+        .await;
+
+        let started = Instant::now();
+        let req = TestRequest::get()
+            .uri("/failing")
+            .insert_header(("x-session", "42"))
+            .to_request();
+        let res = call_service(&app, req).await;
+        assert!(res.status().is_server_error());
+        (transport.fetch_and_clear_events(), started.elapsed())
+    }
+
+    /// Ensures events carry the user looked up before the handler runs.
+    #[actix_rt::test]
+    async fn test_async_user_extractor() {
+        let (events, _) = capture(
+            Sentry::builder().with_async_user_extractor(session_store(Duration::from_millis(10))),
+        )
+        .await;
+
+        assert_eq!(events.len(), 1);
+        let user = events[0].user.as_ref().expect("User should be set.");
+        assert_eq!(user.id.as_deref(), Some("42"));
+    }
+
+    /// Ensures slow lookups are abandoned after the timeout.
+    #[actix_rt::test]
+    async fn test_async_user_extractor_timeout() {
+        let (events, elapsed) = capture(
+            Sentry::builder()
+                .with_async_user_extractor(session_store(Duration::from_secs(5)))
+                .async_user_timeout(Duration::from_millis(20)),
+        )
+        .await;
+
+        assert!(elapsed < Duration::from_secs(2));
+        assert_eq!(events.len(), 1);
+        assert!(events[0].user.is_none());
+    }
+
+    /// Ensures panicking lookups do not fail the request.
+    #[actix_rt::test]
+    async fn test_async_user_extractor_panic() {
+        fn unavailable() -> User {
+            panic!("session store unavailable")
+        }
+
+        let middleware = Sentry::builder()
+            .with_async_user_extractor(|_: &ServiceRequest| {
+                async { Some(unavailable()) }.boxed_local()
+            })
+            .debug_log_captures_with(|_| {});
+        let (events, _) = capture(middleware).await;
+
+        assert_eq!(events.len(), 1);
+        assert!(events[0].user.is_none());
+    }
+}
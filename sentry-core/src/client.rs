@@ -157,13 +157,16 @@ impl Client {
         &self,
         mut event: Event<'static>,
         scope: Option<&Scope>,
+        sampled: bool,
     ) -> Option<Event<'static>> {
-        if let Some(scope) = scope {
-            scope.update_session_from_event(&event);
-        }
+        if !sampled {
+            if let Some(scope) = scope {
+                scope.update_session_from_event(&event);
+            }
 
-        if !self.sample_should_send() {
-            return None;
+            if !self.sample_should_send() {
+                return None;
+            }
         }
 
         // event_id and sdk_info are set before the processors run so that the
@@ -259,8 +262,21 @@ impl Client {
 
     /// Captures an event and sends it to sentry.
     pub fn capture_event(&self, event: Event<'static>, scope: Option<&Scope>) -> Uuid {
+        self.send_event(event, scope, false)
+    }
+
+    /// Captures an event that was already sampled by an earlier capture.
+    ///
+    /// The event is neither sampled again nor counted in the session of the
+    /// scope, such as when an event held back by an event processor is sent
+    /// later on.
+    pub fn capture_sampled_event(&self, event: Event<'static>, scope: Option<&Scope>) -> Uuid {
+        self.send_event(event, scope, true)
+    }
+
+    fn send_event(&self, event: Event<'static>, scope: Option<&Scope>, sampled: bool) -> Uuid {
         if let Some(ref transport) = *self.transport.read().unwrap() {
-            if let Some(event) = self.prepare_event(event, scope) {
+            if let Some(event) = self.prepare_event(event, scope, sampled) {
                 let event_id = event.event_id;
                 let mut envelope: Envelope = event.into();
                 // For request-mode sessions, we aggregate them all instead of
@@ -39,6 +39,35 @@ pub enum SessionMode {
     Request,
 }
 
+/// The maximum size of an HTTP request body that the SDK captures.
+///
+/// Only request bodies that are read by the application are captured, and only
+/// by integrations that support it.  Integrations may impose stricter limits of
+/// their own, but never looser ones.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MaxRequestBodySize {
+    /// Request bodies are never captured.
+    None,
+    /// Request bodies up to 1KB are captured.
+    Small,
+    /// Request bodies up to 10KB are captured.
+    Medium,
+    /// Request bodies are always captured, up to the limit of the integration.
+    Always,
+}
+
+impl MaxRequestBodySize {
+    /// Returns the maximum number of bytes to capture, or `None` if unbounded.
+    pub fn max_bytes(self) -> Option<usize> {
+        match self {
+            MaxRequestBodySize::None => Some(0),
+            MaxRequestBodySize::Small => Some(1_000),
+            MaxRequestBodySize::Medium => Some(10_000),
+            MaxRequestBodySize::Always => None,
+        }
+    }
+}
+
 /// Configuration settings for the client.
 ///
 /// These options are explained in more detail in the general
@@ -78,6 +107,8 @@ pub struct ClientOptions {
     pub attach_stacktrace: bool,
     /// If turned on some default PII informat is attached.
     pub send_default_pii: bool,
+    /// The maximum size of HTTP request bodies captured by integrations. (defaults to `None`)
+    pub max_request_body_size: MaxRequestBodySize,
     /// The server name to be reported.
     pub server_name: Option<Cow<'static, str>>,
     /// Module prefixes that are always considered "in_app".
@@ -182,6 +213,7 @@ impl fmt::Debug for ClientOptions {
             .field("max_breadcrumbs", &self.max_breadcrumbs)
             .field("attach_stacktrace", &self.attach_stacktrace)
             .field("send_default_pii", &self.send_default_pii)
+            .field("max_request_body_size", &self.max_request_body_size)
             .field("server_name", &self.server_name)
             .field("in_app_include", &self.in_app_include)
             .field("in_app_exclude", &self.in_app_exclude)
@@ -213,6 +245,7 @@ impl Default for ClientOptions {
             max_breadcrumbs: 100,
             attach_stacktrace: false,
             send_default_pii: false,
+            max_request_body_size: MaxRequestBodySize::None,
             server_name: None,
             in_app_include: vec![],
             in_app_exclude: vec![],
@@ -288,6 +288,26 @@ impl Hub {
         }}
     }
 
+    /// Sends an event that was already sampled to the current client with the
+    /// current scope.
+    ///
+    /// This is like [`capture_event`](#method.capture_event), without sampling
+    /// the event or counting it in the session again.
+    pub fn capture_sampled_event(&self, event: Event<'static>) -> Uuid {
+        with_client_impl! {{
+            self.inner.with(|stack| {
+                let top = stack.top();
+                if let Some(ref client) = top.client {
+                    let event_id = client.capture_sampled_event(event, Some(&top.scope));
+                    *self.last_event_id.write().unwrap() = Some(event_id);
+                    event_id
+                } else {
+                    Default::default()
+                }
+            })
+        }}
+    }
+
     /// Captures an arbitrary message.
     ///
     /// See the global [`capture_message`](fn.capture_message.html)
@@ -427,6 +447,7 @@ impl Hub {
                 if let Some(ref client) = top.client {
                     let scope = Arc::make_mut(&mut top.scope);
                     let options = client.options();
+                    let max_breadcrumbs = scope.max_breadcrumbs.unwrap_or(options.max_breadcrumbs);
                     let breadcrumbs = Arc::make_mut(&mut scope.breadcrumbs);
                     for breadcrumb in breadcrumb.into_breadcrumbs() {
                         let breadcrumb_opt = match options.before_breadcrumb {
@@ -436,8 +457,11 @@ impl Hub {
                         if let Some(breadcrumb) = breadcrumb_opt {
                             breadcrumbs.push_back(breadcrumb);
                         }
-                        while breadcrumbs.len() > options.max_breadcrumbs {
+                        while breadcrumbs.len() > max_breadcrumbs {
                             breadcrumbs.pop_front();
+                            if scope.max_breadcrumbs.is_some() {
+                                scope.breadcrumbs_dropped += 1;
+                            }
                         }
                     }
                 }
@@ -31,6 +31,17 @@ impl Scope {
         minimal_unreachable!();
     }
 
+    /// Overrides the maximum number of breadcrumbs kept by the scope.
+    pub fn set_max_breadcrumbs(&mut self, max_breadcrumbs: Option<usize>) {
+        let _max_breadcrumbs = max_breadcrumbs;
+        minimal_unreachable!();
+    }
+
+    /// Detaches the scope from the current Release Health Session.
+    pub fn detach_session(&mut self) {
+        minimal_unreachable!();
+    }
+
     /// Sets a level override.
     pub fn set_level(&mut self, level: Option<Level>) {
         let _level = level;
@@ -49,6 +60,11 @@ impl Scope {
         minimal_unreachable!();
     }
 
+    /// Returns the transaction.
+    pub fn transaction(&self) -> Option<&str> {
+        minimal_unreachable!();
+    }
+
     /// Sets the user for the current scope.
     pub fn set_user(&mut self, user: Option<User>) {
         let _user = user;
@@ -103,6 +119,15 @@ impl Scope {
         minimal_unreachable!();
     }
 
+    /// Add an event processor that runs after all other event processors.
+    pub fn add_final_event_processor(
+        &mut self,
+        f: Box<dyn Fn(Event<'static>) -> Option<Event<'static>> + Send + Sync>,
+    ) {
+        let _f = f;
+        minimal_unreachable!();
+    }
+
     /// Applies the contained scoped data to fill an event.
     pub fn apply_to_event(&self, event: Event<'static>) -> Option<Event<'static>> {
         let _event = event;
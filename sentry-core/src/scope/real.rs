@@ -38,11 +38,14 @@ pub struct Scope {
     pub(crate) fingerprint: Option<Arc<[Cow<'static, str>]>>,
     pub(crate) transaction: Option<Arc<str>>,
     pub(crate) breadcrumbs: Arc<VecDeque<Breadcrumb>>,
+    pub(crate) max_breadcrumbs: Option<usize>,
+    pub(crate) breadcrumbs_dropped: usize,
     pub(crate) user: Option<Arc<User>>,
     pub(crate) extra: Arc<HashMap<String, Value>>,
     pub(crate) tags: Arc<HashMap<String, String>>,
     pub(crate) contexts: Arc<HashMap<String, Context>>,
     pub(crate) event_processors: Arc<Vec<Arc<EventProcessor>>>,
+    pub(crate) final_event_processors: Arc<Vec<Arc<EventProcessor>>>,
     pub(crate) session: Arc<Mutex<Option<Session>>>,
 }
 
@@ -53,11 +56,14 @@ impl fmt::Debug for Scope {
             .field("fingerprint", &self.fingerprint)
             .field("transaction", &self.transaction)
             .field("breadcrumbs", &self.breadcrumbs)
+            .field("max_breadcrumbs", &self.max_breadcrumbs)
+            .field("breadcrumbs_dropped", &self.breadcrumbs_dropped)
             .field("user", &self.user)
             .field("extra", &self.extra)
             .field("tags", &self.tags)
             .field("contexts", &self.contexts)
             .field("event_processors", &self.event_processors.len())
+            .field("final_event_processors", &self.final_event_processors.len())
             .field("session", &self.session)
             .finish()
     }
@@ -70,11 +76,14 @@ impl Default for Scope {
             fingerprint: None,
             transaction: None,
             breadcrumbs: Default::default(),
+            max_breadcrumbs: None,
+            breadcrumbs_dropped: 0,
             user: None,
             extra: Default::default(),
             tags: Default::default(),
             contexts: Default::default(),
             event_processors: Default::default(),
+            final_event_processors: Default::default(),
             session: Default::default(),
         }
     }
@@ -159,6 +168,24 @@ impl Scope {
     /// Deletes current breadcrumbs from the scope.
     pub fn clear_breadcrumbs(&mut self) {
         self.breadcrumbs = Default::default();
+        self.breadcrumbs_dropped = 0;
+    }
+
+    /// Overrides the maximum number of breadcrumbs kept by the scope.
+    ///
+    /// By default, the `max_breadcrumbs` option of the client applies.  When an
+    /// override is set, the number of breadcrumbs dropped because of it is added
+    /// to events as the `breadcrumbs_dropped` extra.
+    pub fn set_max_breadcrumbs(&mut self, max_breadcrumbs: Option<usize>) {
+        self.max_breadcrumbs = max_breadcrumbs;
+    }
+
+    /// Detaches the scope from the current Release Health Session.
+    ///
+    /// Events captured with this scope no longer update the session, and ending
+    /// the session on a hub using this scope does not end the original session.
+    pub fn detach_session(&mut self) {
+        self.session = Default::default();
     }
 
     /// Sets a level override.
@@ -177,6 +204,11 @@ impl Scope {
         self.transaction = transaction.map(Arc::from);
     }
 
+    /// Returns the transaction.
+    pub fn transaction(&self) -> Option<&str> {
+        self.transaction.as_deref()
+    }
+
     /// Sets the user for the current scope.
     pub fn set_user(&mut self, user: Option<User>) {
         self.user = user.map(Arc::new);
@@ -222,6 +254,18 @@ impl Scope {
         Arc::make_mut(&mut self.event_processors).push(Arc::new(f));
     }
 
+    /// Add an event processor that runs after all other event processors.
+    ///
+    /// Processors added with [`add_event_processor`](Self::add_event_processor),
+    /// even after this one, run first.  Final processors run in the order they
+    /// were added.
+    pub fn add_final_event_processor(
+        &mut self,
+        f: Box<dyn Fn(Event<'static>) -> Option<Event<'static>> + Send + Sync>,
+    ) {
+        Arc::make_mut(&mut self.final_event_processors).push(Arc::new(f));
+    }
+
     /// Applies the contained scoped data to fill an event.
     pub fn apply_to_event(&self, mut event: Event<'static>) -> Option<Event<'static>> {
         // TODO: event really should have an optional level
@@ -236,6 +280,12 @@ impl Scope {
         }
 
         event.breadcrumbs.extend(self.breadcrumbs.iter().cloned());
+        if self.breadcrumbs_dropped > 0 {
+            event.extra.insert(
+                "breadcrumbs_dropped".into(),
+                self.breadcrumbs_dropped.into(),
+            );
+        }
         event
             .extra
             .extend(self.extra.iter().map(|(k, v)| (k.to_owned(), v.to_owned())));
@@ -262,7 +312,8 @@ impl Scope {
             }
         }
 
-        for processor in self.event_processors.as_ref() {
+        let processors = self.event_processors.iter();
+        for processor in processors.chain(self.final_event_processors.iter()) {
             let id = event.event_id;
             event = match processor(event) {
                 Some(event) => event,
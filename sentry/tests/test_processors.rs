@@ -30,6 +30,29 @@ fn test_event_processors() {
     );
 }
 
+#[test]
+fn test_final_event_processors() {
+    let events = sentry::test::with_captured_events(|| {
+        sentry::configure_scope(|scope| {
+            scope.add_final_event_processor(Box::new(move |mut event| {
+                event.message = event.message.map(|message| format!("{} last", message));
+                Some(event)
+            }));
+            scope.add_event_processor(Box::new(move |mut event| {
+                event.message = event.message.map(|message| format!("{} first", message));
+                Some(event)
+            }));
+        });
+        sentry::capture_message("Hello World!", sentry::Level::Warning);
+    });
+
+    assert_eq!(events.len(), 1);
+    assert_eq!(
+        events[0].message.as_deref(),
+        Some("Hello World! first last")
+    );
+}
+
 #[test]
 fn test_before_callbacks() {
     fn before_send(